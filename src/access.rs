@@ -0,0 +1,168 @@
+// Which databases and tables can be written to and read from
+//
+// [allow]
+// databases = ["site", "lab"]
+// tables = ["events", "readings"]
+// existing_only = true
+//
+// [deny]
+// tables = ["debug"]
+//
+// [databases.lab]
+// tables = ["readings"]
+//
+// An empty list allows any name, requests for anything else are refused with
+// 403 Forbidden, as are requests for a denied name whether it is allowed or
+// not. With `existing_only` writes only go to tables which already exist,
+// databases and tables are never created, so the tables a template resolves
+// to have to be created ahead of time.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::errors::Error;
+use crate::storage::{self, Storage};
+
+/// The database and table names requests are allowed to use
+#[derive(Clone, Debug, Default)]
+pub struct AccessRules {
+    pub databases: Vec<String>,
+    pub tables: Vec<String>,
+    /// Tables allowed in a single database
+    pub database_tables: HashMap<String, Vec<String>>,
+    pub denied_databases: Vec<String>,
+    pub denied_tables: Vec<String>,
+    /// Only tables which already exist are written to when set
+    pub existing_only: Option<ExistingTables>,
+}
+
+// An empty list allows any name
+fn allows(names: &[String], name: &str) -> bool {
+    names.is_empty() || names.iter().any(|allowed| allowed == name)
+}
+
+impl AccessRules {
+    /// Make sure a table of a database can be used, the reason is returned when not
+    pub fn check(&self, database: &str, table: &str) -> Result<(), String> {
+        if self
+            .denied_databases
+            .iter()
+            .any(|denied| denied == database)
+        {
+            return Err(format!("database {database} is denied"));
+        }
+        if self.denied_tables.iter().any(|denied| denied == table) {
+            return Err(format!("table {table} is denied"));
+        }
+        if !allows(&self.databases, database) {
+            return Err(format!("database {database} is not allowed"));
+        }
+        let database_tables = self.database_tables.get(database);
+        if !allows(&self.tables, table)
+            || database_tables.is_some_and(|tables| !allows(tables, table))
+        {
+            return Err(format!(
+                "table {table} is not allowed in database {database}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The tables seen to exist, which are never dropped while the server runs
+#[derive(Clone, Debug, Default)]
+pub struct ExistingTables {
+    seen: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl ExistingTables {
+    /// Make sure a table exists before it is written to
+    pub async fn check(
+        &self,
+        storage: &dyn Storage,
+        database: &str,
+        table: &str,
+    ) -> Result<(), Error> {
+        let key = (database.to_string(), table.to_string());
+        if self.seen.lock().unwrap().contains(&key) {
+            return Ok(());
+        }
+        let tables = match storage.tables(database).await {
+            Ok(tables) => tables,
+            Err(storage::Error::NotFound(_)) => {
+                return Err(Error::Forbidden(format!(
+                    "database {database} does not exist and is not created"
+                )))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !tables.iter().any(|name| name == table) {
+            return Err(Error::Forbidden(format!(
+                "table {table} does not exist in database {database} and is not created"
+            )));
+        }
+        self.seen.lock().unwrap().insert(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{record, SqliteStorage};
+
+    #[test]
+    fn test_check() {
+        let rules = AccessRules::default();
+        assert!(rules.check("site", "events").is_ok());
+
+        let rules = AccessRules {
+            databases: vec![String::from("site"), String::from("lab")],
+            tables: vec![String::from("events"), String::from("readings")],
+            database_tables: HashMap::from([(String::from("lab"), vec![String::from("readings")])]),
+            ..Default::default()
+        };
+        assert!(rules.check("site", "events").is_ok());
+        assert!(rules.check("lab", "readings").is_ok());
+        assert!(rules.check("lab", "events").is_err());
+        assert!(rules.check("site", "logs").is_err());
+        assert!(rules.check("other", "events").is_err());
+
+        // Denied names are refused even when they are allowed
+        let rules = AccessRules {
+            tables: vec![String::from("events"), String::from("debug")],
+            denied_databases: vec![String::from("scratch")],
+            denied_tables: vec![String::from("debug")],
+            ..Default::default()
+        };
+        assert!(rules.check("site", "events").is_ok());
+        assert!(rules.check("site", "debug").is_err());
+        assert!(rules.check("scratch", "events").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_existing_tables() {
+        let database_files = std::env::temp_dir().join("adr_test_existing_tables");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let storage = SqliteStorage::new(database_files.to_str().unwrap());
+        let existing = ExistingTables::default();
+
+        // Nothing exists yet, and nothing is created by checking
+        assert!(matches!(
+            existing.check(&storage, "site", "events").await,
+            Err(Error::Forbidden(_))
+        ));
+        assert!(storage.databases().await.unwrap().is_empty());
+
+        let record = record("{}");
+        storage
+            .insert_batch("site", "events", vec![record])
+            .await
+            .unwrap();
+        assert!(existing.check(&storage, "site", "events").await.is_ok());
+        assert!(existing.check(&storage, "site", "readings").await.is_err());
+
+        // Post test, remove any database files created
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+// Where each stored record came from
+//
+// [server]
+// record_metadata = true
+// trusted_proxies = ["10.0.0.0/8", "127.0.0.1"]
+//
+// Every response carries an `X-Request-Id` header, the id the client sent
+// or one made up for the request. With `record_metadata` (or
+// `--record-metadata`) the rows a request writes store the address of the
+// client, its `User-Agent` and the request id in the `source_ip`,
+// `user_agent` and `request_id` columns of the table.
+//
+// The address of the client is the peer of the connection, unless the peer
+// is one of the `trusted_proxies`. Then `X-Forwarded-For` is read from the
+// right, each trusted proxy naming the address it was reached from, and the
+// first address which is not a trusted proxy is the client. Addresses added
+// by the client itself are never read, so they can't be spoofed.
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderName, HeaderValue},
+    middleware::Next,
+    HttpMessage, HttpRequest,
+};
+
+// Network address types for Rust
+// https://docs.rs/ipnet/latest/ipnet/
+// cargo add ipnet
+use ipnet::IpNet;
+
+// Generate and parse UUIDs
+// https://docs.rs/uuid/latest/uuid/
+// cargo add uuid --features v4
+use uuid::Uuid;
+
+use crate::storage::RecordMetadata;
+
+/// The header naming the request a response answers
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest request id kept from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of a request, in the extensions of every request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+/// The proxies whose `X-Forwarded-For` header is believed
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Parse addresses such as `127.0.0.1` and networks such as `10.0.0.0/8`
+    pub fn parse(proxies: &[String]) -> Result<Self, String> {
+        let networks = proxies
+            .iter()
+            .map(|proxy| match proxy.parse::<IpAddr>() {
+                Ok(addr) => Ok(IpNet::from(addr)),
+                Err(_) => proxy
+                    .parse::<IpNet>()
+                    .map_err(|err| format!("invalid trusted proxy {proxy}: {err}")),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&addr))
+    }
+}
+
+/// The address of the client, read through the trusted proxies in front of
+/// the peer of the connection
+pub fn client_ip(peer: IpAddr, forwarded_for: &[&str], trusted: &TrustedProxies) -> IpAddr {
+    let mut client = peer.to_canonical();
+    let hops = forwarded_for
+        .iter()
+        .rev()
+        .flat_map(|value| value.rsplit(','));
+    for hop in hops {
+        if !trusted.contains(client) {
+            break;
+        }
+        match parse_hop(hop.trim()) {
+            Some(addr) => client = addr.to_canonical(),
+            None => break,
+        }
+    }
+    client
+}
+
+// An address of `X-Forwarded-For`, which some proxies send with a port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// The metadata recorded with the rows a request writes
+pub fn record_metadata(req: &HttpRequest, trusted: &TrustedProxies) -> RecordMetadata {
+    let forwarded_for: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    RecordMetadata {
+        source_ip: req
+            .peer_addr()
+            .map(|peer| client_ip(peer.ip(), &forwarded_for, trusted).to_string()),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        request_id: req
+            .extensions()
+            .get::<RequestId>()
+            .map(|request_id| request_id.0.clone()),
+    }
+}
+
+// The id sent by the client when it is short visible ASCII, or a new one
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Middleware giving every request an id, answered in `X-Request-Id`
+/// App::new().wrap(from_fn(audit::assign_request_id))
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = request_id(&req);
+    let value = HeaderValue::from_str(&id);
+    req.extensions_mut().insert(RequestId(id));
+    let mut res = next.call(req).await?;
+    if let Ok(value) = value {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{middleware::from_fn, web, App, HttpResponse};
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted = TrustedProxies::parse(&[
+            String::from("10.0.0.0/8"),
+            String::from("127.0.0.1"),
+            String::from("fd00::/8"),
+        ])
+        .unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("127.0.0.1".parse().unwrap()));
+        assert!(trusted.contains("fd00::1".parse().unwrap()));
+        assert!(!trusted.contains("127.0.0.2".parse().unwrap()));
+        assert!(TrustedProxies::parse(&[String::from("10.0.0.0/33")]).is_err());
+        assert!(TrustedProxies::parse(&[String::from("proxy")]).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = TrustedProxies::parse(&[String::from("10.0.0.0/8")]).unwrap();
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        // A client connecting directly is the peer, whatever it claims
+        let client = client_ip(ip("203.0.113.7"), &["198.51.100.1"], &trusted);
+        assert_eq!(client, ip("203.0.113.7"));
+
+        // Through trusted proxies the first untrusted address from the right
+        let client = client_ip(
+            ip("10.0.0.2"),
+            &["198.51.100.1, 203.0.113.7", "10.0.0.1"],
+            &trusted,
+        );
+        assert_eq!(client, ip("203.0.113.7"));
+
+        // Ports and IPv4 mapped IPv6 peers
+        let client = client_ip(ip("::ffff:10.0.0.2"), &["203.0.113.7:5443"], &trusted);
+        assert_eq!(client, ip("203.0.113.7"));
+
+        // Every hop is trusted, or the header can't be read
+        let client = client_ip(ip("10.0.0.2"), &["10.0.0.1"], &trusted);
+        assert_eq!(client, ip("10.0.0.1"));
+        let client = client_ip(ip("10.0.0.2"), &["unknown"], &trusted);
+        assert_eq!(client, ip("10.0.0.2"));
+        let client = client_ip(ip("10.0.0.2"), &[], &trusted);
+        assert_eq!(client, ip("10.0.0.2"));
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = init_service(App::new().wrap(from_fn(assign_request_id)).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move {
+                let trusted = TrustedProxies::parse(&[String::from("127.0.0.1")]).unwrap();
+                let metadata = record_metadata(&req, &trusted);
+                HttpResponse::Ok().body(format!("{metadata:?}"))
+            }),
+        ))
+        .await;
+
+        // An id is made up for a request without one
+        let req = TestRequest::get().uri("/").to_request();
+        let res = call_service(&app, req).await;
+        let id = res
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+
+        // The id of the client is kept and recorded
+        let req = TestRequest::get()
+            .uri("/")
+            .peer_addr("127.0.0.1:40000".parse().unwrap())
+            .insert_header((REQUEST_ID_HEADER, "req-7f3c"))
+            .insert_header((header::USER_AGENT, "sensor/1.2"))
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "req-7f3c");
+        let body = read_body(res).await;
+        let expected = RecordMetadata {
+            source_ip: Some(String::from("203.0.113.7")),
+            user_agent: Some(String::from("sensor/1.2")),
+            request_id: Some(String::from("req-7f3c")),
+        };
+        assert_eq!(body, format!("{expected:?}"));
+
+        // An id which is not short visible ASCII is replaced
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "a".repeat(MAX_REQUEST_ID_LEN + 1)))
+            .to_request();
+        let res = call_service(&app, req).await;
+        let id = res
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+    }
+}
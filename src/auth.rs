@@ -0,0 +1,160 @@
+// API key authentication
+//
+// [[auth.keys]]
+// name = "gateway"
+// key = "..."
+//
+// When keys are configured every request except /ping, the /healthz probes
+// and the API documentation (/openapi.json and /_docs/) has to carry one of
+// them, either as `Authorization: Bearer <key>` or as `X-API-Key: <key>`.
+// The name of the key is available to table templates as {api_key_name}.
+//
+// [auth]
+// debug_keys = ["oncall"]
+//
+// Profiles and the log filter are only answered for the keys named in
+// `debug_keys`, so without API keys nobody can use them.
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap},
+    middleware::Next,
+    web, HttpMessage, HttpRequest,
+};
+use serde::Deserialize;
+
+use crate::errors::Error;
+
+/// Paths answered without a key
+const PUBLIC_PATHS: &[&str] = &[
+    "/ping",
+    "/healthz",
+    "/healthz/live",
+    "/healthz/ready",
+    "/openapi.json",
+];
+
+/// The prefix of the Swagger UI pages, answered without a key
+const DOCS_PREFIX: &str = "/_docs";
+
+/// Whether a path is answered without a key
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path)
+        || path
+            .strip_prefix(DOCS_PREFIX)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// A named API key
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+}
+
+/// The keys accepted by the server, any request is accepted when empty
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    pub keys: Vec<ApiKey>,
+}
+
+/// The name of the key a request was authenticated with
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKeyName(pub String);
+
+impl ApiKeys {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The key matching what a request presented
+    pub fn find(&self, presented: &str) -> Option<&ApiKey> {
+        self.keys
+            .iter()
+            .find(|key| constant_time_eq(key.key.as_bytes(), presented.as_bytes()))
+    }
+}
+
+// Compare without returning early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// The key presented by a request, if any
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    headers.get("X-API-Key")?.to_str().ok()
+}
+
+// The key name of an authenticated request, nothing when no key is needed
+fn authenticate(req: &ServiceRequest) -> Result<Option<ApiKeyName>, Error> {
+    let Some(api_keys) = req.app_data::<web::Data<ApiKeys>>() else {
+        return Ok(None);
+    };
+    if api_keys.is_empty() || is_public(req.path()) {
+        return Ok(None);
+    }
+    let presented = presented_key(req.headers())
+        .ok_or_else(|| Error::Unauthorized(String::from("an API key is required")))?;
+    let api_key = api_keys
+        .find(presented)
+        .ok_or_else(|| Error::Unauthorized(String::from("invalid API key")))?;
+    Ok(Some(ApiKeyName(api_key.name.clone())))
+}
+
+/// Middleware refusing requests without a valid key
+/// App::new().app_data(web::Data::new(api_keys)).wrap(from_fn(auth::require_api_key))
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    match authenticate(&req) {
+        Ok(api_key_name) => {
+            if let Some(api_key_name) = api_key_name {
+                req.extensions_mut().insert(api_key_name);
+            }
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Err(err) => Ok(req.error_response(err).map_into_right_body()),
+    }
+}
+
+/// Refuse a request unless it was made with one of the debug keys, `what`
+/// names what it asked for in the error
+pub fn authorize_debug(req: &HttpRequest, debug_keys: &[String], what: &str) -> Result<(), Error> {
+    match req.extensions().get::<ApiKeyName>() {
+        Some(ApiKeyName(name)) if debug_keys.contains(name) => Ok(()),
+        _ => Err(Error::Forbidden(format!(
+            "{what} needs an API key listed in auth.debug_keys"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_authorize_debug() {
+        let debug_keys = vec![String::from("oncall")];
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(
+            authorize_debug(&req, &debug_keys, "a profile"),
+            Err(Error::Forbidden(_))
+        ));
+        req.extensions_mut()
+            .insert(ApiKeyName(String::from("gateway")));
+        assert!(authorize_debug(&req, &debug_keys, "a profile").is_err());
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut()
+            .insert(ApiKeyName(String::from("oncall")));
+        assert!(authorize_debug(&req, &debug_keys, "a profile").is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+// Online backups of the databases
+//
+// [backup]
+// directory = "/var/backups/receiver"
+// interval = "6h"
+// keep = 7
+//
+// curl -i -X POST http://localhost:8888/_admin/databases/site/backup
+//
+// A backup is a consistent copy of a SQLite database taken with the online
+// backup API while the database carries on taking writes, written to
+// <directory>/<database name>/<UTC time>.db, e.g.
+// /var/backups/receiver/site/20240903T140000.000Z.db
+// A backup is written to a `.partial` file first, so a file named `.db` is
+// always a complete backup. After each backup only the last `keep` backups of
+// the database are kept, every backup with `keep = 0`.
+//
+// Every `interval` each database is backed up, by the leader alone when
+// instances elect one (see leader.rs). PostgreSQL databases are backed up
+// with pg_dump instead.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::{spawn, time};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+
+use crate::leader::Leader;
+use crate::metrics;
+use crate::storage::{self, Storage, StorageResult};
+
+/// Where backups are written and how often
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupSettings {
+    pub directory: PathBuf,
+    /// Back up every database this often, only on request when unset
+    pub interval: Option<Duration>,
+    /// The backups of a database kept, 0 keeps every backup
+    pub keep: usize,
+}
+
+/// A backup written
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct BackupFile {
+    pub database: String,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+/// Back up a database, then remove the backups of it beyond the last `keep`
+pub async fn backup(
+    storage: &dyn Storage,
+    settings: &BackupSettings,
+    database: &str,
+) -> StorageResult<BackupFile> {
+    storage::validate_name(database)?;
+    let result = write(storage, settings, database).await;
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    metrics::BACKUPS
+        .with_label_values(&[database, outcome])
+        .inc();
+    result
+}
+
+async fn write(
+    storage: &dyn Storage,
+    settings: &BackupSettings,
+    database: &str,
+) -> StorageResult<BackupFile> {
+    let directory = settings.directory.join(database);
+    fs::create_dir_all(&directory).map_err(|err| io_error(&directory, err))?;
+    let name = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let path = directory.join(format!("{name}.db"));
+    let partial = directory.join(format!("{name}.db.partial"));
+
+    if let Err(err) = storage.backup(database, &partial).await {
+        let _ = fs::remove_file(&partial);
+        // Only removed when it holds no backups
+        let _ = fs::remove_dir(&directory);
+        return Err(err);
+    }
+    fs::rename(&partial, &path).map_err(|err| io_error(&path, err))?;
+    let size_bytes = fs::metadata(&path)
+        .map_err(|err| io_error(&path, err))?
+        .len();
+    info!("backed up {database} to {}", path.display());
+
+    prune(&directory, settings.keep).map_err(|err| io_error(&directory, err))?;
+    Ok(BackupFile {
+        database: database.to_string(),
+        path,
+        size_bytes,
+    })
+}
+
+// Remove the oldest backups in a directory beyond the last `keep`
+fn prune(directory: &Path, keep: usize) -> std::io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let mut backups: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "db"))
+        .collect();
+    // The names are times which sort as text
+    backups.sort();
+    for path in &backups[..backups.len().saturating_sub(keep)] {
+        fs::remove_file(path)?;
+        debug!("removed backup {}", path.display());
+    }
+    Ok(())
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> storage::Error {
+    storage::Error::Internal(format!("{}: {err}", path.display()))
+}
+
+/// Back up every database in the background every `interval` while this
+/// instance leads
+pub fn spawn_backup_task(storage: Arc<dyn Storage>, settings: BackupSettings, leader: Leader) {
+    let Some(interval) = settings.interval else {
+        return;
+    };
+    info!(
+        "Backing up the databases to {} every {}",
+        settings.directory.display(),
+        humantime::format_duration(interval)
+    );
+    spawn(async move {
+        let mut ticks = time::interval(interval);
+        // The first backup is taken one interval after starting
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if !leader.is_leader() {
+                debug!("backups left to the leader");
+                continue;
+            }
+            let databases = match storage.databases().await {
+                Ok(databases) => databases,
+                Err(err) => {
+                    error!("unable to list the databases to back up: {err}");
+                    continue;
+                }
+            };
+            for database in databases {
+                if let Err(err) = backup(storage.as_ref(), &settings, &database).await {
+                    error!("backup of {database} failed: {err}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rusqlite::Connection;
+
+    use crate::storage::{record, SqliteStorage};
+
+    #[actix_web::test]
+    async fn test_backup() {
+        let database_files = std::env::temp_dir().join("adr_test_backup");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let storage = SqliteStorage::new(database_files.to_str().unwrap());
+        let records = (0..3).map(|_| record("{}")).collect();
+        storage
+            .insert_batch("site", "events", records)
+            .await
+            .unwrap();
+        let settings = BackupSettings {
+            directory: database_files.join("backups"),
+            interval: None,
+            keep: 2,
+        };
+
+        // Only the last two backups are kept
+        let mut paths = vec![];
+        for _ in 0..3 {
+            let backup = backup(&storage, &settings, "site").await.unwrap();
+            assert!(backup.size_bytes > 0);
+            paths.push(backup.path);
+            time::sleep(Duration::from_millis(2)).await;
+        }
+        assert!(!paths[0].exists());
+        assert_eq!(
+            fs::read_dir(settings.directory.join("site"))
+                .unwrap()
+                .count(),
+            2
+        );
+
+        // A backup holds the rows of the database
+        let conn = Connection::open(&paths[2]).unwrap();
+        let rows: i64 = conn
+            .query_row("SELECT count(*) FROM events;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 3);
+
+        // A missing database is not backed up
+        assert!(matches!(
+            backup(&storage, &settings, "missing").await,
+            Err(storage::Error::NotFound(_))
+        ));
+        assert!(backup(&storage, &settings, "_leases").await.is_err());
+
+        // Post test, remove any database files created
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+// Compressed exports
+//
+// [export]
+// compression = "zstd"
+//
+// curl -o readings.ndjson.zst 'http://localhost:8888/site/readings/export?compression=zstd'
+// curl --compressed -o readings.ndjson 'http://localhost:8888/site/readings/export'
+//
+// An export is written compressed with gzip, zstd or lz4 in two ways
+// - as a compressed file, asked for with `compression` in the query string or
+//   by default with `compression` in the [export] configuration, the file
+//   name gets the extension of the codec, e.g. readings.csv.gz
+// - as the `Content-Encoding` of the response, when the request sends an
+//   `Accept-Encoding` naming one of them and no file compression applies, so
+//   HTTP clients decompress it as it arrives
+// `compression=none` asks for an uncompressed file when one is configured.
+// zstd and lz4 are built with the `zstd` and `lz4` features.
+//
+// Each codec implements `Codec`, anything writing compressed data streams it
+// through the `Encoder` a codec makes, a chunk at a time.
+use std::io::{self, Write};
+
+use actix_web::{
+    http::header::{self, HeaderMap},
+    web::Bytes,
+};
+use clap::ValueEnum;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+// DEFLATE, zlib and gzip compression
+// https://docs.rs/flate2/latest/flate2/
+// cargo add flate2
+use flate2::write::GzEncoder;
+
+// LZ4 frame compression
+// https://docs.rs/lz4_flex/latest/lz4_flex/
+// cargo add lz4_flex --optional
+#[cfg(feature = "lz4")]
+use lz4_flex::frame::FrameEncoder;
+
+// Zstandard compression
+// https://docs.rs/zstd/latest/zstd/
+// cargo add zstd --optional
+#[cfg(feature = "zstd")]
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+/// A compression format
+pub trait Codec: Send + Sync {
+    /// The `Content-Encoding` naming the codec
+    fn encoding(&self) -> &'static str;
+
+    /// The extension of files compressed with the codec
+    fn extension(&self) -> &'static str;
+
+    /// The media type of files compressed with the codec
+    fn content_type(&self) -> &'static str;
+
+    /// Start compressing a stream
+    fn encoder(&self) -> io::Result<Box<dyn Encoder>>;
+}
+
+/// Compresses a stream a chunk at a time
+pub trait Encoder: Send {
+    /// Compress a chunk, returning what has been compressed so far
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes>;
+
+    /// End the stream, returning the rest of it
+    fn finish(self: Box<Self>) -> io::Result<Bytes>;
+}
+
+/// gzip, as gzip(1) and every HTTP client read it
+pub struct Gzip;
+
+impl Codec for Gzip {
+    fn encoding(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn extension(&self) -> &'static str {
+        "gz"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/gzip"
+    }
+
+    fn encoder(&self) -> io::Result<Box<dyn Encoder>> {
+        Ok(Box::new(Streaming {
+            inner: GzEncoder::new(Vec::new(), flate2::Compression::default()),
+            buffer: GzEncoder::get_mut,
+            finish: GzEncoder::finish,
+        }))
+    }
+}
+
+/// Zstandard, smaller than gzip and faster to compress and decompress
+#[cfg(feature = "zstd")]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn encoding(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn extension(&self) -> &'static str {
+        "zst"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/zstd"
+    }
+
+    fn encoder(&self) -> io::Result<Box<dyn Encoder>> {
+        Ok(Box::new(Streaming {
+            inner: ZstdEncoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)?,
+            buffer: ZstdEncoder::get_mut,
+            finish: ZstdEncoder::finish,
+        }))
+    }
+}
+
+/// LZ4 frames, the fastest of them and the largest
+#[cfg(feature = "lz4")]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn encoding(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn extension(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/x-lz4"
+    }
+
+    fn encoder(&self) -> io::Result<Box<dyn Encoder>> {
+        Ok(Box::new(Streaming {
+            inner: FrameEncoder::new(Vec::new()),
+            buffer: FrameEncoder::get_mut,
+            finish: |encoder| encoder.finish().map_err(io::Error::other),
+        }))
+    }
+}
+
+// An encoder writing to a buffer which is taken after every chunk
+struct Streaming<W> {
+    inner: W,
+    buffer: fn(&mut W) -> &mut Vec<u8>,
+    finish: fn(W) -> io::Result<Vec<u8>>,
+}
+
+impl<W: Write + Send> Encoder for Streaming<W> {
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        self.inner.write_all(chunk)?;
+        Ok(Bytes::from(std::mem::take((self.buffer)(&mut self.inner))))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        (self.finish)(self.inner).map(Bytes::from)
+    }
+}
+
+/// The compressions an export can be asked for with
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// The codec compressing with it, none for `None`
+    pub fn codec(&self) -> Option<&'static dyn Codec> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(&Gzip),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some(&Zstd),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(&Lz4),
+        }
+    }
+
+    /// The preferred codec the `Accept-Encoding` header of a request names
+    /// Codecs are preferred by their quality value, then zstd, gzip and lz4
+    pub fn accepted(headers: &HeaderMap) -> Option<&'static dyn Codec> {
+        let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+        let mut best: Option<(f32, &'static dyn Codec)> = None;
+        let codecs: &[&'static dyn Codec] = &[
+            #[cfg(feature = "zstd")]
+            &Zstd,
+            &Gzip,
+            #[cfg(feature = "lz4")]
+            &Lz4,
+        ];
+        for &codec in codecs {
+            let quality = quality(accept, codec.encoding());
+            if quality > 0.0 && best.is_none_or(|(best, _)| quality > best) {
+                best = Some((quality, codec));
+            }
+        }
+        best.map(|(_, codec)| codec)
+    }
+}
+
+// The quality value an Accept-Encoding gives a coding, a coding it names
+// overrides `*` and a coding it leaves out is not acceptable
+// https://www.rfc-editor.org/rfc/rfc9110#name-accept-encoding
+fn quality(accept: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for coding in accept.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |quality| quality.trim().parse().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use actix_web::http::header::HeaderValue;
+
+    #[test]
+    fn test_codecs() {
+        let chunks = [&b"{\"count\": 1}\n"[..], &b"{\"count\": 2}\n"[..]];
+        let codecs: &[&dyn Codec] = &[
+            &Gzip,
+            #[cfg(feature = "zstd")]
+            &Zstd,
+            #[cfg(feature = "lz4")]
+            &Lz4,
+        ];
+        for &codec in codecs {
+            let mut encoder = codec.encoder().unwrap();
+            let mut compressed = vec![];
+            for chunk in chunks {
+                compressed.extend_from_slice(&encoder.write(chunk).unwrap());
+            }
+            compressed.extend_from_slice(&encoder.finish().unwrap());
+
+            let mut decompressed = vec![];
+            let mut reader: Box<dyn Read> = match codec.encoding() {
+                "gzip" => Box::new(flate2::read::GzDecoder::new(&compressed[..])),
+                #[cfg(feature = "zstd")]
+                "zstd" => Box::new(zstd::stream::read::Decoder::new(&compressed[..]).unwrap()),
+                #[cfg(feature = "lz4")]
+                "lz4" => Box::new(lz4_flex::frame::FrameDecoder::new(&compressed[..])),
+                encoding => unreachable!("{encoding}"),
+            };
+            reader.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, chunks.concat(), "{}", codec.encoding());
+        }
+    }
+
+    #[test]
+    fn test_accepted() {
+        let accepted = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            Compression::accepted(&headers).map(|codec| codec.encoding())
+        };
+        assert_eq!(accepted("gzip, deflate, br"), Some("gzip"));
+        assert_eq!(accepted("zstd;q=0.5, gzip"), Some("gzip"));
+        assert_eq!(accepted("zstd;q=0, *;q=0.5"), Some("gzip"));
+        assert_eq!(accepted("br, identity"), None);
+        assert!(Compression::accepted(&HeaderMap::new()).is_none());
+
+        // Codecs left out of the build are not acceptable
+        let zstd = cfg!(feature = "zstd").then_some("zstd");
+        assert_eq!(accepted("gzip, zstd"), zstd.or(Some("gzip")));
+        assert_eq!(accepted("*"), zstd.or(Some("gzip")));
+        let lz4 = cfg!(feature = "lz4").then_some("lz4");
+        assert_eq!(accepted("lz4, zstd;q=0"), lz4);
+    }
+}
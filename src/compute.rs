@@ -0,0 +1,595 @@
+// Computed fields added to each document at insert
+//
+// --computed-field 'readings:temperature_f=$.temperature_c * 9 / 5 + 32'
+// --computed-field 'readings:hour=date_trunc("hour", now())'
+// --computed-field 'readings:site=lookup("sites", $.device_id)'
+//
+// An expression is made of
+//   numbers, 'strings' or "strings", true, false, null
+//   JSON paths into the document such as $.device.id
+//   + - * / % on numbers and ( ) to group
+//   concat(a, b, ...)        the values joined as text, null is left out
+//   coalesce(a, b, ...)      the first value which is not null
+//   date_trunc(unit, value)  an RFC 3339 date or unix seconds truncated to a
+//                            second, minute, hour, day, month or year
+//   lookup(map, key)         the value of a key in a static map of the config file
+//   now()                    the insert timestamp
+//
+// A value which does not fit an operation, a number times a string for one,
+// gives null instead of refusing the document.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, SecondsFormat, Timelike, Utc};
+use serde::Deserialize;
+use serde_json::{Number, Value};
+
+use crate::jsonpath::JsonPath;
+
+/// Static maps used by `lookup()`, by name
+pub type Maps = HashMap<String, HashMap<String, Value>>;
+
+/// An arithmetic operator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+/// The units `date_trunc()` can truncate to
+const DATE_UNITS: &[&str] = &["second", "minute", "hour", "day", "month", "year"];
+
+/// A parsed expression
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Expr {
+    Literal(Value),
+    Path(JsonPath),
+    Negate(Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+    Concat(Vec<Expr>),
+    Coalesce(Vec<Expr>),
+    DateTrunc(String, Box<Expr>),
+    Lookup(String, Box<Expr>),
+    Now,
+}
+
+/// What an expression is evaluated against
+pub struct Context<'a> {
+    pub document: &'a Value,
+    pub timestamp: DateTime<Utc>,
+    pub maps: &'a Maps,
+}
+
+impl Expr {
+    /// The value of the expression for a document
+    pub fn eval(&self, context: &Context) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Path(path) => path
+                .select(context.document)
+                .cloned()
+                .unwrap_or(Value::Null),
+            Expr::Negate(expr) => match expr.eval(context) {
+                Value::Number(number) => match number.as_i64() {
+                    Some(integer) => integer.checked_neg().map_or(Value::Null, Value::from),
+                    None => float(-number.as_f64().unwrap_or(f64::NAN)),
+                },
+                _ => Value::Null,
+            },
+            Expr::Binary(operator, left, right) => {
+                match (left.eval(context), right.eval(context)) {
+                    (Value::Number(left), Value::Number(right)) => {
+                        arithmetic(*operator, &left, &right)
+                    }
+                    _ => Value::Null,
+                }
+            }
+            Expr::Concat(exprs) => Value::String(
+                exprs
+                    .iter()
+                    .map(|expr| match expr.eval(context) {
+                        Value::Null => String::new(),
+                        Value::String(text) => text,
+                        value => value.to_string(),
+                    })
+                    .collect(),
+            ),
+            Expr::Coalesce(exprs) => exprs
+                .iter()
+                .map(|expr| expr.eval(context))
+                .find(|value| !value.is_null())
+                .unwrap_or(Value::Null),
+            Expr::DateTrunc(unit, expr) => date_trunc(unit, &expr.eval(context))
+                .map(|timestamp| {
+                    Value::from(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                })
+                .unwrap_or(Value::Null),
+            Expr::Lookup(map, expr) => {
+                let key = match expr.eval(context) {
+                    Value::String(key) => key,
+                    Value::Null => return Value::Null,
+                    key => key.to_string(),
+                };
+                context
+                    .maps
+                    .get(map)
+                    .and_then(|map| map.get(&key))
+                    .cloned()
+                    .unwrap_or(Value::Null)
+            }
+            Expr::Now => Value::from(
+                context
+                    .timestamp
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            ),
+        }
+    }
+
+    /// The names of the maps the expression looks up
+    pub fn maps(&self) -> Vec<&str> {
+        match self {
+            Expr::Literal(_) | Expr::Path(_) | Expr::Now => vec![],
+            Expr::Negate(expr) | Expr::DateTrunc(_, expr) => expr.maps(),
+            Expr::Binary(_, left, right) => [left.maps(), right.maps()].concat(),
+            Expr::Concat(exprs) | Expr::Coalesce(exprs) => {
+                exprs.iter().flat_map(|expr| expr.maps()).collect()
+            }
+            Expr::Lookup(map, expr) => [vec![map.as_str()], expr.maps()].concat(),
+        }
+    }
+}
+
+// Numbers stay integers as long as the operation allows it
+fn arithmetic(operator: Operator, left: &Number, right: &Number) -> Value {
+    if let (Some(left), Some(right), true) =
+        (left.as_i64(), right.as_i64(), operator != Operator::Divide)
+    {
+        let result = match operator {
+            Operator::Add => left.checked_add(right),
+            Operator::Subtract => left.checked_sub(right),
+            Operator::Multiply => left.checked_mul(right),
+            Operator::Remainder => left.checked_rem(right),
+            Operator::Divide => None,
+        };
+        return result.map_or(Value::Null, Value::from);
+    }
+    let (Some(left), Some(right)) = (left.as_f64(), right.as_f64()) else {
+        return Value::Null;
+    };
+    float(match operator {
+        Operator::Add => left + right,
+        Operator::Subtract => left - right,
+        Operator::Multiply => left * right,
+        Operator::Divide => left / right,
+        Operator::Remainder => left % right,
+    })
+}
+
+// NaN and infinity are not JSON numbers
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+// An RFC 3339 date or unix seconds truncated to a unit
+fn date_trunc(unit: &str, value: &Value) -> Option<DateTime<Utc>> {
+    let timestamp = match value {
+        Value::String(text) => DateTime::parse_from_rfc3339(text).ok()?.with_timezone(&Utc),
+        Value::Number(number) => DateTime::from_timestamp(number.as_i64()?, 0)?,
+        _ => return None,
+    };
+    let date = timestamp.date_naive();
+    let truncated: NaiveDateTime = match unit {
+        "second" => date.and_hms_opt(timestamp.hour(), timestamp.minute(), timestamp.second())?,
+        "minute" => date.and_hms_opt(timestamp.hour(), timestamp.minute(), 0)?,
+        "hour" => date.and_hms_opt(timestamp.hour(), 0, 0)?,
+        "day" => date.and_hms_opt(0, 0, 0)?,
+        "month" => date.with_day(1)?.and_hms_opt(0, 0, 0)?,
+        "year" => date.with_ordinal(1)?.and_hms_opt(0, 0, 0)?,
+        _ => return None,
+    };
+    Some(truncated.and_utc())
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let tokens =
+            tokenize(expression).map_err(|err| format!("{err} in expression: {expression}"))?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expr = parser
+            .expr()
+            .map_err(|err| format!("{err} in expression: {expression}"))?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {token:?} in expression: {expression}")),
+        }
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Number),
+    Text(String),
+    Path(JsonPath),
+    Name(String),
+    Symbol(char),
+}
+
+// Split an expression into tokens
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut position = 0;
+    while let Some(&c) = chars.get(position) {
+        let start = position;
+        match c {
+            c if c.is_whitespace() => position += 1,
+            '0'..='9' => {
+                while chars
+                    .get(position)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+                {
+                    position += 1;
+                }
+                let number: String = chars[start..position].iter().collect();
+                let number = match number.parse::<i64>() {
+                    Ok(integer) => Number::from(integer),
+                    Err(_) => number
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .ok_or_else(|| format!("bad number '{number}'"))?,
+                };
+                tokens.push(Token::Number(number));
+            }
+            '\'' | '"' => {
+                let mut text = String::new();
+                position += 1;
+                loop {
+                    match chars.get(position) {
+                        None => return Err(String::from("unclosed string")),
+                        Some('\\') => {
+                            text.extend(chars.get(position + 1));
+                            position += 2;
+                        }
+                        Some(&quote) if quote == c => break,
+                        Some(&other) => {
+                            text.push(other);
+                            position += 1;
+                        }
+                    }
+                }
+                position += 1;
+                tokens.push(Token::Text(text));
+            }
+            '$' => {
+                position += 1;
+                loop {
+                    match chars.get(position) {
+                        Some('.') => {
+                            position += 1;
+                            while chars
+                                .get(position)
+                                .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                            {
+                                position += 1;
+                            }
+                        }
+                        Some('[') => {
+                            while chars.get(position).is_some_and(|c| *c != ']') {
+                                position += 1;
+                            }
+                            position += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                let end = position.min(chars.len());
+                let path: String = chars[start..end].iter().collect();
+                tokens.push(Token::Path(path.parse()?));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while chars
+                    .get(position)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                {
+                    position += 1;
+                }
+                tokens.push(Token::Name(chars[start..position].iter().collect()));
+            }
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' | ',' => {
+                position += 1;
+                tokens.push(Token::Symbol(c));
+            }
+            c => return Err(format!("unexpected '{c}'")),
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent parser over the tokens
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol('+')) => Operator::Add,
+                Some(Token::Symbol('-')) => Operator::Subtract,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(operator, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    // term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol('*')) => Operator::Multiply,
+                Some(Token::Symbol('/')) => Operator::Divide,
+                Some(Token::Symbol('%')) => Operator::Remainder,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(operator, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    // unary := '-' unary | primary
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.eat('-') {
+            true => Ok(Expr::Negate(Box::new(self.unary()?))),
+            false => self.primary(),
+        }
+    }
+
+    // primary := number | string | path | name | name '(' arguments ')' | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Literal(Value::Number(number))),
+            Some(Token::Text(text)) => Ok(Expr::Literal(Value::String(text))),
+            Some(Token::Path(path)) => Ok(Expr::Path(path)),
+            Some(Token::Symbol('(')) => {
+                let expr = self.expr()?;
+                match self.eat(')') {
+                    true => Ok(expr),
+                    false => Err(String::from("missing ')'")),
+                }
+            }
+            Some(Token::Name(name)) if !self.eat('(') => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ => Err(format!("unknown name '{name}'")),
+            },
+            Some(Token::Name(name)) => {
+                let arguments = self.arguments()?;
+                function(&name, arguments)
+            }
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err(String::from("unexpected end")),
+        }
+    }
+
+    // arguments := (expr (',' expr)*)? ')'
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut arguments = vec![];
+        if self.eat(')') {
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.expr()?);
+            if self.eat(')') {
+                return Ok(arguments);
+            }
+            if !self.eat(',') {
+                return Err(String::from("expected ',' or ')'"));
+            }
+        }
+    }
+}
+
+// date_trunc("hour", ...) and lookup("sites", ...) name their unit or map with a string
+fn named(name: &str, what: &str, mut arguments: Vec<Expr>) -> Result<(String, Box<Expr>), String> {
+    match (arguments.pop(), arguments.pop(), arguments.is_empty()) {
+        (Some(expr), Some(Expr::Literal(Value::String(named))), true) => {
+            Ok((named, Box::new(expr)))
+        }
+        _ => Err(format!("{name}() takes a {what} string and a value")),
+    }
+}
+
+// A function call, checked against what the function takes
+fn function(name: &str, arguments: Vec<Expr>) -> Result<Expr, String> {
+    match name {
+        "concat" | "coalesce" if arguments.is_empty() => {
+            Err(format!("{name}() takes at least 1 argument"))
+        }
+        "concat" => Ok(Expr::Concat(arguments)),
+        "coalesce" => Ok(Expr::Coalesce(arguments)),
+        "date_trunc" => {
+            let (unit, expr) = named(name, "unit", arguments)?;
+            match DATE_UNITS.contains(&unit.as_str()) {
+                true => Ok(Expr::DateTrunc(unit, expr)),
+                false => Err(format!("unknown date_trunc() unit '{unit}'")),
+            }
+        }
+        "lookup" => {
+            let (map, expr) = named(name, "map", arguments)?;
+            Ok(Expr::Lookup(map, expr))
+        }
+        "now" if arguments.is_empty() => Ok(Expr::Now),
+        "now" => Err(String::from("now() takes no arguments")),
+        _ => Err(format!("unknown function {name}()")),
+    }
+}
+
+/// A field computed for every document of a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComputedField {
+    pub table: String,
+    pub field: String,
+    pub expr: Expr,
+}
+
+impl FromStr for ComputedField {
+    type Err = String;
+
+    // <table>:<field>=<expression>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (target, expr) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>:<field>=<expression>: {value}"))?;
+        let (table, field) = target
+            .split_once(':')
+            .ok_or_else(|| format!("expected <table>:<field>=<expression>: {value}"))?;
+        Ok(ComputedField {
+            table: table.trim().to_string(),
+            field: field.trim().to_string(),
+            expr: expr.parse()?,
+        })
+    }
+}
+
+/// Add the computed fields of a table to a document, in the order they are defined
+/// Fields can use the fields computed before them
+pub fn apply(
+    fields: &[ComputedField],
+    table: &str,
+    document: &mut Value,
+    timestamp: DateTime<Utc>,
+    maps: &Maps,
+) {
+    for field in fields.iter().filter(|field| field.table == table) {
+        let value = field.expr.eval(&Context {
+            document,
+            timestamp,
+            maps,
+        });
+        if let Value::Object(map) = document {
+            map.insert(field.field.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn eval(expression: &str, document: Value) -> Value {
+        let maps = Maps::from([(
+            String::from("sites"),
+            HashMap::from([(String::from("d1"), json!("lab"))]),
+        )]);
+        let expr: Expr = expression.parse().unwrap();
+        expr.eval(&Context {
+            document: &document,
+            timestamp: Utc.with_ymd_and_hms(2024, 9, 3, 14, 25, 7).unwrap(),
+            maps: &maps,
+        })
+    }
+
+    #[test]
+    fn test_eval() {
+        let document = json!({"c": 21.5, "n": 7, "id": "d1", "device": {"name": "probe"}});
+        assert_eq!(eval("$.c * 9 / 5 + 32", document.clone()), json!(70.7));
+        assert_eq!(eval("($.n + 1) * 2 % 5", document.clone()), json!(1));
+        assert_eq!(eval("-$.n - 1", document.clone()), json!(-8));
+        assert_eq!(eval("$.n * 'x'", document.clone()), Value::Null);
+        assert_eq!(eval("1 / 0", document.clone()), Value::Null);
+        assert_eq!(
+            eval(
+                "concat($.id, '-', $.device.name, $.missing)",
+                document.clone()
+            ),
+            json!("d1-probe")
+        );
+        assert_eq!(
+            eval("coalesce($.missing, $[\"id\"])", document.clone()),
+            json!("d1")
+        );
+        assert_eq!(
+            eval("lookup('sites', $.id)", document.clone()),
+            json!("lab")
+        );
+        assert_eq!(eval("lookup('sites', 'd2')", document.clone()), Value::Null);
+        assert_eq!(
+            eval("date_trunc('hour', now())", document.clone()),
+            json!("2024-09-03T14:00:00Z")
+        );
+        assert_eq!(
+            eval("date_trunc('month', 1725373507)", document.clone()),
+            json!("2024-09-01T00:00:00Z")
+        );
+
+        for bad in [
+            "1 +",
+            "(1",
+            "upper($.id)",
+            "date_trunc('week', now())",
+            "now(1)",
+            "$.a ^ 2",
+        ] {
+            assert!(bad.parse::<Expr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let fields: Vec<ComputedField> = vec![
+            "readings:f=$.c * 2 + 12".parse().unwrap(),
+            "readings:label=concat($.f, 'F')".parse().unwrap(),
+            "other:ignored=1".parse().unwrap(),
+        ];
+        let mut document = json!({"c": 100});
+        apply(&fields, "readings", &mut document, Utc::now(), &Maps::new());
+        assert_eq!(document, json!({"c": 100, "f": 212, "label": "212F"}));
+
+        assert!("readings=1".parse::<ComputedField>().is_err());
+    }
+}
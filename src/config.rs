@@ -0,0 +1,1480 @@
+// The configuration file
+//
+// --config /etc/receiver/config.toml
+//
+// [server]
+// addr = "0.0.0.0"
+// port = 8443
+// max_decompressed_size = 4194304
+// memory_budget = 134217728
+// workers = 4
+// read_port = 8444
+// read_workers = 2
+// reuse_port = true
+// unix_socket = "/run/receiver.sock"
+// unix_socket_mode = "0660"
+// drain_delay = "5s"
+// shutdown_timeout = "30s"
+// log_filter = "warn,actix_data_receiver::storage=debug"
+// watch_config = true
+// default_database = "site"
+// record_metadata = true
+// trusted_proxies = ["10.0.0.0/8"]
+//
+// [server.tls]
+// cert = "/etc/receiver/cert.pem"
+// key = "/etc/receiver/key.pem"
+//
+// [storage]
+// backend = "sqlite"
+// database_files = "/var/lib/receiver"
+//
+// [storage.sqlite]
+// journal_mode = "wal"
+// synchronous = "normal"
+// busy_timeout = "5s"
+// cache_size = -16000
+// key_file = "/etc/receiver/db.key"
+//
+// [storage.shadow]
+// backend = "postgres"
+// dsn = "postgres://receiver@db:5432/receiver"
+//
+// [auth]
+// debug_keys = ["gateway"]
+//
+// [[auth.keys]]
+// name = "gateway"
+// key = "..."
+//
+// [retention]
+// default = "30d"
+// vacuum = "incremental"
+//
+// [allow]
+// databases = ["site", "lab"]
+// existing_only = true
+//
+// [deny]
+// tables = ["debug"]
+//
+// [databases.lab]
+// tables = ["readings"]
+// retention = "7d"
+//
+// [tables.readings]
+// template = "readings_{yyyy_mm}"
+// split = "$.readings[]"
+// flatten = true
+// schema = "/etc/receiver/readings.schema.json"
+// computed = [
+//     { field = "temperature_f", expr = "$.temperature_c * 9 / 5 + 32" },
+//     { field = "site", expr = "lookup('sites', $.device_id)" },
+// ]
+//
+// enrich = [{ lookup = "devices", key = "$.device_id" }]
+//
+// [maps.sites]
+// d1 = "lab"
+// d2 = "roof"
+//
+// [lookups.devices]
+// path = "/etc/receiver/devices.csv"
+// key = "device_id"
+//
+// [proxy]
+// upstream = "https://api.internal:8443"
+// database = "archive"
+// table = "traffic"
+//
+// [udp]
+// port = 5514
+// database = "network"
+// table = "syslog"
+//
+// [write_queue]
+// capacity = 10000
+// batch_rows = 1000
+// flush_interval = "50ms"
+// adaptive = true
+// target_latency = "100ms"
+//
+// [export]
+// compression = "zstd"
+//
+// [forward]
+// dead_letter_table = "dead_letters"
+// max_attempts = 5
+//
+// [[forward.endpoints]]
+// url = "https://downstream.internal/ingest"
+// tables = ["readings"]
+//
+// [rate_limit]
+// requests_per_second = 20.0
+// burst = 40
+//
+// [leader_election]
+// ttl = "30s"
+//
+// [backup]
+// directory = "/var/backups/receiver"
+// interval = "6h"
+// keep = 7
+//
+// Every setting is optional, options given on the command line win over the file.
+// Settings of what a build leaves out, such as the postgres backend without the
+// `postgres` feature, fail to load rather than being ignored.
+//
+// Every option can also be given as an environment variable named after it,
+// ADR__PORT=8443 for --port or ADR__DATABASE_FILES=/data for --database-files,
+// the options of a subcommand after it too, ADR__REPLAY__TABLE=events for the
+// --table of replay, and every key of the file as ADR__<SECTION>__<KEY>,
+// ADR__SERVER__DRAIN_DELAY=10s for `drain_delay` of the [server] section. The
+// value of a key is read as JSON, or as text when it isn't JSON, so whole
+// sections such as the per-table settings fit in one variable:
+//
+// ADR__TABLES='{"readings": {"split": "$.readings[]", "retention": "1d"}}'
+//
+// Keys are lower case, JSON keeps names with upper case letters. A variable
+// named after an option sets the option, so sections sharing the name of an
+// option, such as [retention], are set key by key. The keys are applied over
+// the file, the options over both.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::access::{AccessRules, ExistingTables};
+use crate::auth::{ApiKey, ApiKeys};
+use crate::backup::BackupSettings;
+use crate::compress::Compression;
+use crate::compute::{ComputedField, Expr, Maps};
+use crate::decompress::BodyLimits;
+use crate::flatten::FlattenRule;
+use crate::forward::{Endpoint, ForwardSettings};
+use crate::jsonpath::JsonPath;
+use crate::leader::{self, LeaderSettings};
+use crate::listener::SocketMode;
+use crate::lookups::{EnrichRule, LookupSource};
+use crate::rate_limit::RateLimitSettings;
+use crate::retention::{RetentionPolicy, TableRetention};
+use crate::schemas::{SchemaPath, TableSchema};
+use crate::split::SplitRule;
+use crate::storage::{DbKey, JournalMode, SchemaMode, SqlitePragmas, Synchronous, VacuumMode};
+use crate::templates::TableTemplate;
+use crate::udp::UdpSettings;
+use crate::write_queue::WriteQueueSettings;
+
+/// Environment variables setting an option or a key of the configuration
+/// start with this
+pub const ENV_PREFIX: &str = "ADR__";
+
+// The shortest interval background tasks tick at, the resolution of the timer
+const MIN_TICK: Duration = Duration::from_millis(1);
+
+/// Storage backends which can be selected
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Sqlite,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+/// Everything the server can be configured with
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub retention: RetentionConfig,
+    pub allow: AllowConfig,
+    pub deny: DenyConfig,
+    /// Settings of a single database
+    pub databases: BTreeMap<String, DatabaseConfig>,
+    /// Settings of a single table, `*` matches any table in a template
+    pub tables: BTreeMap<String, TableConfig>,
+    /// Static maps computed fields can look values up in
+    pub maps: Maps,
+    /// Lookup table files joined into documents, by name
+    pub lookups: BTreeMap<String, LookupConfig>,
+    /// Forward requests to an upstream and store a copy of each
+    pub proxy: Option<ProxyConfig>,
+    /// Store syslog and JSON lines received on a UDP port
+    pub udp: Option<UdpConfig>,
+    /// Acknowledge writes once queued and store them in batches
+    pub write_queue: Option<WriteQueueConfig>,
+    pub export: ExportConfig,
+    /// POST stored rows on to other HTTP endpoints
+    pub forward: Option<ForwardConfig>,
+    /// Answer clients making too many requests with 429
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Run the scheduled jobs on one of the instances sharing the backend
+    pub leader_election: Option<LeaderElectionConfig>,
+    /// Copy the databases to a backup directory, on request or every interval
+    pub backup: Option<BackupConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub port: u16,
+    pub max_body_size: usize,
+    /// The largest request body accepted once decompressed
+    pub max_decompressed_size: usize,
+    /// Bytes request bodies and queued writes can take together, unlimited when left out,
+    /// at least `max_body_size` + `max_decompressed_size`
+    pub memory_budget: Option<usize>,
+    pub default_database: Option<String>,
+    pub ordering_key: Option<String>,
+    /// How often lookup table files are checked for changes
+    #[serde(with = "humantime_serde")]
+    pub lookup_reload_interval: Duration,
+    pub tls: Option<TlsConfig>,
+    /// Worker threads of the server, one per CPU when left out
+    pub workers: Option<usize>,
+    /// Serve the read endpoints on their own port and worker threads, so
+    /// heavy queries never delay writes
+    pub read_port: Option<u16>,
+    /// Worker threads of the read server, one per CPU when left out
+    pub read_workers: Option<usize>,
+    /// Listen with SO_REUSEPORT so a new version can start before this one stops
+    pub reuse_port: bool,
+    /// Listen on this unix socket instead of the port
+    pub unix_socket: Option<PathBuf>,
+    /// The permissions of the unix socket, e.g. 0660
+    pub unix_socket_mode: Option<SocketMode>,
+    /// How long `/healthz` answers 503 before the server stops on SIGTERM
+    #[serde(with = "humantime_serde")]
+    pub drain_delay: Duration,
+    /// How long requests in flight, then queued writes, get to finish when stopping
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+    /// Log filter directives, e.g. warn,actix_data_receiver::storage=debug
+    pub log_filter: Option<String>,
+    /// Reload the settings which can change while the server runs when this file changes
+    pub watch_config: bool,
+    /// Store the client address, user agent and request id with every row
+    pub record_metadata: bool,
+    /// Proxies, by address or network, whose X-Forwarded-For names the client
+    pub trusted_proxies: Vec<String>,
+}
+
+impl ServerConfig {
+    /// How large request bodies can be
+    pub fn body_limits(&self) -> BodyLimits {
+        BodyLimits {
+            max_body_size: self.max_body_size,
+            max_decompressed_size: self.max_decompressed_size,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: String::from("0.0.0.0"),
+            port: 8888,
+            max_body_size: 262_144,
+            max_decompressed_size: 4_194_304,
+            memory_budget: None,
+            default_database: None,
+            ordering_key: None,
+            lookup_reload_interval: Duration::from_secs(30),
+            tls: None,
+            workers: None,
+            read_port: None,
+            read_workers: None,
+            reuse_port: false,
+            unix_socket: None,
+            unix_socket_mode: None,
+            drain_delay: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            log_filter: None,
+            watch_config: false,
+            record_metadata: false,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    pub database_files: String,
+    pub dsn: Option<String>,
+    pub schema_mode: SchemaMode,
+    /// Pragmas set on every SQLite connection
+    pub sqlite: SqliteConfig,
+    /// A second backend which gets every operation and is compared to this one
+    pub shadow: Option<Box<StorageConfig>>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::Sqlite,
+            database_files: String::from("./"),
+            dsn: None,
+            schema_mode: SchemaMode::Migrate,
+            sqlite: SqliteConfig::default(),
+            shadow: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// How long a connection waits for a lock held by another
+    #[serde(with = "humantime_serde")]
+    pub busy_timeout: Duration,
+    /// Pages cached per connection, or KiB when negative
+    pub cache_size: i64,
+    /// Encrypt the database files with this key, needs the sqlcipher feature
+    pub key: Option<DbKey>,
+    /// Read the key from this file
+    pub key_file: Option<PathBuf>,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        let pragmas = SqlitePragmas::default();
+        SqliteConfig {
+            journal_mode: pragmas.journal_mode,
+            synchronous: pragmas.synchronous,
+            busy_timeout: pragmas.busy_timeout,
+            cache_size: pragmas.cache_size,
+            key: None,
+            key_file: None,
+        }
+    }
+}
+
+impl SqliteConfig {
+    pub fn pragmas(&self) -> SqlitePragmas {
+        SqlitePragmas {
+            journal_mode: self.journal_mode,
+            synchronous: self.synchronous,
+            busy_timeout: self.busy_timeout,
+            cache_size: self.cache_size,
+        }
+    }
+
+    /// The key of the database files, read from `key_file` when it is set
+    pub fn key(&self) -> Result<Option<DbKey>, String> {
+        match (&self.key, &self.key_file) {
+            (Some(_), Some(_)) => Err(String::from("set either a key or a key_file")),
+            (key, None) => Ok(key.clone()),
+            (None, Some(path)) => {
+                let key = std::fs::read_to_string(path)
+                    .map_err(|err| format!("unable to read {}: {err}", path.display()))?;
+                match key.trim_end_matches(['\r', '\n']) {
+                    "" => Err(format!("{} holds no key", path.display())),
+                    key => Ok(Some(DbKey::new(key))),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys: Vec<ApiKey>,
+    /// Names of the keys allowed to ask for the echo of an insert with `X-Debug`
+    pub debug_keys: Vec<String>,
+}
+
+// Durations are written like 30d or 1h 30m
+// https://docs.rs/humantime-serde/latest/humantime_serde/
+// cargo add humantime-serde
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    #[serde(with = "humantime_serde")]
+    pub default: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    pub vacuum: VacuumMode,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            default: None,
+            interval: Duration::from_secs(3600),
+            vacuum: VacuumMode::None,
+        }
+    }
+}
+
+/// How table exports are written
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Export compressed files unless a request asks otherwise
+    pub compression: Compression,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    pub endpoints: Vec<Endpoint>,
+    /// The database failed deliveries are stored in, the default database when unset
+    pub dead_letter_database: Option<String>,
+    pub dead_letter_table: String,
+    /// How long an endpoint has to answer a delivery
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Deliveries are given up on after this many attempts
+    pub max_attempts: u32,
+    /// How long the first retry waits, doubled with every attempt
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    /// The most deliveries waiting for each endpoint, more are dead lettered
+    pub capacity: usize,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        ForwardConfig {
+            endpoints: vec![],
+            dead_letter_database: None,
+            dead_letter_table: String::from("dead_letters"),
+            timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            capacity: 1_000,
+        }
+    }
+}
+
+impl ForwardConfig {
+    pub fn settings(&self, default_database: Option<&str>) -> Result<ForwardSettings, String> {
+        let dead_letter_database = self
+            .dead_letter_database
+            .as_deref()
+            .or(default_database)
+            .ok_or("forwarding needs a dead_letter_database or a default database")?;
+        Ok(ForwardSettings {
+            endpoints: self.endpoints.clone(),
+            dead_letter_database: dead_letter_database.to_string(),
+            dead_letter_table: self.dead_letter_table.clone(),
+            timeout: self.timeout,
+            max_attempts: self.max_attempts.max(1),
+            backoff: self.backoff,
+            capacity: self.capacity,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// The requests each client can make a second
+    pub requests_per_second: f64,
+    /// The requests a client can make at once after being idle
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_second: 20.0,
+            burst: 40,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn settings(&self) -> Result<RateLimitSettings, String> {
+        if !(self.requests_per_second.is_finite() && self.requests_per_second > 0.0) {
+            return Err(format!(
+                "rate_limit requests_per_second must be above 0, not {}",
+                self.requests_per_second
+            ));
+        }
+        Ok(RateLimitSettings {
+            requests_per_second: self.requests_per_second,
+            burst: self.burst.max(1),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderElectionConfig {
+    /// The name of the lease, instances campaigning for the same lease elect one leader
+    pub lease: String,
+    /// The name of this instance [default: <host name>:<process id>]
+    pub holder: Option<String>,
+    /// How long the lease lasts without being renewed
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig {
+            lease: String::from("scheduled_jobs"),
+            holder: None,
+            ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+impl LeaderElectionConfig {
+    pub fn settings(&self) -> LeaderSettings {
+        LeaderSettings {
+            lease: self.lease.clone(),
+            holder: self.holder.clone().unwrap_or_else(leader::default_holder),
+            ttl: self.ttl,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Backups are written to <directory>/<database name>/
+    pub directory: PathBuf,
+    /// Back up every database this often, only on request when unset
+    #[serde(with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    /// The backups of a database kept, 0 keeps every backup
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            directory: PathBuf::from("./backups"),
+            interval: None,
+            keep: 7,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn settings(&self) -> BackupSettings {
+        BackupSettings {
+            directory: self.directory.clone(),
+            interval: self.interval,
+            keep: self.keep,
+        }
+    }
+}
+
+/// Names which can be used, an empty list allows any name
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllowConfig {
+    pub databases: Vec<String>,
+    pub tables: Vec<String>,
+    /// Only write to tables which already exist, never creating any
+    pub existing_only: bool,
+}
+
+/// Names which can't be used, even when they are allowed
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DenyConfig {
+    pub databases: Vec<String>,
+    pub tables: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// The tables allowed in the database, an empty list allows any table
+    pub tables: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    pub retention: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableConfig {
+    pub template: Option<String>,
+    pub split: Option<String>,
+    pub flatten: Option<Flatten>,
+    pub schema: Option<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub retention: Option<Duration>,
+    /// Fields added to each document, in order
+    pub computed: Vec<ComputedConfig>,
+    /// Lookup tables joined into each document
+    pub enrich: Vec<EnrichConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnrichConfig {
+    pub lookup: String,
+    pub key: JsonPath,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LookupConfig {
+    pub path: PathBuf,
+    /// The column or field rows are keyed by, the first CSV column when unset
+    pub key: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComputedConfig {
+    pub field: String,
+    pub expr: Expr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// The URL requests are forwarded to, e.g. https://api.internal:8443
+    pub upstream: String,
+    /// The database requests are stored in, the default database when unset
+    pub database: Option<String>,
+    #[serde(default = "ProxyConfig::default_table")]
+    pub table: String,
+    /// How long to wait for the upstream to answer
+    #[serde(default = "ProxyConfig::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl ProxyConfig {
+    pub fn new(upstream: String) -> Self {
+        ProxyConfig {
+            upstream,
+            database: None,
+            table: ProxyConfig::default_table(),
+            timeout: ProxyConfig::default_timeout(),
+        }
+    }
+
+    fn default_table() -> String {
+        String::from("traffic")
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    pub port: u16,
+    /// The IP address to listen on, the address of the server when unset
+    pub addr: Option<String>,
+    /// The database messages are stored in, the default database when unset
+    pub database: Option<String>,
+    #[serde(default = "UdpConfig::default_table")]
+    pub table: String,
+    /// Messages written at a time
+    #[serde(default = "UdpConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Write the messages received at the latest this long after the first
+    #[serde(
+        default = "UdpConfig::default_flush_interval",
+        with = "humantime_serde"
+    )]
+    pub flush_interval: Duration,
+}
+
+impl UdpConfig {
+    pub fn new(port: u16) -> Self {
+        UdpConfig {
+            port,
+            addr: None,
+            database: None,
+            table: UdpConfig::default_table(),
+            batch_size: UdpConfig::default_batch_size(),
+            flush_interval: UdpConfig::default_flush_interval(),
+        }
+    }
+
+    fn default_table() -> String {
+        String::from("syslog")
+    }
+
+    fn default_batch_size() -> usize {
+        500
+    }
+
+    fn default_flush_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub fn settings(&self, server: &ServerConfig) -> Result<UdpSettings, String> {
+        let database = self
+            .database
+            .as_ref()
+            .or(server.default_database.as_ref())
+            .ok_or("the UDP listener needs a database or --default-database")?;
+        Ok(UdpSettings {
+            addr: self.addr.clone().unwrap_or_else(|| server.addr.clone()),
+            port: self.port,
+            database: database.clone(),
+            table: self.table.clone(),
+            batch_size: self.batch_size.max(1),
+            flush_interval: self.flush_interval,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteQueueConfig {
+    /// The most writes waiting to be stored, more are answered with 503
+    pub capacity: usize,
+    /// Store a batch once this many rows are waiting, the largest batch when adaptive
+    pub batch_rows: usize,
+    /// Store a batch at the latest this long after its first write arrived
+    #[serde(with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// Size batches by how long they take to commit
+    pub adaptive: bool,
+    /// How long an adaptive batch should take to commit
+    #[serde(with = "humantime_serde")]
+    pub target_latency: Duration,
+}
+
+impl Default for WriteQueueConfig {
+    fn default() -> Self {
+        WriteQueueConfig {
+            capacity: 10_000,
+            batch_rows: 1_000,
+            flush_interval: Duration::from_millis(50),
+            adaptive: true,
+            target_latency: Duration::from_millis(100),
+        }
+    }
+}
+
+impl WriteQueueConfig {
+    pub fn settings(&self) -> WriteQueueSettings {
+        WriteQueueSettings {
+            capacity: self.capacity,
+            batch_rows: self.batch_rows,
+            flush_interval: self.flush_interval,
+            target_latency: self.adaptive.then_some(self.target_latency),
+        }
+    }
+}
+
+/// `flatten = true` for every level, `flatten = 2` for the first two levels
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Flatten {
+    All(bool),
+    Depth(usize),
+}
+
+impl Config {
+    /// Read a configuration file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Self::load_with_env(Some(path), [])
+    }
+
+    /// Read a configuration file, or start from the defaults without one, and
+    /// apply the keys set by `ADR__` environment variables over it
+    pub fn load_with_env<I>(path: Option<&Path>, vars: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let (mut config, mut source) = match path {
+            Some(path) => {
+                let config = std::fs::read_to_string(path)
+                    .map_err(|err| format!("unable to read {}: {err}", path.display()))?;
+                let config = toml::from_str(&config)
+                    .map_err(|err| format!("invalid {}: {err}", path.display()))?;
+                (config, path.display().to_string())
+            }
+            None => (toml::Table::new(), String::from("configuration")),
+        };
+
+        // A section set as a whole comes before the keys set in it
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+        for (name, value) in &vars {
+            set_key(&mut config, &name[ENV_PREFIX.len()..], value)
+                .map_err(|err| format!("invalid {name}: {err}"))?;
+        }
+        if !vars.is_empty() {
+            source.push_str(" with the ADR__ environment variables");
+        }
+        let config: Config = toml::Value::Table(config)
+            .try_into()
+            .map_err(|err| format!("invalid {source}: {err}"))?;
+        config
+            .validate()
+            .map_err(|err| format!("invalid {source}: {err}"))?;
+        Ok(config)
+    }
+
+    /// Refuse the settings which load but can't work, a task ticking at a
+    /// zero interval would stop at its first tick
+    pub fn validate(&self) -> Result<(), String> {
+        let mut intervals = vec![
+            (
+                "server.lookup_reload_interval",
+                self.server.lookup_reload_interval,
+                MIN_TICK,
+            ),
+            ("retention.interval", self.retention.interval, MIN_TICK),
+        ];
+        if let Some(interval) = self.backup.as_ref().and_then(|backup| backup.interval) {
+            intervals.push(("backup.interval", interval, MIN_TICK));
+        }
+        // The lease is renewed every third of its ttl
+        if let Some(leader_election) = &self.leader_election {
+            intervals.push(("leader_election.ttl", leader_election.ttl, MIN_TICK * 3));
+        }
+        for (key, interval, minimum) in intervals {
+            if interval < minimum {
+                return Err(format!(
+                    "{key} is {interval:?}, it can't be under {minimum:?}"
+                ));
+            }
+        }
+        // A compressed body is counted as sent and as large as it can expand to
+        let server = &self.server;
+        let largest = server
+            .max_body_size
+            .saturating_add(server.max_decompressed_size);
+        if let Some(memory_budget) = server.memory_budget.filter(|budget| *budget < largest) {
+            return Err(format!(
+                "server.memory_budget is {memory_budget}, it can't be under \
+                server.max_body_size + server.max_decompressed_size ({largest})"
+            ));
+        }
+        Ok(())
+    }
+
+    // The settings of every table which have a value
+    fn table_settings<'a, T>(
+        &'a self,
+        setting: impl Fn(&'a TableConfig) -> Option<T>,
+    ) -> impl Iterator<Item = (&'a String, T)> {
+        self.tables
+            .iter()
+            .filter_map(move |(table, config)| Some((table, setting(config)?)))
+    }
+
+    pub fn table_templates(&self) -> Result<Vec<TableTemplate>, String> {
+        self.table_settings(|config| config.template.as_ref())
+            .map(|(table, template)| format!("{table}={template}").parse())
+            .collect()
+    }
+
+    pub fn split_rules(&self) -> Result<Vec<SplitRule>, String> {
+        self.table_settings(|config| config.split.as_ref())
+            .map(|(table, path)| format!("{table}={path}").parse())
+            .collect()
+    }
+
+    pub fn flatten_rules(&self) -> Vec<FlattenRule> {
+        self.table_settings(|config| match config.flatten? {
+            Flatten::All(true) => Some(None),
+            Flatten::All(false) => None,
+            Flatten::Depth(depth) => Some(Some(depth)),
+        })
+        .map(|(table, depth)| FlattenRule {
+            table: table.clone(),
+            depth,
+        })
+        .collect()
+    }
+
+    pub fn computed_fields(&self) -> Result<Vec<ComputedField>, String> {
+        let mut fields = vec![];
+        for (table, config) in &self.tables {
+            for computed in &config.computed {
+                if let Some(map) = computed
+                    .expr
+                    .maps()
+                    .into_iter()
+                    .find(|map| !self.maps.contains_key(*map))
+                {
+                    return Err(format!(
+                        "computed field {table}:{} looks up the unknown map '{map}'",
+                        computed.field
+                    ));
+                }
+                fields.push(ComputedField {
+                    table: table.clone(),
+                    field: computed.field.clone(),
+                    expr: computed.expr.clone(),
+                });
+            }
+        }
+        Ok(fields)
+    }
+
+    pub fn lookup_sources(&self) -> Vec<LookupSource> {
+        self.lookups
+            .iter()
+            .map(|(name, config)| LookupSource {
+                name: name.clone(),
+                path: config.path.clone(),
+                key: config.key.clone(),
+            })
+            .collect()
+    }
+
+    pub fn enrich_rules(&self) -> Result<Vec<EnrichRule>, String> {
+        let mut rules = vec![];
+        for (table, config) in &self.tables {
+            for enrich in &config.enrich {
+                if !self.lookups.contains_key(&enrich.lookup) {
+                    return Err(format!(
+                        "table {table} is enriched from the unknown lookup '{}'",
+                        enrich.lookup
+                    ));
+                }
+                rules.push(EnrichRule {
+                    table: table.clone(),
+                    lookup: enrich.lookup.clone(),
+                    key: enrich.key.clone(),
+                });
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn table_schemas(&self) -> Result<Vec<TableSchema>, String> {
+        self.table_settings(|config| config.schema.clone())
+            .map(|(table, path)| {
+                TableSchema::load(&SchemaPath {
+                    table: table.clone(),
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    pub fn ordering_key(&self) -> Result<Option<JsonPath>, String> {
+        self.server
+            .ordering_key
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+    }
+
+    /// The settings which take a restart to change, written out by key so a
+    /// reloaded configuration can be compared to the running one
+    pub fn restart_settings(&self) -> BTreeMap<&'static str, String> {
+        let server = &self.server;
+        // The key is kept out of the Debug output, its hash tells keys apart
+        let db_key = self.storage.sqlite.key().map(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        });
+        let settings: &[(&'static str, &dyn fmt::Debug)] = &[
+            ("server.addr", &server.addr),
+            ("server.port", &server.port),
+            ("server.max_body_size", &server.max_body_size),
+            (
+                "server.max_decompressed_size",
+                &server.max_decompressed_size,
+            ),
+            ("server.memory_budget", &server.memory_budget),
+            ("server.default_database", &server.default_database),
+            ("server.ordering_key", &server.ordering_key),
+            (
+                "server.lookup_reload_interval",
+                &server.lookup_reload_interval,
+            ),
+            ("server.tls", &server.tls),
+            ("server.workers", &server.workers),
+            ("server.read_port", &server.read_port),
+            ("server.read_workers", &server.read_workers),
+            ("server.reuse_port", &server.reuse_port),
+            ("server.unix_socket", &server.unix_socket),
+            ("server.unix_socket_mode", &server.unix_socket_mode),
+            ("server.drain_delay", &server.drain_delay),
+            ("server.shutdown_timeout", &server.shutdown_timeout),
+            ("server.watch_config", &server.watch_config),
+            ("server.record_metadata", &server.record_metadata),
+            ("server.trusted_proxies", &server.trusted_proxies),
+            ("storage", &self.storage),
+            ("storage.sqlite.key", &db_key),
+            ("auth", &self.auth),
+            ("retention", &self.retention),
+            ("allow", &self.allow),
+            ("deny", &self.deny),
+            ("databases", &self.databases),
+            ("tables", &self.tables),
+            ("maps", &self.maps),
+            ("lookups", &self.lookups),
+            ("proxy", &self.proxy),
+            ("udp", &self.udp),
+            ("write_queue", &self.write_queue),
+            ("export", &self.export),
+            ("forward", &self.forward),
+            ("leader_election", &self.leader_election),
+            ("backup", &self.backup),
+            // Only turning rate limiting on or off, the rate changes while running
+            ("rate_limit", &self.rate_limit.is_some()),
+        ];
+        settings
+            .iter()
+            .map(|(key, value)| (*key, format!("{value:?}")))
+            .collect()
+    }
+
+    pub fn api_keys(&self) -> ApiKeys {
+        ApiKeys {
+            keys: self.auth.keys.clone(),
+        }
+    }
+
+    pub fn access_rules(&self) -> AccessRules {
+        AccessRules {
+            databases: self.allow.databases.clone(),
+            tables: self.allow.tables.clone(),
+            database_tables: self
+                .databases
+                .iter()
+                .filter(|(_, config)| !config.tables.is_empty())
+                .map(|(database, config)| (database.clone(), config.tables.clone()))
+                .collect(),
+            denied_databases: self.deny.databases.clone(),
+            denied_tables: self.deny.tables.clone(),
+            existing_only: self.allow.existing_only.then(ExistingTables::default),
+        }
+    }
+
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            default: self.retention.default,
+            databases: self
+                .databases
+                .iter()
+                .filter_map(|(database, config)| Some((database.clone(), config.retention?)))
+                .collect::<HashMap<_, _>>(),
+            tables: self
+                .table_settings(|config| config.retention)
+                .map(|(table, retention)| TableRetention {
+                    table: table.clone(),
+                    retention,
+                })
+                .collect(),
+            vacuum: self.retention.vacuum,
+        }
+    }
+}
+
+// Set a key of the configuration to the value of an environment variable,
+// objects are merged into the sections they set
+fn set_key(config: &mut toml::Table, key: &str, value: &str) -> Result<(), String> {
+    let keys: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+    if keys.iter().any(String::is_empty) {
+        return Err(String::from("empty key"));
+    }
+    let value = env_value(&keys, value);
+
+    let (last, sections) = keys.split_last().expect("split yields a key");
+    let mut table = config;
+    for section in sections {
+        table = match table
+            .entry(section.as_str())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        {
+            toml::Value::Table(table) => table,
+            _ => return Err(format!("{section} is not a section")),
+        };
+    }
+    match (table.get_mut(last.as_str()), value) {
+        (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => merge(existing, value),
+        (_, value) => {
+            table.insert(last.clone(), value);
+        }
+    }
+    Ok(())
+}
+
+// The value of an environment variable, JSON when it parses as JSON and
+// suits the key or else text, so `2024` is a number for a port and text
+// for a database name
+fn env_value(keys: &[String], value: &str) -> toml::Value {
+    let text = toml::Value::String(value.to_string());
+    let json = serde_json::from_str::<serde_json::Value>(value).ok();
+    let Some(typed) = json.and_then(|json| toml::Value::try_from(json).ok()) else {
+        return text;
+    };
+    if typed.is_table() || typed.is_array() || typed.is_str() {
+        return typed;
+    }
+    // The key is tried on its own, every section has defaults for the rest
+    let suits = |value: &toml::Value| {
+        let config = keys.iter().rev().fold(value.clone(), |value, key| {
+            toml::Value::Table(toml::Table::from_iter([(key.clone(), value)]))
+        });
+        config.try_into::<Config>().is_ok()
+    };
+    match !suits(&typed) && suits(&text) {
+        true => text,
+        false => typed,
+    }
+}
+
+// Merge the keys of a table into another, replacing all but sections
+fn merge(table: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                merge(existing, value)
+            }
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 8443
+            ordering_key = "$.device_id"
+            watch_config = true
+            record_metadata = true
+            trusted_proxies = ["10.0.0.0/8"]
+
+            [storage]
+            backend = "sqlite"
+            schema_mode = "strict"
+
+            [storage.sqlite]
+            synchronous = "normal"
+            busy_timeout = "250ms"
+
+            [[auth.keys]]
+            name = "gateway"
+            key = "secret"
+
+            [retention]
+            default = "30d"
+
+            [allow]
+            existing_only = true
+
+            [deny]
+            tables = ["debug"]
+
+            [databases.lab]
+            tables = ["readings"]
+            retention = "7d"
+
+            [tables.readings]
+            template = "readings_{yyyy_mm}"
+            split = "$.readings[]"
+            retention = "1d"
+
+            [tables.vendor_events]
+            flatten = 2
+            computed = [{ field = "site", expr = "lookup('sites', $.device)" }]
+            enrich = [{ lookup = "devices", key = "$.device" }]
+
+            [lookups.devices]
+            path = "devices.csv"
+
+            [maps.sites]
+            d1 = "lab"
+
+            [proxy]
+            upstream = "http://localhost:9000"
+            timeout = "5s"
+
+            [udp]
+            port = 5514
+            database = "network"
+
+            [write_queue]
+            flush_interval = "10ms"
+            adaptive = false
+
+            [export]
+            compression = "gzip"
+
+            [forward]
+            dead_letter_database = "site"
+
+            [[forward.endpoints]]
+            url = "http://localhost:9001/ingest"
+            tables = ["readings"]
+
+            [rate_limit]
+            requests_per_second = 5
+
+            [leader_election]
+            holder = "receiver-0"
+
+            [backup]
+            directory = "/var/backups/receiver"
+            interval = "6h"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.addr, "0.0.0.0");
+        assert_eq!(config.server.port, 8443);
+        assert!(config.server.watch_config);
+        assert!(config.server.record_metadata);
+        assert_eq!(config.server.trusted_proxies, vec!["10.0.0.0/8"]);
+        assert_eq!(config.storage.backend, Backend::Sqlite);
+        assert_eq!(config.storage.schema_mode, SchemaMode::Strict);
+        let pragmas = config.storage.sqlite.pragmas();
+        assert_eq!(pragmas.journal_mode, JournalMode::Wal);
+        assert_eq!(pragmas.synchronous, Synchronous::Normal);
+        assert_eq!(pragmas.busy_timeout, Duration::from_millis(250));
+        assert!(config.ordering_key().unwrap().is_some());
+        assert_eq!(config.api_keys().find("secret").unwrap().name, "gateway");
+        assert_eq!(config.table_templates().unwrap().len(), 1);
+        assert_eq!(config.split_rules().unwrap()[0].table, "readings");
+        assert_eq!(config.flatten_rules()[0].depth, Some(2));
+        assert_eq!(config.computed_fields().unwrap()[0].field, "site");
+        assert_eq!(config.enrich_rules().unwrap()[0].lookup, "devices");
+        assert_eq!(config.lookup_sources()[0].key, None);
+        assert!(config.access_rules().check("lab", "events").is_err());
+        assert!(config.access_rules().check("site", "debug").is_err());
+        assert!(config.access_rules().existing_only.is_some());
+        let proxy = config.proxy.as_ref().unwrap();
+        assert_eq!(proxy.table, "traffic");
+        assert_eq!(proxy.timeout, Duration::from_secs(5));
+        let udp = config.udp.as_ref().unwrap();
+        assert_eq!((udp.port, udp.table.as_str()), (5514, "syslog"));
+        let settings = udp.settings(&config.server).unwrap();
+        assert_eq!(settings.addr, config.server.addr);
+        assert_eq!(settings.flush_interval, Duration::from_secs(1));
+        let write_queue = config.write_queue.as_ref().unwrap().settings();
+        assert_eq!(write_queue.flush_interval, Duration::from_millis(10));
+        assert_eq!(write_queue.batch_rows, 1_000);
+        assert_eq!(write_queue.target_latency, None);
+        assert_eq!(config.export.compression, Compression::Gzip);
+        let forward = config.forward.as_ref().unwrap().settings(None).unwrap();
+        assert_eq!(forward.dead_letter_table, "dead_letters");
+        assert_eq!(forward.endpoints[0].tables, ["readings"]);
+        let rate_limit = config.rate_limit.as_ref().unwrap().settings().unwrap();
+        assert_eq!(rate_limit.requests_per_second, 5.0);
+        assert_eq!(rate_limit.burst, 40);
+        let leader = config.leader_election.as_ref().unwrap().settings();
+        assert_eq!(leader.lease, "scheduled_jobs");
+        assert_eq!(leader.holder, "receiver-0");
+        let backup = config.backup.as_ref().unwrap().settings();
+        assert_eq!(backup.interval, Some(Duration::from_secs(6 * 3600)));
+        assert_eq!(backup.keep, 7);
+
+        let policy = config.retention_policy();
+        let day = Duration::from_secs(86400);
+        assert_eq!(policy.retention("lab", "readings"), Some(day));
+        assert_eq!(policy.retention("lab", "events"), Some(day * 7));
+        assert_eq!(policy.retention("site", "events"), Some(day * 30));
+
+        // Typos are reported instead of ignored
+        assert!(toml::from_str::<Config>("[server]\nprot = 8443").is_err());
+        let config: Config = toml::from_str("[tables.events]\ntemplate = \"{week}\"").unwrap();
+        assert!(config.table_templates().is_err());
+        let config: Config = toml::from_str(
+            "[tables.events]\ncomputed = [{ field = \"site\", expr = \"lookup('sites', $.id)\" }]",
+        )
+        .unwrap();
+        assert!(config.computed_fields().is_err());
+        assert!(toml::from_str::<Config>(
+            "[tables.events]\ncomputed = [{ field = \"f\", expr = \"$.c *\" }]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_optional_features() {
+        // What a build leaves out is refused rather than ignored
+        let postgres = toml::from_str::<Config>("[storage]\nbackend = \"postgres\"");
+        assert_eq!(postgres.is_ok(), cfg!(feature = "postgres"));
+        let zstd = toml::from_str::<Config>("[export]\ncompression = \"zstd\"");
+        assert_eq!(zstd.is_ok(), cfg!(feature = "zstd"));
+        let lz4 = toml::from_str::<Config>("[export]\ncompression = \"lz4\"");
+        assert_eq!(lz4.is_ok(), cfg!(feature = "lz4"));
+    }
+
+    #[test]
+    fn test_load_with_env() {
+        let path = std::env::temp_dir().join("adr_test_load_with_env.toml");
+        std::fs::write(
+            &path,
+            "[server]\nport = 8443\n\n[tables.readings]\ntemplate = \"readings_{yyyy_mm}\"\n",
+        )
+        .unwrap();
+        let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        // Keys are read as JSON when it suits them or else as text, sections
+        // are merged
+        let config = Config::load_with_env(
+            Some(&path),
+            vars(&[
+                ("ADR__SERVER__DEFAULT_DATABASE", "2024"),
+                ("ADR__SERVER__DRAIN_DELAY", "10s"),
+                ("ADR__SERVER__REUSE_PORT", "true"),
+                ("ADR__SERVER__UNIX_SOCKET_MODE", "0660"),
+                ("ADR__STORAGE__SQLITE__CACHE_SIZE", "-4000"),
+                ("ADR__STORAGE__SQLITE__KEY", "1234"),
+                ("ADR__TABLES", r#"{"Events": {"retention": "1d"}}"#),
+                ("ADR__TABLES__NULL__TEMPLATE", "null"),
+                ("ADR__TABLES__READINGS__SPLIT", "$.readings[]"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8443);
+        assert_eq!(config.server.default_database.as_deref(), Some("2024"));
+        assert_eq!(config.server.drain_delay, Duration::from_secs(10));
+        assert!(config.server.reuse_port);
+        assert_eq!(config.server.unix_socket_mode, Some(SocketMode(0o660)));
+        assert_eq!(config.storage.sqlite.cache_size, -4000);
+        assert_eq!(config.storage.sqlite.key, Some(DbKey::new("1234")));
+        assert_eq!(config.tables.len(), 3);
+        assert_eq!(config.tables["null"].template.as_deref(), Some("null"));
+        assert!(config.tables["Events"].retention.is_some());
+        let readings = &config.tables["readings"];
+        assert!(readings.template.is_some());
+        assert_eq!(readings.split.as_deref(), Some("$.readings[]"));
+
+        // Without a file the keys apply to the defaults
+        let config = Config::load_with_env(None, vars(&[("ADR__SERVER__PORT", "9000")])).unwrap();
+        assert_eq!(config.server.port, 9000);
+
+        // Typos are reported, naming the variables
+        let err = Config::load_with_env(None, vars(&[("ADR__SERVER__PROT", "9000")])).unwrap_err();
+        assert!(err.contains("ADR__"), "{err}");
+        assert!(Config::load_with_env(None, vars(&[("ADR__SERVER__PORT__X", "1")])).is_err());
+        assert!(Config::load_with_env(None, vars(&[("ADR__SERVER____PORT", "1")])).is_err());
+
+        // Post test, remove any files created
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let load = |name: &str, value: &str| {
+            Config::load_with_env(None, [(name.to_string(), value.to_string())])
+        };
+        assert!(load("ADR__RETENTION__INTERVAL", "1s").is_ok());
+
+        // Zero intervals are refused naming the key
+        for name in [
+            "ADR__SERVER__LOOKUP_RELOAD_INTERVAL",
+            "ADR__RETENTION__INTERVAL",
+            "ADR__BACKUP__INTERVAL",
+            "ADR__LEADER_ELECTION__TTL",
+        ] {
+            let err = load(name, "0s").unwrap_err();
+            let key = name[ENV_PREFIX.len()..].replace("__", ".").to_lowercase();
+            assert!(err.contains(&key), "{err}");
+        }
+        // A lease is renewed every third of its ttl
+        assert!(load("ADR__LEADER_ELECTION__TTL", "2ms").is_err());
+        assert!(load("ADR__LEADER_ELECTION__TTL", "3ms").is_ok());
+
+        // The budget holds the largest compressed body
+        let err = load("ADR__SERVER__MEMORY_BUDGET", "2097152").unwrap_err();
+        assert!(err.contains("server.memory_budget"), "{err}");
+        assert!(load("ADR__SERVER__MEMORY_BUDGET", "4456448").is_ok());
+    }
+
+    #[test]
+    fn test_restart_settings() {
+        let load = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()));
+            Config::load_with_env(None, vars)
+                .unwrap()
+                .restart_settings()
+        };
+        let running = load(&[]);
+        assert_eq!(load(&[]), running);
+
+        // The log filter and the rate change while running, the others are told apart
+        let changed = |vars: &[(&str, &str)]| {
+            let reloaded = load(vars);
+            let keys: Vec<_> = reloaded
+                .iter()
+                .filter(|(key, value)| running.get(*key) != Some(value))
+                .map(|(key, _)| *key)
+                .collect();
+            keys
+        };
+        assert!(changed(&[("ADR__SERVER__LOG_FILTER", "debug")]).is_empty());
+        assert_eq!(
+            changed(&[("ADR__AUTH__KEYS", r#"[{"name": "ci", "key": "k"}]"#)]),
+            vec!["auth"]
+        );
+        assert_eq!(
+            changed(&[("ADR__STORAGE__SQLITE__KEY", "secret")]),
+            vec!["storage", "storage.sqlite.key"]
+        );
+        assert_eq!(
+            changed(&[("ADR__RATE_LIMIT__BURST", "10")]),
+            vec!["rate_limit"]
+        );
+    }
+
+    #[test]
+    fn test_sqlite_key() {
+        let path = std::env::temp_dir().join("adr_test_sqlite_key");
+        std::fs::write(&path, "secret\n").unwrap();
+        let mut sqlite = SqliteConfig {
+            key_file: Some(path.clone()),
+            ..SqliteConfig::default()
+        };
+        assert_eq!(sqlite.key().unwrap(), Some(DbKey::new("secret")));
+
+        // A key and a key file are one too many, the key is kept out of logs
+        sqlite.key = Some(DbKey::new("hunter2"));
+        assert!(sqlite.key().is_err());
+        assert!(!format!("{sqlite:?}").contains("hunter2"));
+
+        // An empty key file is refused
+        sqlite.key = None;
+        std::fs::write(&path, "\n").unwrap();
+        assert!(sqlite.key().is_err());
+
+        // Post test, remove any files created
+        std::fs::remove_file(path).unwrap();
+    }
+}
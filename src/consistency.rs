@@ -0,0 +1,173 @@
+// Read-your-writes consistency tokens
+//
+// A write answers with the table and sequence number it committed
+// X-Consistency-Token: site/readings/42
+// or with the ticket of the write when the write queue stores it later
+// X-Consistency-Token: site/readings/q1234
+//
+// A read of the same table passing the token back waits until that commit
+// is visible, for up to `X-Consistency-Wait` (default 5s, at most 30s), and
+// is answered with 412 `not_yet_visible` when it is still not visible.
+// X-Consistency-Token: site/readings/42
+// X-Consistency-Wait: 500ms
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::{rt::time, HttpRequest};
+
+use crate::errors::Error;
+use crate::storage::{self, Storage};
+use crate::write_queue::{TicketStatus, WriteQueue};
+
+/// The header a write answers with and a read passes back
+pub const TOKEN_HEADER: &str = "X-Consistency-Token";
+
+/// The header limiting how long a read waits for its token
+pub const WAIT_HEADER: &str = "X-Consistency-Wait";
+
+/// How long a read waits for its token unless it asks otherwise
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+/// The longest a read can wait for its token
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// How often the table is checked while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A write to a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub database: String,
+    pub table: String,
+    pub commit: Commit,
+}
+
+/// How far a table has to get for a write to be visible
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Commit {
+    /// The sequence number of the last row written
+    Seq(i64),
+    /// The ticket of a write in the write queue
+    Queued(u64),
+}
+
+impl FromStr for Token {
+    type Err = String;
+
+    // <database>/<table>/<seq> or <database>/<table>/q<ticket>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid consistency token: {value}");
+        let mut parts = value.trim().splitn(3, '/');
+        let (Some(database), Some(table), Some(commit)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let commit = match commit.strip_prefix('q') {
+            Some(ticket) => Commit::Queued(ticket.parse().map_err(|_| invalid())?),
+            None => Commit::Seq(commit.parse().map_err(|_| invalid())?),
+        };
+        Ok(Token {
+            database: database.to_string(),
+            table: table.to_string(),
+            commit,
+        })
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (database, table) = (&self.database, &self.table);
+        match self.commit {
+            Commit::Seq(seq) => write!(f, "{database}/{table}/{seq}"),
+            Commit::Queued(ticket) => write!(f, "{database}/{table}/q{ticket}"),
+        }
+    }
+}
+
+/// Wait until the commit of the token a read passed, if any, is visible
+pub async fn wait_visible(
+    storage: &dyn Storage,
+    write_queue: Option<&WriteQueue>,
+    req: &HttpRequest,
+    database: &str,
+    table: &str,
+) -> Result<(), Error> {
+    let headers = req.headers();
+    let Some(token) = headers.get(TOKEN_HEADER) else {
+        return Ok(());
+    };
+    let token: Token = token
+        .to_str()
+        .map_err(|err| Error::BadRequest(err.to_string()))?
+        .parse()
+        .map_err(Error::BadRequest)?;
+    if token.database != database || token.table != table {
+        return Err(Error::BadRequest(format!(
+            "the consistency token is for {}/{}",
+            token.database, token.table
+        )));
+    }
+    let wait = match headers.get(WAIT_HEADER) {
+        Some(wait) => wait
+            .to_str()
+            .ok()
+            .and_then(|wait| humantime::parse_duration(wait).ok())
+            .ok_or_else(|| Error::BadRequest(format!("invalid {WAIT_HEADER}")))?
+            .min(MAX_WAIT),
+        None => DEFAULT_WAIT,
+    };
+
+    let deadline = time::Instant::now() + wait;
+    loop {
+        let visible = match token.commit {
+            Commit::Seq(seq) => match storage.last_seq(database, table).await {
+                Ok(last_seq) => last_seq >= seq,
+                // Nothing has been committed to the table yet
+                Err(storage::Error::NotFound(_)) => false,
+                Err(err) => return Err(err.into()),
+            },
+            Commit::Queued(ticket) => match write_queue.map(|queue| queue.status(ticket)) {
+                Some(TicketStatus::Queued) => false,
+                Some(TicketStatus::Stored) => true,
+                Some(TicketStatus::Failed) => {
+                    return Err(Error::Internal(format!("the write of {token} failed")))
+                }
+                None => {
+                    return Err(Error::BadRequest(String::from(
+                        "the consistency token is for a write queue, which is not enabled",
+                    )))
+                }
+            },
+        };
+        if visible {
+            return Ok(());
+        }
+        if time::Instant::now() + POLL_INTERVAL > deadline {
+            return Err(Error::NotVisible(format!(
+                "{token} after waiting {}",
+                humantime::format_duration(wait)
+            )));
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let token: Token = "site/readings/42".parse().unwrap();
+        assert_eq!(token.table, "readings");
+        assert_eq!(token.commit, Commit::Seq(42));
+        assert_eq!(token.to_string(), "site/readings/42");
+        let token: Token = "site/readings/q7".parse().unwrap();
+        assert_eq!(token.commit, Commit::Queued(7));
+        assert_eq!(token.to_string(), "site/readings/q7");
+        assert!("site/readings".parse::<Token>().is_err());
+        assert!("site/readings/last".parse::<Token>().is_err());
+    }
+}
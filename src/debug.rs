@@ -0,0 +1,134 @@
+// Echo what an insert would store instead of storing it
+//
+// [auth]
+// debug_keys = ["onboarding"]
+//
+// curl -i -X PUT -H 'X-Debug: true' -H 'X-API-Key: ...' -d '{"a": 1}' http://localhost:8888/site/events
+//
+// A request with `X-Debug: true` made with one of the debug keys goes through
+// the whole pipeline, splitting, schemas, computed fields, templates and
+// flattening, without being stored. It is answered with the document as
+// received, the rows as they would be stored and the statements which would
+// store them. Everything the request logs is logged at DEBUG level whatever
+// the log filter of the server. Without API keys there are no debug keys.
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::{
+    filter,
+    layer::{Context, Filter},
+    registry::LookupSpan,
+};
+
+use crate::auth::ApiKeyName;
+use crate::errors::Error;
+use crate::storage::NewRecord;
+
+/// The header asking for the echo of an insert
+pub const DEBUG_HEADER: &str = "X-Debug";
+
+/// The span everything a debug request logs is recorded in
+pub const SPAN_NAME: &str = "debug_request";
+
+/// Whether a request asks for an echo, refused unless made with a debug key
+pub fn requested(
+    req: &HttpRequest,
+    debug_keys: &[String],
+    api_key_name: Option<&ApiKeyName>,
+) -> Result<bool, Error> {
+    let asked = req
+        .headers()
+        .get(DEBUG_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    if !asked {
+        return Ok(false);
+    }
+    match api_key_name {
+        Some(ApiKeyName(name)) if debug_keys.contains(name) => Ok(true),
+        _ => Err(Error::Forbidden(format!(
+            "{DEBUG_HEADER} needs an API key listed in auth.debug_keys"
+        ))),
+    }
+}
+
+/// A row as it would be stored
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EchoRow {
+    pub timestamp: String,
+    pub data: Value,
+    pub ordering_key: Option<String>,
+    pub idempotency_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The answer to a debug request
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Echo {
+    pub database: String,
+    pub table: String,
+    pub received: Value,
+    pub rows: Vec<EchoRow>,
+    pub statements: Vec<String>,
+}
+
+impl EchoRow {
+    pub fn new(record: &NewRecord) -> Self {
+        EchoRow {
+            timestamp: record.timestamp.to_string(),
+            data: serde_json::from_str(&record.data).unwrap_or(Value::Null),
+            ordering_key: record.ordering_key.clone(),
+            idempotency_key: record.idempotency_key.clone(),
+            source_ip: record.metadata.source_ip.clone(),
+            user_agent: record.metadata.user_agent.clone(),
+            request_id: record.metadata.request_id.clone(),
+        }
+    }
+}
+
+/// Log at DEBUG level within a debug request, to be combined with the log
+/// filter of the server
+pub fn filter<S>() -> impl Filter<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    filter::dynamic_filter_fn(|metadata: &Metadata<'_>, cx: &Context<'_, S>| {
+        if metadata.name() == SPAN_NAME {
+            return true;
+        }
+        metadata.level() <= &Level::DEBUG
+            && cx
+                .lookup_current()
+                .is_some_and(|span| span.scope().any(|span| span.name() == SPAN_NAME))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_requested() {
+        let debug_keys = vec![String::from("onboarding")];
+        let onboarding = ApiKeyName(String::from("onboarding"));
+        let gateway = ApiKeyName(String::from("gateway"));
+
+        let req = TestRequest::put().to_http_request();
+        assert!(!requested(&req, &debug_keys, Some(&onboarding)).unwrap());
+
+        let req = TestRequest::put()
+            .insert_header((DEBUG_HEADER, "true"))
+            .to_http_request();
+        assert!(requested(&req, &debug_keys, Some(&onboarding)).unwrap());
+        assert!(requested(&req, &debug_keys, Some(&gateway)).is_err());
+        assert!(requested(&req, &debug_keys, None).is_err());
+    }
+}
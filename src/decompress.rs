@@ -0,0 +1,182 @@
+// Compressed request bodies
+//
+// curl -i -X PUT -H 'Content-Encoding: gzip' --data-binary @data.json.gz http://localhost:8888/database/test
+//
+// Bodies sent with `Content-Encoding: gzip`, `deflate` or `br` are
+// decompressed before they are validated and stored. The body as sent is
+// limited by `max_body_size` and the decompressed body by
+// `max_decompressed_size`, decompression stops as soon as it goes over so a
+// small body which expands to gigabytes is refused without being expanded.
+// Other encodings are answered with 415 Unsupported Media Type.
+//
+// A body is counted against the memory budget while it is read and handled,
+// a compressed one by as much as it can expand to until it is decompressed.
+use std::io::Read;
+
+use actix_web::{
+    http::header::{self, HeaderMap},
+    web::{Bytes, Payload},
+};
+
+// Brotli decompression
+// https://docs.rs/brotli-decompressor/latest/brotli_decompressor/
+// cargo add brotli-decompressor
+use brotli_decompressor::Decompressor;
+
+// DEFLATE, zlib and gzip decompression
+// https://docs.rs/flate2/latest/flate2/
+// cargo add flate2
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::errors::Error;
+use crate::memory::{MemoryBudget, Reservation};
+
+/// How large a request body can be
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BodyLimits {
+    /// The body as sent
+    pub max_body_size: usize,
+    /// The body once decompressed
+    pub max_decompressed_size: usize,
+}
+
+/// The compressions a request body can be sent with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The encoding named by the `Content-Encoding` header of a request
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+            return Ok(Encoding::Identity);
+        };
+        let value = value.to_str().unwrap_or_default().trim();
+        match value.to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(Encoding::Identity),
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            "br" => Ok(Encoding::Brotli),
+            _ => Err(Error::UnsupportedMediaType(format!(
+                "content encoding {value} is not supported, use gzip, deflate or br"
+            ))),
+        }
+    }
+}
+
+/// Read a request body, decompressed when it was sent compressed
+pub async fn read_body(
+    headers: &HeaderMap,
+    payload: Payload,
+    limits: BodyLimits,
+    budget: &MemoryBudget,
+) -> Result<(Bytes, Reservation), Error> {
+    let encoding = Encoding::from_headers(headers)?;
+    let (body, mut reservation) = read_raw(headers, payload, limits, budget).await?;
+    if encoding == Encoding::Identity {
+        return Ok((body, reservation));
+    }
+    reservation.grow(limits.max_decompressed_size)?;
+    let body = decompress(body, encoding, limits.max_decompressed_size)?;
+    reservation.shrink_to(body.len());
+    Ok((body, reservation))
+}
+
+/// Read a request body as it was sent
+pub async fn read_raw(
+    headers: &HeaderMap,
+    payload: Payload,
+    limits: BodyLimits,
+    budget: &MemoryBudget,
+) -> Result<(Bytes, Reservation), Error> {
+    // Counted by the length it was sent with, or the most it can be
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .map_or(limits.max_body_size, |length: usize| {
+            length.min(limits.max_body_size)
+        });
+    let mut reservation = budget.reserve(length)?;
+    let body = payload
+        .to_bytes_limited(limits.max_body_size)
+        .await
+        .map_err(|_| {
+            Error::PayloadTooLarge(format!(
+                "the body is larger than {} bytes",
+                limits.max_body_size
+            ))
+        })?
+        .map_err(|err| Error::BadRequest(format!("unable to read the body: {err}")))?;
+    reservation.shrink_to(body.len());
+    Ok((body, reservation))
+}
+
+/// Decompress a body, refusing it when it expands to more than `limit` bytes
+pub fn decompress(body: Bytes, encoding: Encoding, limit: usize) -> Result<Bytes, Error> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        Encoding::Identity => return Ok(body),
+        Encoding::Gzip => Box::new(GzDecoder::new(&body[..])),
+        Encoding::Deflate => Box::new(ZlibDecoder::new(&body[..])),
+        Encoding::Brotli => Box::new(Decompressor::new(&body[..], 4096)),
+    };
+
+    // One byte past the limit tells a body at the limit from a larger one
+    let mut decompressed = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|err| Error::BadRequest(format!("unable to decompress the body: {err}")))?;
+    if decompressed.len() > limit {
+        return Err(Error::PayloadTooLarge(format!(
+            "the body is larger than {limit} bytes once decompressed"
+        )));
+    }
+    Ok(Bytes::from(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use actix_web::test::TestRequest;
+    use flate2::{write::GzEncoder, Compression};
+
+    #[test]
+    fn test_decompress() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[b' '; 10_000]).unwrap();
+        let body = Bytes::from(encoder.finish().unwrap());
+        assert!(body.len() < 1_000);
+
+        assert_eq!(
+            decompress(body.clone(), Encoding::Gzip, 10_000)
+                .unwrap()
+                .len(),
+            10_000
+        );
+        assert!(decompress(body.clone(), Encoding::Gzip, 9_999).is_err());
+        assert!(decompress(body.clone(), Encoding::Brotli, 10_000).is_err());
+        assert_eq!(
+            decompress(body.clone(), Encoding::Identity, 0).unwrap(),
+            body
+        );
+
+        let req = TestRequest::put()
+            .insert_header((header::CONTENT_ENCODING, "GZIP"))
+            .to_http_request();
+        assert_eq!(
+            Encoding::from_headers(req.headers()).unwrap(),
+            Encoding::Gzip
+        );
+        let req = TestRequest::put()
+            .insert_header((header::CONTENT_ENCODING, "compress"))
+            .to_http_request();
+        assert!(Encoding::from_headers(req.headers()).is_err());
+    }
+}
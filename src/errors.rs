@@ -0,0 +1,202 @@
+// Errors returned by the endpoints
+//
+// Every error is answered with a JSON body like
+// {"error": "not found: test", "code": "not_found"}
+// and counted by code in the `actix_data_receiver_errors_total` metric.
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use rusqlite::ErrorCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::metrics;
+use crate::storage;
+
+/// The JSON body of an error response
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: String,
+}
+
+/// Errors returned by the endpoints
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    BadGateway(String),
+    NotVisible(String),
+    /// Over the rate limit, with how long until a request is let through
+    RateLimited(Duration),
+    Unavailable(String),
+    Internal(String),
+    Storage(storage::Error),
+}
+
+impl Error {
+    /// A short machine readable name of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound(_) => "not_found",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::UnsupportedMediaType(_) => "unsupported_media_type",
+            Error::BadGateway(_) => "bad_gateway",
+            Error::NotVisible(_) => "not_yet_visible",
+            Error::RateLimited(_) => "rate_limited",
+            Error::Unavailable(_) => "unavailable",
+            Error::Internal(_) => "internal_error",
+            Error::Storage(err) => match err {
+                storage::Error::InvalidName(_) => "invalid_name",
+                storage::Error::NotFound(_) => "not_found",
+                storage::Error::Schema(_) => "schema_mismatch",
+                storage::Error::Sqlite(err) => match err.sqlite_error_code() {
+                    Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => {
+                        "database_locked"
+                    }
+                    Some(ErrorCode::CannotOpen)
+                    | Some(ErrorCode::PermissionDenied)
+                    | Some(ErrorCode::ReadOnly) => "database_unavailable",
+                    _ => "storage_error",
+                },
+                #[cfg(feature = "postgres")]
+                storage::Error::Postgres(_) => "storage_error",
+                storage::Error::Pool(_) => "database_unavailable",
+                storage::Error::Unsupported(_) => "not_implemented",
+                storage::Error::Internal(_) => "internal_error",
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(message) => write!(f, "{message}"),
+            Error::Unauthorized(message) => write!(f, "unauthorized: {message}"),
+            Error::Forbidden(message) => write!(f, "forbidden: {message}"),
+            Error::NotFound(message) => write!(f, "not found: {message}"),
+            Error::PayloadTooLarge(message) => write!(f, "payload too large: {message}"),
+            Error::UnsupportedMediaType(message) => write!(f, "{message}"),
+            Error::BadGateway(message) => write!(f, "upstream: {message}"),
+            Error::NotVisible(message) => write!(f, "not yet visible: {message}"),
+            Error::RateLimited(retry_after) => {
+                write!(f, "rate limited: retry in {}s", retry_seconds(retry_after))
+            }
+            Error::Unavailable(message) => write!(f, "unavailable: {message}"),
+            Error::Internal(message) => write!(f, "internal error: {message}"),
+            Error::Storage(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<storage::Error> for Error {
+    fn from(err: storage::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self.code() {
+            "bad_request" | "invalid_name" => StatusCode::BAD_REQUEST,
+            "unauthorized" => StatusCode::UNAUTHORIZED,
+            "forbidden" => StatusCode::FORBIDDEN,
+            "not_found" => StatusCode::NOT_FOUND,
+            "payload_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
+            "unsupported_media_type" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "bad_gateway" => StatusCode::BAD_GATEWAY,
+            "not_yet_visible" => StatusCode::PRECONDITION_FAILED,
+            "rate_limited" => StatusCode::TOO_MANY_REQUESTS,
+            "not_implemented" => StatusCode::NOT_IMPLEMENTED,
+            // Worth retrying later
+            "database_locked" | "database_unavailable" | "unavailable" => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let code = self.code();
+        match status.is_server_error() {
+            true => error!("{status} {code}: {self}"),
+            false => warn!("{status} {code}: {self}"),
+        }
+        metrics::ERRORS.with_label_values(&[code]).inc();
+        let mut response = HttpResponse::build(status);
+        if status == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        if let Error::RateLimited(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_seconds(retry_after)));
+        }
+        response.json(ErrorResponse {
+            error: self.to_string(),
+            code: code.to_string(),
+        })
+    }
+}
+
+// Whole seconds until a retry, rounded up so the retry is let through
+fn retry_seconds(retry_after: &Duration) -> u64 {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    seconds.max(1)
+}
+
+/// Answer request extractor errors (path, query string, ...) with a JSON body
+pub fn bad_request<E: fmt::Display>(err: E, _req: &actix_web::HttpRequest) -> actix_web::Error {
+    Error::BadRequest(err.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::body::to_bytes;
+
+    #[actix_web::test]
+    async fn test_error_response() {
+        let err = Error::from(storage::Error::Sqlite(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        )));
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let before = metrics::ERRORS
+            .with_label_values(&["database_locked"])
+            .get();
+        let body = to_bytes(err.error_response().into_body()).await.unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "database_locked");
+        assert_eq!(
+            metrics::ERRORS
+                .with_label_values(&["database_locked"])
+                .get(),
+            before + 1
+        );
+
+        let err = Error::from(storage::Error::InvalidName(String::from("_sequences")));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        let err = Error::RateLimited(Duration::from_millis(1_500));
+        let response = err.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+}
@@ -0,0 +1,398 @@
+// Stream the rows of a table as NDJSON or CSV
+//
+// GET /<database name>/<table name>/export?format=csv&since=<seq>
+//
+// Rows are read a page at a time in sequence order and written to the
+// response as they are read, so a table of any size is exported without
+// holding it in memory on either side. A compressed export is compressed
+// a page at a time as it is written, see compress.rs.
+//
+// GET /<database name>/_export?tables=<table,table>
+//
+// A snapshot export reads several tables, or every table of the database,
+// in a single read transaction so the rows exported are the rows of every
+// table at one point in time. It is written as NDJSON, each line naming the
+// table of the row.
+// {"table":"readings","seq":1,"id":1,"timestamp":"...","data":{...}}
+//
+// GET /<database name>/<table name>/export?summary=true
+//
+// With `summary=true` an export ends with a summary of what was written
+// before it, so a consumer on a flaky connection can tell a complete export
+// from one cut off between two rows. actix-web has no way to send HTTP
+// trailers, the summary is the last record of the export instead
+// - NDJSON: {"summary":{"rows":2,"bytes":180,"crc32":"1c291ca3"}}
+// - CSV: # rows=2 bytes=180 crc32=1c291ca3
+// `bytes` and `crc32` are the length and CRC-32 of the export up to the
+// summary line, before any compression.
+use std::sync::Arc;
+
+use actix_web::web::Bytes;
+// CRC-32 checksums
+// https://docs.rs/crc32fast/latest/crc32fast/
+// cargo add crc32fast
+use crc32fast::Hasher;
+// Combinators for asynchronous streams
+// https://docs.rs/futures-util/latest/futures_util/
+// cargo add futures-util
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::compress::Encoder;
+use crate::storage::{self, ChangeEvent, ChangesFilter, SnapshotStream, Storage};
+
+/// Rows read from storage at a time
+pub const PAGE_SIZE: i64 = 1000;
+
+/// The formats rows can be exported in
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One JSON changefeed event per line
+    #[default]
+    Ndjson,
+    /// A header line then one line per row, the data columns are the
+    /// top-level keys of the document
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// The columns every CSV export starts with
+const CSV_COLUMNS: &[&str] = &["seq", "id", "timestamp"];
+
+/// Turns pages of rows into response chunks
+pub struct Writer {
+    format: Format,
+    /// The data columns of a CSV export, taken from the first page when not given
+    columns: Option<Vec<String>>,
+    header_written: bool,
+}
+
+impl Writer {
+    pub fn new(format: Format, columns: Option<Vec<String>>) -> Self {
+        Writer {
+            format,
+            columns,
+            header_written: false,
+        }
+    }
+
+    /// Render a page of rows, a CSV export gets its header with the first page
+    pub fn write(&mut self, events: &[ChangeEvent]) -> Bytes {
+        let mut chunk = String::new();
+        match self.format {
+            Format::Ndjson => {
+                for event in events {
+                    chunk.push_str(&serde_json::to_string(event).unwrap_or_default());
+                    chunk.push('\n');
+                }
+            }
+            Format::Csv => {
+                let columns = self.columns.get_or_insert_with(|| data_columns(events));
+                if !self.header_written {
+                    let header = CSV_COLUMNS.iter().map(|column| column.to_string());
+                    csv_line(&mut chunk, header.chain(columns.iter().cloned()));
+                    self.header_written = true;
+                }
+                for event in events {
+                    let fields = [
+                        event.seq.to_string(),
+                        event.id.to_string(),
+                        event.timestamp.clone(),
+                    ];
+                    let data = columns.iter().map(|column| match event.data.get(column) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(text)) => text.clone(),
+                        Some(value) => value.to_string(),
+                    });
+                    csv_line(&mut chunk, fields.into_iter().chain(data));
+                }
+            }
+        }
+        Bytes::from(chunk)
+    }
+}
+
+// The top-level keys of the documents in the order they are first seen
+fn data_columns(events: &[ChangeEvent]) -> Vec<String> {
+    let mut columns: Vec<String> = vec![];
+    for event in events {
+        if let Value::Object(fields) = &event.data {
+            for key in fields.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+        }
+    }
+    columns
+}
+
+// https://www.rfc-editor.org/rfc/rfc4180
+fn csv_line(chunk: &mut String, fields: impl Iterator<Item = String>) {
+    for (index, field) in fields.enumerate() {
+        if index > 0 {
+            chunk.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            chunk.push('"');
+            chunk.push_str(&field.replace('"', "\"\""));
+            chunk.push('"');
+        } else {
+            chunk.push_str(&field);
+        }
+    }
+    chunk.push_str("\r\n");
+}
+
+/// A table being exported
+pub struct Export {
+    pub storage: Arc<dyn Storage>,
+    pub database: String,
+    pub table: String,
+    pub writer: Writer,
+}
+
+/// A chunk of an export and the rows written in it
+pub type Chunk = (usize, Bytes);
+
+/// Stream the rows of a table starting with a page already read
+/// Reading the first page before the response starts lets errors such as a
+/// missing table be answered with a status instead of a cut off body
+pub fn stream(
+    export: Export,
+    first_page: Vec<ChangeEvent>,
+) -> impl Stream<Item = Result<Chunk, storage::Error>> {
+    stream::try_unfold((export, first_page), |(mut export, page)| async move {
+        let Some(last) = page.last() else {
+            return Ok(None);
+        };
+        let chunk = (page.len(), export.writer.write(&page));
+        let next_page = match (page.len() as i64) < PAGE_SIZE {
+            true => vec![],
+            false => {
+                let filter = ChangesFilter {
+                    since: last.seq,
+                    after_id: None,
+                    limit: PAGE_SIZE,
+                    key: None,
+                };
+                export
+                    .storage
+                    .changes(&export.database, &export.table, &filter)
+                    .await?
+            }
+        };
+        Ok(Some((chunk, (export, next_page))))
+    })
+}
+
+// A row of a snapshot export
+#[derive(Serialize)]
+struct SnapshotEvent<'a> {
+    table: &'a str,
+    #[serde(flatten)]
+    event: &'a ChangeEvent,
+}
+
+/// Stream the pages of a snapshot as NDJSON starting with a page already
+/// read, which answers a missing table with a status as `stream` does
+pub fn snapshot(
+    first_page: Option<(String, Vec<ChangeEvent>)>,
+    pages: SnapshotStream,
+) -> impl Stream<Item = Result<Chunk, storage::Error>> {
+    stream::iter(first_page.map(Ok))
+        .chain(pages)
+        .map_ok(|(table, events)| {
+            let mut chunk = String::new();
+            for event in &events {
+                let event = SnapshotEvent {
+                    table: &table,
+                    event,
+                };
+                chunk.push_str(&serde_json::to_string(&event).unwrap_or_default());
+                chunk.push('\n');
+            }
+            (events.len(), Bytes::from(chunk))
+        })
+}
+
+/// What an export wrote before its summary
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Summary {
+    pub rows: usize,
+    pub bytes: usize,
+    /// The CRC-32 of the bytes as 8 hex digits
+    pub crc32: String,
+}
+
+impl Summary {
+    // The last line of an export in a format
+    fn line(&self, format: Format) -> String {
+        match format {
+            Format::Ndjson => format!("{}\n", serde_json::json!({ "summary": self })),
+            Format::Csv => format!(
+                "# rows={} bytes={} crc32={}\r\n",
+                self.rows, self.bytes, self.crc32
+            ),
+        }
+    }
+}
+
+/// The chunks of an export, ending with its summary when one is asked for
+pub fn summarize(
+    chunks: impl Stream<Item = Result<Chunk, storage::Error>> + 'static,
+    summary: Option<Format>,
+) -> impl Stream<Item = Result<Bytes, storage::Error>> {
+    let Some(format) = summary else {
+        return chunks.map_ok(|(_, chunk)| chunk).left_stream();
+    };
+    let state = (Box::pin(chunks), Some((0, 0, Hasher::new())));
+    stream::try_unfold(state, move |(mut chunks, totals)| async move {
+        let Some((rows, bytes, mut hasher)) = totals else {
+            return Ok(None);
+        };
+        match chunks.try_next().await? {
+            Some((chunk_rows, chunk)) => {
+                hasher.update(&chunk);
+                let totals = (rows + chunk_rows, bytes + chunk.len(), hasher);
+                Ok(Some((chunk, (chunks, Some(totals)))))
+            }
+            None => {
+                let summary = Summary {
+                    rows,
+                    bytes,
+                    crc32: format!("{:08x}", hasher.finalize()),
+                };
+                Ok(Some((Bytes::from(summary.line(format)), (chunks, None))))
+            }
+        }
+    })
+    .right_stream()
+}
+
+/// Compress the chunks of an export as they are written
+pub fn compress(
+    chunks: impl Stream<Item = Result<Bytes, storage::Error>> + 'static,
+    encoder: Box<dyn Encoder>,
+) -> impl Stream<Item = Result<Bytes, storage::Error>> {
+    let compression_error = |err: std::io::Error| {
+        storage::Error::Internal(format!("unable to compress the export: {err}"))
+    };
+    stream::try_unfold(
+        (Box::pin(chunks), Some(encoder)),
+        move |(mut chunks, encoder)| async move {
+            let Some(mut encoder) = encoder else {
+                return Ok(None);
+            };
+            match chunks.try_next().await? {
+                Some(chunk) => {
+                    let chunk = encoder.write(&chunk).map_err(compression_error)?;
+                    Ok(Some((chunk, (chunks, Some(encoder)))))
+                }
+                // The export ends with the rest of the compressed stream
+                None => {
+                    let chunk = encoder.finish().map_err(compression_error)?;
+                    Ok(Some((chunk, (chunks, None))))
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_summarize() {
+        let chunks = || {
+            stream::iter([
+                Ok((1, Bytes::from("{\"count\":1}\n"))),
+                Ok((2, Bytes::from("{\"count\":2}\n{\"count\":3}\n"))),
+            ])
+        };
+        let body: Vec<Bytes> = summarize(chunks(), None).try_collect().await.unwrap();
+        assert_eq!(
+            body.concat(),
+            b"{\"count\":1}\n{\"count\":2}\n{\"count\":3}\n"
+        );
+
+        let body: Vec<Bytes> = summarize(chunks(), Some(Format::Ndjson))
+            .try_collect()
+            .await
+            .unwrap();
+        let (summary, rows) = body.split_last().unwrap();
+        let rows = rows.concat();
+        let mut record: Value = serde_json::from_slice(summary).unwrap();
+        let summary: Summary = serde_json::from_value(record["summary"].take()).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                rows: 3,
+                bytes: rows.len(),
+                crc32: format!("{:08x}", crc32fast::hash(&rows)),
+            }
+        );
+
+        let body: Vec<Bytes> = summarize(chunks(), Some(Format::Csv))
+            .try_collect()
+            .await
+            .unwrap();
+        let last = body.last().unwrap();
+        assert!(last.starts_with(b"# rows=3 bytes=36 crc32="));
+    }
+
+    #[test]
+    fn test_csv() {
+        let events = vec![
+            ChangeEvent {
+                seq: 1,
+                id: 1,
+                timestamp: String::from("2024-09-03 14:00:00 UTC"),
+                data: json!({"device": "d1", "note": "a, \"b\""}),
+            },
+            ChangeEvent {
+                seq: 2,
+                id: 2,
+                timestamp: String::from("2024-09-03 14:00:01 UTC"),
+                data: json!({"device": "d2", "tags": [1, 2]}),
+            },
+        ];
+        let mut writer = Writer::new(Format::Csv, None);
+        assert_eq!(
+            writer.write(&events),
+            "seq,id,timestamp,device,note,tags\r\n\
+             1,1,2024-09-03 14:00:00 UTC,d1,\"a, \"\"b\"\"\",\r\n\
+             2,2,2024-09-03 14:00:01 UTC,d2,,\"[1,2]\"\r\n"
+        );
+
+        // The header is only written once
+        let chunk = writer.write(&events[..1]);
+        assert!(chunk.starts_with(b"1,1,"));
+
+        let mut writer = Writer::new(Format::Csv, Some(vec![String::from("device")]));
+        assert!(writer
+            .write(&events)
+            .starts_with(b"seq,id,timestamp,device\r\n"));
+    }
+}
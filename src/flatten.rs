@@ -0,0 +1,134 @@
+// Flatten nested objects into dotted keys before storage
+//
+// --flatten vendor_events        every level
+// --flatten vendor_events=1      one level
+//
+// {"device": {"id": "d1", "location": {"site": "lab"}}, "value": 20}
+// is stored as, every level
+// {"device.id": "d1", "device.location.site": "lab", "value": 20}
+// or as, one level
+// {"device.id": "d1", "device.location": {"site": "lab"}, "value": 20}
+//
+// Arrays and empty objects are kept as they are.
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+/// Flatten the documents of a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlattenRule {
+    pub table: String,
+    /// How many levels of nested objects are merged into their parent, every level when unset
+    pub depth: Option<usize>,
+}
+
+impl FromStr for FlattenRule {
+    type Err = String;
+
+    // <table> or <table>=<depth>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, depth) = match value.split_once('=') {
+            Some((table, depth)) => {
+                let depth = depth
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("invalid depth '{depth}': {err}"))?;
+                (table, Some(depth))
+            }
+            None => (value, None),
+        };
+        Ok(FlattenRule {
+            table: table.trim().to_string(),
+            depth,
+        })
+    }
+}
+
+/// The rule for a table, if any
+pub fn find<'a>(rules: &'a [FlattenRule], table: &str) -> Option<&'a FlattenRule> {
+    rules.iter().find(|rule| rule.table == table)
+}
+
+impl FlattenRule {
+    /// Flatten the nested objects of a document
+    pub fn flatten(&self, document: Value) -> Value {
+        match document {
+            Value::Object(fields) => {
+                let mut flat = Map::new();
+                flatten_into(&mut flat, None, fields, self.depth);
+                Value::Object(flat)
+            }
+            document => document,
+        }
+    }
+}
+
+// Move the fields of an object into `flat`, prefixing their keys
+fn flatten_into(
+    flat: &mut Map<String, Value>,
+    prefix: Option<&str>,
+    fields: Map<String, Value>,
+    depth: Option<usize>,
+) {
+    for (key, value) in fields {
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}.{key}"),
+            None => key,
+        };
+        match value {
+            Value::Object(fields) if !fields.is_empty() && depth != Some(0) => {
+                flatten_into(flat, Some(&key), fields, depth.map(|depth| depth - 1))
+            }
+            value => {
+                flat.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_flatten() {
+        let document = json!({
+            "device": {"id": "d1", "location": {"site": "lab"}},
+            "tags": [{"name": "a"}],
+            "empty": {},
+            "value": 20
+        });
+
+        let rule: FlattenRule = "vendor_events".parse().unwrap();
+        assert_eq!(
+            rule.flatten(document.clone()),
+            json!({
+                "device.id": "d1",
+                "device.location.site": "lab",
+                "tags": [{"name": "a"}],
+                "empty": {},
+                "value": 20
+            })
+        );
+
+        let rule: FlattenRule = "vendor_events=1".parse().unwrap();
+        assert_eq!(
+            rule.flatten(document.clone()),
+            json!({
+                "device.id": "d1",
+                "device.location": {"site": "lab"},
+                "tags": [{"name": "a"}],
+                "empty": {},
+                "value": 20
+            })
+        );
+
+        let rule: FlattenRule = "vendor_events=0".parse().unwrap();
+        assert_eq!(rule.flatten(document.clone()), document);
+        assert_eq!(rule.flatten(json!([1, 2])), json!([1, 2]));
+
+        assert!("vendor_events=deep".parse::<FlattenRule>().is_err());
+    }
+}
@@ -0,0 +1,500 @@
+// Forwarding stored rows to other HTTP endpoints
+//
+// [forward]
+// dead_letter_database = "site"
+// dead_letter_table = "dead_letters"
+// timeout = "10s"
+// max_attempts = 5
+// backoff = "1s"
+// capacity = 1000
+//
+// [[forward.endpoints]]
+// url = "https://downstream.internal/ingest"
+// databases = ["site"]
+// tables = ["readings"]
+// headers = { Authorization = "Bearer ..." }
+//
+// Once rows are stored, by a request or by the write queue, they are POSTed
+// to every endpoint whose `databases` and `tables` match, an empty list
+// matching any, as
+// {"database": "site", "table": "readings", "rows": [{"seq": 1, "id": 1, "timestamp": "...", "data": {...}}]}
+// Rows already stored under their idempotency key are not forwarded again.
+//
+// Deliveries are made in the background, in order for each endpoint, and
+// never hold up or fail the write. A delivery the endpoint could not be
+// reached for, or answered with 429 or 5xx, is retried after `backoff`,
+// doubled with every attempt up to MAX_BACKOFF. A delivery still failing
+// after `max_attempts`, refused with another status, or finding `capacity`
+// deliveries already waiting for its endpoint is stored in the dead letter
+// table of `dead_letter_database` (the default database when unset) as
+// {"endpoint": "...", "database": "site", "table": "readings", "attempts": 5, "error": "...", "payload": {...}}
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::rt::{self, task::JoinHandle, time};
+use chrono::Utc;
+use futures_util::future::join_all;
+// An HTTP client
+// https://docs.rs/reqwest/latest/reqwest/
+// cargo add reqwest --no-default-features --features stream
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, warn};
+
+use crate::metrics;
+use crate::storage::{self, Inserted, NewRecord, RecordMetadata, Storage};
+
+/// The longest a failed delivery waits before it is retried
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Where stored rows are forwarded to
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub url: String,
+    /// The databases forwarded, any when empty
+    #[serde(default)]
+    pub databases: Vec<String>,
+    /// The tables forwarded, any when empty
+    #[serde(default)]
+    pub tables: Vec<String>,
+    /// Headers sent with every delivery, e.g. Authorization
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Endpoint {
+    fn matches(&self, database: &str, table: &str) -> bool {
+        (self.databases.is_empty() || self.databases.iter().any(|name| name == database))
+            && (self.tables.is_empty() || self.tables.iter().any(|name| name == table))
+    }
+}
+
+/// How stored rows are forwarded
+#[derive(Clone, Debug)]
+pub struct ForwardSettings {
+    pub endpoints: Vec<Endpoint>,
+    pub dead_letter_database: String,
+    pub dead_letter_table: String,
+    /// How long an endpoint has to answer a delivery
+    pub timeout: Duration,
+    /// Deliveries are given up on after this many attempts
+    pub max_attempts: u32,
+    /// How long the first retry waits
+    pub backoff: Duration,
+    /// The most deliveries waiting for each endpoint
+    pub capacity: usize,
+}
+
+// The rows of an insert on their way to an endpoint
+struct Delivery {
+    database: String,
+    table: String,
+    payload: Value,
+}
+
+// Where deliveries which failed are kept
+#[derive(Clone)]
+struct DeadLetters {
+    storage: Arc<dyn Storage>,
+    database: String,
+    table: String,
+}
+
+impl DeadLetters {
+    async fn store(&self, url: &str, delivery: Delivery, attempts: u32, error: &str) {
+        warn!(
+            "unable to forward rows of {}/{} to {url}, keeping a dead letter: {error}",
+            delivery.database, delivery.table
+        );
+        metrics::FORWARD_DELIVERIES
+            .with_label_values(&[url, "dead_lettered"])
+            .inc();
+        let data = json!({
+            "endpoint": url,
+            "database": delivery.database,
+            "table": delivery.table,
+            "attempts": attempts,
+            "error": error,
+            "payload": delivery.payload,
+        });
+        let record = NewRecord {
+            timestamp: Utc::now(),
+            data: data.to_string(),
+            ordering_key: None,
+            idempotency_key: None,
+            metadata: RecordMetadata::default(),
+        };
+        if let Err(err) = self
+            .storage
+            .insert_batch(&self.database, &self.table, vec![record])
+            .await
+        {
+            error!(
+                "unable to store a dead letter in {}/{}: {err}",
+                self.database, self.table
+            );
+        }
+    }
+}
+
+// An endpoint with the deliveries waiting for it, no sender once closed
+struct Queue {
+    endpoint: Endpoint,
+    sender: Mutex<Option<mpsc::Sender<Delivery>>>,
+}
+
+/// Forwards stored rows to the endpoints they match
+#[derive(Clone)]
+pub struct Forwarder {
+    queues: Arc<Vec<Queue>>,
+    dead_letters: DeadLetters,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Forwarder {
+    /// Start a task delivering to each endpoint
+    pub fn start(storage: Arc<dyn Storage>, settings: ForwardSettings) -> Result<Self, String> {
+        storage::validate_name(&settings.dead_letter_database)
+            .and_then(|()| storage::validate_name(&settings.dead_letter_table))
+            .map_err(|err| format!("invalid dead letter table: {err}"))?;
+        let client = Client::builder()
+            .timeout(settings.timeout)
+            .build()
+            .map_err(|err| format!("unable to create the forwarding client: {err}"))?;
+        let dead_letters = DeadLetters {
+            storage,
+            database: settings.dead_letter_database.clone(),
+            table: settings.dead_letter_table.clone(),
+        };
+
+        let mut queues = vec![];
+        let mut workers = vec![];
+        for endpoint in &settings.endpoints {
+            if !endpoint.url.starts_with("http://") && !endpoint.url.starts_with("https://") {
+                return Err(format!(
+                    "the forward endpoint is not an HTTP URL: {}",
+                    endpoint.url
+                ));
+            }
+            #[cfg(not(feature = "tls"))]
+            if endpoint.url.starts_with("https://") {
+                return Err(format!(
+                    "an https:// forward endpoint needs a build with the tls feature: {}",
+                    endpoint.url
+                ));
+            }
+            let headers = endpoint_headers(endpoint)?;
+            let (sender, receiver) = mpsc::channel(settings.capacity.max(1));
+            workers.push(rt::spawn(deliver(
+                client.clone(),
+                endpoint.clone(),
+                headers,
+                receiver,
+                dead_letters.clone(),
+                settings.clone(),
+            )));
+            queues.push(Queue {
+                endpoint: endpoint.clone(),
+                sender: Mutex::new(Some(sender)),
+            });
+        }
+        Ok(Forwarder {
+            queues: Arc::new(queues),
+            dead_letters,
+            workers: Arc::new(Mutex::new(workers)),
+        })
+    }
+
+    /// Whether rows stored in a table are forwarded anywhere
+    pub fn forwards(&self, database: &str, table: &str) -> bool {
+        self.queues
+            .iter()
+            .any(|queue| queue.endpoint.matches(database, table))
+    }
+
+    /// Queue the rows an insert stored for every endpoint they match
+    pub fn send(&self, database: &str, table: &str, records: &[NewRecord], inserted: &[Inserted]) {
+        let rows: Vec<Value> = records
+            .iter()
+            .zip(inserted)
+            .filter(|(_, inserted)| !inserted.duplicate)
+            .map(|(record, inserted)| {
+                json!({
+                    "seq": inserted.seq,
+                    "id": inserted.id,
+                    "timestamp": record.timestamp.to_rfc3339(),
+                    "data": serde_json::from_str(&record.data)
+                        .unwrap_or(Value::String(record.data.clone())),
+                })
+            })
+            .collect();
+        if rows.is_empty() {
+            return;
+        }
+        let payload = json!({"database": database, "table": table, "rows": rows});
+
+        for queue in self.queues.iter() {
+            if !queue.endpoint.matches(database, table) {
+                continue;
+            }
+            let delivery = Delivery {
+                database: database.to_string(),
+                table: table.to_string(),
+                payload: payload.clone(),
+            };
+            let sender = queue.sender.lock().unwrap().clone();
+            let sent = match sender {
+                Some(sender) => sender.try_send(delivery),
+                None => Err(TrySendError::Closed(delivery)),
+            };
+            let (delivery, error) = match sent {
+                Ok(()) => continue,
+                Err(TrySendError::Full(delivery)) => (delivery, "the delivery queue is full"),
+                Err(TrySendError::Closed(delivery)) => (delivery, "forwarding has stopped"),
+            };
+            let (dead_letters, url) = (self.dead_letters.clone(), queue.endpoint.url.clone());
+            rt::spawn(async move { dead_letters.store(&url, delivery, 0, error).await });
+        }
+    }
+
+    /// Stop taking deliveries and wait up to `timeout` for the waiting ones
+    /// to be made, false when some were left
+    pub async fn close(&self, timeout: Duration) -> bool {
+        for queue in self.queues.iter() {
+            queue.sender.lock().unwrap().take();
+        }
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        match time::timeout(timeout, join_all(workers)).await {
+            Ok(_) => true,
+            Err(_) => {
+                error!(
+                    "forwarding was not done within {}",
+                    humantime::format_duration(timeout)
+                );
+                false
+            }
+        }
+    }
+}
+
+// Why a delivery failed
+enum Failure {
+    // Worth retrying, the endpoint was unreachable or busy
+    Retry(String),
+    // Refused, retrying would be refused again
+    Refused(String),
+}
+
+// The headers sent to an endpoint, checked when forwarding starts as an
+// invalid one would fail every delivery
+fn endpoint_headers(endpoint: &Endpoint) -> Result<HeaderMap, String> {
+    let invalid = |name: &str, err: &dyn std::fmt::Display| {
+        format!(
+            "invalid header {name} for the forward endpoint {}: {err}",
+            endpoint.url
+        )
+    };
+    endpoint
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let header_name =
+                HeaderName::try_from(name.as_str()).map_err(|err| invalid(name, &err))?;
+            let header_value =
+                HeaderValue::try_from(value.as_str()).map_err(|err| invalid(name, &err))?;
+            Ok((header_name, header_value))
+        })
+        .collect()
+}
+
+// Make the deliveries for an endpoint in order until the sender is gone
+async fn deliver(
+    client: Client,
+    endpoint: Endpoint,
+    headers: HeaderMap,
+    mut receiver: mpsc::Receiver<Delivery>,
+    dead_letters: DeadLetters,
+    settings: ForwardSettings,
+) {
+    let url = endpoint.url.as_str();
+    while let Some(delivery) = receiver.recv().await {
+        let mut backoff = settings.backoff;
+        let mut attempts = 0;
+        let failure = loop {
+            attempts += 1;
+            match post(&client, &endpoint, &headers, &delivery.payload).await {
+                Ok(()) => break None,
+                Err(Failure::Retry(err)) if attempts < settings.max_attempts => {
+                    debug!("retrying the delivery to {url} in {backoff:?}: {err}");
+                    metrics::FORWARD_DELIVERIES
+                        .with_label_values(&[url, "retried"])
+                        .inc();
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(Failure::Retry(err) | Failure::Refused(err)) => break Some(err),
+            }
+        };
+        match failure {
+            None => metrics::FORWARD_DELIVERIES
+                .with_label_values(&[url, "delivered"])
+                .inc(),
+            Some(err) => dead_letters.store(url, delivery, attempts, &err).await,
+        }
+    }
+}
+
+// POST a payload to an endpoint
+async fn post(
+    client: &Client,
+    endpoint: &Endpoint,
+    headers: &HeaderMap,
+    payload: &Value,
+) -> Result<(), Failure> {
+    let response = client
+        .post(&endpoint.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .headers(headers.clone())
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(|err| Failure::Retry(err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Err(Failure::Retry(format!("answered {status}")))
+    } else {
+        Err(Failure::Refused(format!("answered {status}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use crate::storage::{record, ChangesFilter, SqliteStorage};
+
+    #[actix_web::test]
+    async fn test_forwarder() {
+        // An endpoint busy for its first request and one refusing every request
+        let requests = web::Data::new(AtomicUsize::new(0));
+        let received = requests.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(requests.clone())
+                .route(
+                    "/busy",
+                    web::post().to(|requests: web::Data<AtomicUsize>| async move {
+                        match requests.fetch_add(1, Ordering::Relaxed) {
+                            0 => HttpResponse::ServiceUnavailable().finish(),
+                            _ => HttpResponse::Ok().finish(),
+                        }
+                    }),
+                )
+                .route("/refuse", web::post().to(HttpResponse::BadRequest))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let storage = Arc::new(SqliteStorage::new("./"));
+        let endpoint = |path: &str, tables: Vec<String>| Endpoint {
+            url: format!("http://127.0.0.1:{port}{path}"),
+            databases: vec![],
+            tables,
+            headers: BTreeMap::new(),
+        };
+        let forwarder = Forwarder::start(
+            storage.clone(),
+            ForwardSettings {
+                endpoints: vec![
+                    endpoint("/busy", vec![]),
+                    endpoint("/refuse", vec![String::from("readings")]),
+                ],
+                dead_letter_database: String::from("test_forwarder"),
+                dead_letter_table: String::from("dead_letters"),
+                timeout: Duration::from_secs(5),
+                max_attempts: 3,
+                backoff: Duration::from_millis(10),
+                capacity: 10,
+            },
+        )
+        .unwrap();
+        assert!(forwarder.forwards("site", "events"));
+
+        let inserted = |seq| Inserted {
+            id: seq,
+            seq,
+            duplicate: false,
+        };
+        forwarder.send("site", "events", &[record("{}")], &[inserted(1)]);
+        forwarder.send("site", "readings", &[record("{}")], &[inserted(1)]);
+        assert!(forwarder.close(Duration::from_secs(5)).await);
+        handle.stop(true).await;
+
+        // Retried until the busy endpoint took it, refused and dead lettered by the other
+        assert_eq!(received.load(Ordering::Relaxed), 3);
+        let filter = ChangesFilter {
+            since: 0,
+            after_id: None,
+            limit: 10,
+            key: None,
+        };
+        let dead_letters = storage
+            .changes("test_forwarder", "dead_letters", &filter)
+            .await
+            .unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].data["table"], "readings");
+        assert_eq!(dead_letters[0].data["attempts"], 1);
+        assert_eq!(dead_letters[0].data["payload"]["rows"][0]["seq"], 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_forwarder.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_headers() {
+        let settings = |name: &str, value: &str| ForwardSettings {
+            endpoints: vec![Endpoint {
+                url: String::from("http://127.0.0.1:9/"),
+                databases: vec![],
+                tables: vec![],
+                headers: BTreeMap::from([(name.to_string(), value.to_string())]),
+            }],
+            dead_letter_database: String::from("test_invalid_headers"),
+            dead_letter_table: String::from("dead_letters"),
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            capacity: 10,
+        };
+        let storage = Arc::new(SqliteStorage::new("./"));
+
+        // Refused when forwarding starts rather than on every delivery
+        for (name, value) in [("X Token", "secret"), ("X-Token", "line\nbreak")] {
+            let err = Forwarder::start(storage.clone(), settings(name, value))
+                .err()
+                .unwrap();
+            assert!(err.contains(name), "{err}");
+            assert!(!err.contains(value), "{err}");
+        }
+        let forwarder = Forwarder::start(storage, settings("X-Token", "secret")).unwrap();
+        assert!(forwarder.close(Duration::from_secs(5)).await);
+    }
+}
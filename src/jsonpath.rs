@@ -0,0 +1,169 @@
+// A small subset of JSON path used to point at values inside a document
+// $.readings[0].value
+// $["site name"].id
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A single step of a JSON path
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// A parsed JSON path such as `$.readings[0].value`, `$` by default
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct JsonPath {
+    pub segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    /// Look up the value the path points at, if any
+    pub fn select<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.segments
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                Segment::Key(key) => value.get(key),
+                Segment::Index(index) => value.get(index),
+            })
+    }
+
+    /// Remove the value the path points at from a document and return it
+    pub fn take(&self, value: &mut Value) -> Option<Value> {
+        let (last, parents) = self.segments.split_last()?;
+        let parent = parents
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                Segment::Key(key) => value.get_mut(key),
+                Segment::Index(index) => value.get_mut(index),
+            })?;
+        match (last, parent) {
+            (Segment::Key(key), Value::Object(map)) => map.remove(key),
+            (Segment::Index(index), Value::Array(items)) if *index < items.len() => {
+                Some(items.remove(*index))
+            }
+            _ => None,
+        }
+    }
+
+    /// The last key of the path, if it ends in a key
+    pub fn last_key(&self) -> Option<&str> {
+        match self.segments.last() {
+            Some(Segment::Key(key)) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = String;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        path.parse()
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let mut rest = path
+            .trim()
+            .strip_prefix('$')
+            .ok_or_else(|| format!("JSON path must start with '$': {path}"))?;
+        let mut segments = vec![];
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                // .name
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    return Err(format!("JSON path has an empty key: {path}"));
+                }
+                segments.push(Segment::Key(after[..end].to_string()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                // [0] or ["name"]
+                let end = after
+                    .find(']')
+                    .ok_or_else(|| format!("JSON path has an unclosed '[': {path}"))?;
+                let inner = after[..end].trim();
+                let segment = if let Some(quoted) = inner
+                    .strip_prefix('"')
+                    .and_then(|inner| inner.strip_suffix('"'))
+                    .or_else(|| {
+                        inner
+                            .strip_prefix('\'')
+                            .and_then(|inner| inner.strip_suffix('\''))
+                    }) {
+                    Segment::Key(quoted.to_string())
+                } else {
+                    Segment::Index(
+                        inner
+                            .parse()
+                            .map_err(|_| format!("JSON path has a bad index '{inner}': {path}"))?,
+                    )
+                };
+                segments.push(segment);
+                rest = &after[end + 1..];
+            } else {
+                return Err(format!("JSON path is malformed near '{rest}': {path}"));
+            }
+        }
+        Ok(JsonPath { segments })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$")?;
+        for segment in &self.segments {
+            match segment {
+                Segment::Key(key) if key.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                    write!(f, ".{key}")?
+                }
+                Segment::Key(key) => write!(f, "[\"{key}\"]")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_and_select() {
+        let data =
+            json!({"site": {"name": "a", "readings": [{"value": 1}, {"value": 2}]}, "a b": 3});
+
+        let path: JsonPath = "$.site.readings[1].value".parse().unwrap();
+        assert_eq!(path.select(&data), Some(&json!(2)));
+        assert_eq!(path.to_string(), "$.site.readings[1].value");
+
+        let path: JsonPath = "$[\"a b\"]".parse().unwrap();
+        assert_eq!(path.select(&data), Some(&json!(3)));
+
+        let path: JsonPath = "$.missing".parse().unwrap();
+        assert_eq!(path.select(&data), None);
+
+        let mut data = data.clone();
+        let path: JsonPath = "$.site.readings".parse().unwrap();
+        assert_eq!(path.last_key(), Some("readings"));
+        assert_eq!(
+            path.take(&mut data),
+            Some(json!([{"value": 1}, {"value": 2}]))
+        );
+        assert_eq!(data, json!({"site": {"name": "a"}, "a b": 3}));
+
+        assert!("site.name".parse::<JsonPath>().is_err());
+        assert!("$.site[".parse::<JsonPath>().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// Leader election for scheduled jobs
+//
+// [leader_election]
+// lease = "scheduled_jobs"
+// ttl = "30s"
+//
+// Instances sharing a backend, the same PostgreSQL database or the same
+// directory of SQLite files, elect a leader with a lease kept in the backend,
+// so the scheduled jobs such as the retention purge run once instead of on
+// every instance. Each instance tries to take the lease every third of its
+// `ttl`, the leader renews it and the others take it over once the leader
+// stops renewing it. A leader which can't renew its lease steps down when the
+// lease it last renewed runs out, before another instance can take it over.
+// The leader gives the lease up as it shuts down.
+//
+// The `holder` naming an instance defaults to its host name and process id.
+// Without leader election every instance runs the scheduled jobs.
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::{spawn, time};
+use tracing::{error, info, warn};
+
+use crate::metrics;
+use crate::storage::Storage;
+
+/// The lease an instance campaigns for
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaderSettings {
+    pub lease: String,
+    pub holder: String,
+    pub ttl: Duration,
+}
+
+/// The name of this instance, unique between the instances sharing a backend
+pub fn default_holder() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| String::from("localhost"));
+    format!("{host}:{}", process::id())
+}
+
+// The lease of this instance and when it runs out
+struct Lease {
+    settings: LeaderSettings,
+    expires: Mutex<Option<Instant>>,
+    resigned: AtomicBool,
+}
+
+/// Whether this instance runs the scheduled jobs, always without an election
+#[derive(Clone, Default)]
+pub struct Leader {
+    lease: Option<Arc<Lease>>,
+}
+
+impl Leader {
+    /// Campaign for the lease, then keep campaigning in the background
+    pub async fn start(storage: Arc<dyn Storage>, settings: LeaderSettings) -> Self {
+        info!(
+            "Campaigning for the {} lease as {}",
+            settings.lease, settings.holder
+        );
+        let interval = settings.ttl / 3;
+        let leader = Leader {
+            lease: Some(Arc::new(Lease {
+                settings,
+                expires: Mutex::default(),
+                resigned: AtomicBool::new(false),
+            })),
+        };
+        leader.campaign(storage.as_ref()).await;
+        let campaigner = leader.clone();
+        spawn(async move {
+            let mut ticks = time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                campaigner.campaign(storage.as_ref()).await;
+            }
+        });
+        leader
+    }
+
+    /// Whether this instance is the leader
+    pub fn is_leader(&self) -> bool {
+        let Some(lease) = &self.lease else {
+            return true;
+        };
+        let expires = lease.expires.lock().unwrap();
+        expires.is_some_and(|expires| Instant::now() < expires)
+    }
+
+    /// Take or renew the lease
+    async fn campaign(&self, storage: &dyn Storage) {
+        let Some(lease) = &self.lease else {
+            return;
+        };
+        if lease.resigned.load(Ordering::Relaxed) {
+            return;
+        }
+        let LeaderSettings {
+            lease: name,
+            holder,
+            ttl,
+        } = &lease.settings;
+        // The lease runs out here before it does in the backend
+        let started = Instant::now();
+        let was_leader = self.is_leader();
+        match storage.acquire_lease(name, holder, *ttl).await {
+            Ok(acquired) => {
+                *lease.expires.lock().unwrap() = acquired.then_some(started + *ttl);
+                match (was_leader, acquired) {
+                    (false, true) => info!("Leading the scheduled jobs as {holder}"),
+                    (true, false) => warn!("Another instance took over the {name} lease"),
+                    _ => {}
+                }
+            }
+            Err(err) => error!("unable to renew the {name} lease: {err}"),
+        }
+        metrics::LEADER.set(i64::from(self.is_leader()));
+    }
+
+    /// Give up the lease so another instance takes over at once, and stop
+    /// campaigning for it
+    pub async fn resign(&self, storage: &dyn Storage) {
+        let Some(lease) = &self.lease else {
+            return;
+        };
+        lease.resigned.store(true, Ordering::Relaxed);
+        let LeaderSettings {
+            lease: name,
+            holder,
+            ..
+        } = &lease.settings;
+        if lease.expires.lock().unwrap().take().is_none() {
+            return;
+        }
+        metrics::LEADER.set(0);
+        match storage.release_lease(name, holder).await {
+            Ok(()) => info!("Gave up the {name} lease"),
+            Err(err) => error!("unable to give up the {name} lease: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::SqliteStorage;
+
+    #[actix_web::test]
+    async fn test_leader() {
+        let database_files = std::env::temp_dir().join("adr_test_leader");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let storage: Arc<dyn Storage> =
+            Arc::new(SqliteStorage::new(database_files.to_str().unwrap()));
+        let settings = |holder: &str| LeaderSettings {
+            lease: String::from("scheduled_jobs"),
+            holder: holder.to_string(),
+            ttl: Duration::from_secs(30),
+        };
+
+        // The first instance leads, the second waits for the lease
+        let first = Leader::start(storage.clone(), settings("first")).await;
+        let second = Leader::start(storage.clone(), settings("second")).await;
+        assert!(first.is_leader());
+        assert!(!second.is_leader());
+        first.campaign(storage.as_ref()).await;
+        assert!(first.is_leader());
+
+        // The second takes over once the first gives the lease up
+        first.resign(storage.as_ref()).await;
+        assert!(!first.is_leader());
+        second.campaign(storage.as_ref()).await;
+        assert!(second.is_leader());
+
+        // A lease which ran out can be taken over
+        let ttl = Duration::ZERO;
+        assert!(storage
+            .acquire_lease("expired", "first", ttl)
+            .await
+            .unwrap());
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(storage
+            .acquire_lease("expired", "second", ttl)
+            .await
+            .unwrap());
+
+        // Without an election every instance leads
+        assert!(Leader::default().is_leader());
+
+        // Post test, remove any database files created
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+}
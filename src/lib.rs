@@ -0,0 +1,879 @@
+// A data receiver saving JSON formatted data into SQLite or PostgreSQL
+//
+// The receiver can be embedded in another actix-web server, or run as a whole
+// server with the scheduled jobs and the shutdown handling of the binary:
+//
+// let config = Config::load("config.toml")?;
+// let app = actix_data_receiver::build_app(&config)?;
+// HttpServer::new(move || app.app(Endpoints::All)).bind(("0.0.0.0", 8888))?.run().await?;
+// app.close(Duration::from_secs(30)).await;
+//
+// actix_data_receiver::run(config).await?;
+//
+// `build_app` starts the write queue, the forwarder and the lookup table
+// reloads the configuration asks for, so it is called from within the actix
+// runtime. The retention purge, the backups and leader election are left to
+// `run` and `serve`.
+//
+// The heap bytes on /metrics are counted by `memory::CountingAllocator`,
+// installed with `#[global_allocator]` by the binary.
+//
+// The optional parts are cargo features, the default build has `metrics`,
+// `tls` (HTTPS, for the server and for forwarding) and `pprof`, so a running
+// server can be profiled without a rebuild, `full` has them all:
+// - `metrics`: the /metrics endpoint and the HTTP request metrics
+// - `tls`: HTTPS with rustls
+// - `postgres`: the PostgreSQL storage backend
+// - `msgpack`, `cbor`: MessagePack and CBOR request bodies
+// - `zstd`, `lz4`: zstd and lz4 compressed exports
+// - `pprof`: CPU and heap profiles on /debug/pprof
+// - `swagger-ui`: Swagger UI on /_docs/ for the OpenAPI document on /openapi.json
+// - `sqlcipher`: SQLite database files encrypted with SQLCipher, not in `full`
+//   as it builds OpenSSL linked SQLCipher in place of SQLite
+//
+// cargo build --release --no-default-features --features metrics,zstd
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// A web framework for Rust
+// https://docs.rs/actix-web/latest/actix_web/web/index.html
+// cargo add actix-web
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    middleware::{from_fn, Logger},
+    rt, web, App, HttpServer,
+};
+
+// Combinators for futures and streams
+// https://docs.rs/futures-util/latest/futures_util/
+// cargo add futures-util
+use futures_util::future::try_join_all;
+
+// A framework for instrumenting Rust
+// https://docs.rs/tracing/latest/tracing
+// cargo add tracing
+use tracing::{error, info, warn};
+
+mod access;
+mod audit;
+mod auth;
+mod backup;
+pub mod compress;
+pub mod compute;
+pub mod config;
+mod consistency;
+pub mod debug;
+mod decompress;
+pub mod errors;
+mod export;
+pub mod flatten;
+mod forward;
+pub mod jsonpath;
+mod leader;
+pub mod listener;
+pub mod log_filter;
+pub mod lookups;
+pub mod memory;
+mod metrics;
+mod ordering;
+mod pod;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod proxy;
+mod rate_limit;
+pub mod replay;
+pub mod retention;
+pub mod routes;
+pub mod schemas;
+mod shutdown;
+pub mod split;
+pub mod storage;
+pub mod templates;
+#[cfg(feature = "tls")]
+mod tls;
+mod transcode;
+mod udp;
+pub mod watch;
+mod write_queue;
+
+pub use config::Config;
+pub use routes::Endpoints;
+
+use auth::ApiKeys;
+use config::{Backend, StorageConfig};
+use forward::Forwarder;
+use leader::Leader;
+use listener::Listener;
+use log_filter::LogFilter;
+use proxy::Proxy;
+use rate_limit::RateLimiter;
+use replay::{ReplayReport, ReplaySettings, ReplaySource};
+use routes::AppData;
+use shutdown::Readiness;
+#[cfg(feature = "postgres")]
+use storage::PostgresStorage;
+use storage::{RecordMetadata, ShadowStorage, SqliteStorage, Storage};
+use udp::UdpListener;
+use write_queue::WriteQueue;
+
+/// The state shared by the workers of the servers answering a configuration,
+/// each worker builds its application with `app`
+#[derive(Clone)]
+pub struct AppFactory {
+    appdata: web::Data<AppData>,
+    api_keys: web::Data<ApiKeys>,
+    log_filter: Option<web::Data<LogFilter>>,
+    rate_limiter: Option<web::Data<RateLimiter>>,
+    proxy: Option<web::Data<Proxy>>,
+    readiness: web::Data<Readiness>,
+    prometheus: metrics::Middleware,
+    max_body_size: usize,
+    /// The settings running which take a restart to change
+    restart_settings: Arc<BTreeMap<&'static str, String>>,
+}
+
+/// Build the application state described by a configuration
+pub fn build_app(config: &Config) -> io::Result<AppFactory> {
+    let storage = create_storage(&config.storage)?;
+    let mut appdata = AppData::new(storage, config).map_err(invalid_input)?;
+    if let Some(forward) = &config.forward {
+        let settings = forward
+            .settings(config.server.default_database.as_deref())
+            .map_err(invalid_input)?;
+        appdata.forwarder =
+            Some(Forwarder::start(appdata.storage.clone(), settings).map_err(invalid_input)?);
+    }
+    if let Some(write_queue) = &config.write_queue {
+        appdata.write_queue = Some(WriteQueue::start(
+            appdata.storage.clone(),
+            write_queue.settings(),
+            appdata.memory_budget.clone(),
+            appdata.forwarder.clone(),
+        ));
+    }
+    lookups::spawn_reload_task(
+        appdata.lookups.clone(),
+        config.server.lookup_reload_interval,
+    );
+    let rate_limiter = match &config.rate_limit {
+        Some(rate_limit) => {
+            let settings = rate_limit.settings().map_err(invalid_input)?;
+            Some(web::Data::new(RateLimiter::new(settings)))
+        }
+        None => None,
+    };
+
+    // Proxy mode forwards every request to the upstream instead of serving the data API
+    let proxy = match &config.proxy {
+        Some(proxy_config) => {
+            let database_name = proxy_config
+                .database
+                .as_ref()
+                .or(appdata.default_database.as_ref())
+                .ok_or_else(|| invalid_input("the proxy needs a database or --default-database"))?;
+            routes::check_table(&appdata, database_name, &proxy_config.table)
+                .map_err(invalid_input)?;
+            let proxy = Proxy::new(
+                &proxy_config.upstream,
+                database_name,
+                &proxy_config.table,
+                proxy_config.timeout,
+            )
+            .map_err(invalid_input)?;
+            info!("Forwarding requests to {}", proxy_config.upstream);
+            Some(web::Data::new(proxy))
+        }
+        None => None,
+    };
+
+    // Prometheus middleware
+    let prometheus = metrics::middleware()?;
+
+    Ok(AppFactory {
+        appdata: web::Data::new(appdata),
+        api_keys: web::Data::new(config.api_keys()),
+        log_filter: None,
+        rate_limiter,
+        proxy,
+        // Load balancers see the server is stopping before it stops accepting connections
+        readiness: web::Data::new(Readiness::default()),
+        prometheus,
+        max_body_size: config.server.max_body_size,
+        restart_settings: Arc::new(config.restart_settings()),
+    })
+}
+
+impl AppFactory {
+    /// Answer `/_admin/log-filter` with the filter of the tracing subscriber
+    pub fn log_filter(mut self, log_filter: LogFilter) -> Self {
+        self.log_filter = Some(web::Data::new(log_filter));
+        self
+    }
+
+    /// The application answering `endpoints`
+    pub fn app(
+        &self,
+        endpoints: Endpoints,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            // Inside the API key check, so clients are told apart by their key
+            .wrap(from_fn(rate_limit::limit))
+            .wrap(from_fn(auth::require_api_key))
+            .wrap(Logger::default())
+            .wrap(self.prometheus.clone())
+            .wrap(from_fn(pod::instrument))
+            // Outermost, so every response names its request
+            .wrap(from_fn(audit::assign_request_id))
+            .app_data(self.appdata.clone())
+            .app_data(self.api_keys.clone())
+            .app_data(self.readiness.clone())
+            .app_data(web::PayloadConfig::new(self.max_body_size))
+            .app_data(web::PathConfig::default().error_handler(errors::bad_request))
+            .app_data(web::QueryConfig::default().error_handler(errors::bad_request))
+            .configure(|cfg| {
+                if let Some(log_filter) = &self.log_filter {
+                    cfg.app_data(log_filter.clone());
+                }
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    cfg.app_data(rate_limiter.clone());
+                }
+                if let Some(proxy) = &self.proxy {
+                    cfg.app_data(proxy.clone());
+                }
+                routes::configure(
+                    cfg,
+                    endpoints,
+                    self.proxy.is_some(),
+                    !self.api_keys.is_empty(),
+                    self.log_filter.is_some(),
+                );
+            })
+    }
+
+    /// Apply the settings of a reloaded configuration which can change while
+    /// the server runs, warning of the changes to the others which take a restart
+    pub fn reload(&self, config: &Config) {
+        if let (Some(log_filter), Some(directives)) = (&self.log_filter, &config.server.log_filter)
+        {
+            if let Err(err) = log_filter.set(directives) {
+                error!("kept the running log filter: {err}");
+            }
+        }
+        if let (Some(rate_limiter), Some(rate_limit)) = (&self.rate_limiter, &config.rate_limit) {
+            match rate_limit.settings() {
+                Ok(settings) => rate_limiter.update(settings),
+                Err(err) => error!("kept the running rate limit: {err}"),
+            }
+        }
+        // Compared to the settings started with, until a restart applies them
+        let changed: Vec<_> = config
+            .restart_settings()
+            .into_iter()
+            .filter(|(key, value)| self.restart_settings.get(key) != Some(value))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &changed {
+            warn!("{key} changed, it takes a restart to apply");
+        }
+        match changed.is_empty() {
+            true => info!("Reloaded the configuration"),
+            false => info!("Reloaded the log filter and the rate limit of the configuration"),
+        }
+    }
+
+    /// Store the queued writes, make the waiting forward deliveries and close
+    /// the storage backend, once nothing is answered anymore
+    pub async fn close(&self, timeout: Duration) {
+        if let Some(write_queue) = &self.appdata.write_queue {
+            info!("Storing the queued writes");
+            write_queue.close(timeout).await;
+        }
+        if let Some(forwarder) = &self.appdata.forwarder {
+            info!("Making the waiting forward deliveries");
+            forwarder.close(timeout).await;
+        }
+        if let Err(err) = self.appdata.storage.close().await {
+            error!("unable to close the storage backend: {err}");
+        }
+    }
+}
+
+/// Serve a configuration until SIGTERM or SIGINT
+pub async fn run(config: Config) -> io::Result<()> {
+    let app = build_app(&config)?;
+    serve(app, &config).await
+}
+
+/// Serve the application of a configuration on the ports it names, running
+/// the scheduled jobs, until SIGTERM or SIGINT
+pub async fn serve(app: AppFactory, config: &Config) -> io::Result<()> {
+    let storage = app.appdata.storage.clone();
+
+    // With instances sharing the backend, one of them runs the scheduled jobs
+    let leader = match &config.leader_election {
+        Some(leader_election) => Leader::start(storage.clone(), leader_election.settings()).await,
+        None => Leader::default(),
+    };
+
+    // Purge expired rows in the background
+    retention::spawn_purge_task(
+        storage.clone(),
+        config.retention_policy(),
+        config.retention.interval,
+        leader.clone(),
+    );
+    if let Some(backup) = &config.backup {
+        backup::spawn_backup_task(storage.clone(), backup.settings(), leader.clone());
+    }
+
+    // Syslog and JSON lines from senders which can't speak HTTP
+    let udp_listener = match &config.udp {
+        Some(udp) => {
+            let settings = udp.settings(&config.server).map_err(invalid_input)?;
+            let udp_listener = UdpListener::start(app.appdata.clone(), settings).await?;
+            info!(
+                "Receiving syslog and JSON on UDP {}",
+                udp_listener.local_addr()
+            );
+            Some(udp_listener)
+        }
+        None => None,
+    };
+
+    // HTTPS when a certificate is configured
+    #[cfg(feature = "tls")]
+    let tls_config = match &config.server.tls {
+        Some(tls) => {
+            let tls_config = tls::server_config(&tls.cert, &tls.key).map_err(invalid_input)?;
+            info!("Serving HTTPS with {}", tls.cert.display());
+            Some(tls_config)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    if config.server.tls.is_some() {
+        return Err(invalid_input("HTTPS needs a build with the tls feature"));
+    }
+    let server_config = &config.server;
+
+    // Initialize an HTTP server with the application, each server has its own workers
+    let start = |endpoints: Endpoints,
+                 port: u16,
+                 workers: Option<usize>,
+                 sockets: Vec<Listener>| {
+        let factory = app.clone();
+        let server = HttpServer::new(move || factory.app(endpoints));
+        // Stopping is left to `shutdown::stop_on_signal`
+        let server = server
+            .disable_signals()
+            .shutdown_timeout(server_config.shutdown_timeout.as_secs());
+        let server = match workers {
+            Some(workers) => server.workers(workers),
+            None => server,
+        };
+        // Sockets passed by systemd, or the unix socket, take the place of the port
+        if !sockets.is_empty() {
+            let mut server = server;
+            for socket in sockets {
+                match socket {
+                    Listener::Tcp(socket) => {
+                        info!(
+                            "Serving {endpoints:?} endpoints on {}",
+                            socket.local_addr()?
+                        );
+                        #[cfg(feature = "tls")]
+                        if let Some(tls_config) = &tls_config {
+                            server = server.listen_rustls_0_23(socket, tls_config.clone())?;
+                            continue;
+                        }
+                        server = server.listen(socket)?;
+                    }
+                    #[cfg(unix)]
+                    Listener::Unix(socket) => {
+                        if server_config.tls.is_some() {
+                            return Err(invalid_input("HTTPS can't be served on a unix socket"));
+                        }
+                        let addr = socket.local_addr()?;
+                        let path = addr.as_pathname().unwrap_or(Path::new("a unix socket"));
+                        info!("Serving {endpoints:?} endpoints on {}", path.display());
+                        server = server.listen_uds(socket)?;
+                    }
+                }
+            }
+            return io::Result::Ok(server.run());
+        }
+        let listen = (server_config.addr.as_str(), port);
+        #[cfg(feature = "tls")]
+        let server = match (&tls_config, server_config.reuse_port) {
+            // The next version of the server can listen on the same port
+            (Some(tls_config), true) => {
+                let listener = listener::bind_reuse_port(listen.0, port)?;
+                server.listen_rustls_0_23(listener, tls_config.clone())?
+            }
+            (None, true) => server.listen(listener::bind_reuse_port(listen.0, port)?)?,
+            (Some(tls_config), false) => server.bind_rustls_0_23(listen, tls_config.clone())?,
+            (None, false) => server.bind(listen)?,
+        };
+        #[cfg(not(feature = "tls"))]
+        let server = match server_config.reuse_port {
+            true => server.listen(listener::bind_reuse_port(listen.0, port)?)?,
+            false => server.bind(listen)?,
+        };
+        info!("Serving {endpoints:?} endpoints on port {port}");
+        io::Result::Ok(server.run())
+    };
+
+    // Refused before any socket file is created
+    if server_config.read_port.is_some() && app.proxy.is_some() {
+        return Err(invalid_input("the read port can't be used in proxy mode"));
+    }
+    if server_config.unix_socket.is_some() && server_config.tls.is_some() {
+        return Err(invalid_input("HTTPS can't be served on a unix socket"));
+    }
+
+    // Started by systemd socket activation, or asked to listen on a unix
+    // socket, the server opens no TCP port of its own
+    let mut sockets = listener::inherited()?;
+    let mut unix_socket = None;
+    match &server_config.unix_socket {
+        Some(path) if sockets.is_empty() => {
+            sockets.push(listener::bind_unix_socket(
+                path,
+                server_config.unix_socket_mode,
+            )?);
+            unix_socket = Some(path);
+        }
+        Some(path) => warn!(
+            "Serving the sockets passed by systemd, not {}",
+            path.display()
+        ),
+        None => {}
+    }
+
+    info!("Starting actix-data-receiver");
+    let served = async {
+        let servers = match server_config.read_port {
+            // Reads are kept off the workers acknowledging writes
+            Some(read_port) => vec![
+                start(
+                    Endpoints::Write,
+                    server_config.port,
+                    server_config.workers,
+                    sockets,
+                )?,
+                start(
+                    Endpoints::Read,
+                    read_port,
+                    server_config.read_workers,
+                    vec![],
+                )?,
+            ],
+            None => vec![start(
+                Endpoints::All,
+                server_config.port,
+                server_config.workers,
+                sockets,
+            )?],
+        };
+        rt::spawn(shutdown::stop_on_signal(
+            servers.iter().map(|server| server.handle()).collect(),
+            app.readiness.clone(),
+            server_config.drain_delay,
+        ));
+        try_join_all(servers).await.map(drop)
+    }
+    .await;
+    // The socket file goes with the servers, whether they stopped or failed to start
+    if let Some(path) = unix_socket {
+        if let Err(err) = std::fs::remove_file(path) {
+            warn!("unable to remove {}: {err}", path.display());
+        }
+    }
+    served?;
+
+    // Nothing is answered anymore, store what was accepted and close up
+    if let Some(udp_listener) = udp_listener {
+        udp_listener.close(server_config.shutdown_timeout).await;
+    }
+    leader.resign(storage.as_ref()).await;
+    app.close(server_config.shutdown_timeout).await;
+    info!("Stopped actix-data-receiver");
+    Ok(())
+}
+
+/// Read NDJSON into a table without the web frontend, the documents go
+/// through the same pipeline as `PUT /<database>/<table>`
+pub async fn ingest<R: BufRead>(
+    config: &Config,
+    database: Option<&str>,
+    table_name: &str,
+    batch_size: usize,
+    reader: R,
+) -> io::Result<()> {
+    let storage = create_storage(&config.storage)?;
+    let appdata = AppData::new(storage, config).map_err(invalid_input)?;
+
+    let database_name = match database.or(appdata.default_database.as_deref()) {
+        Some(database_name) => database_name.to_string(),
+        None => {
+            return Err(invalid_input(
+                "--database or --default-database is required",
+            ))
+        }
+    };
+    routes::check_table(&appdata, &database_name, table_name).map_err(invalid_input)?;
+
+    let batch_size = batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let (mut ingested, mut skipped) = (0, 0);
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(value) => batch.push(value),
+            Err(err) => {
+                error!("line {} is not valid JSON: {err}", index + 1);
+                skipped += 1;
+                continue;
+            }
+        }
+        if batch.len() >= batch_size {
+            let values = std::mem::take(&mut batch);
+            let (_, inserted) = routes::ingest(
+                &appdata,
+                &database_name,
+                table_name,
+                values,
+                None,
+                None,
+                &RecordMetadata::default(),
+            )
+            .await
+            .map_err(io::Error::other)?;
+            ingested += inserted.len();
+        }
+    }
+    if !batch.is_empty() {
+        let (_, inserted) = routes::ingest(
+            &appdata,
+            &database_name,
+            table_name,
+            batch,
+            None,
+            None,
+            &RecordMetadata::default(),
+        )
+        .await
+        .map_err(io::Error::other)?;
+        ingested += inserted.len();
+    }
+
+    info!("ingested {ingested} rows into {database_name}/{table_name}, skipped {skipped} lines");
+    match skipped {
+        0 => Ok(()),
+        _ => Err(invalid_input(format!(
+            "skipped {skipped} lines of invalid JSON"
+        ))),
+    }
+}
+
+/// Send the rows of an export, or of a stored table, to another instance at
+/// the pace they were stored at, see replay.rs
+pub async fn replay(
+    config: &Config,
+    source: &ReplaySource,
+    settings: &ReplaySettings,
+) -> io::Result<ReplayReport> {
+    let storage = match source {
+        ReplaySource::Table { .. } => Some(create_storage(&config.storage)?),
+        ReplaySource::File(_) => None,
+    };
+    let lines = replay::read(source, storage)?;
+    let report = replay::replay(lines, source, settings).await?;
+    info!(
+        "replayed {} requests to {} in {:.1}s",
+        report.sent, settings.target, report.seconds
+    );
+    Ok(report)
+}
+
+// The storage backend is selected with `--backend`
+fn create_storage(storage_config: &StorageConfig) -> io::Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = match storage_config.backend {
+        Backend::Sqlite => Arc::new(sqlite_storage(storage_config)?),
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => {
+            let dsn = storage_config
+                .dsn
+                .as_deref()
+                .ok_or_else(|| invalid_input("--dsn is required with --backend postgres"))?;
+            let storage = PostgresStorage::new(dsn)
+                .map_err(|err| invalid_input(format!("invalid PostgreSQL --dsn: {err}")))?;
+            Arc::new(storage.schema_mode(storage_config.schema_mode))
+        }
+    };
+    info!("Using the {:?} storage backend", storage_config.backend);
+
+    // Every operation is repeated on the shadow backend and compared
+    match &storage_config.shadow {
+        Some(shadow_config) if shadow_config.shadow.is_some() => {
+            Err(invalid_input("a shadow backend can not have a shadow"))
+        }
+        Some(shadow_config) => {
+            let shadow = create_storage(shadow_config)?;
+            info!(
+                "Shadowing with the {:?} storage backend",
+                shadow_config.backend
+            );
+            Ok(Arc::new(ShadowStorage::new(storage, shadow)))
+        }
+        None => Ok(storage),
+    }
+}
+
+// The SQLite backend, with the files encrypted when a key is configured
+fn sqlite_storage(storage_config: &StorageConfig) -> io::Result<SqliteStorage> {
+    let key = storage_config.sqlite.key().map_err(invalid_input)?;
+    // SQLite without SQLCipher ignores the key, leaving the files in plaintext
+    #[cfg(not(feature = "sqlcipher"))]
+    if key.is_some() {
+        return Err(invalid_input(
+            "an encryption key needs a build with the sqlcipher feature",
+        ));
+    }
+    Ok(SqliteStorage::new(&storage_config.database_files)
+        .schema_mode(storage_config.schema_mode)
+        .pragmas(storage_config.sqlite.pragmas())
+        .key(key))
+}
+
+/// Encrypt plaintext SQLite databases in place with the configured key,
+/// every database when none are named, while no server uses them
+/// The leases are encrypted too, backups already written stay in plaintext
+pub async fn encrypt(config: &Config, databases: &[String]) -> io::Result<Vec<String>> {
+    if config.storage.backend != Backend::Sqlite {
+        return Err(invalid_input("only SQLite databases can be encrypted"));
+    }
+    if config
+        .storage
+        .sqlite
+        .key()
+        .map_err(invalid_input)?
+        .is_none()
+    {
+        return Err(invalid_input("--db-key or --db-key-file is required"));
+    }
+    let storage = sqlite_storage(&config.storage)?;
+    let databases = match databases {
+        [] => storage.databases().await.map_err(io::Error::other)?,
+        databases => databases.to_vec(),
+    };
+    for database in &databases {
+        storage
+            .encrypt(database)
+            .map_err(|err| io::Error::other(format!("unable to encrypt {database}: {err}")))?;
+    }
+    // A keyed server couldn't open plaintext leases to elect a leader
+    storage
+        .encrypt_leases()
+        .map_err(|err| io::Error::other(format!("unable to encrypt the leases: {err}")))?;
+    Ok(databases)
+}
+
+/// Rebuild the SQLite tables created by an older version so the ids of
+/// deleted rows are never handed out again, in every database when none are
+/// named, returning the `<database>/<table>` names of the tables rebuilt
+/// Writes to a table wait while its rows are copied
+pub async fn migrate(config: &Config, databases: &[String]) -> io::Result<Vec<String>> {
+    if config.storage.backend != Backend::Sqlite {
+        return Err(invalid_input("only SQLite databases need migrating"));
+    }
+    let storage = sqlite_storage(&config.storage)?;
+    let databases = match databases {
+        [] => storage.databases().await.map_err(io::Error::other)?,
+        databases => databases.to_vec(),
+    };
+    let mut rebuilt = vec![];
+    for database in &databases {
+        let tables = storage
+            .tables(database)
+            .await
+            .map_err(|err| io::Error::other(format!("unable to list {database}: {err}")))?;
+        for table in tables {
+            let name = format!("{database}/{table}");
+            if storage
+                .rebuild_ids(database, &table)
+                .map_err(|err| io::Error::other(format!("unable to rebuild {name}: {err}")))?
+            {
+                rebuilt.push(name);
+            }
+        }
+    }
+    Ok(rebuilt)
+}
+
+// A startup error caused by the configuration
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn test_build_app() {
+        let mut config = Config::default();
+        config.server.default_database = Some(String::from("test_build_app"));
+        let app = build_app(&config).unwrap();
+
+        // Every endpoint, the probes and the metrics are answered
+        let service = test::init_service(app.app(Endpoints::All)).await;
+        let req = test::TestRequest::put()
+            .uri("/events")
+            .set_payload("{\"embedded\": true}")
+            .to_request();
+        let response = test::call_service(&service, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let uris = [
+            "/ping",
+            "/healthz/live",
+            #[cfg(feature = "metrics")]
+            "/metrics",
+        ];
+        for uri in uris {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&service, req).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+
+        // The read endpoints alone don't take writes
+        let service = test::init_service(app.app(Endpoints::Read)).await;
+        let req = test::TestRequest::put()
+            .uri("/events")
+            .set_payload("{\"embedded\": true}")
+            .to_request();
+        let response = test::call_service(&service, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        app.close(Duration::from_secs(1)).await;
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_build_app.db").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn test_serve_unix_socket_error() {
+        // The read port is taken, the server can't start
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join("adr_test_serve_error.sock");
+        let mut config = Config::default();
+        config.server.addr = String::from("127.0.0.1");
+        config.server.read_port = Some(taken.local_addr().unwrap().port());
+        config.server.unix_socket = Some(path.clone());
+        let app = build_app(&config).unwrap();
+        assert!(serve(app.clone(), &config).await.is_err());
+
+        // The socket file isn't left behind for the next start to trip on
+        assert!(!path.exists());
+        app.close(Duration::from_secs(1)).await;
+    }
+
+    #[actix_web::test]
+    async fn test_migrate() {
+        let database_files = std::env::temp_dir().join("adr_test_migrate");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let mut config = Config::default();
+        config.storage.database_files = database_files.to_str().unwrap().to_string();
+
+        // A table as created before ids were AUTOINCREMENT, next to a current one
+        let storage = sqlite_storage(&config.storage).unwrap();
+        storage
+            .insert_batch("test_migrate", "current", vec![storage::record("{}")])
+            .await
+            .unwrap();
+        let conn = rusqlite::Connection::open(storage.path("test_migrate")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE older (
+                id INTEGER PRIMARY KEY,
+                seq INTEGER NOT NULL,
+                timestamp DATETIME NOT NULL,
+                data TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+        drop(conn);
+
+        // Only the older table is rebuilt, once
+        let rebuilt = migrate(&config, &[]).await.unwrap();
+        assert_eq!(rebuilt, vec![String::from("test_migrate/older")]);
+        assert!(migrate(&config, &[]).await.unwrap().is_empty());
+        assert!(migrate(&config, &[String::from("test_missing")])
+            .await
+            .is_err());
+
+        // Post test, remove any database files created
+        drop(storage);
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[actix_web::test]
+    async fn test_encrypt() {
+        use crate::storage::{record, ChangesFilter, DbKey, SqliteStorage};
+
+        let database_files = std::env::temp_dir().join("adr_test_encrypt");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let mut config = Config::default();
+        config.storage.database_files = database_files.to_str().unwrap().to_string();
+
+        // A plaintext database and leases, as a server without a key left them
+        let plaintext = SqliteStorage::new(&config.storage.database_files);
+        plaintext
+            .insert_batch("test_encrypt", "events", vec![record("{}")])
+            .await
+            .unwrap();
+        let ttl = Duration::from_secs(30);
+        assert!(plaintext.acquire_lease("leader", "old", ttl).await.unwrap());
+        drop(plaintext);
+
+        // Every database and the leases are encrypted
+        config.storage.sqlite.key = Some(DbKey::new("correct horse battery staple"));
+        let databases = encrypt(&config, &[]).await.unwrap();
+        assert_eq!(databases, vec![String::from("test_encrypt")]);
+        let storage = create_storage(&config.storage).unwrap();
+        let filter = ChangesFilter {
+            limit: 10,
+            ..Default::default()
+        };
+        let events = storage
+            .changes("test_encrypt", "events", &filter)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(!storage.acquire_lease("leader", "new", ttl).await.unwrap());
+        storage.release_lease("leader", "old").await.unwrap();
+        assert!(storage.acquire_lease("leader", "new", ttl).await.unwrap());
+
+        // Post test, remove any database files created
+        drop(storage);
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+// Listening sockets shared with the next version of the server
+//
+// [server]
+// reuse_port = true
+//
+// With `reuse_port` the listening sockets are opened with SO_REUSEPORT, so a
+// new binary can start on the same ports while the old one still runs, and
+// the kernel hands new connections to both. Upgrading without a gap in
+// ingestion is then
+// 1. start the new binary with the same configuration
+// 2. once its /healthz/ready answers 200, send SIGTERM to the old process
+// The old process drains and stops accepting as described in shutdown.rs.
+// Connections still waiting in its backlog when it closes its sockets are
+// reset, senders retrying a failed connection carry on with the new process.
+//
+// [server]
+// unix_socket = "/run/receiver.sock"
+// unix_socket_mode = "0660"
+//
+// With `unix_socket` (or `--unix-socket`) the server listens on a unix socket
+// instead of its TCP port, so it can sit behind a reverse proxy on the same
+// host without a port open to the network, e.g. with nginx
+// proxy_pass http://unix:/run/receiver.sock;
+// The socket gets the permissions of `unix_socket_mode` and is removed when
+// the server stops. A socket left behind by a server which did not stop is
+// replaced, one a server still answers on is not.
+//
+// Started by systemd socket activation, the server listens on the sockets
+// systemd passes it (LISTEN_FDS) instead of opening its own, e.g.
+// # receiver.socket
+// [Socket]
+// ListenStream=/run/receiver.sock
+// SocketMode=0660
+// The read port, when there is one, is still opened by the server.
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(unix)]
+use std::{
+    fs::{self, Permissions},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+};
+
+// Sockets passed by systemd socket activation
+// https://docs.rs/listenfd/latest/listenfd/
+// cargo add listenfd
+use listenfd::ListenFd;
+use serde::Deserialize;
+
+// Sockets with the options the standard library leaves out
+// https://docs.rs/socket2/latest/socket2/
+// cargo add socket2 --features all
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Connections waiting to be accepted, as actix-web has by default
+const BACKLOG: i32 = 2048;
+
+/// A socket the server listens on in place of its port
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// The permissions of a unix socket, in octal e.g. 0660
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct SocketMode(pub u32);
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let digits = value.strip_prefix("0o").unwrap_or(value);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("expected permissions in octal, e.g. 0660: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for SocketMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The sockets passed by systemd socket activation, none when the server
+/// was not started by it
+pub fn inherited() -> io::Result<Vec<Listener>> {
+    let mut fds = ListenFd::from_env();
+    (0..fds.len()).map(|index| take(&mut fds, index)).collect()
+}
+
+// A passed socket is a TCP socket, or a unix socket when it is not one
+fn take(fds: &mut ListenFd, index: usize) -> io::Result<Listener> {
+    let err = match fds.take_tcp_listener(index) {
+        Ok(Some(listener)) => return Ok(Listener::Tcp(listener)),
+        Ok(None) => io::Error::other(format!("socket {index} was already taken")),
+        Err(err) => err,
+    };
+    #[cfg(unix)]
+    if let Ok(Some(listener)) = fds.take_unix_listener(index) {
+        return Ok(Listener::Unix(listener));
+    }
+    Err(err)
+}
+
+/// A unix socket with the permissions of `mode`, replacing a socket left
+/// behind at `path`
+#[cfg(unix)]
+pub fn bind_unix_socket(path: &Path, mode: Option<SocketMode>) -> io::Result<Listener> {
+    let stale = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if stale {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a server is listening on {} already", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode.0))?;
+    }
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix_socket(path: &Path, _mode: Option<SocketMode>) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} can't be listened on, this system has no unix sockets",
+            path.display()
+        ),
+    ))
+}
+
+/// A socket listening on the first address `addr` resolves to, which other
+/// processes can listen on too
+pub fn bind_reuse_port(addr: &str, port: u16) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for address in (addr, port).to_socket_addrs()? {
+        match listen(address) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{addr} has no address to listen on"),
+        )
+    }))
+}
+
+fn listen(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    set_reuse_port(&socket)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reuse_port needs SO_REUSEPORT, which this system does not have",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_reuse_port() {
+        // Two listeners on the same port, as an old and a new server
+        let old = bind_reuse_port("127.0.0.1", 0).unwrap();
+        let port = old.local_addr().unwrap().port();
+        let new = bind_reuse_port("127.0.0.1", port).unwrap();
+        assert_eq!(new.local_addr().unwrap().port(), port);
+
+        // A listener without SO_REUSEPORT can't share it
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        assert_eq!("0660".parse(), Ok(SocketMode(0o660)));
+        assert_eq!("0o600".parse(), Ok(SocketMode(0o600)));
+        assert!("0680".parse::<SocketMode>().is_err());
+        assert!("1777".parse::<SocketMode>().is_err());
+        assert!("rw-rw----".parse::<SocketMode>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_socket() {
+        let path =
+            std::env::temp_dir().join(format!("test_bind_unix_socket_{}.sock", std::process::id()));
+        let Listener::Unix(listener) = bind_unix_socket(&path, Some(SocketMode(0o660))).unwrap()
+        else {
+            panic!("expected a unix socket");
+        };
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // A socket still listened on is kept, a socket left behind is replaced
+        assert!(bind_unix_socket(&path, None).is_err());
+        drop(listener);
+        assert!(bind_unix_socket(&path, None).is_ok());
+
+        // Post test, remove the socket
+        fs::remove_file(&path).unwrap();
+    }
+}
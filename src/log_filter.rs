@@ -0,0 +1,64 @@
+// Log filter directives which can be changed while the server runs
+//
+// GET /_admin/log-filter
+// PUT /_admin/log-filter
+// curl -i -X PUT -d 'warn,actix_data_receiver::storage=debug' http://localhost:8888/_admin/log-filter
+//
+// Directives are a default level followed by `<target>=<level>` pairs, a
+// target being a module path such as `actix_data_receiver::storage`. The
+// change lasts until the server restarts, it starts again at the level of
+// `--debug`, `--verbose` or RUST_LOG.
+// https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/targets/struct.Targets.html
+use tracing::{level_filters::LevelFilter, Level};
+use tracing_subscriber::{filter::Targets, reload, Registry};
+
+/// A handle on the log filter of the running server
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    handle: reload::Handle<Targets, Registry>,
+}
+
+impl LogFilter {
+    /// A filter logging at `level`, and the handle to change it with
+    pub fn new(level: Level) -> (reload::Layer<Targets, Registry>, Self) {
+        let targets = Targets::new().with_default(LevelFilter::from_level(level));
+        let (layer, handle) = reload::Layer::new(targets);
+        (layer, LogFilter { handle })
+    }
+
+    /// The directives in use
+    pub fn directives(&self) -> Result<String, String> {
+        self.handle
+            .with_current(|targets| targets.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Replace the directives in use
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let targets: Targets = directives
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid log filter {directives}: {err}"))?;
+        self.handle.reload(targets).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let (_layer, log_filter) = LogFilter::new(Level::WARN);
+        assert_eq!(log_filter.directives().unwrap(), "warn");
+
+        log_filter
+            .set("warn,actix_data_receiver::storage=debug")
+            .unwrap();
+        assert_eq!(
+            log_filter.directives().unwrap(),
+            "actix_data_receiver::storage=debug,warn"
+        );
+        assert!(log_filter.set("storage=loud").is_err());
+    }
+}
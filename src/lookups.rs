@@ -0,0 +1,369 @@
+// Lookup tables joined into documents at insert
+//
+// --lookup devices=/etc/receiver/devices.csv
+// --enrich 'readings=devices:$.device_id'
+//
+// devices.csv
+// device_id,site,firmware
+// d1,lab,1.4.2
+//
+// {"device_id": "d1", "value": 20} is stored as
+// {"device_id": "d1", "value": 20, "site": "lab", "firmware": "1.4.2"}
+//
+// A CSV file is keyed by its first column unless another key column is
+// configured. A JSON file is either an object of rows by key, or an array of
+// rows with a key field. Fields already in a document are never replaced.
+// Files are checked for changes every `--lookup-reload-interval` and loaded
+// again when they change, a file which fails to load keeps the rows it had.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_web::rt::{spawn, time};
+use serde_json::{Map, Value};
+use tracing::{error, info};
+
+use crate::jsonpath::JsonPath;
+
+/// A lookup table file
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookupSource {
+    pub name: String,
+    pub path: PathBuf,
+    /// The column or field rows are keyed by
+    pub key: Option<String>,
+}
+
+impl FromStr for LookupSource {
+    type Err = String;
+
+    // <name>=<path to a .csv or .json file>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, path) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <name>=<path>: {value}"))?;
+        Ok(LookupSource {
+            name: name.trim().to_string(),
+            path: PathBuf::from(path.trim()),
+            key: None,
+        })
+    }
+}
+
+/// The rows of a lookup table by key
+#[derive(Debug, Default)]
+pub struct LookupTable {
+    pub rows: HashMap<String, Map<String, Value>>,
+    modified: Option<SystemTime>,
+}
+
+impl LookupSource {
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    /// Read the rows of the file
+    pub fn load(&self) -> Result<LookupTable, String> {
+        let path = self.path.display();
+        let modified = self.modified();
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|err| format!("unable to read lookup {path}: {err}"))?;
+        let is_json = self
+            .path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let rows = match is_json {
+            true => self.json_rows(&text),
+            false => self.csv_rows(&text),
+        }
+        .map_err(|err| format!("invalid lookup {path}: {err}"))?;
+        Ok(LookupTable { rows, modified })
+    }
+
+    fn csv_rows(&self, text: &str) -> Result<HashMap<String, Map<String, Value>>, String> {
+        let mut records = parse_csv(text).into_iter();
+        let header = records.next().ok_or("the file is empty")?;
+        let key_index = match &self.key {
+            Some(key) => header
+                .iter()
+                .position(|column| column == key)
+                .ok_or_else(|| format!("no column named {key}"))?,
+            None => 0,
+        };
+        let mut rows = HashMap::new();
+        for record in records {
+            let Some(key) = record.get(key_index) else {
+                continue;
+            };
+            let row = header
+                .iter()
+                .cloned()
+                .zip(record.iter().cloned().map(Value::String))
+                .collect();
+            rows.insert(key.clone(), row);
+        }
+        Ok(rows)
+    }
+
+    fn json_rows(&self, text: &str) -> Result<HashMap<String, Map<String, Value>>, String> {
+        let rows = match serde_json::from_str(text).map_err(|err| err.to_string())? {
+            // {"d1": {"site": "lab"}}
+            Value::Object(rows) => rows
+                .into_iter()
+                .filter_map(|(key, row)| match row {
+                    Value::Object(row) => Some((key, row)),
+                    _ => None,
+                })
+                .collect(),
+            // [{"device_id": "d1", "site": "lab"}]
+            Value::Array(rows) => {
+                let key = self
+                    .key
+                    .as_deref()
+                    .ok_or("an array of rows needs a key field")?;
+                rows.into_iter()
+                    .filter_map(|row| match row {
+                        Value::Object(row) => Some((key_text(row.get(key)?)?, row)),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            _ => return Err(String::from("expected an object or an array of rows")),
+        };
+        Ok(rows)
+    }
+}
+
+// Keys are matched as text, numbers included
+fn key_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(boolean) => Some(boolean.to_string()),
+        _ => None,
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc4180
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records.retain(|record| record.iter().any(|field| !field.is_empty()));
+    records
+}
+
+/// Every lookup table by name, shared with the reload task
+#[derive(Clone, Debug, Default)]
+pub struct Lookups {
+    sources: Arc<Vec<LookupSource>>,
+    tables: Arc<RwLock<HashMap<String, Arc<LookupTable>>>>,
+}
+
+impl Lookups {
+    /// Read every lookup table, a file which can not be read is an error
+    pub fn load(sources: Vec<LookupSource>) -> Result<Self, String> {
+        let mut tables = HashMap::new();
+        for source in &sources {
+            tables.insert(source.name.clone(), Arc::new(source.load()?));
+        }
+        Ok(Lookups {
+            sources: Arc::new(sources),
+            tables: Arc::new(RwLock::new(tables)),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// The rows of a lookup table
+    pub fn get(&self, name: &str) -> Option<Arc<LookupTable>> {
+        let tables = self.tables.read().unwrap_or_else(|err| err.into_inner());
+        tables.get(name).cloned()
+    }
+
+    /// Load the files which changed since they were last loaded
+    pub fn reload(&self) {
+        for source in self.sources.iter() {
+            let loaded = self.get(&source.name).and_then(|table| table.modified);
+            if source.modified() == loaded {
+                continue;
+            }
+            match source.load() {
+                Ok(table) => {
+                    info!(
+                        "reloaded lookup {} with {} rows",
+                        source.name,
+                        table.rows.len()
+                    );
+                    let mut tables = self.tables.write().unwrap_or_else(|err| err.into_inner());
+                    tables.insert(source.name.clone(), Arc::new(table));
+                }
+                Err(err) => error!("lookup {} kept its rows: {err}", source.name),
+            }
+        }
+    }
+}
+
+/// Check the lookup files for changes in the background every `interval`
+pub fn spawn_reload_task(lookups: Lookups, interval: Duration) {
+    if lookups.is_empty() {
+        return;
+    }
+    spawn(async move {
+        let mut ticks = time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            lookups.reload();
+        }
+    });
+}
+
+/// Join the rows of a lookup table into the documents of a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnrichRule {
+    pub table: String,
+    pub lookup: String,
+    /// Where the key is found in a document
+    pub key: JsonPath,
+}
+
+impl FromStr for EnrichRule {
+    type Err = String;
+
+    // <table>=<lookup>:<JSON path of the key>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, rule) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>=<lookup>:<JSON path>: {value}"))?;
+        let (lookup, key) = rule
+            .split_once(':')
+            .ok_or_else(|| format!("expected <table>=<lookup>:<JSON path>: {value}"))?;
+        Ok(EnrichRule {
+            table: table.trim().to_string(),
+            lookup: lookup.trim().to_string(),
+            key: key.parse()?,
+        })
+    }
+}
+
+/// Add the fields of the matching lookup rows to a document
+pub fn enrich(rules: &[EnrichRule], lookups: &Lookups, table: &str, document: &mut Value) {
+    for rule in rules.iter().filter(|rule| rule.table == table) {
+        let Some(key) = rule.key.select(document).and_then(key_text) else {
+            continue;
+        };
+        let Some(lookup) = lookups.get(&rule.lookup) else {
+            continue;
+        };
+        let (Some(row), Value::Object(fields)) = (lookup.rows.get(&key), &mut *document) else {
+            continue;
+        };
+        for (field, value) in row {
+            if !fields.contains_key(field) {
+                fields.insert(field.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_enrich() {
+        let directory = std::env::temp_dir().join("adr_test_lookups");
+        std::fs::create_dir_all(&directory).unwrap();
+        let csv = directory.join("devices.csv");
+        std::fs::write(
+            &csv,
+            "device_id,site,note\r\nd1,lab,\"first, \"\"old\"\"\"\nd2,roof,\n",
+        )
+        .unwrap();
+        let json = directory.join("firmware.json");
+        std::fs::write(&json, r#"[{"model": 7, "firmware": "1.4.2"}]"#).unwrap();
+
+        let lookups = Lookups::load(vec![
+            LookupSource {
+                name: String::from("devices"),
+                path: csv.clone(),
+                key: None,
+            },
+            LookupSource {
+                name: String::from("firmware"),
+                path: json,
+                key: Some(String::from("model")),
+            },
+        ])
+        .unwrap();
+        let rules: Vec<EnrichRule> = vec![
+            "readings=devices:$.device_id".parse().unwrap(),
+            "readings=firmware:$.model".parse().unwrap(),
+        ];
+
+        let mut document = json!({"device_id": "d1", "model": 7, "site": "kept"});
+        enrich(&rules, &lookups, "readings", &mut document);
+        assert_eq!(
+            document,
+            json!({
+                "device_id": "d1",
+                "model": 7,
+                "site": "kept",
+                "note": "first, \"old\"",
+                "firmware": "1.4.2"
+            })
+        );
+
+        // Unknown keys are left alone
+        let mut document = json!({"device_id": "d9"});
+        enrich(&rules, &lookups, "readings", &mut document);
+        assert_eq!(document, json!({"device_id": "d9"}));
+
+        // A changed file is loaded again
+        std::fs::write(&csv, "device_id,site\nd9,basement\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&csv)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        lookups.reload();
+        enrich(&rules, &lookups, "readings", &mut document);
+        assert_eq!(document["site"], "basement");
+
+        // Post test, remove any files created
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Memory budget for buffered request bodies and queued writes
+//
+// [server]
+// memory_budget = 134217728
+//
+// Every request body is counted against the budget while it is read and
+// handled, by its Content-Length (or `max_body_size` when it has none) and
+// by its decompressed size, and every queued write until it is stored. A
+// request which would go over the budget is answered with 503 so senders
+// back off and retry, instead of a burst growing the server until it is
+// killed. There is no budget unless one is configured.
+//
+// Alongside the budget /metrics exports the bytes allocated on the heap,
+// counted by the allocator, and the resident memory of the process. With the
+// `pprof` feature the allocator also samples the heap profile being taken.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntGauge, Opts,
+};
+
+use crate::errors::Error;
+use crate::metrics;
+
+/// The system allocator, counting the bytes allocated
+pub struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            #[cfg(feature = "pprof")]
+            crate::profiling::sample_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            #[cfg(feature = "pprof")]
+            crate::profiling::sample_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            #[cfg(feature = "pprof")]
+            crate::profiling::sample_allocation(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Bytes allocated on the heap and not yet freed
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// The resident memory of the process in bytes, where /proc is available
+pub fn resident() -> Option<usize> {
+    // VmRSS:     12345 kB
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Heap and resident memory gauges, read when /metrics is scraped
+pub struct MemoryCollector {
+    allocated: IntGauge,
+    resident: IntGauge,
+}
+
+impl Default for MemoryCollector {
+    fn default() -> Self {
+        let gauge = |name: &str, help: &str| {
+            IntGauge::with_opts(Opts::new(name, help).namespace(metrics::NAMESPACE)).unwrap()
+        };
+        MemoryCollector {
+            allocated: gauge("heap_allocated_bytes", "Bytes allocated on the heap"),
+            resident: gauge("resident_memory_bytes", "Resident memory of the process"),
+        }
+    }
+}
+
+impl Collector for MemoryCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut desc = self.allocated.desc();
+        desc.extend(self.resident.desc());
+        desc
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.allocated.set(allocated() as i64);
+        let mut families = self.allocated.collect();
+        if let Some(resident) = resident() {
+            self.resident.set(resident as i64);
+            families.extend(self.resident.collect());
+        }
+        families
+    }
+}
+
+/// The bytes buffered payloads and queued writes can take
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: Arc<AtomicUsize>,
+}
+
+/// Bytes counted against the budget until dropped
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: usize,
+    budget: MemoryBudget,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes, unlimited without one
+    pub fn new(limit: Option<usize>) -> Self {
+        metrics::MEMORY_BUDGET_BYTES.set(limit.unwrap_or(0) as i64);
+        MemoryBudget {
+            limit,
+            used: Arc::default(),
+        }
+    }
+
+    /// Count `bytes` against the budget, refused when it would go over
+    pub fn reserve(&self, bytes: usize) -> Result<Reservation, Error> {
+        self.take(bytes, bytes)?;
+        Ok(Reservation {
+            bytes,
+            budget: self.clone(),
+        })
+    }
+
+    /// Count `bytes` more of a reservation of `total` bytes against the budget
+    /// A reservation larger than the whole budget is refused as too large,
+    /// retrying it would never succeed
+    fn take(&self, bytes: usize, total: usize) -> Result<(), Error> {
+        if let Some(limit) = self.limit {
+            if total > limit {
+                return Err(Error::PayloadTooLarge(format!(
+                    "{total} bytes would be over the memory budget of {limit} bytes"
+                )));
+            }
+        }
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(limit) = self.limit {
+            if used > limit {
+                self.used.fetch_sub(bytes, Ordering::Relaxed);
+                metrics::MEMORY_SHED.inc();
+                return Err(Error::Unavailable(format!(
+                    "the memory budget of {limit} bytes is used up, retry later"
+                )));
+            }
+        }
+        metrics::MEMORY_RESERVED_BYTES.add(bytes as i64);
+        Ok(())
+    }
+
+    /// Bytes counted against the budget
+    #[cfg(test)]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+impl Reservation {
+    /// Count `bytes` more against the budget
+    pub fn grow(&mut self, bytes: usize) -> Result<(), Error> {
+        self.budget.take(bytes, self.bytes + bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Give back what is counted above `bytes`
+    pub fn shrink_to(&mut self, bytes: usize) {
+        if bytes < self.bytes {
+            let freed = self.bytes - bytes;
+            self.budget.used.fetch_sub(freed, Ordering::Relaxed);
+            metrics::MEMORY_RESERVED_BYTES.sub(freed as i64);
+            self.bytes = bytes;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+        metrics::MEMORY_RESERVED_BYTES.sub(self.bytes as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counted as in the binary, which installs the allocator
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(Some(100));
+        let mut first = budget.reserve(60).unwrap();
+        assert!(budget.reserve(50).is_err());
+        assert_eq!(budget.used(), 60);
+
+        first.grow(40).unwrap();
+        assert!(matches!(first.grow(1), Err(Error::PayloadTooLarge(_))));
+        assert!(matches!(budget.reserve(1), Err(Error::Unavailable(_))));
+        assert_eq!(budget.used(), 100);
+        first.shrink_to(70);
+        assert_eq!(budget.used(), 70);
+
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        assert!(budget.reserve(100).is_ok());
+        assert!(matches!(
+            budget.reserve(101),
+            Err(Error::PayloadTooLarge(_))
+        ));
+
+        // Unlimited without a budget
+        assert!(MemoryBudget::default().reserve(1 << 40).is_ok());
+        assert!(allocated() > 0);
+    }
+}
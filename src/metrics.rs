@@ -0,0 +1,347 @@
+// Application metrics served alongside the actix-web-prom HTTP metrics on /metrics
+//
+// Without the `metrics` feature there is no /metrics endpoint, the metrics
+// are still counted but nothing serves them.
+// https://docs.rs/prometheus/latest/prometheus/
+// cargo add prometheus
+use std::io;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+// A Prometheus instrumentation middleware for use with actix-web
+// https://docs.rs/actix-web-prom/latest/actix_web_prom/
+// cargo add actix-web-prom --optional
+#[cfg(feature = "metrics")]
+use actix_web_prom::{PrometheusMetrics, PrometheusMetricsBuilder};
+
+#[cfg(feature = "metrics")]
+use prometheus::Registry;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts,
+};
+
+#[cfg(feature = "metrics")]
+use crate::memory::MemoryCollector;
+#[cfg(feature = "metrics")]
+use crate::pod::POD;
+use crate::storage::Inserted;
+
+/// The registry shared with the Prometheus middleware, labelling every
+/// metric with the pod it comes from when running in Kubernetes
+#[cfg(feature = "metrics")]
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(|| {
+    let labels = Some(POD.labels()).filter(|labels| !labels.is_empty());
+    let registry = Registry::new_custom(None, labels).unwrap();
+    registry.register(Box::new(ERRORS.clone())).unwrap();
+    registry.register(Box::new(ROWS_PURGED.clone())).unwrap();
+    registry.register(Box::new(LEADER.clone())).unwrap();
+    registry.register(Box::new(BACKUPS.clone())).unwrap();
+    registry.register(Box::new(ROWS_INSERTED.clone())).unwrap();
+    registry.register(Box::new(INSERT_SECONDS.clone())).unwrap();
+    registry.register(Box::new(PAYLOAD_BYTES.clone())).unwrap();
+    registry
+        .register(Box::new(VALIDATION_FAILURES.clone()))
+        .unwrap();
+    registry.register(Box::new(SQLITE_ERRORS.clone())).unwrap();
+    registry
+        .register(Box::new(FORWARD_DELIVERIES.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(SHADOW_OPERATIONS.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(SHADOW_DIVERGENCES.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(WRITE_QUEUE_DEPTH.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(WRITE_QUEUE_FLUSH_SECONDS.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(WRITE_QUEUE_FAILED_ROWS.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(WRITE_QUEUE_BATCH_ROWS.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(MEMORY_BUDGET_BYTES.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(MEMORY_RESERVED_BYTES.clone()))
+        .unwrap();
+    registry.register(Box::new(MEMORY_SHED.clone())).unwrap();
+    registry.register(Box::new(UDP_MESSAGES.clone())).unwrap();
+    registry.register(Box::new(UDP_DROPPED.clone())).unwrap();
+    registry
+        .register(Box::new(MemoryCollector::default()))
+        .unwrap();
+    registry
+});
+
+/// The middleware serving /metrics and measuring the HTTP requests
+#[cfg(feature = "metrics")]
+pub type Middleware = PrometheusMetrics;
+
+/// Nothing is measured without the `metrics` feature
+#[cfg(not(feature = "metrics"))]
+pub type Middleware = actix_web::middleware::Identity;
+
+#[cfg(feature = "metrics")]
+pub fn middleware() -> io::Result<Middleware> {
+    PrometheusMetricsBuilder::new(NAMESPACE)
+        .endpoint("/metrics")
+        .registry(REGISTRY.clone())
+        .build()
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn middleware() -> io::Result<Middleware> {
+    Ok(Middleware::default())
+}
+
+/// Error responses by error code
+pub static ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("errors_total", "Error responses by error code").namespace(NAMESPACE),
+        &["code"],
+    )
+    .unwrap()
+});
+
+/// Rows deleted by the retention policy
+pub static ROWS_PURGED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("rows_purged_total", "Rows deleted by the retention policy").namespace(NAMESPACE),
+        &["database", "table"],
+    )
+    .unwrap()
+});
+
+/// 1 while this instance holds the lease of the scheduled jobs
+pub static LEADER: LazyLock<IntGauge> = LazyLock::new(|| {
+    IntGauge::with_opts(
+        Opts::new(
+            "leader",
+            "1 while this instance holds the lease of the scheduled jobs",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Database backups taken, by outcome
+pub static BACKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("backups_total", "Database backups taken").namespace(NAMESPACE),
+        &["database", "outcome"],
+    )
+    .unwrap()
+});
+
+/// Rows stored, leaving out retries of rows already stored
+pub static ROWS_INSERTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("rows_inserted_total", "Rows stored").namespace(NAMESPACE),
+        &["database", "table"],
+    )
+    .unwrap()
+});
+
+/// How long storing the rows of a request or of a write queue batch took
+pub static INSERT_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    HistogramVec::new(
+        HistogramOpts::new("insert_seconds", "How long storing a batch of rows took")
+            .namespace(NAMESPACE),
+        &["database"],
+    )
+    .unwrap()
+});
+
+/// Sizes of the documents received, once decompressed
+pub static PAYLOAD_BYTES: LazyLock<Histogram> = LazyLock::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new("payload_bytes", "Sizes of the documents received")
+            .namespace(NAMESPACE)
+            // 64 bytes to 4 MiB
+            .buckets(exponential_buckets(64.0, 4.0, 9).unwrap()),
+    )
+    .unwrap()
+});
+
+/// Documents refused before they were stored, by reason
+/// reason is utf8, json, msgpack, cbor or schema
+pub static VALIDATION_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "validation_failures_total",
+            "Documents refused before they were stored",
+        )
+        .namespace(NAMESPACE),
+        &["database", "table", "reason"],
+    )
+    .unwrap()
+});
+
+/// Errors returned by SQLite, by result code such as DatabaseBusy
+pub static SQLITE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("sqlite_errors_total", "Errors returned by SQLite").namespace(NAMESPACE),
+        &["code"],
+    )
+    .unwrap()
+});
+
+/// Deliveries of stored rows to forwarding endpoints
+/// outcome is delivered, retried or dead_lettered
+pub static FORWARD_DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "forward_deliveries_total",
+            "Deliveries of stored rows to forwarding endpoints",
+        )
+        .namespace(NAMESPACE),
+        &["endpoint", "outcome"],
+    )
+    .unwrap()
+});
+
+/// Count the rows an insert stored and how long it took
+pub fn observe_insert(database: &str, table: &str, inserted: &[Inserted], elapsed: Duration) {
+    let stored = inserted.iter().filter(|row| !row.duplicate).count();
+    ROWS_INSERTED
+        .with_label_values(&[database, table])
+        .inc_by(stored as u64);
+    INSERT_SECONDS
+        .with_label_values(&[database])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Operations sent to both the primary and the shadow storage backend
+pub static SHADOW_OPERATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "shadow_operations_total",
+            "Operations sent to both the primary and the shadow storage backend",
+        )
+        .namespace(NAMESPACE),
+        &["operation"],
+    )
+    .unwrap()
+});
+
+/// Operations where the shadow storage backend disagreed with the primary
+/// kind is result, shadow_error or primary_error
+pub static SHADOW_DIVERGENCES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "shadow_divergences_total",
+            "Operations where the shadow storage backend disagreed with the primary",
+        )
+        .namespace(NAMESPACE),
+        &["operation", "kind"],
+    )
+    .unwrap()
+});
+
+/// Writes waiting in the write queue
+pub static WRITE_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    IntGauge::with_opts(
+        Opts::new("write_queue_depth", "Writes waiting in the write queue").namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Rows the write queue stores together, adapted to commit latency
+pub static WRITE_QUEUE_BATCH_ROWS: LazyLock<IntGauge> = LazyLock::new(|| {
+    IntGauge::with_opts(
+        Opts::new(
+            "write_queue_batch_rows",
+            "Rows the write queue stores together",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// How long storing a batch of queued writes took
+pub static WRITE_QUEUE_FLUSH_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new(
+            "write_queue_flush_seconds",
+            "How long storing a batch of queued writes took",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Queued rows which could not be stored
+pub static WRITE_QUEUE_FAILED_ROWS: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::with_opts(
+        Opts::new(
+            "write_queue_failed_rows_total",
+            "Queued rows which could not be stored",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// The namespace all metrics are reported under
+pub const NAMESPACE: &str = "actix_data_receiver";
+
+/// The memory budget in bytes, 0 without one
+pub static MEMORY_BUDGET_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    IntGauge::with_opts(
+        Opts::new("memory_budget_bytes", "The memory budget, 0 without one").namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Bytes of request bodies and queued writes counted against the memory budget
+pub static MEMORY_RESERVED_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    IntGauge::with_opts(
+        Opts::new(
+            "memory_reserved_bytes",
+            "Bytes of request bodies and queued writes counted against the memory budget",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Requests and writes refused because the memory budget was used up
+pub static MEMORY_SHED: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::with_opts(
+        Opts::new(
+            "memory_shed_total",
+            "Requests and writes refused because the memory budget was used up",
+        )
+        .namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Messages received over UDP
+pub static UDP_MESSAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::with_opts(
+        Opts::new("udp_messages_total", "Messages received over UDP").namespace(NAMESPACE),
+    )
+    .unwrap()
+});
+
+/// Messages received over UDP which were not stored, by reason
+pub static UDP_DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "udp_dropped_total",
+            "Messages received over UDP which were not stored",
+        )
+        .namespace(NAMESPACE),
+        &["reason"],
+    )
+    .unwrap()
+});
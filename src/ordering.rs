@@ -0,0 +1,125 @@
+// Serialize inserts which share an ordering key
+//
+// Every distinct (database, table, key) gets a FIFO lock, an insert holds the
+// lock while its sequence number is claimed and the row is written. Tokio's
+// mutex hands the lock out in the order it was asked for, so inserts sharing
+// a key are committed, and numbered, in arrival order.
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::jsonpath::JsonPath;
+
+type LockKey = (String, String, String);
+type LockMap = Arc<Mutex<HashMap<LockKey, Arc<AsyncMutex<()>>>>>;
+
+/// The ordering key value of a document, if the document has one
+pub fn extract_key(path: &JsonPath, data: &Value) -> Option<String> {
+    match path.select(data)? {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Per key FIFO locks shared by all workers
+#[derive(Clone, Default)]
+pub struct OrderingLocks {
+    locks: LockMap,
+}
+
+impl OrderingLocks {
+    /// Wait for the turn of this insert to write under the key
+    pub async fn lock(&self, database: &str, table: &str, key: &str) -> OrderingGuard {
+        let lock_key = (database.to_string(), table.to_string(), key.to_string());
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(lock_key.clone())
+            .or_default()
+            .clone();
+        OrderingGuard {
+            guard: Some(lock.lock_owned().await),
+            locks: self.locks.clone(),
+            lock_key,
+        }
+    }
+
+    /// Wait for the turn of this insert under each of several keys
+    /// Keys are taken in sorted order so two inserts can't wait on each other
+    pub async fn lock_all(
+        &self,
+        database: &str,
+        table: &str,
+        keys: &BTreeSet<String>,
+    ) -> Vec<OrderingGuard> {
+        let mut guards = Vec::with_capacity(keys.len());
+        for key in keys {
+            guards.push(self.lock(database, table, key).await);
+        }
+        guards
+    }
+
+    /// The number of keys with an insert in flight
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+/// Held while an insert is written, the next insert under the key is let in on drop
+pub struct OrderingGuard {
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: LockMap,
+    lock_key: LockKey,
+}
+
+impl Drop for OrderingGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        // Release the lock first, then forget the key when nobody else is waiting on it
+        self.guard.take();
+        if let Some(lock) = locks.get(&self.lock_key) {
+            if Arc::strong_count(lock) == 1 {
+                locks.remove(&self.lock_key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_ordering_locks() {
+        let path: JsonPath = "$.device".parse().unwrap();
+        assert_eq!(
+            extract_key(&path, &json!({"device": "a1"})),
+            Some(String::from("a1"))
+        );
+        assert_eq!(
+            extract_key(&path, &json!({"device": 7})),
+            Some(String::from("7"))
+        );
+        assert_eq!(extract_key(&path, &json!({"other": 7})), None);
+
+        // A second insert under the same key waits, a different key does not
+        let locks = OrderingLocks::default();
+        let first = locks.lock("db", "events", "a1").await;
+        let other = locks.lock("db", "events", "b2").await;
+        assert_eq!(locks.len(), 2);
+        let waiting = locks.lock("db", "events", "a1");
+        drop(other);
+        drop(first);
+        let second = waiting.await;
+        assert_eq!(locks.len(), 1);
+        drop(second);
+        assert_eq!(locks.len(), 0);
+    }
+}
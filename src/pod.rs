@@ -0,0 +1,96 @@
+// Pod metadata from the Kubernetes downward API
+//
+// env:
+//   - name: POD_NAME
+//     valueFrom: { fieldRef: { fieldPath: metadata.name } }
+//   - name: POD_NAMESPACE
+//     valueFrom: { fieldRef: { fieldPath: metadata.namespace } }
+//   - name: NODE_NAME
+//     valueFrom: { fieldRef: { fieldPath: spec.nodeName } }
+//
+// Whichever of these are set label every metric as `pod`, `namespace` and
+// `node`, and the log lines of a request are written in a `pod` span naming
+// them, so the metrics and logs of a fleet can be told apart wherever they
+// are collected.
+// https://kubernetes.io/docs/concepts/workloads/pods/downward-api/
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+};
+use tracing::{info_span, Instrument};
+
+/// The pod this server runs in, read from the environment at start
+pub static POD: LazyLock<PodMetadata> = LazyLock::new(|| PodMetadata {
+    name: std::env::var("POD_NAME").ok(),
+    namespace: std::env::var("POD_NAMESPACE").ok(),
+    node: std::env::var("NODE_NAME").ok(),
+});
+
+/// What the downward API tells a pod about itself
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodMetadata {
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    pub node: Option<String>,
+}
+
+impl PodMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.namespace.is_none() && self.node.is_none()
+    }
+
+    /// The labels every metric gets
+    #[cfg(feature = "metrics")]
+    pub fn labels(&self) -> HashMap<String, String> {
+        [
+            ("pod", &self.name),
+            ("namespace", &self.namespace),
+            ("node", &self.node),
+        ]
+        .into_iter()
+        .filter_map(|(label, value)| Some((label.to_string(), value.clone()?)))
+        .collect()
+    }
+}
+
+/// Middleware writing the log lines of a request in a span naming the pod
+/// App::new().wrap(from_fn(pod::instrument))
+pub async fn instrument(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if POD.is_empty() {
+        return next.call(req).await;
+    }
+    let span = info_span!(
+        "pod",
+        name = POD.name.as_deref().unwrap_or_default(),
+        namespace = POD.namespace.as_deref().unwrap_or_default(),
+        node = POD.node.as_deref().unwrap_or_default(),
+    );
+    next.call(req).instrument(span).await
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        let pod = PodMetadata {
+            name: Some(String::from("receiver-0")),
+            namespace: Some(String::from("ingest")),
+            node: None,
+        };
+        let labels = pod.labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["pod"], "receiver-0");
+        assert_eq!(labels["namespace"], "ingest");
+        assert!(PodMetadata::default().labels().is_empty());
+    }
+}
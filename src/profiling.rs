@@ -0,0 +1,538 @@
+// CPU and heap profiles of the running server
+//
+// [auth]
+// debug_keys = ["oncall"]
+//
+// curl -o cpu.pb -H 'X-API-Key: ...' 'http://localhost:8888/debug/pprof/profile?seconds=30'
+// curl -o cpu.svg -H 'X-API-Key: ...' 'http://localhost:8888/debug/pprof/profile?format=flamegraph'
+// curl -o heap.pb -H 'X-API-Key: ...' 'http://localhost:8888/debug/pprof/heap?seconds=30'
+// go tool pprof -http :8080 cpu.pb
+//
+// A build with the `pprof` feature takes a profile on demand for `seconds`
+// (30 by default, at most MAX_SECONDS) and answers it as a pprof protobuf or
+// as a flamegraph SVG.
+// - The CPU profile samples the stacks of every thread `frequency` times a
+//   second, 99 by default.
+// - The heap profile samples the allocations made while it is taken, one
+//   about every SAMPLE_BYTES bytes allocated, and adds them up by the stack
+//   allocating them. It is counted by `memory::CountingAllocator`, so it is
+//   empty unless that is the global allocator, which the binary installs.
+// One profile of each kind is taken at a time, others are answered with 503.
+//
+// A profile shows what the server is doing, so it is only answered for a
+// request made with one of the `auth.debug_keys`, see `auth::authorize_debug`.
+// Without API keys there are no debug keys and no profiles.
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::rt;
+// A library for acquiring a backtrace at runtime
+// https://docs.rs/backtrace/latest/backtrace/
+// cargo add backtrace --optional
+// A CPU profiler
+// https://docs.rs/pprof/latest/pprof/
+// cargo add pprof --no-default-features --features flamegraph,prost-codec --optional
+use pprof::{
+    flamegraph,
+    protos::{self, Message},
+    Frames, ProfilerGuardBuilder, Symbol,
+};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::errors::Error;
+
+/// The longest profile which can be asked for
+pub const MAX_SECONDS: u64 = 300;
+
+/// How long a profile is taken for unless asked otherwise
+pub const DEFAULT_SECONDS: u64 = 30;
+
+/// How many times a second the CPU profile samples unless asked otherwise
+pub const DEFAULT_FREQUENCY: i32 = 99;
+
+/// Bytes allocated by a thread between two samples of the heap profile
+pub const SAMPLE_BYTES: usize = 512 * 1024;
+
+/// Frames kept of the stack of a heap sample
+const MAX_DEPTH: usize = 64;
+
+/// How a profile is answered
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// The protobuf of `go tool pprof`
+    #[default]
+    Pprof,
+    /// An SVG flamegraph, for a browser
+    Flamegraph,
+}
+
+impl ProfileFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ProfileFormat::Pprof => "application/octet-stream",
+            ProfileFormat::Flamegraph => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ProfileFormat::Pprof => "pb",
+            ProfileFormat::Flamegraph => "svg",
+        }
+    }
+}
+
+/// How long a profile asked for with `seconds` is taken for
+pub fn duration(seconds: Option<u64>) -> Result<Duration, Error> {
+    match seconds.unwrap_or(DEFAULT_SECONDS) {
+        seconds @ 1..=MAX_SECONDS => Ok(Duration::from_secs(seconds)),
+        seconds => Err(Error::BadRequest(format!(
+            "seconds must be from 1 to {MAX_SECONDS}, not {seconds}"
+        ))),
+    }
+}
+
+/// Sample the stacks of every thread `frequency` times a second for `duration`
+pub async fn cpu(
+    duration: Duration,
+    frequency: i32,
+    format: ProfileFormat,
+) -> Result<Vec<u8>, Error> {
+    if !(1..=1000).contains(&frequency) {
+        return Err(Error::BadRequest(format!(
+            "frequency must be from 1 to 1000, not {frequency}"
+        )));
+    }
+    // The profiler stops when the guard is dropped, also when the client goes away
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        // Stacks walked through these are not reliable
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|err| Error::Unavailable(format!("unable to start the CPU profiler: {err}")))?;
+    rt::time::sleep(duration).await;
+    let report = guard
+        .report()
+        .build()
+        .map_err(|err| Error::Internal(format!("unable to build the CPU profile: {err}")))?;
+    drop(guard);
+
+    let mut body = vec![];
+    match format {
+        ProfileFormat::Pprof => report
+            .pprof()
+            .map_err(|err| Error::Internal(err.to_string()))?
+            .encode(&mut body)
+            .map_err(|err| Error::Internal(err.to_string()))?,
+        ProfileFormat::Flamegraph => report
+            .flamegraph(&mut body)
+            .map_err(|err| Error::Internal(err.to_string()))?,
+    }
+    Ok(body)
+}
+
+/// Sample the allocations made during `duration`
+pub async fn heap(duration: Duration, format: ProfileFormat) -> Result<Vec<u8>, Error> {
+    let profile = HeapProfile::take(duration).await?;
+    match format {
+        ProfileFormat::Pprof => Ok(profile.pprof().encode_to_vec()),
+        ProfileFormat::Flamegraph => profile
+            .flamegraph()
+            .map_err(|err| Error::Internal(format!("unable to draw the flamegraph: {err}"))),
+    }
+}
+
+// The heap profile being taken, allocations are only sampled while it is on
+static HEAP_PROFILING: AtomicBool = AtomicBool::new(false);
+static HEAP_SAMPLES: Mutex<Option<HashMap<Stack, Allocations>>> = Mutex::new(None);
+
+thread_local! {
+    // Bytes the thread can allocate until its next sample
+    static UNTIL_SAMPLE: Cell<usize> = const { Cell::new(SAMPLE_BYTES) };
+    // Set while the thread records a sample, which allocates too
+    static SAMPLING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Where sampled allocations were made, as return addresses
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Stack {
+    thread: [u8; 16],
+    addresses: Vec<usize>,
+}
+
+/// The allocations a stack made
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Allocations {
+    pub count: i64,
+    pub bytes: i64,
+}
+
+/// Count an allocation of `size` bytes into the heap profile being taken,
+/// called by the allocator for every allocation
+#[inline]
+pub fn sample_allocation(size: usize) {
+    if !HEAP_PROFILING.load(Ordering::Relaxed) {
+        return;
+    }
+    // The thread locals are gone while a thread exits
+    if SAMPLING.try_with(Cell::get).unwrap_or(true) {
+        return;
+    }
+    let due = UNTIL_SAMPLE
+        .try_with(|until| match until.get().checked_sub(size) {
+            Some(left) if left > 0 => {
+                until.set(left);
+                false
+            }
+            _ => {
+                until.set(SAMPLE_BYTES);
+                true
+            }
+        })
+        .unwrap_or(false);
+    if due {
+        unsampled(|| record(size));
+    }
+}
+
+// Run `f` with the allocations of the thread left out of the profile, so the
+// samples are never locked again by an allocation made holding the lock
+fn unsampled<T>(f: impl FnOnce() -> T) -> T {
+    let sampling = SAMPLING.try_with(|sampling| sampling.replace(true));
+    let result = f();
+    if let Ok(sampling) = sampling {
+        let _ = SAMPLING.try_with(|cell| cell.set(sampling));
+    }
+    result
+}
+
+// Add a sampled allocation to the profile, it stands for the SAMPLE_BYTES
+// allocated since the previous sample of the thread
+fn record(size: usize) {
+    let mut addresses = [0; MAX_DEPTH];
+    let mut depth = 0;
+    // Unsynchronized as the synchronized trace takes a lock which allocates
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            addresses[depth] = frame.ip() as usize;
+            depth += 1;
+            depth < MAX_DEPTH
+        });
+    }
+    let mut thread = [0; 16];
+    unsafe {
+        libc::pthread_getname_np(
+            libc::pthread_self(),
+            thread.as_mut_ptr().cast(),
+            thread.len(),
+        );
+    }
+
+    let stack = Stack {
+        thread,
+        addresses: addresses[..depth].to_vec(),
+    };
+    let bytes = size.max(SAMPLE_BYTES);
+    if let Ok(mut samples) = HEAP_SAMPLES.lock() {
+        if let Some(samples) = samples.as_mut() {
+            let allocations = samples.entry(stack).or_default();
+            allocations.count += (bytes / size.max(1)) as i64;
+            allocations.bytes += bytes as i64;
+        }
+    }
+}
+
+// Sampling is turned off again however a profile ends
+struct HeapProfiling;
+
+impl HeapProfiling {
+    fn start() -> Result<Self, Error> {
+        if HEAP_PROFILING.swap(true, Ordering::SeqCst) {
+            return Err(Error::Unavailable(String::from(
+                "a heap profile is already being taken",
+            )));
+        }
+        unsampled(|| *HEAP_SAMPLES.lock().unwrap() = Some(HashMap::new()));
+        Ok(HeapProfiling)
+    }
+
+    fn samples(&self) -> HashMap<Stack, Allocations> {
+        unsampled(|| HEAP_SAMPLES.lock().unwrap().take()).unwrap_or_default()
+    }
+}
+
+impl Drop for HeapProfiling {
+    fn drop(&mut self) {
+        let _ = unsampled(|| HEAP_SAMPLES.lock().map(|mut samples| samples.take()));
+        HEAP_PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The allocations sampled while a heap profile was taken, by stack
+pub struct HeapProfile {
+    stacks: Vec<(Frames, Allocations)>,
+    start: SystemTime,
+    duration: Duration,
+}
+
+impl HeapProfile {
+    /// Sample the allocations made during `duration`
+    pub async fn take(duration: Duration) -> Result<Self, Error> {
+        let profiling = HeapProfiling::start()?;
+        let start = SystemTime::now();
+        rt::time::sleep(duration).await;
+        let samples = profiling.samples();
+        drop(profiling);
+
+        // Addresses are resolved to symbols once the sampling is over
+        let mut symbols = HashMap::new();
+        let stacks = samples
+            .into_iter()
+            .map(|(stack, allocations)| {
+                let frames = stack
+                    .addresses
+                    .iter()
+                    .map(|&address| {
+                        symbols
+                            .entry(address)
+                            .or_insert_with(|| resolve(address))
+                            .clone()
+                    })
+                    .collect();
+                let thread_name = String::from_utf8_lossy(&stack.thread)
+                    .trim_end_matches('\0')
+                    .to_string();
+                let frames = Frames {
+                    frames: without_allocator(frames),
+                    thread_name,
+                    thread_id: 0,
+                    sample_timestamp: start,
+                };
+                (frames, allocations)
+            })
+            .collect();
+        Ok(HeapProfile {
+            stacks,
+            start,
+            duration,
+        })
+    }
+
+    /// The profile as read by `go tool pprof`
+    /// https://github.com/google/pprof/blob/main/proto/profile.proto
+    pub fn pprof(&self) -> protos::Profile {
+        let mut strings = StringTable::default();
+        let objects = value_type(&mut strings, "alloc_objects", "count");
+        let space = value_type(&mut strings, "alloc_space", "bytes");
+        let thread = strings.index("thread");
+
+        // A location for each function, as the CPU profiles of pprof-rs have
+        let mut functions = HashMap::new();
+        let mut profile = protos::Profile::default();
+        for (frames, allocations) in &self.stacks {
+            let mut location_id = vec![];
+            for symbol in frames.frames.iter().flatten() {
+                let name = symbol.name();
+                if let Some(&id) = functions.get(&name) {
+                    location_id.push(id);
+                    continue;
+                }
+                let id = profile.function.len() as u64 + 1;
+                profile.function.push(protos::Function {
+                    id,
+                    name: strings.index(&name),
+                    system_name: strings.index(&symbol.sys_name()),
+                    filename: strings.index(&symbol.filename()),
+                    ..Default::default()
+                });
+                profile.location.push(protos::Location {
+                    id,
+                    line: vec![protos::Line {
+                        function_id: id,
+                        line: i64::from(symbol.lineno()),
+                    }],
+                    ..Default::default()
+                });
+                functions.insert(name, id);
+                location_id.push(id);
+            }
+            profile.sample.push(protos::Sample {
+                location_id,
+                value: vec![allocations.count, allocations.bytes],
+                label: vec![protos::Label {
+                    key: thread,
+                    str: strings.index(&frames.thread_name_or_id()),
+                    ..Default::default()
+                }],
+            });
+        }
+        profile.sample_type = vec![objects, space.clone()];
+        profile.period_type = Some(space);
+        profile.period = SAMPLE_BYTES as i64;
+        profile.time_nanos = self
+            .start
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i64);
+        profile.duration_nanos = self.duration.as_nanos() as i64;
+        profile.string_table = strings.table;
+        profile
+    }
+
+    /// The bytes allocated by stack as an SVG flamegraph
+    pub fn flamegraph(&self) -> Result<Vec<u8>, String> {
+        // thread;outermost;...;allocating function bytes
+        let lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(frames, allocations)| {
+                let mut line = frames.thread_name_or_id();
+                for symbol in frames
+                    .frames
+                    .iter()
+                    .rev()
+                    .flat_map(|frame| frame.iter().rev())
+                {
+                    line.push(';');
+                    line.push_str(&symbol.to_string());
+                }
+                format!("{line} {}", allocations.bytes)
+            })
+            .collect();
+        let mut options = flamegraph::Options::default();
+        options.title = String::from("Heap allocations");
+        options.count_name = String::from("bytes");
+        let mut svg = vec![];
+        flamegraph::from_lines(&mut options, lines.iter().map(String::as_str), &mut svg)
+            .map_err(|err| err.to_string())?;
+        Ok(svg)
+    }
+}
+
+// The symbols of a return address, more than one where calls were inlined
+fn resolve(address: usize) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    backtrace::resolve(address as *mut c_void, |symbol| {
+        symbols.push(Symbol {
+            name: symbol.name().map(|name| name.as_bytes().to_vec()),
+            addr: symbol.addr(),
+            lineno: symbol.lineno(),
+            filename: symbol.filename().map(Path::to_path_buf),
+        });
+    });
+    symbols
+}
+
+// The frames of a stack after those of the allocator and the sampling
+fn without_allocator(mut frames: Vec<Vec<Symbol>>) -> Vec<Vec<Symbol>> {
+    let allocator = frames.iter().rposition(|frame| {
+        frame
+            .iter()
+            .any(|symbol| symbol.name().contains("CountingAllocator"))
+    });
+    if let Some(allocator) = allocator {
+        frames.drain(..=allocator);
+    }
+    frames
+}
+
+// The strings of a pprof profile, referred to by their index
+struct StringTable {
+    table: Vec<String>,
+    indexes: HashMap<String, i64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        // The first string is always the empty one
+        StringTable {
+            table: vec![String::new()],
+            indexes: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, string: &str) -> i64 {
+        if let Some(&index) = self.indexes.get(string) {
+            return index;
+        }
+        let index = self.table.len() as i64;
+        self.table.push(string.to_string());
+        self.indexes.insert(string.to_string(), index);
+        index
+    }
+}
+
+fn value_type(strings: &mut StringTable, ty: &str, unit: &str) -> protos::ValueType {
+    protos::ValueType {
+        ty: strings.index(ty),
+        unit: strings.index(unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::hint::black_box;
+    use std::thread;
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration(None).unwrap(), Duration::from_secs(30));
+        assert!(duration(Some(0)).is_err());
+        assert!(duration(Some(MAX_SECONDS + 1)).is_err());
+    }
+
+    #[inline(never)]
+    fn allocate_buffers() {
+        for _ in 0..256 {
+            black_box(vec![0u8; 64 * 1024]);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_heap_profile() {
+        let allocating = thread::Builder::new()
+            .name(String::from("heap-test"))
+            .spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                allocate_buffers();
+            })
+            .unwrap();
+        let profile = HeapProfile::take(Duration::from_millis(300)).await.unwrap();
+        allocating.join().unwrap();
+
+        // The buffers are found where they were allocated
+        let (frames, allocations) = profile
+            .stacks
+            .iter()
+            .find(|(frames, _)| frames.thread_name == "heap-test")
+            .unwrap();
+        assert!(frames
+            .frames
+            .iter()
+            .flatten()
+            .any(|symbol| symbol.name().contains("allocate_buffers")));
+        assert!(allocations.bytes >= SAMPLE_BYTES as i64);
+
+        let decoded = protos::Profile::decode(&profile.pprof().encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded.sample_type.len(), 2);
+        assert_eq!(decoded.sample.len(), profile.stacks.len());
+        let svg = String::from_utf8(profile.flamegraph().unwrap()).unwrap();
+        assert!(svg.contains("allocate_buffers"));
+
+        // One at a time, the next can be taken once it is over
+        let profiling = HeapProfiling::start().unwrap();
+        assert!(matches!(HeapProfiling::start(), Err(Error::Unavailable(_))));
+        drop(profiling);
+        HeapProfile::take(Duration::from_millis(1)).await.unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+// Reverse proxy passthrough with a copy of each request stored
+//
+// --proxy-upstream https://api.internal:8443 --proxy-database archive
+//
+// Every request other than /ping and /metrics is forwarded to the upstream
+// with its method, path, query string, headers and body, and answered with
+// the response of the upstream. The receiver can be put in front of an
+// existing API to archive its traffic without the clients noticing.
+//
+// Each request is stored in the proxy table as
+// {"method": "POST", "path": "/v1/orders", "query": "dry_run=1", "status": 201, "body": {...}}
+// A body which is not JSON is stored as text. The status is null when the
+// upstream could not be reached. Failing to store a request never fails it.
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, web::Bytes, HttpRequest, HttpResponse};
+use futures_util::TryStreamExt;
+// An HTTP client
+// https://docs.rs/reqwest/latest/reqwest/
+// cargo add reqwest --no-default-features --features stream
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Client, Method,
+};
+use serde_json::{json, Value};
+
+use crate::errors::Error;
+
+/// Headers which only apply to a single connection and are not forwarded
+/// https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Where requests are forwarded to and stored
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    client: Client,
+    upstream: String,
+    pub database: String,
+    pub table: String,
+}
+
+impl Proxy {
+    pub fn new(
+        upstream: &str,
+        database: &str,
+        table: &str,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        if !upstream.starts_with("http://") && !upstream.starts_with("https://") {
+            return Err(format!("the proxy upstream is not an HTTP URL: {upstream}"));
+        }
+        #[cfg(not(feature = "tls"))]
+        if upstream.starts_with("https://") {
+            return Err(format!(
+                "an https:// proxy upstream needs a build with the tls feature: {upstream}"
+            ));
+        }
+        // Redirects and compressed bodies are passed to the client as they are
+        let client = Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|err| format!("unable to create the proxy client: {err}"))?;
+        Ok(Proxy {
+            client,
+            upstream: upstream.trim_end_matches('/').to_string(),
+            database: database.to_string(),
+            table: table.to_string(),
+        })
+    }
+
+    /// Send a request to the upstream and stream its response back
+    pub async fn forward(&self, req: &HttpRequest, body: Bytes) -> Result<HttpResponse, Error> {
+        let uri = req.uri();
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let method = Method::from_bytes(req.method().as_str().as_bytes())
+            .map_err(|err| Error::BadRequest(err.to_string()))?;
+
+        let mut request = self
+            .client
+            .request(method, format!("{}{path}", self.upstream));
+        for (name, value) in req.headers() {
+            if is_hop_by_hop(name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_str().as_bytes()),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                request = request.header(name, value);
+            }
+        }
+        {
+            let info = req.connection_info();
+            if let Some(peer) = info.peer_addr() {
+                request = request.header("x-forwarded-for", peer);
+            }
+            request = request
+                .header("x-forwarded-host", info.host())
+                .header("x-forwarded-proto", info.scheme());
+        }
+
+        let upstream = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| Error::BadGateway(err.to_string()))?;
+        let status = StatusCode::from_u16(upstream.status().as_u16())
+            .map_err(|err| Error::BadGateway(err.to_string()))?;
+        let mut response = HttpResponse::build(status);
+        for (name, value) in upstream.headers() {
+            if !is_hop_by_hop(name.as_str()) {
+                response.append_header((name.as_str(), value.as_bytes()));
+            }
+        }
+        let stream = upstream
+            .bytes_stream()
+            .map_err(|err| Error::BadGateway(err.to_string()));
+        Ok(response.streaming(stream))
+    }
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+}
+
+/// The document stored for a forwarded request
+pub fn document(req: &HttpRequest, body: &[u8], status: Option<u16>) -> Value {
+    let body = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(_) if body.is_empty() => Value::Null,
+        Err(_) => Value::String(String::from_utf8_lossy(body).into_owned()),
+    };
+    json!({
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": req.query_string(),
+        "status": status,
+        "body": body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_document() {
+        let req = TestRequest::post()
+            .uri("/v1/orders?dry_run=1")
+            .to_http_request();
+        assert_eq!(
+            document(&req, b"{\"id\": 7}", Some(201)),
+            json!({
+                "method": "POST",
+                "path": "/v1/orders",
+                "query": "dry_run=1",
+                "status": 201,
+                "body": {"id": 7}
+            })
+        );
+        assert_eq!(document(&req, b"a=1", None)["body"], "a=1");
+        assert!(is_hop_by_hop("Transfer-Encoding"));
+        assert!(Proxy::new("ftp://upstream", "archive", "traffic", Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+// Per-client rate limiting
+//
+// [rate_limit]
+// requests_per_second = 20.0
+// burst = 40
+//
+// Each client has a token bucket holding up to `burst` requests, refilled
+// at `requests_per_second`. A request finding the bucket of its client empty
+// is answered with 429 Too Many Requests and a `Retry-After` of the seconds
+// until the bucket holds a request again, so one misbehaving collector can't
+// starve the writer for everyone else. A client is the name of the API key
+// the request carried, or its IP address when keys are not required. The
+// /ping and /healthz probes are never limited.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, HttpMessage,
+};
+
+use crate::auth::{self, ApiKeyName};
+use crate::errors::Error;
+
+/// Buckets kept before the full ones are forgotten
+const MAX_BUCKETS: usize = 10_000;
+
+/// How many requests a client can make
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitSettings {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+// The requests a client has left
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The token buckets of the clients seen
+pub struct RateLimiter {
+    settings: Mutex<RateLimitSettings>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        RateLimiter {
+            settings: Mutex::new(settings),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Change the rate and burst, the requests clients have left are kept
+    pub fn update(&self, settings: RateLimitSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// Take a request from the bucket of a client, how long until it has one
+    /// again when it is empty
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let RateLimitSettings {
+            requests_per_second: rate,
+            burst,
+        } = *self.settings.lock().unwrap();
+        let burst = f64::from(burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(client) {
+            // A full bucket is the same as a new one
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate
+                    < burst
+            });
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+// The client a request is counted against
+fn client(req: &ServiceRequest) -> String {
+    if let Some(ApiKeyName(name)) = req.extensions().get::<ApiKeyName>() {
+        return format!("key:{name}");
+    }
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => String::from("ip:unknown"),
+    }
+}
+
+/// Middleware answering clients over their rate with 429
+/// App::new().app_data(web::Data::new(limiter)).wrap(from_fn(rate_limit::limit))
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        if !auth::is_public(req.path()) {
+            if let Err(retry_after) = limiter.check(&client(&req), Instant::now()) {
+                let err = Error::RateLimited(retry_after);
+                return Ok(req.error_response(err).map_into_right_body());
+            }
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimitSettings {
+            requests_per_second: 2.0,
+            burst: 3,
+        });
+        let start = Instant::now();
+
+        // A burst, then a request every half second
+        for _ in 0..3 {
+            assert!(limiter.check("ip:10.0.0.1", start).is_ok());
+        }
+        let retry_after = limiter.check("ip:10.0.0.1", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check("ip:10.0.0.1", later).is_ok());
+        assert!(limiter.check("ip:10.0.0.1", later).is_err());
+
+        // Every client has a bucket of its own
+        assert!(limiter.check("key:gateway", start).is_ok());
+
+        // A new rate applies to the requests left
+        limiter.update(RateLimitSettings {
+            requests_per_second: 4.0,
+            burst: 3,
+        });
+        let retry_after = limiter.check("ip:10.0.0.1", later).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,541 @@
+// Replay recorded traffic against another instance
+//
+// actix_data_receiver replay --file archive.ndjson --target http://localhost:8888 --speed 10
+// actix_data_receiver replay --database archive --table requests --target http://staging:8888
+//
+// Rows are read in sequence order from an NDJSON export (`-` for standard
+// input), or from a table of the configured storage, and sent to the target
+// at the pace they were stored at. `--speed 10` sends them ten times faster,
+// `--speed 0` as fast as the target answers. At most `--concurrency`
+// requests wait for an answer at a time, a slow target holds the replay up
+// rather than the requests piling up.
+//
+// A row stored in proxy mode
+// {"method": "POST", "path": "/v1/orders", "query": "dry_run=1", "status": 201, "body": {...}}
+// is sent again as the request it recorded. Any other row has its document
+// PUT to /<database>/<table> of the target, the table it was read from or
+// the table a snapshot export names, unless `--to-database` and
+// `--to-table` say otherwise. The summary line of an export is skipped.
+//
+// Once every request is answered the number sent, the statuses answered and
+// the latency of the target are written to standard output as JSON.
+// {"sent":2,"errors":0,"skipped":0,"statuses":{"200":2},"seconds":1.5,"latency_ms":{...}}
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::{
+    self,
+    time::{self, Instant},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures_util::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Client, Method,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+use tracing::{debug, error};
+
+use crate::export::PAGE_SIZE;
+use crate::storage::{ChangesFilter, Storage};
+
+/// Where the rows replayed are read from
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplaySource {
+    /// An NDJSON export, `-` for standard input
+    File(String),
+    /// A table of the configured storage
+    Table { database: String, table: String },
+}
+
+/// How rows are replayed
+#[derive(Clone, Debug)]
+pub struct ReplaySettings {
+    /// The instance requests are sent to, e.g. http://localhost:8888
+    pub target: String,
+    /// How many times faster than recorded, as fast as possible when 0
+    pub speed: f64,
+    /// Requests waiting for an answer at a time
+    pub concurrency: usize,
+    /// The database documents are written to instead of their own
+    pub to_database: Option<String>,
+    /// The table documents are written to instead of their own
+    pub to_table: Option<String>,
+    /// Headers sent with every request, e.g. an Authorization header
+    pub headers: Vec<(String, String)>,
+    pub timeout: Duration,
+}
+
+/// A request to send again
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replayed {
+    /// When the row was stored
+    pub timestamp: Option<DateTime<Utc>>,
+    pub method: Method,
+    /// The path and query string
+    pub path: String,
+    pub body: Option<Value>,
+}
+
+/// What the target answered
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    /// Requests sent
+    pub sent: u64,
+    /// Requests the target could not be reached for
+    pub errors: u64,
+    /// Lines which are not a row
+    pub skipped: u64,
+    /// Requests answered with each status
+    pub statuses: BTreeMap<u16, u64>,
+    pub seconds: f64,
+    pub latency_ms: Latency,
+}
+
+/// Time taken for the target to answer
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Latency {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort_unstable();
+        let percentile = |percent: usize| match latencies.len() {
+            0 => 0.0,
+            len => latencies[(len * percent).div_ceil(100).max(1) - 1].as_secs_f64() * 1000.0,
+        };
+        Latency {
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        }
+    }
+}
+
+/// The lines of an export, or the rows of a table as lines of an export
+pub fn read(
+    source: &ReplaySource,
+    storage: Option<Arc<dyn Storage>>,
+) -> io::Result<LocalBoxStream<'static, io::Result<String>>> {
+    match (source, storage) {
+        (ReplaySource::File(path), _) => {
+            let reader: Box<dyn BufRead> = match path.as_str() {
+                "-" => Box::new(io::stdin().lock()),
+                path => Box::new(io::BufReader::new(std::fs::File::open(path)?)),
+            };
+            Ok(stream::iter(reader.lines()).boxed_local())
+        }
+        (ReplaySource::Table { database, table }, Some(storage)) => {
+            let (database, table) = (database.clone(), table.clone());
+            let pages = stream::try_unfold(0, move |since| {
+                let storage = storage.clone();
+                let (database, table) = (database.clone(), table.clone());
+                async move {
+                    let filter = ChangesFilter {
+                        since,
+                        after_id: None,
+                        limit: PAGE_SIZE,
+                        key: None,
+                    };
+                    let events = storage
+                        .changes(&database, &table, &filter)
+                        .await
+                        .map_err(io::Error::other)?;
+                    let Some(last) = events.last() else {
+                        return Ok::<_, io::Error>(None);
+                    };
+                    let since = last.seq;
+                    let lines = events
+                        .iter()
+                        .map(|event| serde_json::to_string(event).map_err(io::Error::other))
+                        .collect::<Vec<_>>();
+                    Ok(Some((stream::iter(lines), since)))
+                }
+            });
+            Ok(pages.try_flatten().boxed_local())
+        }
+        (ReplaySource::Table { .. }, None) => Err(io::Error::other("no storage to read from")),
+    }
+}
+
+/// The request a line of an export is replayed as, none for a summary line
+pub fn request(
+    line: &str,
+    source: &ReplaySource,
+    settings: &ReplaySettings,
+) -> Result<Option<Replayed>, String> {
+    let row: Value = serde_json::from_str(line).map_err(|err| format!("not valid JSON: {err}"))?;
+    if row.get("summary").is_some() {
+        return Ok(None);
+    }
+    let (Some(timestamp), Some(data)) = (row["timestamp"].as_str(), row.get("data")) else {
+        return Err(String::from("not a row of an export"));
+    };
+    let timestamp = parse_timestamp(timestamp);
+
+    // A request stored in proxy mode
+    if let (Some(method), Some(path), Some(body)) = (
+        data["method"].as_str(),
+        data["path"].as_str(),
+        data.get("body"),
+    ) {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|err| format!("method {method}: {err}"))?;
+        let path = match data["query"].as_str() {
+            Some("") | None => path.to_string(),
+            Some(query) => format!("{path}?{query}"),
+        };
+        let body = match body {
+            Value::Null => None,
+            body => Some(body.clone()),
+        };
+        return Ok(Some(Replayed {
+            timestamp,
+            method,
+            path,
+            body,
+        }));
+    }
+
+    let (source_database, source_table) = match source {
+        ReplaySource::Table { database, table } => (Some(database.as_str()), Some(table.as_str())),
+        ReplaySource::File(_) => (None, None),
+    };
+    let database = settings.to_database.as_deref().or(source_database);
+    let table = settings
+        .to_table
+        .as_deref()
+        .or(row["table"].as_str())
+        .or(source_table);
+    match (database, table) {
+        (Some(database), Some(table)) => Ok(Some(Replayed {
+            timestamp,
+            method: Method::PUT,
+            path: format!("/{database}/{table}"),
+            body: Some(data.clone()),
+        })),
+        (None, _) => Err(String::from(
+            "no database to write the row to, see --to-database",
+        )),
+        (_, None) => Err(String::from("no table to write the row to, see --to-table")),
+    }
+}
+
+// Timestamps are stored as `2024-05-01 12:00:00.123 UTC`, RFC 3339 is read too
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|timestamp| timestamp.to_utc())
+        .ok()
+        .or_else(|| {
+            let timestamp = timestamp.trim_end_matches(" UTC");
+            NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f")
+                .map(|timestamp| timestamp.and_utc())
+                .ok()
+        })
+}
+
+/// When each request is due, relative to the first
+#[derive(Debug)]
+pub struct Pace {
+    speed: f64,
+    first: Option<DateTime<Utc>>,
+}
+
+impl Pace {
+    pub fn new(speed: f64) -> Self {
+        Pace { speed, first: None }
+    }
+
+    /// How long after the first request a request stored at `timestamp` is
+    /// sent, right away when it has no timestamp or is out of order
+    pub fn offset(&mut self, timestamp: Option<DateTime<Utc>>) -> Duration {
+        if self.speed <= 0.0 {
+            return Duration::ZERO;
+        }
+        let Some(timestamp) = timestamp else {
+            return Duration::ZERO;
+        };
+        let first = *self.first.get_or_insert(timestamp);
+        (timestamp - first)
+            .to_std()
+            .map(|offset| offset.div_f64(self.speed))
+            .unwrap_or_default()
+    }
+}
+
+// What the target answered each request with
+#[derive(Default)]
+struct Tally {
+    report: ReplayReport,
+    latencies: Vec<Duration>,
+}
+
+/// Send the requests of the lines read to the target
+pub async fn replay(
+    lines: LocalBoxStream<'_, io::Result<String>>,
+    source: &ReplaySource,
+    settings: &ReplaySettings,
+) -> io::Result<ReplayReport> {
+    let target = settings.target.trim_end_matches('/').to_string();
+    if !target.starts_with("http://") && !target.starts_with("https://") {
+        return Err(io::Error::other(format!(
+            "the replay target is not an HTTP URL: {target}"
+        )));
+    }
+    #[cfg(not(feature = "tls"))]
+    if target.starts_with("https://") {
+        return Err(io::Error::other(format!(
+            "an https:// replay target needs a build with the tls feature: {target}"
+        )));
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in &settings.headers {
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(io::Error::other)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(io::Error::other)?;
+        headers.insert(name, value);
+    }
+    let client = Client::builder()
+        .timeout(settings.timeout)
+        .default_headers(headers)
+        .build()
+        .map_err(|err| io::Error::other(format!("unable to create the replay client: {err}")))?;
+
+    let concurrency = settings.concurrency.max(1);
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let tally = Rc::new(RefCell::new(Tally::default()));
+    let mut pace = Pace::new(settings.speed);
+    let started = Instant::now();
+    let mut lines = lines.enumerate();
+    while let Some((index, line)) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let replayed = match request(&line, source, settings) {
+            Ok(Some(replayed)) => replayed,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("line {} is skipped: {err}", index + 1);
+                tally.borrow_mut().report.skipped += 1;
+                continue;
+            }
+        };
+        time::sleep_until(started + pace.offset(replayed.timestamp)).await;
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        let (client, tally) = (client.clone(), tally.clone());
+        let url = format!("{target}{}", replayed.path);
+        rt::spawn(async move {
+            let mut request = client.request(replayed.method, &url);
+            match replayed.body {
+                Some(Value::String(text)) => request = request.body(text),
+                Some(body) => {
+                    request = request
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(body.to_string())
+                }
+                None => {}
+            }
+            let sent = Instant::now();
+            let result = request.send().await;
+            let mut tally = tally.borrow_mut();
+            tally.report.sent += 1;
+            match result {
+                Ok(res) => {
+                    debug!("{url} answered {}", res.status());
+                    tally.latencies.push(sent.elapsed());
+                    *tally
+                        .report
+                        .statuses
+                        .entry(res.status().as_u16())
+                        .or_default() += 1;
+                }
+                Err(err) => {
+                    error!("unable to send {url}: {err}");
+                    tally.report.errors += 1;
+                }
+            }
+            drop(permit);
+        });
+    }
+    // Every request has been answered once every permit is back
+    let _ = permits.acquire_many(concurrency as u32).await;
+
+    let Tally {
+        mut report,
+        latencies,
+    } = tally.take();
+    report.seconds = started.elapsed().as_secs_f64();
+    report.latency_ms = Latency::new(latencies);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use chrono::TimeZone;
+
+    fn replay_settings(target: &str) -> ReplaySettings {
+        ReplaySettings {
+            target: target.to_string(),
+            speed: 0.0,
+            concurrency: 4,
+            to_database: None,
+            to_table: None,
+            headers: vec![],
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let file = ReplaySource::File(String::from("export.ndjson"));
+        let table = ReplaySource::Table {
+            database: String::from("archive"),
+            table: String::from("readings"),
+        };
+        let settings = replay_settings("http://localhost:8888");
+
+        // A request stored in proxy mode is sent as it was
+        let line = r#"{"seq":1,"id":1,"timestamp":"2024-05-01 12:00:00.5 UTC","data":{"method":"POST","path":"/v1/orders","query":"dry_run=1","status":201,"body":{"id":7}}}"#;
+        let expected = Replayed {
+            timestamp: Some(
+                Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap() + Duration::from_millis(500),
+            ),
+            method: Method::POST,
+            path: String::from("/v1/orders?dry_run=1"),
+            body: Some(serde_json::json!({"id": 7})),
+        };
+        assert_eq!(request(line, &file, &settings).unwrap(), Some(expected));
+
+        // Any other row is written to its table
+        let line =
+            r#"{"seq":2,"id":2,"timestamp":"2024-05-01T12:00:01Z","data":{"temperature":21.5}}"#;
+        let replayed = request(line, &table, &settings).unwrap().unwrap();
+        assert_eq!(replayed.method, Method::PUT);
+        assert_eq!(replayed.path, "/archive/readings");
+        assert_eq!(
+            replayed.body,
+            Some(serde_json::json!({"temperature": 21.5}))
+        );
+
+        // The table of an export is named by the row or the settings
+        assert!(request(line, &file, &settings).is_err());
+        let snapshot =
+            r#"{"table":"events","seq":2,"id":2,"timestamp":"2024-05-01T12:00:01Z","data":{}}"#;
+        let to = ReplaySettings {
+            to_database: Some(String::from("staging")),
+            ..settings.clone()
+        };
+        let replayed = request(snapshot, &file, &to).unwrap().unwrap();
+        assert_eq!(replayed.path, "/staging/events");
+
+        // Summary lines are skipped, other lines refused
+        let summary = r#"{"summary":{"rows":2,"bytes":180,"crc32":"1c291ca3"}}"#;
+        assert_eq!(request(summary, &file, &settings).unwrap(), None);
+        assert!(request(r#"{"temperature":21.5}"#, &table, &settings).is_err());
+        assert!(request("not json", &table, &settings).is_err());
+    }
+
+    #[test]
+    fn test_pace() {
+        let first = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let later = first + Duration::from_secs(10);
+
+        let mut pace = Pace::new(1.0);
+        assert_eq!(pace.offset(Some(first)), Duration::ZERO);
+        assert_eq!(pace.offset(Some(later)), Duration::from_secs(10));
+        assert_eq!(pace.offset(None), Duration::ZERO);
+
+        // Faster, out of order and as fast as possible
+        let mut pace = Pace::new(4.0);
+        assert_eq!(pace.offset(Some(later)), Duration::ZERO);
+        assert_eq!(pace.offset(Some(first)), Duration::ZERO);
+        assert_eq!(
+            pace.offset(Some(later + Duration::from_secs(2))),
+            Duration::from_millis(500)
+        );
+        let mut pace = Pace::new(0.0);
+        pace.offset(Some(first));
+        assert_eq!(pace.offset(Some(later)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(Latency::new(vec![]), Latency::default());
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        let latency = Latency::new(latencies);
+        assert_eq!((latency.p50, latency.p99, latency.max), (50.0, 99.0, 100.0));
+    }
+
+    #[actix_web::test]
+    async fn test_replay() {
+        // A target answering with the request it received
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|req: HttpRequest, body: web::Bytes| async move {
+                match req.headers().get("authorization") {
+                    Some(_) if req.path() == "/refuse" => HttpResponse::BadRequest().finish(),
+                    Some(_) => HttpResponse::Ok().body(body),
+                    None => HttpResponse::Unauthorized().finish(),
+                }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let lines = [
+            r#"{"seq":1,"id":1,"timestamp":"2024-05-01 12:00:00 UTC","data":{"method":"POST","path":"/v1/orders","query":"","status":201,"body":{"id":7}}}"#,
+            r#"{"seq":2,"id":2,"timestamp":"2024-05-01 12:00:00.2 UTC","data":{"method":"GET","path":"/refuse","query":"","status":400,"body":null}}"#,
+            r#"{"seq":3,"id":3,"timestamp":"2024-05-01 12:00:00.4 UTC","data":{"temperature":21.5}}"#,
+            "",
+            "not json",
+            r#"{"summary":{"rows":3,"bytes":180,"crc32":"1c291ca3"}}"#,
+        ];
+        let lines = stream::iter(lines.map(|line| Ok(line.to_string()))).boxed_local();
+        let source = ReplaySource::Table {
+            database: String::from("archive"),
+            table: String::from("readings"),
+        };
+        let settings = ReplaySettings {
+            speed: 2.0,
+            headers: vec![(String::from("Authorization"), String::from("Bearer secret"))],
+            ..replay_settings(&format!("http://127.0.0.1:{port}/"))
+        };
+        let report = replay(lines, &source, &settings).await.unwrap();
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.statuses, BTreeMap::from([(200, 2), (400, 1)]));
+        // Stored 0.4s apart, sent twice as fast
+        assert!(report.seconds >= 0.2);
+        assert!(report.latency_ms.max > 0.0);
+
+        // A target which is not HTTP
+        let lines = stream::empty().boxed_local();
+        assert!(replay(lines, &source, &replay_settings("ftp://127.0.0.1"))
+            .await
+            .is_err());
+
+        handle.stop(true).await;
+    }
+}
@@ -0,0 +1,173 @@
+// Retention policy and the background purge task
+//
+// --retention 30d --table-retention debug_logs=1d --retention-vacuum incremental
+//
+// Every `--retention-interval` the rows older than the retention window of
+// their table are deleted, then the space they used is optionally vacuumed.
+// A table retention wins over the retention of its database, which wins over
+// the default.
+// With leader election only the leader purges, see leader.rs.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::{spawn, time};
+use chrono::Utc;
+use tracing::{debug, error, info};
+
+use crate::leader::Leader;
+use crate::metrics;
+use crate::storage::{Storage, StorageResult, VacuumMode};
+
+/// A retention window for a single table
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableRetention {
+    pub table: String,
+    pub retention: Duration,
+}
+
+impl FromStr for TableRetention {
+    type Err = String;
+
+    // <table>=<duration>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, retention) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>=<duration>: {value}"))?;
+        let retention = humantime::parse_duration(retention.trim())
+            .map_err(|err| format!("invalid duration '{retention}': {err}"))?;
+        Ok(TableRetention {
+            table: table.trim().to_string(),
+            retention,
+        })
+    }
+}
+
+/// How long rows are kept
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Applies to every table without a retention of its own, forever when unset
+    pub default: Option<Duration>,
+    pub databases: HashMap<String, Duration>,
+    pub tables: Vec<TableRetention>,
+    pub vacuum: VacuumMode,
+}
+
+impl RetentionPolicy {
+    /// The retention window of a table, if rows of the table expire at all
+    pub fn retention(&self, database: &str, table: &str) -> Option<Duration> {
+        self.tables
+            .iter()
+            .find(|retention| retention.table == table)
+            .map(|retention| retention.retention)
+            .or_else(|| self.databases.get(database).copied())
+            .or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.databases.is_empty() && self.tables.is_empty()
+    }
+
+    /// Delete the expired rows of every table, returns the number of rows purged
+    pub async fn purge(&self, storage: &dyn Storage) -> StorageResult<u64> {
+        let now = Utc::now();
+        let mut total = 0;
+        for database in storage.databases().await? {
+            let mut purged_database = 0;
+            for table in storage.tables(&database).await? {
+                let Some(retention) = self.retention(&database, &table) else {
+                    continue;
+                };
+                let Ok(retention) = chrono::Duration::from_std(retention) else {
+                    continue;
+                };
+                let purged = storage.purge(&database, &table, now - retention).await?;
+                debug!("purged {purged} rows from {database}/{table}");
+                metrics::ROWS_PURGED
+                    .with_label_values(&[&database, &table])
+                    .inc_by(purged);
+                purged_database += purged;
+            }
+            if purged_database > 0 && self.vacuum != VacuumMode::None {
+                storage.vacuum(&database, self.vacuum).await?;
+                info!("vacuumed {database} ({:?})", self.vacuum);
+            }
+            total += purged_database;
+        }
+        Ok(total)
+    }
+}
+
+/// Run the purge in the background every `interval` while this instance leads
+pub fn spawn_purge_task(
+    storage: Arc<dyn Storage>,
+    policy: RetentionPolicy,
+    interval: Duration,
+    leader: Leader,
+) {
+    if policy.is_empty() {
+        return;
+    }
+    info!(
+        "Purging expired rows every {}",
+        humantime::format_duration(interval)
+    );
+    spawn(async move {
+        let mut ticks = time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if !leader.is_leader() {
+                debug!("retention purge left to the leader");
+                continue;
+            }
+            match policy.purge(storage.as_ref()).await {
+                Ok(purged) => info!("retention purged {purged} rows"),
+                Err(err) => error!("retention purge failed: {err}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{record, NewRecord, SqliteStorage};
+
+    #[actix_web::test]
+    async fn test_purge() {
+        let policy = RetentionPolicy {
+            default: None,
+            databases: HashMap::new(),
+            tables: vec!["expiring=1h".parse().unwrap()],
+            vacuum: VacuumMode::Full,
+        };
+        assert_eq!(
+            policy.retention("test", "expiring"),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(policy.retention("test", "kept"), None);
+
+        // One row from two hours ago, one from now, in each table
+        let database_files = std::env::temp_dir().join("adr_test_purge");
+        std::fs::create_dir_all(&database_files).unwrap();
+        let storage = SqliteStorage::new(database_files.to_str().unwrap());
+        for table in ["expiring", "kept"] {
+            let records = [Utc::now() - chrono::Duration::hours(2), Utc::now()]
+                .into_iter()
+                .map(|timestamp| NewRecord {
+                    timestamp,
+                    ..record("{}")
+                })
+                .collect();
+            storage.insert_batch("test", table, records).await.unwrap();
+        }
+
+        assert_eq!(policy.purge(&storage).await.unwrap(), 1);
+        assert_eq!(policy.purge(&storage).await.unwrap(), 0);
+
+        // Post test, remove any database files created
+        std::fs::remove_dir_all(database_files).unwrap();
+    }
+}
@@ -0,0 +1,3687 @@
+// The HTTP endpoints of the data receiver
+//
+// PUT  /<database name>/<table name>
+// GET  /<database name>/<table name>/<changes|query|stats|export>
+// POST /<database name>/<table name>/query
+// GET  /<database name>/_export
+// GET  /_admin/...
+// GET  /debug/pprof/<profile|heap>
+// GET  /<ping|healthz|healthz/live|healthz/ready>
+// GET  /openapi.json
+// GET  /_docs/
+//
+// The handlers share the `AppData` built from the configuration, see
+// `build_app` in lib.rs for the application answering them.
+//
+// The OpenAPI 3 document on /openapi.json is generated from the
+// `#[utoipa::path]` attributes of the handlers, so clients can be generated
+// from it. A build with the `swagger-ui` feature serves Swagger UI on /_docs/.
+use std::str;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+// A web framework for Rust
+// https://docs.rs/actix-web/latest/actix_web/web/index.html
+// cargo add actix-web
+use actix_web::{
+    get,
+    http::{header, Method},
+    post, put, routes, rt, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+    Responder, Result,
+};
+
+// Timezone-aware date and time
+// https://docs.rs/chrono/latest/chrono/
+// cargo add chrono
+use chrono::{DateTime, Utc};
+
+// Combinators for futures and streams
+// https://docs.rs/futures-util/latest/futures_util/
+// cargo add futures-util
+use futures_util::stream::{Stream, StreamExt};
+
+// https://docs.rs/serde/latest/serde/
+// https://serde.rs
+// cargo add serde --features derive
+use serde::{Deserialize, Serialize};
+
+// https://docs.rs/serde_json/latest/serde_json/
+// cargo add serde_json
+use serde_json::Value;
+
+// A framework for instrumenting Rust
+// https://docs.rs/tracing/latest/tracing
+// cargo add tracing
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+// Generated OpenAPI documentation
+// https://docs.rs/utoipa/latest/utoipa/
+// cargo add utoipa --features actix_extras
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+// Swagger UI for the OpenAPI document
+// https://docs.rs/utoipa-swagger-ui/latest/utoipa_swagger_ui/
+// cargo add utoipa-swagger-ui --features actix-web,vendored --optional
+#[cfg(feature = "swagger-ui")]
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::access::AccessRules;
+use crate::audit::{self, TrustedProxies};
+use crate::auth::{self, ApiKeyName};
+use crate::backup::{self, BackupFile, BackupSettings};
+use crate::compress::Compression;
+use crate::compute::{self, ComputedField, Maps};
+use crate::config::{BackupConfig, Config, ServerConfig};
+use crate::consistency;
+use crate::debug;
+use crate::decompress::{self, BodyLimits};
+use crate::errors::{Error, ErrorResponse};
+use crate::export;
+use crate::flatten::{self, FlattenRule};
+use crate::forward::Forwarder;
+use crate::jsonpath::JsonPath;
+use crate::log_filter::LogFilter;
+use crate::lookups::{self, EnrichRule, Lookups};
+use crate::memory::MemoryBudget;
+use crate::metrics;
+use crate::ordering::{self, OrderingGuard, OrderingLocks};
+#[cfg(feature = "pprof")]
+use crate::profiling;
+use crate::proxy::{self, Proxy};
+use crate::schemas::{self, TableSchema};
+use crate::shutdown::Readiness;
+use crate::split::{self, SplitRule};
+use crate::storage::{
+    self, ChangeEvent, ChangesFilter, Condition, ConditionSpec, HealthCheck, Inserted, NewRecord,
+    QueryFilter, RecordMetadata, SqliteStorage, StatsBucket, StatsFilter, Storage,
+};
+use crate::templates::{self, TableTemplate, TemplateContext};
+use crate::transcode::BodyFormat;
+use crate::write_queue::WriteQueue;
+
+// TODO: DELETE /<database name>/<table name>/<key>
+// TODO: GET /<database name>/<table name>/<key>
+// TODO: PATCH /<database name>/<table name>/<key>
+
+/// Create data in a database table using JSON formatted data
+/// PUT /<database name>/<table name>
+/// curl -i -X PUT -d '{"curl test": true}' http://localhost:8888/database/test
+/// A retry with the same `Idempotency-Key` header or `_id` field gets 200 with the stored rows
+/// curl -i -X PUT -H 'Idempotency-Key: 7f3c' -d '{"curl test": true}' http://localhost:8888/database/test
+/// Bodies can be sent compressed with gzip, deflate or br
+/// curl -i -X PUT -H 'Content-Encoding: gzip' --data-binary @test.json.gz http://localhost:8888/database/test
+/// or as MessagePack or CBOR
+/// curl -i -X PUT -H 'Content-Type: application/msgpack' --data-binary @test.msgpack http://localhost:8888/database/test
+#[utoipa::path(
+    tag = "data",
+    params(
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the stored rows"),
+    ),
+    request_body(
+        content = Value,
+        description = "A JSON document, or MessagePack or CBOR sent with their Content-Type",
+        content_type = "application/json",
+    ),
+    responses(
+        (status = 201, description = "Stored", headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = 200, description = "Already stored under its idempotency key", body = Vec<Inserted>, headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = 202, description = "Queued to be stored", headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[put("/{database_name}/{table_name}")]
+async fn create_data(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    payload: web::Payload,       // Provide access to the request body
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // /{database_name <--- path.0}/{table_name <--- path.1}
+    let (database_name, table_name) = path.into_inner();
+    let (body, _reservation) = decompress::read_body(
+        req.headers(),
+        payload,
+        appdata.body_limits,
+        &appdata.memory_budget,
+    )
+    .await?;
+    insert_data(&appdata, &req, &database_name, &table_name, &body).await
+}
+
+/// Create data in a table of the default database using JSON formatted data
+/// PUT /<table name>
+/// curl -i -X PUT -d '{"curl test": true}' http://localhost:8888/test
+#[utoipa::path(
+    tag = "data",
+    params(
+        ("table_name" = String, Path, description = "The table"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the stored rows"),
+    ),
+    request_body(
+        content = Value,
+        description = "A JSON document, or MessagePack or CBOR sent with their Content-Type",
+        content_type = "application/json",
+    ),
+    responses(
+        (status = 201, description = "Stored in the default database", headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = 200, description = "Already stored under its idempotency key", body = Vec<Inserted>, headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = 202, description = "Queued to be stored", headers(("X-Consistency-Token" = String, description = "Passed back by reads to see this write"))),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[put("/{table_name}")]
+async fn create_default_data(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<String>,     // Provide access to the URI path elements
+    payload: web::Payload,       // Provide access to the request body
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // Only available with `--default-database`
+    let database_name = appdata
+        .default_database
+        .as_deref()
+        .ok_or_else(|| Error::NotFound(String::from("no default database is configured")))?;
+
+    // /{table_name <--- path}
+    let table_name = path.into_inner();
+    let (body, _reservation) = decompress::read_body(
+        req.headers(),
+        payload,
+        appdata.body_limits,
+        &appdata.memory_budget,
+    )
+    .await?;
+    insert_data(&appdata, &req, database_name, &table_name, &body).await
+}
+
+// Insert the request body into a database table
+async fn insert_data(
+    appdata: &AppData,
+    req: &HttpRequest,
+    database_name: &str,
+    table_name: &str,
+    body: &[u8],
+) -> Result<HttpResponse, Error> {
+    // Validate the database and table names are sane and allowed
+    check_table(appdata, database_name, table_name)?;
+
+    // Get the JSON data from the request, MessagePack and CBOR are transcoded to it
+    let format = BodyFormat::from_headers(req.headers())?;
+    metrics::PAYLOAD_BYTES.observe(body.len() as f64);
+    let body = format.to_json(body).map_err(|err| {
+        validation_failure(
+            database_name,
+            table_name,
+            format.reason(),
+            format!("request body is not valid {format}: {err}"),
+        )
+    })?;
+    let data = str::from_utf8(&body).map_err(|err| {
+        validation_failure(
+            database_name,
+            table_name,
+            "utf8",
+            format!("request body is not UTF-8: {err}"),
+        )
+    })?;
+
+    // Validate the data is actual JSON before it reaches the database
+    let value: Value = serde_json::from_str(data).map_err(|err| {
+        validation_failure(
+            database_name,
+            table_name,
+            "json",
+            format!("request body is not valid JSON: {err}"),
+        )
+    })?;
+
+    // The name of the API key is available to table templates
+    let api_key_name = req.extensions().get::<ApiKeyName>().cloned();
+    let idempotency_key = match req.headers().get("Idempotency-Key") {
+        Some(key) => Some(key.to_str().map_err(|_| {
+            Error::BadRequest(String::from("Idempotency-Key is not visible ASCII"))
+        })?),
+        None => None,
+    };
+    let metadata = appdata.record_metadata(req);
+
+    // Show a new sender what would be stored without storing it
+    if debug::requested(req, &appdata.debug_keys, api_key_name.as_ref())? {
+        let span = info_span!(
+            debug::SPAN_NAME,
+            database = database_name,
+            table = table_name
+        );
+        return echo_data(
+            appdata,
+            database_name,
+            table_name,
+            value,
+            api_key_name.as_ref().map(|name| name.0.as_str()),
+            idempotency_key,
+            &metadata,
+        )
+        .instrument(span)
+        .await;
+    }
+
+    // Queued writes are answered before they are stored
+    if let Some(write_queue) = &appdata.write_queue {
+        let prepared = prepare(
+            appdata,
+            database_name,
+            table_name,
+            vec![value],
+            api_key_name.as_ref().map(|name| name.0.as_str()),
+            idempotency_key,
+            &metadata,
+        )
+        .await?;
+        let ticket = write_queue.enqueue(database_name, &prepared.table, prepared.records)?;
+        debug!("queued write: {ticket}");
+        let token = consistency::Token {
+            database: database_name.to_string(),
+            table: prepared.table,
+            commit: consistency::Commit::Queued(ticket),
+        };
+
+        // Return an HTTP 202 Accepted response
+        return Ok(HttpResponse::Accepted()
+            .insert_header((consistency::TOKEN_HEADER, token.to_string()))
+            .finish());
+    }
+
+    let (table_name, result) = ingest(
+        appdata,
+        database_name,
+        table_name,
+        vec![value],
+        api_key_name.as_ref().map(|name| name.0.as_str()),
+        idempotency_key,
+        &metadata,
+    )
+    .await?;
+    debug!("insert result: {result:?}");
+
+    // Readers pass the token back to see this write
+    let token = consistency::Token {
+        database: database_name.to_string(),
+        table: table_name,
+        commit: consistency::Commit::Seq(
+            result
+                .iter()
+                .map(|inserted| inserted.seq)
+                .max()
+                .unwrap_or(0),
+        ),
+    };
+    let token = (consistency::TOKEN_HEADER, token.to_string());
+
+    // A retried request which was already stored gets the rows it stored
+    if result.iter().all(|inserted| inserted.duplicate) {
+        return Ok(HttpResponse::Ok().insert_header(token).json(result));
+    }
+
+    // Return an HTTP 201 Created response
+    Ok(HttpResponse::Created().insert_header(token).finish())
+}
+
+// A document refused before it is stored, counted by reason
+fn validation_failure(
+    database_name: &str,
+    table_name: &str,
+    reason: &str,
+    message: String,
+) -> Error {
+    metrics::VALIDATION_FAILURES
+        .with_label_values(&[database_name, table_name, reason])
+        .inc();
+    Error::BadRequest(message)
+}
+
+// Validate the database and table names are sane and allowed
+pub(crate) fn check_table(
+    appdata: &AppData,
+    database_name: &str,
+    table_name: &str,
+) -> Result<(), Error> {
+    storage::validate_name(database_name)?;
+    storage::validate_name(table_name)?;
+    appdata
+        .access
+        .check(database_name, table_name)
+        .map_err(Error::Forbidden)
+}
+
+// Write documents into a database table, the same way however they arrived
+// The names are expected to have passed `check_table`, the table the rows
+// were written to is returned with them
+pub(crate) async fn ingest(
+    appdata: &AppData,
+    database_name: &str,
+    table_name: &str,
+    values: Vec<Value>,
+    api_key_name: Option<&str>,
+    idempotency_key: Option<&str>,
+    metadata: &RecordMetadata,
+) -> Result<(String, Vec<Inserted>), Error> {
+    let prepared = prepare(
+        appdata,
+        database_name,
+        table_name,
+        values,
+        api_key_name,
+        idempotency_key,
+        metadata,
+    )
+    .await?;
+    let forwarded = appdata
+        .forwarder
+        .as_ref()
+        .filter(|forwarder| forwarder.forwards(database_name, &prepared.table))
+        .map(|forwarder| (forwarder, prepared.records.clone()));
+    let started = Instant::now();
+    let inserted = appdata
+        .storage
+        .insert_batch(database_name, &prepared.table, prepared.records)
+        .await?;
+    metrics::observe_insert(database_name, &prepared.table, &inserted, started.elapsed());
+    if let Some((forwarder, records)) = forwarded {
+        forwarder.send(database_name, &prepared.table, &records, &inserted);
+    }
+    Ok((prepared.table, inserted))
+}
+
+// Answer with what `ingest` would store instead of storing it
+async fn echo_data(
+    appdata: &AppData,
+    database_name: &str,
+    table_name: &str,
+    value: Value,
+    api_key_name: Option<&str>,
+    idempotency_key: Option<&str>,
+    metadata: &RecordMetadata,
+) -> Result<HttpResponse, Error> {
+    let prepared = prepare(
+        appdata,
+        database_name,
+        table_name,
+        vec![value.clone()],
+        api_key_name,
+        idempotency_key,
+        metadata,
+    )
+    .await?;
+    let statements = appdata
+        .storage
+        .insert_statements(database_name, &prepared.table)?;
+    debug!("echo statements: {statements:?}");
+    Ok(HttpResponse::Ok().json(debug::Echo {
+        database: database_name.to_string(),
+        rows: prepared.records.iter().map(debug::EchoRow::new).collect(),
+        table: prepared.table,
+        received: value,
+        statements,
+    }))
+}
+
+// Documents turned into the rows of a table, ready to be stored
+struct Prepared {
+    table: String,
+    records: Vec<NewRecord>,
+    // Inserts sharing an ordering key wait until these are dropped
+    _ordering_guards: Vec<OrderingGuard>,
+}
+
+// Run documents through the pipeline of the table they are sent to
+async fn prepare(
+    appdata: &AppData,
+    database_name: &str,
+    table_name: &str,
+    values: Vec<Value>,
+    api_key_name: Option<&str>,
+    idempotency_key: Option<&str>,
+    metadata: &RecordMetadata,
+) -> Result<Prepared, Error> {
+    // One document can carry many rows
+    let split_rule = split::find(&appdata.split_rules, table_name);
+    let mut documents: Vec<Value> = values
+        .into_iter()
+        .flat_map(|value| match split_rule {
+            Some(rule) => rule.split(value),
+            None => vec![value],
+        })
+        .collect();
+
+    // Every row has to match the schema of the table
+    if let Some(schema) = schemas::find(&appdata.schemas, table_name) {
+        for document in &documents {
+            schema
+                .validate(document)
+                .map_err(|err| validation_failure(database_name, table_name, "schema", err))?;
+        }
+    }
+
+    // Inserts sharing an ordering key are written one at a time in arrival order
+    let ordering_keys: Vec<Option<String>> = documents
+        .iter()
+        .map(|document| {
+            appdata
+                .ordering_key
+                .as_ref()
+                .and_then(|path| ordering::extract_key(path, document))
+        })
+        .collect();
+    let ordering_guards = appdata
+        .ordering_locks
+        .lock_all(
+            database_name,
+            table_name,
+            &ordering_keys.iter().flatten().cloned().collect(),
+        )
+        .await;
+
+    // Set the timestamp to the current time
+    let timestamp: DateTime<Utc> = Utc::now();
+
+    // Join the lookup tables and add the computed fields of the table
+    for document in &mut documents {
+        lookups::enrich(
+            &appdata.enrich_rules,
+            &appdata.lookups,
+            table_name,
+            document,
+        );
+        compute::apply(
+            &appdata.computed_fields,
+            table_name,
+            document,
+            timestamp,
+            &appdata.maps,
+        );
+    }
+
+    // The destination table can be a template such as `events_{yyyy_mm}`
+    let context = TemplateContext {
+        database: database_name,
+        table: table_name,
+        timestamp,
+        api_key_name,
+    };
+    let table_name = &templates::resolve(&appdata.table_templates, &context);
+    storage::validate_name(table_name)?;
+    if let Some(existing) = &appdata.access.existing_only {
+        existing
+            .check(appdata.storage.as_ref(), database_name, table_name)
+            .await?;
+    }
+
+    // A row is stored once per idempotency key, an `_id` field of the row or
+    // the key of the request numbered by row when the request makes many rows
+    let many = documents.len() > 1;
+    let idempotency_keys: Vec<Option<String>> = documents
+        .iter()
+        .enumerate()
+        .map(|(index, document)| match document.get("_id") {
+            Some(Value::String(id)) => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => idempotency_key.map(|key| match many {
+                true => format!("{key}/{index}"),
+                false => key.to_string(),
+            }),
+        })
+        .collect();
+
+    // Insert the data into the table, flattened when the table asks for it
+    let flatten_rule = flatten::find(&appdata.flatten_rules, context.table);
+    let records: Vec<NewRecord> = documents
+        .into_iter()
+        .zip(ordering_keys)
+        .zip(idempotency_keys)
+        .map(|((document, ordering_key), idempotency_key)| {
+            let data = match flatten_rule {
+                Some(rule) => rule.flatten(document).to_string(),
+                None => document.to_string(),
+            };
+            info!("insert table: {table_name}, timestamp: {timestamp}, ordering key: {ordering_key:?}, data: {data}");
+            NewRecord {
+                timestamp,
+                data,
+                ordering_key,
+                idempotency_key,
+                metadata: metadata.clone(),
+            }
+        })
+        .collect();
+    Ok(Prepared {
+        table: table_name.to_string(),
+        records,
+        _ordering_guards: ordering_guards,
+    })
+}
+
+// Changefeed query string options
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ChangesQuery {
+    /// Only the events with a greater `seq`
+    since: Option<i64>,
+    /// Only the events with a greater `id`, in `id` order
+    after_id: Option<i64>,
+    /// At most this many events, 1000 by default
+    limit: Option<i64>,
+    /// Only the events of this ordering key
+    key: Option<String>,
+}
+
+/// Read the changes made to a database table in sequence order
+/// GET /<database name>/<table name>/changes?since=<seq>&limit=<count>&key=<ordering key>
+/// GET /<database name>/<table name>/changes?after_id=<id>&limit=<count>
+/// curl -i http://localhost:8888/database/test/changes?since=0
+/// curl -i -H 'Accept: text/event-stream' http://localhost:8888/database/test/changes
+///
+/// Events are returned with a `seq` greater than `since`, a consumer which
+/// sees a jump in `seq` between two events has missed the events in between.
+/// Server-Sent Events are returned when requested using the Accept header,
+/// the `Last-Event-ID` header is honored in place of `since`. Limiting the
+/// events to a single ordering `key` returns them in the order they arrived.
+///
+/// With `after_id` the events are returned in the order of their `id`, the
+/// primary key, instead. A full page is answered with a `Link: rel="next"`
+/// header to the page after it, and every page with an `X-Total-Count`
+/// estimate of the rows of the table.
+#[utoipa::path(
+    tag = "data",
+    params(
+        ChangesQuery,
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("X-Consistency-Token" = Option<String>, Header, description = "Wait until the write answered with this token is visible"),
+    ),
+    responses(
+        (status = 200, description = "The events after `since`", content(
+            (Vec<ChangeEvent> = "application/json"),
+            (String = "text/event-stream"),
+        ), headers(("Link" = String, description = "The next page, while pages come back full"), ("X-Total-Count" = i64, description = "An estimate of the rows of the table"))),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/{database_name}/{table_name}/changes")]
+async fn read_changes(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    query: web::Query<ChangesQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // /{database_name <--- path.0}/{table_name <--- path.1}/changes
+    let database_name = path.0.to_string();
+    let table_name = path.1.to_string();
+    check_table(&appdata, &database_name, &table_name)?;
+    consistency::wait_visible(
+        appdata.storage.as_ref(),
+        appdata.write_queue.as_ref(),
+        &req,
+        &database_name,
+        &table_name,
+    )
+    .await?;
+
+    // Server-Sent Events when asked for, JSON otherwise
+    let headers = req.headers();
+    let event_stream = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("text/event-stream"));
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok());
+    let filter = ChangesFilter {
+        since: query.since.or(last_event_id).unwrap_or(0),
+        after_id: query.after_id,
+        limit: query.limit.unwrap_or(1000).clamp(1, 10000),
+        key: query.key.clone(),
+    };
+
+    let events = appdata
+        .storage
+        .changes(&database_name, &table_name, &filter)
+        .await?;
+    debug!("changes since: {}, events: {}", filter.since, events.len());
+    let mut response = page(
+        &appdata,
+        (&database_name, &table_name),
+        &req,
+        &events,
+        (filter.after_id, filter.limit),
+    )
+    .await?;
+
+    if !event_stream {
+        return Ok(response.json(events));
+    }
+
+    // https://html.spec.whatwg.org/multipage/server-sent-events.html
+    let mut body = String::new();
+    for event in &events {
+        body.push_str(&format!(
+            "id: {}\nevent: insert\ndata: {}\n\n",
+            event.seq,
+            serde_json::to_string(event).unwrap_or_default()
+        ));
+    }
+    Ok(response
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(body))
+}
+
+// A response to a page of rows, linking to the next page while pages come
+// back full and with an estimate of the rows of the table
+// https://www.rfc-editor.org/rfc/rfc8288#section-3
+async fn page(
+    appdata: &AppData,
+    (database_name, table_name): (&str, &str),
+    req: &HttpRequest,
+    events: &[ChangeEvent],
+    (after_id, limit): (Option<i64>, i64),
+) -> Result<HttpResponseBuilder, Error> {
+    let total = appdata
+        .storage
+        .row_estimate(database_name, table_name)
+        .await?;
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", total));
+
+    // A POST query carries its options in the body, there is no URL to link to
+    let full = events.len() as i64 == limit;
+    if let Some(last) = events
+        .last()
+        .filter(|_| full && req.method() == Method::GET)
+    {
+        // The next page carries on from the last row with the cursor the
+        // request used, the other options are kept as they are
+        let (cursor, value) = match after_id {
+            Some(_) => ("after_id", last.id),
+            None => ("since", last.seq),
+        };
+        let mut options: Vec<String> = req
+            .query_string()
+            .split('&')
+            .filter(|option| {
+                let name = option.split('=').next().unwrap_or_default();
+                !option.is_empty() && name != cursor && name != "limit"
+            })
+            .map(String::from)
+            .collect();
+        options.push(format!("{cursor}={value}"));
+        options.push(format!("limit={limit}"));
+        let link = format!("<{}?{}>; rel=\"next\"", req.path(), options.join("&"));
+        response.insert_header((header::LINK, link));
+    }
+    Ok(response)
+}
+
+// Query string options of a query with a single condition
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FilterQuery {
+    /// The JSON path of the value compared
+    #[param(value_type = String, example = "$.level")]
+    path: JsonPath,
+    eq: Option<String>,
+    ne: Option<String>,
+    lt: Option<String>,
+    le: Option<String>,
+    gt: Option<String>,
+    ge: Option<String>,
+    /// A SQL LIKE pattern
+    like: Option<String>,
+    /// Whether the path points at a value
+    exists: Option<bool>,
+    /// Only the documents with a greater `seq`
+    since: Option<i64>,
+    /// Only the documents with a greater `id`, in `id` order
+    after_id: Option<i64>,
+    /// At most this many documents, 1000 by default
+    limit: Option<i64>,
+}
+
+/// Read the documents of a database table matching a condition in sequence order
+/// GET /<database name>/<table name>/query?path=<JSON path>&<eq|ne|lt|le|gt|ge|like|exists>=<value>
+/// curl -i 'http://localhost:8888/database/test/query?path=$.level&eq=error'
+///
+/// Values are JSON when they parse as JSON, `eq=100` is the number 100 and
+/// `eq="100"` the text 100, anything else is text. Pages are linked as for
+/// the changes, `after_id` reads the documents in the order of their `id`.
+#[utoipa::path(
+    tag = "data",
+    params(
+        FilterQuery,
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("X-Consistency-Token" = Option<String>, Header, description = "Wait until the write answered with this token is visible"),
+    ),
+    responses(
+        (status = 200, description = "The matching documents", body = Vec<ChangeEvent>, headers(("Link" = String, description = "The next page, while pages come back full"), ("X-Total-Count" = i64, description = "An estimate of the rows of the table"))),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/{database_name}/{table_name}/query")]
+async fn query_data(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    query: web::Query<FilterQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let value = |value: Option<String>| value.as_deref().map(storage::parse_value);
+    let spec = ConditionSpec {
+        path: query.path,
+        eq: value(query.eq),
+        ne: value(query.ne),
+        lt: value(query.lt),
+        le: value(query.le),
+        gt: value(query.gt),
+        ge: value(query.ge),
+        like: query.like,
+        exists: query.exists,
+    };
+    let body = QueryBody {
+        conditions: vec![spec],
+        since: query.since,
+        after_id: query.after_id,
+        limit: query.limit,
+    };
+    run_query(&appdata, path.into_inner(), body, &req).await
+}
+
+// The body of a query with any number of conditions
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct QueryBody {
+    #[serde(rename = "where", default)]
+    conditions: Vec<ConditionSpec>,
+    since: Option<i64>,
+    after_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Read the documents of a database table matching every condition in sequence order
+/// POST /<database name>/<table name>/query
+/// curl -i -d '{"where": [{"path": "$.level", "eq": "error"}, {"path": "$.ms", "gt": 250}]}' \
+///     http://localhost:8888/database/test/query
+///
+/// The next page is read with `after_id` set to the `id` of the last document.
+#[utoipa::path(
+    tag = "data",
+    params(
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("X-Consistency-Token" = Option<String>, Header, description = "Wait until the write answered with this token is visible"),
+    ),
+    request_body = QueryBody,
+    responses(
+        (status = 200, description = "The matching documents", body = Vec<ChangeEvent>, headers(("X-Total-Count" = i64, description = "An estimate of the rows of the table"))),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[post("/{database_name}/{table_name}/query")]
+async fn query_data_filter(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    body: web::Bytes,            // Provide access to the request body
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    let body: QueryBody = serde_json::from_slice(&body)
+        .map_err(|err| Error::BadRequest(format!("invalid query: {err}")))?;
+    run_query(&appdata, path.into_inner(), body, &req).await
+}
+
+// Read the documents matching a query
+async fn run_query(
+    appdata: &AppData,
+    (database_name, table_name): (String, String),
+    body: QueryBody,
+    req: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    check_table(appdata, &database_name, &table_name)?;
+    consistency::wait_visible(
+        appdata.storage.as_ref(),
+        appdata.write_queue.as_ref(),
+        req,
+        &database_name,
+        &table_name,
+    )
+    .await?;
+    if body.conditions.len() > storage::MAX_CONDITIONS {
+        return Err(Error::BadRequest(format!(
+            "a query can have at most {} conditions",
+            storage::MAX_CONDITIONS
+        )));
+    }
+    let filter = QueryFilter {
+        conditions: body
+            .conditions
+            .into_iter()
+            .map(Condition::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(Error::BadRequest)?,
+        since: body.since.unwrap_or(0),
+        after_id: body.after_id,
+        limit: body.limit.unwrap_or(1000).clamp(1, 10000),
+    };
+    let events = appdata
+        .storage
+        .query(&database_name, &table_name, &filter)
+        .await?;
+    debug!("query since: {}, events: {}", filter.since, events.len());
+    let mut response = page(
+        appdata,
+        (&database_name, &table_name),
+        req,
+        &events,
+        (filter.after_id, filter.limit),
+    )
+    .await?;
+    Ok(response.json(events))
+}
+
+// Stats query string options
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatsQuery {
+    /// The length of a bucket, 1h by default
+    #[serde(default, with = "humantime_serde")]
+    #[param(value_type = Option<String>, example = "1h")]
+    bucket: Option<std::time::Duration>,
+    /// RFC 3339 or a duration before now, 24h by default
+    since: Option<String>,
+    /// RFC 3339 or a duration before now, now by default
+    until: Option<String>,
+    /// The JSON path of a number summarized by bucket
+    #[param(value_type = Option<String>, example = "$.ms")]
+    field: Option<JsonPath>,
+}
+
+/// Count the rows of a database table by time bucket, with min/max/avg of a numeric field
+/// GET /<database name>/<table name>/stats?bucket=<duration>&since=<time>&until=<time>&field=<JSON path>
+/// curl -i 'http://localhost:8888/database/test/stats?bucket=1h&since=24h&field=$.ms'
+///
+/// Times are RFC 3339 (2024-09-03T14:00:00Z) or a duration before now (24h).
+/// The defaults are 1h buckets over the last 24h.
+#[utoipa::path(
+    tag = "data",
+    params(
+        StatsQuery,
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("X-Consistency-Token" = Option<String>, Header, description = "Wait until the write answered with this token is visible"),
+    ),
+    responses(
+        (status = 200, description = "The buckets holding rows", body = Vec<StatsBucket>),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/{database_name}/{table_name}/stats")]
+async fn read_stats(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    query: web::Query<StatsQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // /{database_name <--- path.0}/{table_name <--- path.1}/stats
+    let (database_name, table_name) = path.into_inner();
+    check_table(&appdata, &database_name, &table_name)?;
+    consistency::wait_visible(
+        appdata.storage.as_ref(),
+        appdata.write_queue.as_ref(),
+        &req,
+        &database_name,
+        &table_name,
+    )
+    .await?;
+
+    let now = Utc::now();
+    let time = |value: &Option<String>, default: &str| {
+        storage::parse_time(value.as_deref().unwrap_or(default), now).map_err(Error::BadRequest)
+    };
+    let filter = StatsFilter {
+        bucket: query.bucket.map_or(3600, |bucket| bucket.as_secs() as i64),
+        since: time(&query.since, "24h")?,
+        until: time(&query.until, "0s")?,
+        field: query.field.clone(),
+    };
+    filter.validate().map_err(Error::BadRequest)?;
+
+    let buckets = appdata
+        .storage
+        .stats(&database_name, &table_name, &filter)
+        .await?;
+    Ok(HttpResponse::Ok().json(buckets))
+}
+
+// Export query string options
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportQuery {
+    format: Option<export::Format>,
+    /// Only the rows with a greater `seq`
+    since: Option<i64>,
+    /// The keys of the documents exported as CSV columns, comma separated
+    columns: Option<String>,
+    compression: Option<Compression>,
+    /// End with a line counting the rows and the bytes before it
+    summary: Option<bool>,
+}
+
+/// Stream the rows of a database table as NDJSON or CSV in sequence order
+/// GET /<database name>/<table name>/export?format=<ndjson|csv>&since=<seq>&columns=<key,key>&compression=<none|gzip|zstd|lz4>&summary=<true|false>
+/// curl -o test.csv 'http://localhost:8888/database/test/export?format=csv'
+/// curl -o test.csv.zst 'http://localhost:8888/database/test/export?format=csv&compression=zstd'
+/// curl -o test.ndjson 'http://localhost:8888/database/test/export?summary=true'
+///
+/// CSV exports have the columns seq, id and timestamp followed by the
+/// top-level keys of the documents, either the `columns` asked for or the
+/// keys found in the first page of rows.
+///
+/// A `compression` (or the configured export compression) exports a
+/// compressed file, otherwise the response is compressed with the best
+/// codec the `Accept-Encoding` of the request names.
+///
+/// With `summary=true` the export ends with a line holding the count of rows
+/// and the length and CRC-32 of what came before it, see export.rs.
+#[utoipa::path(
+    tag = "data",
+    params(
+        ExportQuery,
+        ("database_name" = String, Path, description = "The database"),
+        ("table_name" = String, Path, description = "The table"),
+        ("X-Consistency-Token" = Option<String>, Header, description = "Wait until the write answered with this token is visible"),
+    ),
+    responses(
+        (status = 200, description = "The rows as a file", content(
+            (String = "application/x-ndjson"),
+            (String = "text/csv"),
+        )),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/{database_name}/{table_name}/export")]
+async fn export_data(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<(String, String)>, // Provide access to the URI path elements
+    query: web::Query<ExportQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // /{database_name <--- path.0}/{table_name <--- path.1}/export
+    let (database_name, table_name) = path.into_inner();
+    check_table(&appdata, &database_name, &table_name)?;
+    consistency::wait_visible(
+        appdata.storage.as_ref(),
+        appdata.write_queue.as_ref(),
+        &req,
+        &database_name,
+        &table_name,
+    )
+    .await?;
+
+    // The first page is read up front so a missing table is answered with a 404
+    let filter = ChangesFilter {
+        since: query.since.unwrap_or(0),
+        after_id: None,
+        limit: export::PAGE_SIZE,
+        key: None,
+    };
+    let first_page = appdata
+        .storage
+        .changes(&database_name, &table_name, &filter)
+        .await?;
+
+    let format = query.format.unwrap_or_default();
+    let columns = query.columns.as_ref().map(|columns| {
+        columns
+            .split(',')
+            .map(|column| column.trim().to_string())
+            .collect()
+    });
+    let filename = format!("{table_name}.{}", format.extension());
+    let body = export::stream(
+        export::Export {
+            storage: appdata.storage.clone(),
+            database: database_name,
+            table: table_name,
+            writer: export::Writer::new(format, columns),
+        },
+        first_page,
+    );
+    let body = export::summarize(body, query.summary.unwrap_or(false).then_some(format));
+    export_response(
+        &appdata,
+        &req,
+        query.compression,
+        filename,
+        format.content_type(),
+        body,
+    )
+}
+
+// Snapshot export query string options
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SnapshotQuery {
+    /// The tables exported, comma separated, every table by default
+    tables: Option<String>,
+    compression: Option<Compression>,
+    /// End with a line counting the rows and the bytes before it
+    summary: Option<bool>,
+}
+
+/// Stream the rows of several tables of a database, as of a single point in
+/// time, as NDJSON with the table of each row
+/// GET /<database name>/_export?tables=<table,table>&compression=<none|gzip|zstd|lz4>&summary=<true|false>
+/// curl -o site.ndjson 'http://localhost:8888/site/_export?tables=readings,devices'
+///
+/// Every table of the database is exported when no `tables` are given.
+#[utoipa::path(
+    tag = "data",
+    params(
+        SnapshotQuery,
+        ("database_name" = String, Path, description = "The database"),
+    ),
+    responses(
+        (status = 200, description = "The rows as a file, each with its table", content_type = "application/x-ndjson"),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/{database_name}/_export")]
+async fn export_snapshot(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<String>,     // Provide access to the URI path elements
+    query: web::Query<SnapshotQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the request headers
+) -> Result<HttpResponse, Error> {
+    // /{database_name <--- path}/_export
+    let database_name = path.into_inner();
+    storage::validate_name(&database_name)?;
+    let tables = match &query.tables {
+        Some(tables) => tables
+            .split(',')
+            .map(|table| table.trim().to_string())
+            .collect(),
+        None => appdata.storage.tables(&database_name).await?,
+    };
+    for table_name in &tables {
+        check_table(&appdata, &database_name, table_name)?;
+    }
+
+    // The first page is read up front so a missing table is answered with a 404
+    let mut pages = appdata
+        .storage
+        .snapshot(&database_name, &tables, export::PAGE_SIZE);
+    let first_page = pages.next().await.transpose()?;
+    let body = export::snapshot(first_page, pages);
+    let summary = query.summary.unwrap_or(false);
+    let body = export::summarize(body, summary.then_some(export::Format::Ndjson));
+    export_response(
+        &appdata,
+        &req,
+        query.compression,
+        format!("{database_name}.ndjson"),
+        export::Format::Ndjson.content_type(),
+        body,
+    )
+}
+
+// Answer an export as a file, compressed when the request or the
+// configuration asks for a compressed file, or else with the best
+// Content-Encoding the request accepts
+fn export_response(
+    appdata: &AppData,
+    req: &HttpRequest,
+    compression: Option<Compression>,
+    mut filename: String,
+    content_type: &str,
+    body: impl Stream<Item = Result<web::Bytes, storage::Error>> + 'static,
+) -> Result<HttpResponse, Error> {
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type);
+    let compression = compression.unwrap_or(appdata.export_compression);
+    let codec = match compression.codec() {
+        Some(codec) => {
+            filename = format!("{filename}.{}", codec.extension());
+            response.content_type(codec.content_type());
+            Some(codec)
+        }
+        None => {
+            let codec = Compression::accepted(req.headers());
+            if let Some(codec) = codec {
+                response.insert_header((header::CONTENT_ENCODING, codec.encoding()));
+            }
+            codec
+        }
+    };
+    response
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ))
+        .insert_header((header::VARY, "Accept-Encoding"));
+
+    let Some(codec) = codec else {
+        return Ok(response.streaming(body));
+    };
+    let encoder = codec
+        .encoder()
+        .map_err(|err| Error::Internal(format!("unable to compress the export: {err}")))?;
+    Ok(response.streaming(export::compress(body, encoder)))
+}
+
+// Database summary response structure
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct DatabaseSummary {
+    name: String,
+    size_bytes: u64,
+    tables: usize,
+}
+
+/// List the databases with their size and number of tables
+/// GET /_admin/databases
+/// curl -i http://localhost:8888/_admin/databases
+///
+/// The size of a SQLite database is the size of its file.
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "The databases", body = Vec<DatabaseSummary>),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/_admin/databases")]
+async fn admin_databases(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+) -> Result<HttpResponse, Error> {
+    let mut summaries = vec![];
+    for name in appdata.storage.databases().await? {
+        let summary = async {
+            Ok::<_, storage::Error>(DatabaseSummary {
+                size_bytes: appdata.storage.size(&name).await?,
+                tables: appdata.storage.tables(&name).await?.len(),
+                name: name.clone(),
+            })
+        };
+        match summary.await {
+            Ok(summary) => summaries.push(summary),
+            // Removed since it was listed
+            Err(storage::Error::NotFound(_)) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+// Table summary response structure
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct TableSummary {
+    name: String,
+    rows: i64,
+}
+
+/// List the tables of a database with their row counts
+/// GET /_admin/databases/<database name>/tables
+/// curl -i http://localhost:8888/_admin/databases/database/tables
+#[utoipa::path(
+    tag = "admin",
+    params(
+        ("database_name" = String, Path, description = "The database"),
+    ),
+    responses(
+        (status = 200, description = "The tables of the database", body = Vec<TableSummary>),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/_admin/databases/{database_name}/tables")]
+async fn admin_tables(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<String>,     // Provide access to the URI path elements
+) -> Result<HttpResponse, Error> {
+    // /_admin/databases/{database_name <--- path}/tables
+    let database_name = path.into_inner();
+    storage::validate_name(&database_name)?;
+
+    let mut summaries = vec![];
+    for name in appdata.storage.tables(&database_name).await? {
+        let rows = appdata.storage.row_count(&database_name, &name).await?;
+        summaries.push(TableSummary { name, rows });
+    }
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+/// Write a consistent copy of a SQLite database to the backup directory
+/// POST /_admin/databases/<database name>/backup
+/// curl -i -X POST http://localhost:8888/_admin/databases/database/backup
+///
+/// The database carries on taking writes while it is copied. Only the last
+/// `keep` backups of the database are kept, see backup.rs.
+#[utoipa::path(
+    tag = "admin",
+    params(
+        ("database_name" = String, Path, description = "The database"),
+    ),
+    responses(
+        (status = 201, description = "The backup written", body = BackupFile),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[post("/_admin/databases/{database_name}/backup")]
+async fn admin_backup(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    path: web::Path<String>,     // Provide access to the URI path elements
+) -> Result<HttpResponse, Error> {
+    // /_admin/databases/{database_name <--- path}/backup
+    let database_name = path.into_inner();
+    let settings = appdata.backups.as_ref().ok_or_else(|| {
+        Error::BadRequest(String::from(
+            "no backup directory is configured, see --backup-directory",
+        ))
+    })?;
+    let backup = backup::backup(appdata.storage.as_ref(), settings, &database_name).await?;
+    Ok(HttpResponse::Created().json(backup))
+}
+
+/// Show the log filter directives in use, for a debug key
+/// GET /_admin/log-filter
+/// curl -i -H 'X-API-Key: ...' http://localhost:8888/_admin/log-filter
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "The directives", body = String, content_type = "text/plain"),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/_admin/log-filter")]
+async fn read_log_filter(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    log_filter: web::Data<LogFilter>, // Provide access to the log filter
+    req: HttpRequest,            // Provide access to the request details
+) -> Result<HttpResponse, Error> {
+    auth::authorize_debug(&req, &appdata.debug_keys, "the log filter")?;
+    let directives = log_filter.directives().map_err(Error::Internal)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(directives))
+}
+
+/// Change the log filter directives until the server restarts, for a debug key
+/// PUT /_admin/log-filter
+/// curl -i -X PUT -H 'X-API-Key: ...' -d 'warn,actix_data_receiver::storage=debug' http://localhost:8888/_admin/log-filter
+#[utoipa::path(
+    tag = "admin",
+    request_body(content = String, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "The directives now in use", body = String, content_type = "text/plain"),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[put("/_admin/log-filter")]
+async fn update_log_filter(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    log_filter: web::Data<LogFilter>, // Provide access to the log filter
+    req: HttpRequest,            // Provide access to the request details
+    body: String,                // Provide access to the request body
+) -> Result<HttpResponse, Error> {
+    auth::authorize_debug(&req, &appdata.debug_keys, "the log filter")?;
+    log_filter.set(&body).map_err(Error::BadRequest)?;
+    let directives = log_filter.directives().map_err(Error::Internal)?;
+    warn!("log filter changed to {directives}");
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(directives))
+}
+
+// Profile query string options
+#[cfg(feature = "pprof")]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ProfileQuery {
+    /// How long to profile for, 30 by default
+    seconds: Option<u64>,
+    /// CPU samples a second
+    frequency: Option<i32>,
+    format: Option<profiling::ProfileFormat>,
+}
+
+/// Profile the CPU for some seconds, as pprof or as a flamegraph SVG
+/// GET /debug/pprof/profile?seconds=<seconds>&frequency=<hz>&format=<pprof|flamegraph>
+/// curl -o cpu.pb -H 'X-API-Key: ...' 'http://localhost:8888/debug/pprof/profile?seconds=30'
+/// go tool pprof -http :8080 cpu.pb
+#[cfg(feature = "pprof")]
+#[utoipa::path(
+    tag = "debug",
+    params(ProfileQuery),
+    responses(
+        (status = 200, description = "The profile", content(
+            (Vec<u8> = "application/octet-stream"),
+            (String = "image/svg+xml"),
+        )),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/debug/pprof/profile")]
+async fn cpu_profile(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    query: web::Query<ProfileQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the API key of the request
+) -> Result<HttpResponse, Error> {
+    auth::authorize_debug(&req, &appdata.debug_keys, "a profile")?;
+    let duration = profiling::duration(query.seconds)?;
+    let frequency = query.frequency.unwrap_or(profiling::DEFAULT_FREQUENCY);
+    let format = query.format.unwrap_or_default();
+    info!("Profiling the CPU for {duration:?}");
+    let body = profiling::cpu(duration, frequency, format).await?;
+    Ok(profile_response("cpu", format, body))
+}
+
+/// Profile the heap allocations made during some seconds, as pprof or as a
+/// flamegraph SVG
+/// GET /debug/pprof/heap?seconds=<seconds>&format=<pprof|flamegraph>
+/// curl -o heap.svg -H 'X-API-Key: ...' 'http://localhost:8888/debug/pprof/heap?format=flamegraph'
+#[cfg(feature = "pprof")]
+#[utoipa::path(
+    tag = "debug",
+    params(ProfileQuery),
+    responses(
+        (status = 200, description = "The profile", content(
+            (Vec<u8> = "application/octet-stream"),
+            (String = "image/svg+xml"),
+        )),
+        (status = "4XX", description = "Refused, see the code of the error", body = ErrorResponse),
+        (status = "5XX", description = "Failed, 503 is worth retrying", body = ErrorResponse),
+    ),
+)]
+#[get("/debug/pprof/heap")]
+async fn heap_profile(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    query: web::Query<ProfileQuery>, // Provide access to the query string
+    req: HttpRequest,            // Provide access to the API key of the request
+) -> Result<HttpResponse, Error> {
+    auth::authorize_debug(&req, &appdata.debug_keys, "a profile")?;
+    let duration = profiling::duration(query.seconds)?;
+    let format = query.format.unwrap_or_default();
+    info!("Profiling the heap for {duration:?}");
+    let body = profiling::heap(duration, format).await?;
+    Ok(profile_response("heap", format, body))
+}
+
+// Answer a profile as a file named after it
+#[cfg(feature = "pprof")]
+fn profile_response(name: &str, format: profiling::ProfileFormat, body: Vec<u8>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}.{}\"", format.extension()),
+        ))
+        .body(body)
+}
+
+/// Forward any request to the upstream and store a copy of it (proxy mode)
+/// curl -i -X POST -d '{"id": 7}' http://localhost:8888/v1/orders
+async fn proxy_data(
+    appdata: web::Data<AppData>, // Provide access to options entered on the CLI
+    proxy: web::Data<Proxy>,     // Provide access to the upstream
+    req: HttpRequest,            // Provide access to the request details
+    payload: web::Payload,       // Provide access to the request body
+) -> Result<HttpResponse, Error> {
+    // The body is forwarded as it was sent
+    let (body, mut reservation) = decompress::read_raw(
+        req.headers(),
+        payload,
+        appdata.body_limits,
+        &appdata.memory_budget,
+    )
+    .await?;
+    let result = proxy.forward(&req, body.clone()).await;
+
+    // The copy is stored decompressed, or as sent when it can't be
+    let limit = appdata.body_limits.max_decompressed_size;
+    let body = decompress::Encoding::from_headers(req.headers())
+        .and_then(|encoding| decompress::decompress(body.clone(), encoding, limit))
+        .and_then(|decompressed| {
+            reservation.grow(decompressed.len())?;
+            Ok(decompressed)
+        })
+        .unwrap_or(body);
+
+    // The response of the upstream is returned even when storing fails
+    let status = result
+        .as_ref()
+        .ok()
+        .map(|response| response.status().as_u16());
+    let document = proxy::document(&req, &body, status);
+    let api_key_name = req.extensions().get::<ApiKeyName>().cloned();
+    if let Err(err) = ingest(
+        &appdata,
+        &proxy.database,
+        &proxy.table,
+        vec![document],
+        api_key_name.as_ref().map(|name| name.0.as_str()),
+        None,
+        &appdata.record_metadata(&req),
+    )
+    .await
+    {
+        error!("unable to store the proxied request: {err}");
+    }
+    result
+}
+
+// Pong response structure
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct PongResponse {
+    ping: String,
+}
+
+// Ping/Pong response handler
+#[utoipa::path(
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "Pong", body = PongResponse)),
+)]
+#[get("/ping")]
+async fn ping() -> Result<impl Responder> {
+    // Respond with a pong response as a sanity check
+    let result = PongResponse {
+        ping: String::from("pong"),
+    };
+
+    // Format the result into JSON
+    // https://docs.rs/actix-web/latest/actix_web/web/struct.Json.html
+    Ok(web::Json(result))
+}
+
+// The longest the storage checks of a readiness probe can take
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Health response structure
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct HealthResponse {
+    status: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<HealthCheck>,
+}
+
+// Liveness handler, the process is up and answering
+// curl -i http://localhost:8888/healthz/live
+#[utoipa::path(
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "Alive", body = HealthResponse)),
+)]
+#[get("/healthz/live")]
+async fn healthz_live() -> HttpResponse {
+    HttpResponse::Ok().json(HealthResponse {
+        status: String::from("alive"),
+        checks: vec![],
+    })
+}
+
+// Readiness handler for load balancers, not ready while the storage can't
+// take writes or once the server is stopping
+// curl -i http://localhost:8888/healthz/ready
+#[utoipa::path(
+    get,
+    path = "/healthz/ready",
+    tag = "health",
+    security(()),
+    responses(
+        (status = 200, description = "Ready, also answered on /healthz", body = HealthResponse),
+        (status = 503, description = "Degraded or draining", body = HealthResponse),
+    ),
+)]
+#[routes]
+#[get("/healthz")]
+#[get("/healthz/ready")]
+async fn healthz(
+    // Provide access to the storage backend
+    appdata: web::Data<AppData>,
+    // Provide access to the readiness of the server
+    readiness: web::Data<Readiness>,
+) -> HttpResponse {
+    if !readiness.is_ready() {
+        return HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: String::from("draining"),
+            checks: vec![],
+        });
+    }
+
+    let checks = rt::time::timeout(HEALTH_CHECK_TIMEOUT, appdata.storage.health())
+        .await
+        .unwrap_or_else(|_| vec![HealthCheck::new("storage", Err("timed out"))]);
+    if checks.iter().all(|check| check.healthy) {
+        HttpResponse::Ok().json(HealthResponse {
+            status: String::from("ready"),
+            checks,
+        })
+    } else {
+        warn!("not ready: {checks:?}");
+        HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: String::from("degraded"),
+            checks,
+        })
+    }
+}
+
+// The OpenAPI document of the endpoints, the profiles are added by `api_doc`
+// when the build has them
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        create_data,
+        create_default_data,
+        read_changes,
+        query_data,
+        query_data_filter,
+        read_stats,
+        export_data,
+        export_snapshot,
+        admin_databases,
+        admin_tables,
+        admin_backup,
+        read_log_filter,
+        update_log_filter,
+        ping,
+        healthz_live,
+        healthz,
+    ),
+    info(description = "Saving JSON documents into the tables of SQLite or PostgreSQL databases"),
+    modifiers(&ApiKeySecurity),
+    security(("api_key" = []), ("bearer" = [])),
+    tags(
+        (name = "data", description = "Writing and reading the documents of a table"),
+        (name = "admin", description = "Databases, backups and logging, served with API keys, logging for a key listed in auth.debug_keys"),
+        (name = "debug", description = "Profiles, for an API key listed in auth.debug_keys"),
+        (name = "health", description = "Probes answered without an API key"),
+    ),
+)]
+struct ApiDoc;
+
+#[cfg(feature = "pprof")]
+#[derive(OpenApi)]
+#[openapi(paths(cpu_profile, heap_profile))]
+struct ProfilingApiDoc;
+
+// Keys are sent as `X-API-Key: <key>` or `Authorization: Bearer <key>`
+struct ApiKeySecurity;
+
+impl Modify for ApiKeySecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// The OpenAPI document of the endpoints of this build
+pub fn api_doc() -> utoipa::openapi::OpenApi {
+    let mut doc = ApiDoc::openapi();
+    // The package has no license to name
+    doc.info.license = None;
+    #[cfg(feature = "pprof")]
+    doc.merge(ProfilingApiDoc::openapi());
+    doc
+}
+
+/// The OpenAPI 3 document of the API, to generate clients from
+/// GET /openapi.json
+/// curl -o openapi.json http://localhost:8888/openapi.json
+#[get("/openapi.json")]
+async fn openapi_json() -> HttpResponse {
+    static DOC: LazyLock<utoipa::openapi::OpenApi> = LazyLock::new(api_doc);
+    HttpResponse::Ok().json(&*DOC)
+}
+
+// Application data passed to endpoints
+pub(crate) struct AppData {
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) default_database: Option<String>,
+    table_templates: Vec<TableTemplate>,
+    split_rules: Vec<SplitRule>,
+    flatten_rules: Vec<FlattenRule>,
+    computed_fields: Vec<ComputedField>,
+    maps: Maps,
+    enrich_rules: Vec<EnrichRule>,
+    pub(crate) lookups: Lookups,
+    schemas: Vec<TableSchema>,
+    ordering_key: Option<JsonPath>,
+    ordering_locks: OrderingLocks,
+    access: AccessRules,
+    debug_keys: Vec<String>,
+    body_limits: BodyLimits,
+    pub(crate) memory_budget: MemoryBudget,
+    pub(crate) write_queue: Option<WriteQueue>,
+    pub(crate) forwarder: Option<Forwarder>,
+    export_compression: Compression,
+    backups: Option<BackupSettings>,
+    // Where rows came from is stored when set, read through these proxies
+    pub(crate) record_metadata: Option<TrustedProxies>,
+}
+
+impl AppData {
+    // The application data described by the configuration
+    pub(crate) fn new(storage: Arc<dyn Storage>, config: &Config) -> Result<Self, String> {
+        // A default database lets senders leave the database out of the path
+        let default_database = config.server.default_database.clone();
+        if let Some(database_name) = &default_database {
+            storage::validate_name(database_name)
+                .map_err(|err| format!("invalid default database: {err}"))?;
+        }
+
+        Ok(AppData {
+            storage,
+            default_database,
+            table_templates: config.table_templates()?,
+            split_rules: config.split_rules()?,
+            flatten_rules: config.flatten_rules(),
+            computed_fields: config.computed_fields()?,
+            maps: config.maps.clone(),
+            enrich_rules: config.enrich_rules()?,
+            lookups: Lookups::load(config.lookup_sources())?,
+            schemas: config.table_schemas()?,
+            ordering_key: config.ordering_key()?,
+            ordering_locks: OrderingLocks::default(),
+            access: config.access_rules(),
+            debug_keys: config.auth.debug_keys.clone(),
+            body_limits: config.server.body_limits(),
+            memory_budget: MemoryBudget::new(config.server.memory_budget),
+            write_queue: None,
+            forwarder: None,
+            export_compression: config.export.compression,
+            backups: config.backup.as_ref().map(BackupConfig::settings),
+            record_metadata: match config.server.record_metadata {
+                true => Some(TrustedProxies::parse(&config.server.trusted_proxies)?),
+                false => None,
+            },
+        })
+    }
+
+    // Where the rows written by a request came from, when it is recorded
+    fn record_metadata(&self, req: &HttpRequest) -> RecordMetadata {
+        match &self.record_metadata {
+            Some(trusted_proxies) => audit::record_metadata(req, trusted_proxies),
+            None => RecordMetadata::default(),
+        }
+    }
+}
+
+impl Default for AppData {
+    fn default() -> Self {
+        AppData {
+            storage: Arc::new(SqliteStorage::new("./")),
+            default_database: None,
+            table_templates: vec![],
+            split_rules: vec![],
+            flatten_rules: vec![],
+            computed_fields: vec![],
+            maps: Maps::new(),
+            enrich_rules: vec![],
+            lookups: Lookups::default(),
+            schemas: vec![],
+            ordering_key: None,
+            ordering_locks: OrderingLocks::default(),
+            access: AccessRules::default(),
+            debug_keys: vec![],
+            body_limits: ServerConfig::default().body_limits(),
+            memory_budget: MemoryBudget::default(),
+            write_queue: None,
+            forwarder: None,
+            export_compression: Compression::None,
+            backups: None,
+            record_metadata: None,
+        }
+    }
+}
+
+/// Which endpoints of the data API a server answers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoints {
+    /// Every endpoint
+    All,
+    /// The writes and the admin endpoints
+    Write,
+    /// The changes, query, stats and export endpoints
+    Read,
+}
+
+// Register the endpoints a server answers, the data API is left to the proxy
+// in proxy mode
+// The admin endpoints need API keys, the log filter ones a filter to change
+pub(crate) fn configure(
+    cfg: &mut web::ServiceConfig,
+    endpoints: Endpoints,
+    proxy: bool,
+    api_keys: bool,
+    log_filter: bool,
+) {
+    cfg.service(ping).service(healthz).service(healthz_live);
+    // Before the data API, whose paths would match them too
+    if endpoints != Endpoints::Read && api_keys {
+        admin_api(cfg, log_filter);
+    }
+    if proxy {
+        cfg.default_service(web::to(proxy_data));
+    } else {
+        cfg.service(openapi_json);
+        #[cfg(feature = "swagger-ui")]
+        cfg.service(
+            SwaggerUi::new("/_docs/{_:.*}")
+                .config(utoipa_swagger_ui::Config::from("/openapi.json")),
+        );
+        data_api(cfg, endpoints);
+    }
+}
+
+// Register the admin endpoints, answered along with the writes
+fn admin_api(cfg: &mut web::ServiceConfig, log_filter: bool) {
+    cfg.service(admin_databases)
+        .service(admin_tables)
+        .service(admin_backup);
+    if log_filter {
+        cfg.service(read_log_filter).service(update_log_filter);
+    }
+    #[cfg(feature = "pprof")]
+    cfg.service(cpu_profile).service(heap_profile);
+}
+
+// Register the endpoints of the data API
+fn data_api(cfg: &mut web::ServiceConfig, endpoints: Endpoints) {
+    if endpoints != Endpoints::Read {
+        cfg.service(create_data).service(create_default_data);
+    }
+    if endpoints != Endpoints::Write {
+        cfg.service(read_changes)
+            .service(query_data)
+            .service(query_data_filter)
+            .service(read_stats)
+            .service(export_data)
+            .service(export_snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App, HttpServer};
+
+    use crate::rate_limit::{self, RateLimiter};
+    use crate::storage::record;
+    use crate::{auth, config, errors};
+
+    #[actix_web::test]
+    async fn test_create_data() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+
+        // Send a request to the `client_address` endpoint
+        // curl -i -X PUT -d '{"curl test": true}' http://localhost:8888/test/test
+        //let timestamp: DateTime<Utc> = Utc::now();
+        //let data = format!("{{'actix test': true, 'timestamp': {timestamp}}}");
+        let data = "{\"actix test\": true, \"timestamp\": \"timestamp\"}";
+        let req = test::TestRequest::put()
+            .uri("/test/test")
+            .set_payload(data.as_bytes())
+            .to_request();
+
+        // Send the request and parse the response as JSON
+        let response = test::call_service(&app, req).await;
+
+        // Assert the response is a 201 Created
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Post test, remove any database files created
+    }
+
+    #[actix_web::test]
+    async fn test_create_data_invalid() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    body_limits: BodyLimits {
+                        max_body_size: 64,
+                        max_decompressed_size: 64,
+                    },
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+
+        // Single quoted strings are not JSON
+        let data = "{'actix test': true, 'timestamp': 'timestamp'}";
+        let req = test::TestRequest::put()
+            .uri("/test/test")
+            .set_payload(data.as_bytes())
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result: errors::ErrorResponse = test::read_body_json(response).await;
+        assert!(result.error.starts_with("request body is not valid JSON"));
+
+        // Bodies larger than the limit are refused
+        let data = format!("{{\"padding\": \"{}\"}}", "x".repeat(64));
+        let req = test::TestRequest::put()
+            .uri("/test/test")
+            .set_payload(data)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[cfg(all(feature = "msgpack", feature = "cbor"))]
+    #[actix_web::test]
+    async fn test_transcoded_body() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // MessagePack and CBOR are stored as JSON
+        let document = serde_json::json!({"device": "d1", "temperature": 21.5});
+        let mut cbor = vec![];
+        ciborium::into_writer(&document, &mut cbor).unwrap();
+        let bodies = [
+            (
+                "application/msgpack",
+                rmp_serde::to_vec_named(&document).unwrap(),
+            ),
+            ("application/cbor", cbor),
+        ];
+        for (content_type, body) in bodies {
+            let req = test::TestRequest::put()
+                .uri("/test_transcoded/readings")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{content_type}");
+        }
+        let req = test::TestRequest::get()
+            .uri("/test_transcoded/readings/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|event| event.data == document));
+
+        // A body which isn't what it is said to be is refused
+        let req = test::TestRequest::put()
+            .uri("/test_transcoded/readings")
+            .insert_header(("Content-Type", "application/cbor"))
+            .set_payload("{\"device\": \"d1\"}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Other content types are not supported
+        let req = test::TestRequest::put()
+            .uri("/test_transcoded/readings")
+            .insert_header(("Content-Type", "application/xml"))
+            .set_payload("<device>d1</device>")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_transcoded.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_ingestion_metrics() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    split_rules: vec!["readings=$.readings[]".parse().unwrap()],
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+        let rows = metrics::ROWS_INSERTED.with_label_values(&["test_metrics", "readings"]);
+        let invalid =
+            metrics::VALIDATION_FAILURES.with_label_values(&["test_metrics", "readings", "json"]);
+        let inserts = metrics::INSERT_SECONDS.with_label_values(&["test_metrics"]);
+        let payloads = metrics::PAYLOAD_BYTES.get_sample_count();
+
+        // A batch of two rows is counted as two rows and one insert
+        let req = test::TestRequest::put()
+            .uri("/test_metrics/readings")
+            .set_payload("{\"readings\": [{\"count\": 1}, {\"count\": 2}]}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(rows.get(), 2);
+        assert_eq!(inserts.get_sample_count(), 1);
+
+        // A body which is not JSON is counted as a validation failure
+        let req = test::TestRequest::put()
+            .uri("/test_metrics/readings")
+            .set_payload("{'count': 3}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(invalid.get(), 1);
+        assert_eq!(rows.get(), 2);
+        assert!(metrics::PAYLOAD_BYTES.get_sample_count() >= payloads + 2);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_metrics.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_read_changes() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // Create a few rows to read back
+        for count in 0..3 {
+            let req = test::TestRequest::put()
+                .uri("/test_changes/events")
+                .set_payload(format!("{{\"count\": {count}}}"))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        // Read the changes as JSON
+        let req = test::TestRequest::get()
+            .uri("/test_changes/events/changes?since=1")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+
+        // Assert the sequence numbers are gapless and in order
+        let seqs: Vec<i64> = result.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        assert_eq!(result[0].data["count"], 1);
+
+        // Read the changes as Server-Sent Events
+        let req = test::TestRequest::get()
+            .uri("/test_changes/events/changes")
+            .insert_header(("Accept", "text/event-stream"))
+            .insert_header(("Last-Event-ID", "2"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = str::from_utf8(&body).unwrap();
+        assert!(body.starts_with("id: 3\nevent: insert\n"));
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_changes.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_keyset_pagination() {
+        let storage = SqliteStorage::new("./");
+        let records = (0..5)
+            .map(|count| record(&format!("{{\"count\": {count}}}")))
+            .collect();
+        storage
+            .insert_batch("test_pagination", "events", records)
+            .await
+            .unwrap();
+
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(storage),
+                    ..Default::default()
+                }))
+                .service(read_changes)
+                .service(query_data)
+                .service(query_data_filter),
+        )
+        .await;
+
+        // Follow the links to the next page until the last page
+        for first in [
+            "/test_pagination/events/changes?after_id=0&limit=2",
+            "/test_pagination/events/query?path=$.count&ge=1&limit=2",
+        ] {
+            let (mut uri, mut pages, mut ids) = (Some(first.to_string()), vec![], vec![]);
+            while let Some(next) = uri.take() {
+                let req = test::TestRequest::get().uri(&next).to_request();
+                let response = test::call_service(&app, req).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers().get("X-Total-Count").unwrap(), "5");
+                uri = response.headers().get(header::LINK).map(|link| {
+                    let link = link.to_str().unwrap();
+                    assert!(link.ends_with(">; rel=\"next\""));
+                    link[1..link.find('>').unwrap()].to_string()
+                });
+                pages.push(next);
+                let events: Vec<ChangeEvent> = test::read_body_json(response).await;
+                ids.extend(events.iter().map(|event| event.id));
+            }
+            if first.contains("after_id") {
+                assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+                assert_eq!(
+                    pages[1],
+                    "/test_pagination/events/changes?after_id=2&limit=2"
+                );
+            } else {
+                // The condition is kept, the pages carry on by seq
+                assert_eq!(ids, vec![2, 3, 4, 5]);
+                assert_eq!(
+                    pages[1],
+                    "/test_pagination/events/query?path=$.count&ge=1&since=3&limit=2"
+                );
+            }
+            // The last page is the one which isn't full
+            assert_eq!(pages.len(), 3);
+        }
+
+        // A POST query pages by the id in its body, without a link
+        let req = test::TestRequest::post()
+            .uri("/test_pagination/events/query")
+            .set_payload(r#"{"after_id": 3, "limit": 2}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert!(response.headers().get(header::LINK).is_none());
+        let events: Vec<ChangeEvent> = test::read_body_json(response).await;
+        let ids: Vec<i64> = events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![4, 5]);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_pagination.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_export_data() {
+        // More rows than fit in a page
+        let storage = SqliteStorage::new("./");
+        let rows = export::PAGE_SIZE + 5;
+        let records = (0..rows)
+            .map(|count| record(&format!("{{\"count\": {count}, \"note\": \"a, b\"}}")))
+            .collect();
+        storage
+            .insert_batch("test_export", "events", records)
+            .await
+            .unwrap();
+
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(storage),
+                    ..Default::default()
+                }))
+                .app_data(web::QueryConfig::default().error_handler(errors::bad_request))
+                .service(export_data),
+        )
+        .await;
+
+        // curl 'http://localhost:8888/test_export/events/export'
+        let req = test::TestRequest::get()
+            .uri("/test_export/events/export")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let lines: Vec<&str> = str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len() as i64, rows);
+        let event: ChangeEvent = serde_json::from_str(lines[lines.len() - 1]).unwrap();
+        assert_eq!(event.seq, rows);
+        assert_eq!(event.data["count"], rows - 1);
+
+        // curl 'http://localhost:8888/test_export/events/export?format=csv&since=1000'
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/test_export/events/export?format=csv&since={}",
+                export::PAGE_SIZE
+            ))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let lines: Vec<&str> = str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "seq,id,timestamp,count,note");
+        assert!(lines[5].ends_with(&format!(",{},\"a, b\"", rows - 1)));
+
+        // curl -o events.ndjson.zst 'http://localhost:8888/test_export/events/export?compression=zstd'
+        let req = test::TestRequest::get()
+            .uri("/test_export/events/export?compression=zstd")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        #[cfg(feature = "zstd")]
+        {
+            let disposition = response.headers().get(header::CONTENT_DISPOSITION).unwrap();
+            assert!(disposition
+                .to_str()
+                .unwrap()
+                .ends_with("events.ndjson.zst\""));
+            let body = test::read_body(response).await;
+            let body = zstd::decode_all(&body[..]).unwrap();
+            assert_eq!(str::from_utf8(&body).unwrap().lines().count() as i64, rows);
+        }
+        // A codec left out of the build is not a compression
+        #[cfg(not(feature = "zstd"))]
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // curl --compressed 'http://localhost:8888/test_export/events/export'
+        let req = test::TestRequest::get()
+            .uri("/test_export/events/export?format=csv")
+            .insert_header((header::ACCEPT_ENCODING, "gzip, deflate, br"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        let body = test::read_body(response).await;
+        let mut csv = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut csv)
+            .unwrap();
+        assert_eq!(csv.lines().count() as i64, rows + 1);
+
+        // curl 'http://localhost:8888/test_export/events/export?summary=true'
+        let req = test::TestRequest::get()
+            .uri("/test_export/events/export?summary=true")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = str::from_utf8(&body).unwrap();
+        let (exported, last) = body.trim_end().rsplit_once('\n').unwrap();
+        let exported = &body.as_bytes()[..=exported.len()];
+        let summary: Value = serde_json::from_str(last).unwrap();
+        assert_eq!(summary["summary"]["rows"], rows);
+        assert_eq!(summary["summary"]["bytes"], exported.len());
+        assert_eq!(
+            summary["summary"]["crc32"],
+            format!("{:08x}", crc32fast::hash(exported))
+        );
+
+        // Unknown formats and tables
+        let req = test::TestRequest::get()
+            .uri("/test_export/events/export?format=xml")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::get()
+            .uri("/test_export/missing/export")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_export.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_export_snapshot() {
+        let storage = SqliteStorage::new("./");
+        for table in ["devices", "readings"] {
+            let records = (0..3)
+                .map(|count| record(&format!("{{\"count\": {count}}}")))
+                .collect();
+            storage
+                .insert_batch("test_export_snapshot", table, records)
+                .await
+                .unwrap();
+        }
+
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(storage),
+                    ..Default::default()
+                }))
+                .service(export_snapshot),
+        )
+        .await;
+
+        // curl 'http://localhost:8888/test_export_snapshot/_export?tables=readings,devices'
+        let req = test::TestRequest::get()
+            .uri("/test_export_snapshot/_export?tables=readings,devices")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let lines: Vec<Value> = str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0]["table"], "readings");
+        assert_eq!(lines[5]["table"], "devices");
+        assert_eq!(lines[5]["data"]["count"], 2);
+
+        // Every table without a list of tables
+        let req = test::TestRequest::get()
+            .uri("/test_export_snapshot/_export?compression=gzip")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let mut ndjson = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut ndjson)
+            .unwrap();
+        assert_eq!(ndjson.lines().count(), 6);
+
+        // A missing table is answered before any rows are sent
+        let req = test::TestRequest::get()
+            .uri("/test_export_snapshot/_export?tables=devices,missing")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_export_snapshot.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_error_responses() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData::default()))
+                .service(read_changes),
+        )
+        .await;
+
+        // Reserved table names are refused
+        let req = test::TestRequest::get()
+            .uri("/test/_sequences/changes")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result: errors::ErrorResponse = test::read_body_json(response).await;
+        assert_eq!(result.code, "invalid_name");
+
+        // Reading a database which doesn't exist
+        let req = test::TestRequest::get()
+            .uri("/test_missing/events/changes")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let result: errors::ErrorResponse = test::read_body_json(response).await;
+        assert_eq!(result.code, "not_found");
+    }
+
+    #[actix_web::test]
+    async fn test_create_default_data() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    default_database: Some(String::from("test_default")),
+                    ..Default::default()
+                }))
+                .service(create_default_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // curl -i -X PUT -d '{"curl test": true}' http://localhost:8888/events
+        let req = test::TestRequest::put()
+            .uri("/events")
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The row is found in the default database
+        let req = test::TestRequest::get()
+            .uri("/test_default/events/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_default.db").unwrap();
+
+        // Without a default database there is nowhere to put the data
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData::default()))
+                .service(create_default_data),
+        )
+        .await;
+        let req = test::TestRequest::put()
+            .uri("/events")
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_table_template() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    table_templates: vec!["events={table}_{yyyy}".parse().unwrap()],
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/test_template/events")
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The row lands in the table for the current year
+        let year = Utc::now().format("%Y");
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_template/events_{year}/changes"))
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_template.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_split_rule() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    split_rules: vec!["readings=$.readings[]".parse().unwrap()],
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        let data = "{\"gateway\": \"g1\", \"readings\": [{\"value\": 20}, {\"value\": 21}]}";
+        let req = test::TestRequest::put()
+            .uri("/test_split/readings")
+            .set_payload(data)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // One row per reading with the gateway copied in
+        let req = test::TestRequest::get()
+            .uri("/test_split/readings/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[1].data["gateway"], "g1");
+        assert_eq!(result[1].data["value"], 21);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_split.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_query_data() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(query_data)
+                .service(query_data_filter),
+        )
+        .await;
+
+        for data in [
+            "{\"level\": \"error\", \"ms\": 300, \"site name\": \"lab\"}",
+            "{\"level\": \"info\", \"ms\": 20}",
+            "{\"level\": \"error\", \"ms\": 12.5, \"code\": \"100\"}",
+        ] {
+            let req = test::TestRequest::put()
+                .uri("/test_query/logs")
+                .set_payload(data)
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        // A single condition in the query string
+        let req = test::TestRequest::get()
+            .uri("/test_query/logs/query?path=$.level&eq=error")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        let seqs: Vec<i64> = result.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![1, 3]);
+
+        let req = test::TestRequest::get()
+            .uri("/test_query/logs/query?path=$.code&eq=%22100%22")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+
+        // Every condition of the body has to match
+        let req = test::TestRequest::post()
+            .uri("/test_query/logs/query")
+            .set_payload(
+                "{\"where\": [{\"path\": \"$.level\", \"eq\": \"error\"}, {\"path\": \"$.ms\", \"gt\": 100}]}",
+            )
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data["ms"], 300);
+
+        let req = test::TestRequest::post()
+            .uri("/test_query/logs/query")
+            .set_payload("{\"where\": [{\"path\": \"$[\\\"site name\\\"]\", \"exists\": false}]}")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 2);
+
+        // A condition needs a comparison
+        let req = test::TestRequest::get()
+            .uri("/test_query/logs/query?path=$.level")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_query.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_read_stats() {
+        // Rows two hours ago and now
+        let storage = SqliteStorage::new("./");
+        let now = Utc::now();
+        let records = [(now - chrono::Duration::hours(2), 10), (now, 20), (now, 40)]
+            .into_iter()
+            .map(|(timestamp, ms)| NewRecord {
+                timestamp,
+                ..record(&format!("{{\"ms\": {ms}}}"))
+            })
+            .collect();
+        storage
+            .insert_batch("test_stats", "requests", records)
+            .await
+            .unwrap();
+
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(storage),
+                    ..Default::default()
+                }))
+                .service(read_stats),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/test_stats/requests/stats?bucket=1h&since=3h&field=$.ms")
+            .to_request();
+        let result: Vec<storage::StatsBucket> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].count, 1);
+        assert_eq!(result[1].count, 2);
+        assert_eq!(result[1].min, Some(20.0));
+        assert_eq!(result[1].avg, Some(30.0));
+
+        // Counts only without a field
+        let req = test::TestRequest::get()
+            .uri("/test_stats/requests/stats?bucket=1d")
+            .to_request();
+        let result: Vec<storage::StatsBucket> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.iter().map(|bucket| bucket.count).sum::<i64>(), 3);
+        assert_eq!(result[0].max, None);
+
+        // Too many buckets
+        let req = test::TestRequest::get()
+            .uri("/test_stats/requests/stats?bucket=1s&since=30d")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_stats.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_admin() {
+        // Two rows in one table
+        let storage = SqliteStorage::new("./");
+        let records = (0..2).map(|n| record(&format!("{{\"n\": {n}}}"))).collect();
+        storage
+            .insert_batch("test_admin", "events", records)
+            .await
+            .unwrap();
+
+        // Initialize the application
+        let backups = std::env::temp_dir().join("adr_test_admin_backups");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(storage),
+                    backups: Some(BackupSettings {
+                        directory: backups.clone(),
+                        interval: None,
+                        keep: 7,
+                    }),
+                    ..Default::default()
+                }))
+                .service(admin_databases)
+                .service(admin_tables)
+                .service(admin_backup),
+        )
+        .await;
+
+        // Other tests keep databases in the same directory
+        let req = test::TestRequest::get()
+            .uri("/_admin/databases")
+            .to_request();
+        let result: Vec<DatabaseSummary> = test::call_and_read_body_json(&app, req).await;
+        let database = result
+            .iter()
+            .find(|database| database.name == "test_admin")
+            .unwrap();
+        assert_eq!(database.tables, 1);
+        assert!(database.size_bytes > 0);
+
+        let req = test::TestRequest::get()
+            .uri("/_admin/databases/test_admin/tables")
+            .to_request();
+        let result: Vec<TableSummary> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].name, "events");
+        assert_eq!(result[0].rows, 2);
+
+        let req = test::TestRequest::get()
+            .uri("/_admin/databases/test_admin_missing/tables")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // curl -i -X POST http://localhost:8888/_admin/databases/test_admin/backup
+        let req = test::TestRequest::post()
+            .uri("/_admin/databases/test_admin/backup")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let result: backup::BackupFile = test::read_body_json(response).await;
+        assert!(result.path.starts_with(backups.join("test_admin")));
+        assert!(result.path.exists());
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_admin.db").unwrap();
+        std::fs::remove_dir_all(backups).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_admin_keys() {
+        let api_keys = auth::ApiKeys {
+            keys: vec![
+                auth::ApiKey {
+                    name: String::from("oncall"),
+                    key: String::from("debug"),
+                },
+                auth::ApiKey {
+                    name: String::from("gateway"),
+                    key: String::from("secret"),
+                },
+            ],
+        };
+        // The filter changes as long as its layer is kept
+        let (_layer, log_filter) = LogFilter::new(tracing::Level::WARN);
+        let app = |api_keys: auth::ApiKeys, log_filter: Option<LogFilter>| {
+            let (with_keys, with_filter) = (!api_keys.is_empty(), log_filter.is_some());
+            App::new()
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData {
+                    debug_keys: vec![String::from("oncall")],
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(api_keys))
+                .configure(move |cfg| {
+                    if let Some(log_filter) = log_filter {
+                        cfg.app_data(web::Data::new(log_filter));
+                    }
+                    configure(cfg, Endpoints::All, false, with_keys, with_filter);
+                })
+        };
+
+        // The log filter is changed with a debug key only
+        let service = test::init_service(app(api_keys.clone(), Some(log_filter))).await;
+        for (key, status) in [("secret", StatusCode::FORBIDDEN), ("debug", StatusCode::OK)] {
+            let req = test::TestRequest::put()
+                .uri("/_admin/log-filter")
+                .insert_header(("X-API-Key", key))
+                .set_payload("info")
+                .to_request();
+            let response = test::call_service(&service, req).await;
+            assert_eq!(response.status(), status, "{key}");
+        }
+
+        // Without a filter to change there is no log filter endpoint
+        let service = test::init_service(app(api_keys, None)).await;
+        let req = test::TestRequest::get()
+            .uri("/_admin/log-filter")
+            .insert_header(("X-API-Key", "debug"))
+            .to_request();
+        let response = test::call_service(&service, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Without API keys there are no admin endpoints
+        let service = test::init_service(app(auth::ApiKeys::default(), None)).await;
+        let req = test::TestRequest::get()
+            .uri("/_admin/databases")
+            .to_request();
+        let response = test::call_service(&service, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_consistency_token() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData::default()))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // A write answers with its token
+        let req = test::TestRequest::put()
+            .uri("/test_consistency/events")
+            .set_payload("{\"n\": 1}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let token = response.headers().get(consistency::TOKEN_HEADER).unwrap();
+        assert_eq!(token, "test_consistency/events/1");
+
+        // The write is visible to a read passing the token back
+        let req = test::TestRequest::get()
+            .uri("/test_consistency/events/changes")
+            .insert_header((consistency::TOKEN_HEADER, token.clone()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A commit which is not visible yet
+        let req = test::TestRequest::get()
+            .uri("/test_consistency/events/changes")
+            .insert_header((consistency::TOKEN_HEADER, "test_consistency/events/2"))
+            .insert_header((consistency::WAIT_HEADER, "0s"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        // A token of another table
+        let req = test::TestRequest::get()
+            .uri("/test_consistency/events/changes")
+            .insert_header((consistency::TOKEN_HEADER, "test_consistency/other/1"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_consistency.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_write_queue() {
+        // Initialize the application
+        let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::new("./"));
+        let settings = config::WriteQueueConfig::default().settings();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    write_queue: Some(WriteQueue::start(
+                        storage.clone(),
+                        settings,
+                        MemoryBudget::default(),
+                        None,
+                    )),
+                    storage,
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // A queued write is accepted with the ticket of the write
+        let req = test::TestRequest::put()
+            .uri("/test_write_queue_api/events")
+            .set_payload("{\"n\": 1}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let token = response.headers().get(consistency::TOKEN_HEADER).unwrap();
+        assert_eq!(token, "test_write_queue_api/events/q1");
+
+        // A read passing the token back waits until the write is stored
+        let req = test::TestRequest::get()
+            .uri("/test_write_queue_api/events/changes")
+            .insert_header((consistency::TOKEN_HEADER, token.clone()))
+            .to_request();
+        let result: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_write_queue_api.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_idempotency_key() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        // The first request is stored, the retry gets the stored row
+        for status in [StatusCode::CREATED, StatusCode::OK] {
+            let req = test::TestRequest::put()
+                .uri("/test_idempotency/events")
+                .insert_header(("Idempotency-Key", "7f3c"))
+                .set_payload("{\"attempt\": true}")
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), status);
+        }
+        let req = test::TestRequest::put()
+            .uri("/test_idempotency/events")
+            .insert_header(("Idempotency-Key", "7f3c"))
+            .set_payload("{\"attempt\": true}")
+            .to_request();
+        let result: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result, serde_json::json!([{"id": 1, "seq": 1}]));
+
+        // An `_id` field works the same way
+        for status in [StatusCode::CREATED, StatusCode::OK] {
+            let req = test::TestRequest::put()
+                .uri("/test_idempotency/events")
+                .set_payload("{\"_id\": \"a1\"}")
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), status);
+        }
+
+        // Only two rows were stored, without gaps in the sequence
+        let req = test::TestRequest::get()
+            .uri("/test_idempotency/events/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        let seqs: Vec<i64> = result.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_idempotency.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_record_metadata() {
+        // Initialize the application, behind a proxy on the same host
+        let trusted_proxies = TrustedProxies::parse(&[String::from("127.0.0.1")]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(audit::assign_request_id))
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    record_metadata: Some(trusted_proxies),
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+
+        // The rows of a request are stored with where it came from
+        let req = test::TestRequest::put()
+            .uri("/test_record_metadata/events")
+            .peer_addr("127.0.0.1:40000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .insert_header((header::USER_AGENT, "sensor/1.2"))
+            .set_payload("{\"temperature\": 21.5}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let request_id = response.headers().get(audit::REQUEST_ID_HEADER).unwrap();
+        let request_id = request_id.to_str().unwrap().to_string();
+
+        let conn = rusqlite::Connection::open("./test_record_metadata.db").unwrap();
+        let row: (String, String, String) = conn
+            .query_row(
+                "SELECT source_ip, user_agent, request_id FROM events;",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                String::from("203.0.113.7"),
+                String::from("sensor/1.2"),
+                request_id
+            )
+        );
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_record_metadata.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_openapi() {
+        // Initialize the application, with API keys
+        let api_keys = auth::ApiKeys {
+            keys: vec![auth::ApiKey {
+                name: String::from("gateway"),
+                key: String::from("secret"),
+            }],
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData::default()))
+                .app_data(web::Data::new(api_keys))
+                .configure(|cfg| configure(cfg, Endpoints::All, false, true, false)),
+        )
+        .await;
+
+        // The document is answered without a key
+        let req = test::TestRequest::get().uri("/openapi.json").to_request();
+        let doc: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        let paths = doc["paths"].as_object().unwrap();
+        assert!(paths["/{database_name}/{table_name}"]["put"].is_object());
+        assert!(paths["/{database_name}/{table_name}/query"]["post"].is_object());
+        assert!(paths["/_admin/databases"]["get"].is_object());
+        let parameters = paths["/{database_name}/{table_name}/changes"]["get"]["parameters"]
+            .as_array()
+            .unwrap();
+        for name in [
+            "database_name",
+            "table_name",
+            "since",
+            "after_id",
+            "limit",
+            "key",
+        ] {
+            assert!(parameters.iter().any(|parameter| parameter["name"] == name));
+        }
+        let schemes = &doc["components"]["securitySchemes"];
+        assert_eq!(schemes["api_key"]["name"], "X-API-Key");
+        assert_eq!(schemes["bearer"]["scheme"], "bearer");
+
+        // The profiles are documented by the builds answering them
+        let profiled = paths.contains_key("/debug/pprof/profile");
+        assert_eq!(profiled, cfg!(feature = "pprof"));
+
+        // The data API still needs a key
+        let req = test::TestRequest::get()
+            .uri("/test_openapi/events/changes")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Swagger UI is answered by the builds with it
+        let req = test::TestRequest::get().uri("/_docs/").to_request();
+        let response = test::call_service(&app, req).await;
+        match cfg!(feature = "swagger-ui") {
+            true => assert_eq!(response.status(), StatusCode::OK),
+            false => assert_eq!(response.status(), StatusCode::NOT_FOUND),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_proxy_data() {
+        // An upstream API answering with the body it was sent
+        let upstream = HttpServer::new(|| {
+            App::new().default_service(web::to(|body: web::Bytes| async move {
+                HttpResponse::Accepted()
+                    .insert_header(("X-Upstream", "yes"))
+                    .body(body)
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = upstream.addrs()[0];
+        let upstream = upstream.run();
+        let handle = upstream.handle();
+        actix_web::rt::spawn(upstream);
+
+        // Initialize the application
+        let proxy = Proxy::new(
+            &format!("http://{address}"),
+            "test_proxy",
+            "traffic",
+            std::time::Duration::from_secs(5),
+        )
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(proxy))
+                .service(read_changes)
+                .default_service(web::to(proxy_data)),
+        )
+        .await;
+
+        // The response of the upstream is returned
+        let req = test::TestRequest::post()
+            .uri("/v1/orders?dry_run=1")
+            .set_payload("{\"id\": 7}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers().get("X-Upstream").unwrap(), "yes");
+        let body = test::read_body(response).await;
+        assert_eq!(body, "{\"id\": 7}");
+
+        // A copy of the request is stored
+        let req = test::TestRequest::get()
+            .uri("/test_proxy/traffic/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data["path"], "/v1/orders");
+        assert_eq!(result[0].data["status"], 202);
+        assert_eq!(result[0].data["body"]["id"], 7);
+
+        // Post test, remove any database files created
+        handle.stop(true).await;
+        std::fs::remove_file("./test_proxy.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_ingest() {
+        // Documents read from standard in share the request pipeline
+        let appdata = AppData {
+            split_rules: vec!["readings=$.readings[]".parse().unwrap()],
+            ..Default::default()
+        };
+        let values = vec![
+            serde_json::json!({"gateway": "g1", "readings": [{"value": 20}, {"value": 21}]}),
+            serde_json::json!({"gateway": "g2", "readings": [{"value": 22}]}),
+        ];
+        check_table(&appdata, "test_ingest", "readings").unwrap();
+        let (_, inserted) = ingest(
+            &appdata,
+            "test_ingest",
+            "readings",
+            values,
+            None,
+            None,
+            &RecordMetadata::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(inserted.len(), 3);
+
+        // Names are still checked
+        assert!(check_table(&appdata, "test_ingest", "bad name").is_err());
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_ingest.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_compressed_body() {
+        use std::io::Write;
+
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    body_limits: BodyLimits {
+                        max_body_size: 1_000,
+                        max_decompressed_size: 10_000,
+                    },
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+        let gzip = |body: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        // curl -i -X PUT -H 'Content-Encoding: gzip' --data-binary @test.json.gz http://localhost:8888/database/test
+        let document = format!("{{\"padding\": \"{}\"}}", " ".repeat(5_000));
+        let req = test::TestRequest::put()
+            .uri("/test_compressed/events")
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .set_payload(gzip(document.as_bytes()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Larger than the limit once decompressed
+        let document = format!("{{\"padding\": \"{}\"}}", " ".repeat(50_000));
+        let req = test::TestRequest::put()
+            .uri("/test_compressed/events")
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .set_payload(gzip(document.as_bytes()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = test::TestRequest::put()
+            .uri("/test_compressed/events")
+            .insert_header((header::CONTENT_ENCODING, "compress"))
+            .set_payload("{}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_compressed.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_memory_budget() {
+        // Initialize the application
+        let memory_budget = MemoryBudget::new(Some(5_000));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    body_limits: BodyLimits {
+                        max_body_size: 1_000,
+                        max_decompressed_size: 10_000,
+                    },
+                    memory_budget: memory_budget.clone(),
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/test_memory_budget/events")
+            .set_payload("{\"a\": 1}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // A compressed body counts as much as it can expand to, which can
+        // never fit in the budget
+        let req = test::TestRequest::put()
+            .uri("/test_memory_budget/events")
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .set_payload("{}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(memory_budget.used(), 0);
+
+        // A budget taken by other requests is retried later
+        let taken = memory_budget.reserve(4_995).unwrap();
+        let req = test::TestRequest::put()
+            .uri("/test_memory_budget/events")
+            .set_payload("{\"a\": 1}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(taken);
+        assert_eq!(memory_budget.used(), 0);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_memory_budget.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_endpoints() {
+        // Initialize a write server and a read server sharing the data
+        let appdata = web::Data::new(AppData::default());
+        let writes = test::init_service(
+            App::new()
+                .app_data(appdata.clone())
+                .configure(|cfg| data_api(cfg, Endpoints::Write)),
+        )
+        .await;
+        let reads = test::init_service(
+            App::new()
+                .app_data(appdata.clone())
+                .configure(|cfg| data_api(cfg, Endpoints::Read)),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/test_endpoints/events")
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&writes, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let req = test::TestRequest::put()
+            .uri("/test_endpoints/events")
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&reads, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/test_endpoints/events/changes")
+            .to_request();
+        let result: Vec<storage::ChangeEvent> = test::call_and_read_body_json(&reads, req).await;
+        assert_eq!(result.len(), 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_endpoints.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_debug_echo() {
+        // Initialize the application
+        let api_keys = auth::ApiKeys {
+            keys: vec![
+                auth::ApiKey {
+                    name: String::from("onboarding"),
+                    key: String::from("debug"),
+                },
+                auth::ApiKey {
+                    name: String::from("gateway"),
+                    key: String::from("secret"),
+                },
+            ],
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData {
+                    split_rules: vec!["readings=$.readings[]".parse().unwrap()],
+                    flatten_rules: vec!["readings".parse().unwrap()],
+                    debug_keys: vec![String::from("onboarding")],
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(api_keys))
+                .service(create_data),
+        )
+        .await;
+
+        // curl -i -X PUT -H 'X-Debug: true' -H 'X-API-Key: debug' -d '{"readings": [...]}' http://localhost:8888/database/readings
+        let req = test::TestRequest::put()
+            .uri("/test_debug/readings")
+            .insert_header(("X-API-Key", "debug"))
+            .insert_header((debug::DEBUG_HEADER, "true"))
+            .set_payload(
+                "{\"gateway\": \"g1\", \"readings\": [{\"v\": {\"c\": 20}}, {\"v\": {\"c\": 21}}]}",
+            )
+            .to_request();
+        let result: debug::Echo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.table, "readings");
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.rows[1].data["v.c"], 21);
+        assert_eq!(result.received["gateway"], "g1");
+        assert!(result
+            .statements
+            .iter()
+            .any(|sql| sql.contains("INSERT INTO \"readings\"")));
+
+        // Nothing was stored
+        assert!(!std::path::Path::new("./test_debug.db").exists());
+
+        // Only debug keys can ask for an echo
+        let req = test::TestRequest::put()
+            .uri("/test_debug/readings")
+            .insert_header(("X-API-Key", "secret"))
+            .insert_header((debug::DEBUG_HEADER, "true"))
+            .set_payload("{\"readings\": []}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(feature = "pprof")]
+    #[actix_web::test]
+    async fn test_cpu_profile() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Initialize the application
+        let api_keys = auth::ApiKeys {
+            keys: vec![
+                auth::ApiKey {
+                    name: String::from("oncall"),
+                    key: String::from("debug"),
+                },
+                auth::ApiKey {
+                    name: String::from("gateway"),
+                    key: String::from("secret"),
+                },
+            ],
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData {
+                    debug_keys: vec![String::from("oncall")],
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(api_keys))
+                .service(cpu_profile),
+        )
+        .await;
+
+        // Something to sample until the profile is taken, on a single CPU a
+        // thread busy for a set time can be done before the profiler starts
+        let profiled = Arc::new(AtomicBool::new(false));
+        let busy = std::thread::spawn({
+            let profiled = profiled.clone();
+            move || {
+                let mut sum = 0u64;
+                while !profiled.load(Ordering::Relaxed) {
+                    sum = std::hint::black_box(sum.wrapping_add(1));
+                }
+            }
+        });
+
+        // curl -o cpu.svg -H 'X-API-Key: debug' 'http://localhost:8888/debug/pprof/profile?seconds=1&format=flamegraph'
+        let req = test::TestRequest::get()
+            .uri("/debug/pprof/profile?seconds=1&format=flamegraph")
+            .insert_header(("X-API-Key", "debug"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/svg+xml"
+        );
+        profiled.store(true, Ordering::Relaxed);
+        busy.join().unwrap();
+        let body = test::read_body(response).await;
+        assert!(str::from_utf8(&body).unwrap().contains("<svg"));
+
+        // Only debug keys can take a profile, for at most MAX_SECONDS
+        let req = test::TestRequest::get()
+            .uri("/debug/pprof/profile?seconds=1")
+            .insert_header(("X-API-Key", "secret"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get()
+            .uri("/debug/pprof/profile?seconds=3600")
+            .insert_header(("X-API-Key", "debug"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_flatten() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    flatten_rules: vec!["vendor_events".parse().unwrap()],
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        let data = "{\"device\": {\"id\": \"d1\", \"location\": {\"site\": \"lab\"}}}";
+        let req = test::TestRequest::put()
+            .uri("/test_flatten/vendor_events")
+            .set_payload(data)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The row is stored with dotted keys
+        let req = test::TestRequest::get()
+            .uri("/test_flatten/vendor_events/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result[0].data["device.id"], "d1");
+        assert_eq!(result[0].data["device.location.site"], "lab");
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_flatten.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_computed_fields() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    computed_fields: vec![
+                        "readings:value_x10=$.value * 10".parse().unwrap(),
+                        "readings:site=lookup('sites', $.device)".parse().unwrap(),
+                    ],
+                    maps: Maps::from([(
+                        String::from("sites"),
+                        [(String::from("d1"), Value::from("lab"))].into(),
+                    )]),
+                    ..Default::default()
+                }))
+                .service(create_data)
+                .service(read_changes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/test_computed/readings")
+            .set_payload("{\"device\": \"d1\", \"value\": 2}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The row is stored with the computed fields
+        let req = test::TestRequest::get()
+            .uri("/test_computed/readings/changes")
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result[0].data["value_x10"], 20);
+        assert_eq!(result[0].data["site"], "lab");
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_computed.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_api_keys() {
+        // Initialize the application
+        let api_keys = auth::ApiKeys {
+            keys: vec![auth::ApiKey {
+                name: String::from("gateway"),
+                key: String::from("secret"),
+            }],
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData {
+                    table_templates: vec!["events={table}_{api_key_name}".parse().unwrap()],
+                    ..Default::default()
+                }))
+                .app_data(web::Data::new(api_keys))
+                .service(create_data)
+                .service(read_changes)
+                .service(ping),
+        )
+        .await;
+
+        // Requests without a valid key are refused
+        for key in [None, Some("Bearer wrong")] {
+            let mut req = test::TestRequest::put()
+                .uri("/test_auth/events")
+                .set_payload("{\"actix test\": true}");
+            if let Some(key) = key {
+                req = req.insert_header((header::AUTHORIZATION, key));
+            }
+            let response = test::call_service(&app, req.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // curl -i -X PUT -H 'Authorization: Bearer secret' -d '{"curl test": true}' http://localhost:8888/database/events
+        let req = test::TestRequest::put()
+            .uri("/test_auth/events")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_payload("{\"actix test\": true}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The row lands in the table of the key
+        let req = test::TestRequest::get()
+            .uri("/test_auth/events_gateway/changes")
+            .insert_header(("X-API-Key", "secret"))
+            .to_request();
+        let result: Vec<ChangeEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.len(), 1);
+
+        // /ping is answered without a key
+        let req = test::TestRequest::get().uri("/ping").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_auth.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_table_schema() {
+        // Initialize the application
+        let schema = serde_json::json!({"type": "object", "required": ["device"]});
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    schemas: vec![TableSchema::new("readings", &schema).unwrap()],
+                    access: AccessRules {
+                        databases: vec![String::from("test_schema")],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }))
+                .service(create_data),
+        )
+        .await;
+
+        // Rows have to match the schema of the table
+        let req = test::TestRequest::put()
+            .uri("/test_schema/readings")
+            .set_payload("{\"value\": 20}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri("/test_schema/readings")
+            .set_payload("{\"device\": \"d1\", \"value\": 20}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Databases left out of the allowed list are refused
+        let req = test::TestRequest::put()
+            .uri("/test_other/readings")
+            .set_payload("{\"device\": \"d1\"}")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let result: errors::ErrorResponse = test::read_body_json(response).await;
+        assert_eq!(result.code, "forbidden");
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_schema.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_ping() {
+        // Initialize the application
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./")),
+                    ..Default::default()
+                }))
+                .service(ping),
+        )
+        .await;
+
+        // Send a request to the `client_address` endpoint
+        let req = test::TestRequest::get().uri("/ping").to_request();
+
+        // Send the request and parse the response as JSON
+        let result: PongResponse = test::call_and_read_body_json(&app, req).await;
+
+        // Assert the response
+        assert_eq!(result.ping, String::from("pong"));
+    }
+
+    #[actix_web::test]
+    async fn test_healthz() {
+        // Initialize the application
+        let readiness = web::Data::new(Readiness::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData::default()))
+                .app_data(readiness.clone())
+                .service(healthz)
+                .service(healthz_live),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/healthz/live").to_request();
+        let result: HealthResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.status, "alive");
+
+        // Ready while the storage can take writes
+        let req = test::TestRequest::get().uri("/healthz/ready").to_request();
+        let result: HealthResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result.status, "ready");
+        assert_eq!(result.checks.len(), 2);
+        assert!(result.checks.iter().all(|check| check.healthy));
+
+        // Degraded when the database files can't be written
+        let degraded = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData {
+                    storage: Arc::new(SqliteStorage::new("./test_healthz_missing")),
+                    ..Default::default()
+                }))
+                .app_data(readiness.clone())
+                .service(healthz),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/healthz/ready").to_request();
+        let response = test::call_service(&degraded, req).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let result: HealthResponse = test::read_body_json(response).await;
+        assert_eq!(result.status, "degraded");
+        assert!(result.checks[0].error.is_some());
+
+        // Not ready once the server starts draining
+
+        readiness.drain();
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let result: HealthResponse = test::read_body_json(response).await;
+        assert_eq!(result.status, "draining");
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit() {
+        // Initialize the application, a request every two seconds for each client
+        let api_keys = auth::ApiKeys {
+            keys: vec![
+                auth::ApiKey {
+                    name: String::from("gateway"),
+                    key: String::from("secret"),
+                },
+                auth::ApiKey {
+                    name: String::from("collector"),
+                    key: String::from("other"),
+                },
+            ],
+        };
+        let limiter = RateLimiter::new(rate_limit::RateLimitSettings {
+            requests_per_second: 0.5,
+            burst: 1,
+        });
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(rate_limit::limit))
+                .wrap(from_fn(auth::require_api_key))
+                .app_data(web::Data::new(AppData::default()))
+                .app_data(web::Data::new(api_keys))
+                .app_data(web::Data::new(limiter))
+                .service(ping)
+                .service(create_data),
+        )
+        .await;
+        let put = |key: &str| {
+            test::TestRequest::put()
+                .uri("/test_rate_limit/events")
+                .insert_header(("X-API-Key", key))
+                .set_payload("{\"actix test\": true}")
+                .to_request()
+        };
+
+        let response = test::call_service(&app, put("secret")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, put("secret")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+
+        // Another key has a bucket of its own, probes are never limited
+        let response = test::call_service(&app, put("other")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let req = test::TestRequest::get().uri("/ping").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_rate_limit.db").unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+// JSON Schema validation of documents before they are stored
+//
+// --table-schema events=/etc/receiver/events.schema.json
+//
+// Documents written to `events` which do not match the schema are refused
+// with the reasons, instead of turning up malformed in every query later.
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// A JSON Schema validator
+// https://docs.rs/jsonschema/latest/jsonschema/
+// cargo add jsonschema --no-default-features
+use jsonschema::Validator;
+use serde_json::Value;
+
+/// The most errors reported for one document
+const MAX_ERRORS: usize = 5;
+
+/// Where the JSON Schema of a table is read from
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaPath {
+    pub table: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for SchemaPath {
+    type Err = String;
+
+    // <table>=<path to a JSON Schema file>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, path) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>=<path>: {value}"))?;
+        Ok(SchemaPath {
+            table: table.trim().to_string(),
+            path: PathBuf::from(path.trim()),
+        })
+    }
+}
+
+/// The compiled JSON Schema of a table
+#[derive(Clone)]
+pub struct TableSchema {
+    pub table: String,
+    validator: Arc<Validator>,
+}
+
+impl TableSchema {
+    /// Compile a JSON Schema
+    pub fn new(table: &str, schema: &Value) -> Result<Self, String> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|err| format!("invalid JSON Schema for table {table}: {err}"))?;
+        Ok(TableSchema {
+            table: table.to_string(),
+            validator: Arc::new(validator),
+        })
+    }
+
+    /// Read and compile the JSON Schema file of a table
+    pub fn load(schema_path: &SchemaPath) -> Result<Self, String> {
+        let path = schema_path.path.display();
+        let schema = std::fs::read_to_string(&schema_path.path)
+            .map_err(|err| format!("unable to read {path}: {err}"))?;
+        let schema: Value = serde_json::from_str(&schema)
+            .map_err(|err| format!("{path} is not valid JSON: {err}"))?;
+        TableSchema::new(&schema_path.table, &schema)
+    }
+
+    /// Check a document against the schema, the reasons are returned when it does not match
+    pub fn validate(&self, document: &Value) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(document)
+            .take(MAX_ERRORS)
+            .map(|err| match err.instance_path.to_string() {
+                path if path.is_empty() => err.to_string(),
+                path => format!("{path}: {err}"),
+            })
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(format!(
+                "document does not match the schema of table {}: {}",
+                self.table,
+                errors.join("; ")
+            )),
+        }
+    }
+}
+
+/// The schema of a table, if any
+pub fn find<'a>(schemas: &'a [TableSchema], table: &str) -> Option<&'a TableSchema> {
+    schemas.iter().find(|schema| schema.table == table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["device"],
+            "properties": {"device": {"type": "string"}, "value": {"type": "number"}}
+        });
+        let schema = TableSchema::new("readings", &schema).unwrap();
+        assert!(schema
+            .validate(&json!({"device": "d1", "value": 20}))
+            .is_ok());
+
+        let err = schema.validate(&json!({"value": "high"})).unwrap_err();
+        assert!(err.contains("\"device\" is a required property"));
+        assert!(err.contains("/value: \"high\" is not of type \"number\""));
+
+        assert!(TableSchema::new("readings", &json!({"type": "unknown"})).is_err());
+        assert!("readings".parse::<SchemaPath>().is_err());
+    }
+}
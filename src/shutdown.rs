@@ -0,0 +1,104 @@
+// Graceful shutdown
+//
+// [server]
+// drain_delay = "5s"
+// shutdown_timeout = "30s"
+//
+// On SIGTERM `GET /healthz` starts answering 503 so load balancers take the
+// server out of rotation, and `drain_delay` later the server stops accepting
+// connections. Requests in flight get up to `shutdown_timeout` to finish, the
+// write queue then gets up to `shutdown_timeout` to store what it holds, and
+// the storage backend is closed. SIGINT (Ctrl+C) stops without the delay.
+use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::{
+    dev::ServerHandle,
+    rt::{signal, time},
+    web,
+};
+use futures_util::future::{join_all, select, Either};
+use tracing::{error, info};
+
+/// Whether the server takes new work, shared with `/healthz`
+#[derive(Debug, Default)]
+pub struct Readiness {
+    draining: AtomicBool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Answer `/healthz` with 503 from now on
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The signals the server stops on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Terminate,
+    Interrupt,
+}
+
+/// Wait for SIGTERM or SIGINT
+#[cfg(unix)]
+pub async fn wait_for_signal() -> io::Result<Signal> {
+    use signal::unix::{self, SignalKind};
+
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    let signal = match select(pin!(terminate.recv()), pin!(signal::ctrl_c())).await {
+        Either::Left(_) => Signal::Terminate,
+        Either::Right((result, _)) => result.map(|()| Signal::Interrupt)?,
+    };
+    Ok(signal)
+}
+
+/// Wait for Ctrl+C
+#[cfg(not(unix))]
+pub async fn wait_for_signal() -> io::Result<Signal> {
+    signal::ctrl_c().await.map(|()| Signal::Interrupt)
+}
+
+/// Drain and stop the servers once a signal arrives
+pub async fn stop_on_signal(
+    servers: Vec<ServerHandle>,
+    readiness: web::Data<Readiness>,
+    drain_delay: Duration,
+) {
+    let signal = match wait_for_signal().await {
+        Ok(signal) => signal,
+        Err(err) => {
+            error!("unable to listen for shutdown signals: {err}");
+            return;
+        }
+    };
+    readiness.drain();
+    if signal == Signal::Terminate && !drain_delay.is_zero() {
+        info!(
+            "Draining for {} before stopping",
+            humantime::format_duration(drain_delay)
+        );
+        time::sleep(drain_delay).await;
+    }
+    info!("Stopping, waiting for requests in flight");
+    join_all(servers.iter().map(|server| server.stop(true))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let readiness = Readiness::default();
+        assert!(readiness.is_ready());
+        readiness.drain();
+        assert!(!readiness.is_ready());
+    }
+}
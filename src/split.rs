@@ -0,0 +1,111 @@
+// Split one request into many rows
+//
+// --split-rule readings='$.readings[]'
+//
+// A gateway batching sensor readings sends
+// {"gateway": "g1", "readings": [{"sensor": 1, "value": 20}, {"sensor": 2, "value": 21}]}
+// and the table gets one row per reading with the envelope copied in
+// {"gateway": "g1", "sensor": 1, "value": 20}
+// {"gateway": "g1", "sensor": 2, "value": 21}
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::jsonpath::JsonPath;
+
+/// Store each element of an array as its own row
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitRule {
+    pub table: String,
+    pub path: JsonPath,
+}
+
+impl FromStr for SplitRule {
+    type Err = String;
+
+    // <table>=<JSON path to an array>[]
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, path) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>=<JSON path>[]: {value}"))?;
+        let path = path.trim();
+        let path = path
+            .strip_suffix("[]")
+            .or_else(|| path.strip_suffix("[*]"))
+            .unwrap_or(path);
+        Ok(SplitRule {
+            table: table.trim().to_string(),
+            path: path.parse()?,
+        })
+    }
+}
+
+/// The rule for a table, if any
+pub fn find<'a>(rules: &'a [SplitRule], table: &str) -> Option<&'a SplitRule> {
+    rules.iter().find(|rule| rule.table == table)
+}
+
+impl SplitRule {
+    /// Split a document into one document per array element
+    /// Documents without an array at the path are kept whole
+    pub fn split(&self, mut document: Value) -> Vec<Value> {
+        if !matches!(self.path.select(&document), Some(Value::Array(_))) {
+            return vec![document];
+        }
+        let Some(Value::Array(items)) = self.path.take(&mut document) else {
+            return vec![document];
+        };
+
+        // Whatever is left of the document is the envelope
+        let envelope = match document {
+            Value::Object(map) => map,
+            _ => Default::default(),
+        };
+        let key = self.path.last_key().unwrap_or("value").to_string();
+        items
+            .into_iter()
+            .map(|item| {
+                let mut row = envelope.clone();
+                match item {
+                    // Element fields win over envelope fields of the same name
+                    Value::Object(fields) => row.extend(fields),
+                    item => {
+                        row.insert(key.clone(), item);
+                    }
+                }
+                Value::Object(row)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_split() {
+        let rule: SplitRule = "sensors=$.batch.readings[]".parse().unwrap();
+        assert_eq!(rule.table, "sensors");
+
+        let document = json!({
+            "gateway": "g1",
+            "batch": {"id": 7, "readings": [{"sensor": 1, "value": 20}, 21]}
+        });
+        assert_eq!(
+            rule.split(document),
+            vec![
+                json!({"gateway": "g1", "batch": {"id": 7}, "sensor": 1, "value": 20}),
+                json!({"gateway": "g1", "batch": {"id": 7}, "readings": 21}),
+            ]
+        );
+
+        // No array, no split
+        let document = json!({"gateway": "g1", "batch": {"readings": "none"}});
+        assert_eq!(rule.split(document.clone()), vec![document]);
+
+        assert!("sensors".parse::<SplitRule>().is_err());
+    }
+}
@@ -0,0 +1,348 @@
+// Storage backends for the data received
+//
+// The HTTP API is the same for every backend, a backend decides what a
+// "database" and a "table" are. For SQLite a database is a file under
+// `--database-files`, for PostgreSQL a database is a schema.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+// https://docs.rs/async-trait/latest/async_trait/
+// cargo add async-trait
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// https://docs.rs/serde_json/latest/serde_json/
+// cargo add serde_json
+use serde_json::Value;
+
+use crate::metrics;
+
+#[cfg(feature = "postgres")]
+mod postgres;
+mod query;
+mod schema;
+mod shadow;
+mod sqlite;
+mod stats;
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStorage;
+pub use self::query::{parse_value, Condition, ConditionSpec, QueryFilter, MAX_CONDITIONS};
+pub use self::schema::SchemaMode;
+pub use self::shadow::ShadowStorage;
+pub use self::sqlite::{DbKey, JournalMode, SqlitePragmas, SqliteStorage, Synchronous};
+pub use self::stats::{parse_time, StatsBucket, StatsFilter};
+
+/// A record to be stored
+#[derive(Clone, Debug)]
+pub struct NewRecord {
+    pub timestamp: DateTime<Utc>,
+    pub data: String,
+    pub ordering_key: Option<String>,
+    /// A record with the key of a stored row is not stored again
+    pub idempotency_key: Option<String>,
+    pub metadata: RecordMetadata,
+}
+
+/// A record of a document stored now, without keys or metadata
+#[cfg(test)]
+pub(crate) fn record(data: &str) -> NewRecord {
+    NewRecord {
+        timestamp: Utc::now(),
+        data: data.to_string(),
+        ordering_key: None,
+        idempotency_key: None,
+        metadata: RecordMetadata::default(),
+    }
+}
+
+/// Where a record came from, left empty unless the server records it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecordMetadata {
+    pub source_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Where a stored record ended up
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Inserted {
+    pub id: i64,
+    pub seq: i64,
+    /// The record was already stored under its idempotency key
+    #[serde(skip)]
+    pub duplicate: bool,
+}
+
+/// The outcome of one readiness check of a backend
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct HealthCheck {
+    pub name: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn new<E: fmt::Display>(name: &str, result: Result<(), E>) -> Self {
+        HealthCheck {
+            name: name.to_string(),
+            healthy: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        }
+    }
+}
+
+/// Which changes to read from a table
+#[derive(Clone, Debug, Default)]
+pub struct ChangesFilter {
+    pub since: i64,
+    /// Only the rows with a greater `id`, read in `id` order instead of `seq` order
+    pub after_id: Option<i64>,
+    pub limit: i64,
+    pub key: Option<String>,
+}
+
+/// A single changefeed event
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ChangeEvent {
+    pub seq: i64,
+    pub id: i64,
+    pub timestamp: String,
+    pub data: Value,
+}
+
+/// Pages of rows of the tables of a snapshot, every page of a table before
+/// the next table
+pub type SnapshotStream = BoxStream<'static, StorageResult<(String, Vec<ChangeEvent>)>>;
+
+/// Errors raised by a storage backend
+#[derive(Debug)]
+pub enum Error {
+    InvalidName(String),
+    NotFound(String),
+    Schema(String),
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(tokio_postgres::Error),
+    Pool(String),
+    /// The backend can't do what was asked
+    Unsupported(String),
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidName(name) => write!(f, "invalid name: {name}"),
+            Error::NotFound(name) => write!(f, "not found: {name}"),
+            Error::Schema(diff) => write!(f, "{diff}"),
+            Error::Sqlite(err) => write!(f, "sqlite: {err}"),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(err) => write!(f, "postgres: {err}"),
+            Error::Pool(err) => write!(f, "connection pool: {err}"),
+            Error::Unsupported(message) => write!(f, "unsupported: {message}"),
+            Error::Internal(err) => write!(f, "internal error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        let code = match err.sqlite_error_code() {
+            Some(code) => format!("{code:?}"),
+            None => String::from("other"),
+        };
+        metrics::SQLITE_ERRORS.with_label_values(&[&code]).inc();
+        Error::Sqlite(err)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for Error {
+    fn from(err: tokio_postgres::Error) -> Self {
+        Error::Postgres(err)
+    }
+}
+
+pub type StorageResult<T> = Result<T, Error>;
+
+/// A place to keep the data received
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store records in a table in a single transaction, creating the
+    /// database and table as needed
+    /// A record whose idempotency key is already stored, or repeated earlier
+    /// in the batch, comes back as the row it duplicates
+    async fn insert_batch(
+        &self,
+        database: &str,
+        table: &str,
+        records: Vec<NewRecord>,
+    ) -> StorageResult<Vec<Inserted>>;
+
+    /// The statements `insert_batch` runs to store rows in a table, with
+    /// their parameters left as placeholders
+    fn insert_statements(&self, database: &str, table: &str) -> StorageResult<Vec<String>>;
+
+    /// Read the changes made to a table in sequence order
+    async fn changes(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &ChangesFilter,
+    ) -> StorageResult<Vec<ChangeEvent>>;
+
+    /// Read the documents of a table matching every condition in sequence order
+    async fn query(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &QueryFilter,
+    ) -> StorageResult<Vec<ChangeEvent>>;
+
+    /// Count the rows of a table stored in each time bucket
+    async fn stats(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &StatsFilter,
+    ) -> StorageResult<Vec<StatsBucket>>;
+
+    /// Read every row of several tables of a database as of a single point
+    /// in time, `limit` rows at a time in sequence order
+    /// A missing table is the first item of the stream, before any rows
+    fn snapshot(&self, database: &str, tables: &[String], limit: i64) -> SnapshotStream;
+
+    /// The sequence number of the last row committed to a table, 0 for a
+    /// table without rows
+    async fn last_seq(&self, database: &str, table: &str) -> StorageResult<i64>;
+
+    /// The names of the databases holding data
+    async fn databases(&self) -> StorageResult<Vec<String>>;
+
+    /// The names of the data tables of a database
+    async fn tables(&self, database: &str) -> StorageResult<Vec<String>>;
+
+    /// The space a database takes in bytes
+    async fn size(&self, database: &str) -> StorageResult<u64>;
+
+    /// The number of rows of a table
+    async fn row_count(&self, database: &str, table: &str) -> StorageResult<i64>;
+
+    /// An estimate of the number of rows of a table, cheap enough to answer
+    /// with every page read
+    async fn row_estimate(&self, database: &str, table: &str) -> StorageResult<i64>;
+
+    /// Delete the rows of a table stored before a point in time
+    async fn purge(&self, database: &str, table: &str, before: DateTime<Utc>)
+        -> StorageResult<u64>;
+
+    /// Give the space freed by purged rows back
+    async fn vacuum(&self, database: &str, mode: VacuumMode) -> StorageResult<()>;
+
+    /// Write a consistent copy of a database to the file `target`, while the
+    /// database carries on taking writes
+    async fn backup(&self, database: &str, target: &Path) -> StorageResult<()>;
+
+    /// Take the lease `name` for `holder`, or renew it, until `ttl` from now
+    /// false while another holder has a lease which has not expired
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> StorageResult<bool>;
+
+    /// Give up a lease of `holder` so another holder can take it at once
+    async fn release_lease(&self, name: &str, holder: &str) -> StorageResult<()>;
+
+    /// Leave the databases clean before the server exits, once nothing else
+    /// uses the storage
+    async fn close(&self) -> StorageResult<()>;
+
+    /// Check the backend can take writes, the server is ready when every check is healthy
+    async fn health(&self) -> Vec<HealthCheck>;
+}
+
+/// How to give the space of deleted rows back
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VacuumMode {
+    /// Leave the space for new rows to reuse
+    #[default]
+    None,
+    /// Rebuild the database
+    Full,
+    /// Release free pages when the database was created with auto_vacuum=INCREMENTAL
+    Incremental,
+}
+
+/// Make sure a database or table name is safe to use as a file name and SQL identifier
+/// Names starting with `_` or `sqlite_` are reserved for internal use
+pub fn validate_name(name: &str) -> StorageResult<()> {
+    let sane = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('_')
+        && !name.to_lowercase().starts_with("sqlite_")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match sane {
+        true => Ok(()),
+        false => Err(Error::InvalidName(name.to_string())),
+    }
+}
+
+/// The number of records of a batch to be stored, leaving out the records
+/// whose idempotency key is stored or repeated earlier in the batch
+fn new_records(records: &[NewRecord], stored: &HashMap<&str, Inserted>) -> i64 {
+    let mut keys = HashSet::new();
+    records
+        .iter()
+        .filter(|record| match record.idempotency_key.as_deref() {
+            Some(key) => !stored.contains_key(key) && keys.insert(key),
+            None => true,
+        })
+        .count() as i64
+}
+
+/// A batch made entirely of records which are already stored
+fn duplicates(records: &[NewRecord], stored: &HashMap<&str, Inserted>) -> Vec<Inserted> {
+    records
+        .iter()
+        .filter_map(|record| stored.get(record.idempotency_key.as_deref()?).cloned())
+        .collect()
+}
+
+/// The column rows are read in order of, the primary key when paging by `after_id`
+fn order_column(after_id: Option<i64>) -> &'static str {
+    match after_id {
+        Some(_) => "id",
+        None => "seq",
+    }
+}
+
+/// Quote a SQL identifier
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("events").is_ok());
+        assert!(validate_name("site-a_2024").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("_sequences").is_err());
+        assert!(validate_name("../etc").is_err());
+        assert!(validate_name("events; DROP TABLE events").is_err());
+        assert_eq!(quote("a\"b"), "\"a\"\"b\"");
+    }
+}
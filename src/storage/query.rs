@@ -0,0 +1,204 @@
+// Filters on the values inside stored documents
+//
+// GET /<database>/<table>/query?path=$.level&eq=error
+// POST /<database>/<table>/query
+// {"where": [{"path": "$.level", "eq": "error"}, {"path": "$.ms", "gt": 250}]}
+//
+// Each condition compares the value a JSON path points at, conditions are
+// combined with AND. Paths and values are always bound as parameters, only
+// the comparison operators are written into the SQL.
+use serde::Deserialize;
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::jsonpath::{JsonPath, Segment};
+
+/// The most conditions a single query can have
+pub const MAX_CONDITIONS: usize = 16;
+
+/// How the value a path points at is compared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// SQL LIKE on text values, `%` and `_` are wildcards
+    Like,
+    /// Whether the path points at anything, the value is a boolean
+    Exists,
+}
+
+impl Comparison {
+    /// The SQL operator of the comparison
+    pub fn operator(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "<>",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Like => "LIKE",
+            Comparison::Exists => "IS NOT NULL",
+        }
+    }
+}
+
+/// A single condition on the documents of a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub path: JsonPath,
+    pub comparison: Comparison,
+    pub value: Value,
+}
+
+/// Which documents to read from a table
+#[derive(Clone, Debug, Default)]
+pub struct QueryFilter {
+    pub conditions: Vec<Condition>,
+    pub since: i64,
+    /// Only the rows with a greater `id`, read in `id` order instead of `seq` order
+    pub after_id: Option<i64>,
+    pub limit: i64,
+}
+
+/// A condition as written in a request, with exactly one comparison
+/// {"path": "$.level", "eq": "error"}
+#[derive(Clone, Debug, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionSpec {
+    #[schema(value_type = String, example = "$.level")]
+    pub path: JsonPath,
+    pub eq: Option<Value>,
+    pub ne: Option<Value>,
+    pub lt: Option<Value>,
+    pub le: Option<Value>,
+    pub gt: Option<Value>,
+    pub ge: Option<Value>,
+    pub like: Option<String>,
+    pub exists: Option<bool>,
+}
+
+impl TryFrom<ConditionSpec> for Condition {
+    type Error = String;
+
+    fn try_from(spec: ConditionSpec) -> Result<Self, Self::Error> {
+        let path = spec.path.to_string();
+        let comparisons = [
+            (Comparison::Eq, spec.eq),
+            (Comparison::Ne, spec.ne),
+            (Comparison::Lt, spec.lt),
+            (Comparison::Le, spec.le),
+            (Comparison::Gt, spec.gt),
+            (Comparison::Ge, spec.ge),
+            (Comparison::Like, spec.like.map(Value::String)),
+            (Comparison::Exists, spec.exists.map(Value::Bool)),
+        ];
+        let mut given = comparisons
+            .into_iter()
+            .filter_map(|(comparison, value)| Some((comparison, value?)));
+        let (comparison, value) = given
+            .next()
+            .ok_or_else(|| format!("no comparison for {path}, e.g. eq, gt or like"))?;
+        if given.next().is_some() {
+            return Err(format!("more than one comparison for {path}"));
+        }
+        if value.is_null() {
+            return Err(format!("{path} can't be compared with null, use exists"));
+        }
+        let quoted = spec.path.segments.iter().any(|segment| match segment {
+            Segment::Key(key) => key.contains('"'),
+            Segment::Index(_) => false,
+        });
+        if quoted {
+            return Err(format!("{path} has a key with a double quote"));
+        }
+        let ordered = !matches!(
+            comparison,
+            Comparison::Eq | Comparison::Ne | Comparison::Exists
+        );
+        if ordered && !matches!(value, Value::Number(_) | Value::String(_)) {
+            return Err(format!(
+                "{path} can only be ordered against a number or text"
+            ));
+        }
+        Ok(Condition {
+            path: spec.path,
+            comparison,
+            value,
+        })
+    }
+}
+
+/// A value given in a query string, `100` is a number, `true` a boolean and
+/// `"100"` the text 100, anything which is not JSON is text
+pub fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// The path in the syntax of the SQLite JSON functions, `$."site"[0]`
+/// https://www.sqlite.org/json1.html#path_arguments
+pub fn sqlite_path(path: &JsonPath) -> String {
+    let mut sqlite_path = String::from("$");
+    for segment in &path.segments {
+        match segment {
+            Segment::Key(key) => sqlite_path.push_str(&format!(".\"{key}\"")),
+            Segment::Index(index) => sqlite_path.push_str(&format!("[{index}]")),
+        }
+    }
+    sqlite_path
+}
+
+/// The path as the text array of the PostgreSQL `#>` operator
+/// https://www.postgresql.org/docs/current/functions-json.html
+#[cfg(feature = "postgres")]
+pub fn postgres_path(path: &JsonPath) -> Vec<String> {
+    path.segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Key(key) => key.clone(),
+            Segment::Index(index) => index.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_condition() {
+        let spec: ConditionSpec =
+            serde_json::from_value(json!({"path": "$.site[\"a b\"][0]", "gt": 250})).unwrap();
+        let condition = Condition::try_from(spec).unwrap();
+        assert_eq!(condition.comparison, Comparison::Gt);
+        assert_eq!(sqlite_path(&condition.path), "$.\"site\".\"a b\"[0]");
+        #[cfg(feature = "postgres")]
+        assert_eq!(postgres_path(&condition.path), vec!["site", "a b", "0"]);
+
+        // Exactly one comparison, ordered against numbers or text
+        let spec: ConditionSpec = serde_json::from_value(json!({"path": "$.a"})).unwrap();
+        assert!(Condition::try_from(spec).is_err());
+        let spec: ConditionSpec =
+            serde_json::from_value(json!({"path": "$.a", "eq": 1, "ne": 2})).unwrap();
+        assert!(Condition::try_from(spec).is_err());
+        let spec: ConditionSpec =
+            serde_json::from_value(json!({"path": "$.a", "lt": true})).unwrap();
+        assert!(Condition::try_from(spec).is_err());
+        let spec = ConditionSpec {
+            path: "$.a".parse().unwrap(),
+            eq: Some(parse_value("null")),
+            ..Default::default()
+        };
+        assert!(Condition::try_from(spec).is_err());
+
+        assert_eq!(parse_value("100"), json!(100));
+        assert_eq!(parse_value("\"100\""), json!("100"));
+        assert_eq!(parse_value("error"), json!("error"));
+    }
+}
@@ -0,0 +1,332 @@
+// The table layout every backend is expected to have
+//
+// Tables created by an older version, or altered by hand, are compared to
+// the expected layout the first time they are opened. Columns added in later
+// versions are added in place, anything else is reported as a diff instead
+// of inserting into an incompatible layout.
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// What to do when an existing table does not match the expected layout
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaMode {
+    /// Add missing columns and indexes, fail on incompatible differences
+    #[default]
+    Migrate,
+    /// Fail on any difference
+    Strict,
+}
+
+/// Which backend a column type is spelled for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Sqlite,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+/// An expected column
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub sqlite_type: &'static str,
+    // Only read by the postgres backend
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    pub postgres_type: &'static str,
+    /// Column definition used to add the column to an older table
+    pub sqlite_add: Option<&'static str>,
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    pub postgres_add: Option<&'static str>,
+}
+
+impl Column {
+    pub fn sql_type(&self, dialect: Dialect) -> &'static str {
+        match dialect {
+            Dialect::Sqlite => self.sqlite_type,
+            #[cfg(feature = "postgres")]
+            Dialect::Postgres => self.postgres_type,
+        }
+    }
+
+    pub fn add(&self, dialect: Dialect) -> Option<&'static str> {
+        match dialect {
+            Dialect::Sqlite => self.sqlite_add,
+            #[cfg(feature = "postgres")]
+            Dialect::Postgres => self.postgres_add,
+        }
+    }
+}
+
+/// The columns of a data table
+pub const COLUMNS: &[Column] = &[
+    Column {
+        name: "id",
+        sqlite_type: "INTEGER",
+        postgres_type: "bigint",
+        sqlite_add: None,
+        postgres_add: None,
+    },
+    Column {
+        name: "seq",
+        sqlite_type: "INTEGER",
+        postgres_type: "bigint",
+        sqlite_add: Some("INTEGER NOT NULL DEFAULT 0"),
+        postgres_add: Some("BIGINT NOT NULL DEFAULT 0"),
+    },
+    Column {
+        name: "timestamp",
+        sqlite_type: "DATETIME",
+        postgres_type: "timestamp with time zone",
+        sqlite_add: None,
+        postgres_add: None,
+    },
+    Column {
+        name: "data",
+        sqlite_type: "TEXT",
+        postgres_type: "jsonb",
+        sqlite_add: None,
+        postgres_add: None,
+    },
+    Column {
+        name: "ordering_key",
+        sqlite_type: "TEXT",
+        postgres_type: "text",
+        sqlite_add: Some("TEXT"),
+        postgres_add: Some("TEXT"),
+    },
+    Column {
+        name: "idempotency_key",
+        sqlite_type: "TEXT",
+        postgres_type: "text",
+        sqlite_add: Some("TEXT"),
+        postgres_add: Some("TEXT"),
+    },
+    Column {
+        name: "source_ip",
+        sqlite_type: "TEXT",
+        postgres_type: "text",
+        sqlite_add: Some("TEXT"),
+        postgres_add: Some("TEXT"),
+    },
+    Column {
+        name: "user_agent",
+        sqlite_type: "TEXT",
+        postgres_type: "text",
+        sqlite_add: Some("TEXT"),
+        postgres_add: Some("TEXT"),
+    },
+    Column {
+        name: "request_id",
+        sqlite_type: "TEXT",
+        postgres_type: "text",
+        sqlite_add: Some("TEXT"),
+        postgres_add: Some("TEXT"),
+    },
+];
+
+/// The index name suffixes of a data table, see `index_name`
+pub const INDEXES: &[&str] = &["seq", "ordering_key", "idempotency_key"];
+
+/// The name of an index of a data table, `_idx_<table>_<suffix>`
+/// Indexes share the namespace of tables, the `_` no table name starts with
+/// keeps them apart
+pub fn index_name(table: &str, suffix: &str) -> String {
+    format!("_idx_{table}_{suffix}")
+}
+
+/// The name an older version gave an index of a data table, still accepted
+pub fn legacy_index_name(table: &str, suffix: &str) -> String {
+    format!("{table}_{suffix}")
+}
+
+/// A column found in an existing table
+#[derive(Clone, Debug)]
+pub struct ActualColumn {
+    pub name: String,
+    pub sql_type: String,
+    pub not_null: bool,
+    pub has_default: bool,
+}
+
+/// The differences between an existing table and the expected layout
+#[derive(Debug, Default)]
+pub struct SchemaDiff {
+    pub table: String,
+    pub missing: Vec<&'static Column>,
+    /// (column, expected type, found type)
+    pub mismatched: Vec<(&'static Column, &'static str, String)>,
+    pub extra: Vec<ActualColumn>,
+    pub missing_indexes: Vec<String>,
+}
+
+impl SchemaDiff {
+    /// Compare an existing table to the expected layout
+    pub fn new(
+        dialect: Dialect,
+        table: &str,
+        columns: &[ActualColumn],
+        indexes: &[String],
+    ) -> Self {
+        let mut diff = SchemaDiff {
+            table: table.to_string(),
+            ..Default::default()
+        };
+        for expected in COLUMNS {
+            match columns.iter().find(|column| column.name == expected.name) {
+                None => diff.missing.push(expected),
+                Some(column)
+                    if !column
+                        .sql_type
+                        .eq_ignore_ascii_case(expected.sql_type(dialect)) =>
+                {
+                    diff.mismatched.push((
+                        expected,
+                        expected.sql_type(dialect),
+                        column.sql_type.clone(),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        diff.extra = columns
+            .iter()
+            .filter(|column| !COLUMNS.iter().any(|expected| expected.name == column.name))
+            .cloned()
+            .collect();
+        diff.missing_indexes = INDEXES
+            .iter()
+            .filter(|suffix| !indexes.contains(&legacy_index_name(table, suffix)))
+            .map(|suffix| index_name(table, suffix))
+            .filter(|index| !indexes.contains(index))
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty()
+            && self.mismatched.is_empty()
+            && self.extra.is_empty()
+            && self.missing_indexes.is_empty()
+    }
+
+    /// Whether the table can be brought up to date without losing data
+    /// Extra columns are kept as long as an insert can leave them out
+    pub fn is_repairable(&self, dialect: Dialect) -> bool {
+        self.mismatched.is_empty()
+            && self
+                .missing
+                .iter()
+                .all(|column| column.add(dialect).is_some())
+            && self
+                .extra
+                .iter()
+                .all(|column| !column.not_null || column.has_default)
+    }
+}
+
+impl fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "table {} does not match the expected schema:",
+            self.table
+        )?;
+        for column in &self.missing {
+            write!(f, " [- missing column {}]", column.name)?;
+        }
+        for (column, expected, found) in &self.mismatched {
+            write!(
+                f,
+                " [~ column {} is {found}, expected {expected}]",
+                column.name
+            )?;
+        }
+        for column in &self.extra {
+            let not_null = match column.not_null && !column.has_default {
+                true => " NOT NULL without a default",
+                false => "",
+            };
+            write!(
+                f,
+                " [+ unexpected column {} {}{not_null}]",
+                column.name, column.sql_type
+            )?;
+        }
+        for index in &self.missing_indexes {
+            write!(f, " [- missing index {index}]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, sql_type: &str, not_null: bool) -> ActualColumn {
+        ActualColumn {
+            name: name.to_string(),
+            sql_type: sql_type.to_string(),
+            not_null,
+            has_default: false,
+        }
+    }
+
+    #[test]
+    fn test_schema_diff() {
+        // A table created before sequence numbers, ordering and idempotency keys
+        // and the metadata columns
+        let columns = vec![
+            column("id", "INTEGER", false),
+            column("timestamp", "DATETIME", true),
+            column("data", "TEXT", true),
+        ];
+        let diff = SchemaDiff::new(Dialect::Sqlite, "events", &columns, &[]);
+        assert!(!diff.is_empty());
+        assert!(diff.is_repairable(Dialect::Sqlite));
+        assert_eq!(diff.missing.len(), 6);
+        assert_eq!(
+            diff.missing_indexes,
+            vec![
+                "_idx_events_seq",
+                "_idx_events_ordering_key",
+                "_idx_events_idempotency_key"
+            ]
+        );
+
+        // Indexes named by an older version are accepted
+        let indexes = vec![
+            String::from("events_seq"),
+            String::from("_idx_events_ordering_key"),
+        ];
+        let diff = SchemaDiff::new(Dialect::Sqlite, "events", &columns, &indexes);
+        assert_eq!(diff.missing_indexes, vec!["_idx_events_idempotency_key"]);
+
+        // A table altered by hand
+        let columns = vec![
+            column("id", "INTEGER", false),
+            column("seq", "INTEGER", true),
+            column("timestamp", "DATETIME", true),
+            column("data", "BLOB", true),
+            column("ordering_key", "TEXT", false),
+            column("idempotency_key", "TEXT", false),
+            column("source_ip", "TEXT", false),
+            column("user_agent", "TEXT", false),
+            column("request_id", "TEXT", false),
+            column("owner", "TEXT", true),
+        ];
+        let indexes = vec![
+            String::from("_idx_events_seq"),
+            String::from("_idx_events_ordering_key"),
+            String::from("_idx_events_idempotency_key"),
+        ];
+        let diff = SchemaDiff::new(Dialect::Sqlite, "events", &columns, &indexes);
+        assert!(!diff.is_repairable(Dialect::Sqlite));
+        let message = diff.to_string();
+        assert!(message.contains("column data is BLOB, expected TEXT"));
+        assert!(message.contains("unexpected column owner TEXT NOT NULL without a default"));
+    }
+}
@@ -0,0 +1,303 @@
+// Shadow storage, every operation is sent to a second backend and compared
+//
+// [storage]
+// backend = "sqlite"
+//
+// [storage.shadow]
+// backend = "postgres"
+// dsn = "postgres://receiver@db:5432/receiver"
+//
+// The primary backend answers every request. The shadow backend gets the
+// same writes and reads at the same time, its errors are logged and never
+// fail a request. Where the two disagree the divergence is counted in the
+// `actix_data_receiver_shadow_divergences_total` metric by operation and
+// kind, so a migration can be watched before cutting over to the shadow.
+//
+// A shadow which starts empty diverges on the sequence numbers of tables
+// which already have rows, copy the existing rows over first.
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::future::join;
+use tracing::warn;
+
+use super::{
+    ChangeEvent, ChangesFilter, HealthCheck, Inserted, NewRecord, QueryFilter, SnapshotStream,
+    StatsBucket, StatsFilter, Storage, StorageResult, VacuumMode,
+};
+use crate::metrics;
+
+/// A primary backend with a shadow it is compared to
+pub struct ShadowStorage {
+    primary: Arc<dyn Storage>,
+    shadow: Arc<dyn Storage>,
+}
+
+impl ShadowStorage {
+    pub fn new(primary: Arc<dyn Storage>, shadow: Arc<dyn Storage>) -> Self {
+        ShadowStorage { primary, shadow }
+    }
+}
+
+/// Count and log where the shadow disagrees with the primary
+/// The primary result is returned as it is
+fn compare<T, K: PartialEq>(
+    operation: &str,
+    primary: StorageResult<T>,
+    shadow: StorageResult<T>,
+    key: impl Fn(&T) -> K,
+) -> StorageResult<T> {
+    let kind = match (&primary, &shadow) {
+        (Ok(primary), Ok(shadow)) if key(primary) == key(shadow) => None,
+        (Ok(_), Ok(_)) => Some("result"),
+        (Ok(_), Err(err)) => {
+            warn!("shadow {operation} failed: {err}");
+            Some("shadow_error")
+        }
+        (Err(_), Ok(_)) => Some("primary_error"),
+        (Err(_), Err(_)) => None,
+    };
+    metrics::SHADOW_OPERATIONS
+        .with_label_values(&[operation])
+        .inc();
+    if let Some(kind) = kind {
+        metrics::SHADOW_DIVERGENCES
+            .with_label_values(&[operation, kind])
+            .inc();
+    }
+    primary
+}
+
+// Rows are compared by sequence number and document, ids and the precision
+// of timestamps can differ between backends
+fn events(events: &[ChangeEvent]) -> Vec<(i64, serde_json::Value)> {
+    events
+        .iter()
+        .map(|event| (event.seq, event.data.clone()))
+        .collect()
+}
+
+#[async_trait]
+impl Storage for ShadowStorage {
+    async fn insert_batch(
+        &self,
+        database: &str,
+        table: &str,
+        records: Vec<NewRecord>,
+    ) -> StorageResult<Vec<Inserted>> {
+        let (primary, shadow) = join(
+            self.primary.insert_batch(database, table, records.clone()),
+            self.shadow.insert_batch(database, table, records),
+        )
+        .await;
+        compare("insert", primary, shadow, |inserted| {
+            inserted
+                .iter()
+                .map(|inserted| (inserted.seq, inserted.duplicate))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    async fn changes(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &ChangesFilter,
+    ) -> StorageResult<Vec<ChangeEvent>> {
+        let (primary, shadow) = join(
+            self.primary.changes(database, table, filter),
+            self.shadow.changes(database, table, filter),
+        )
+        .await;
+        compare("changes", primary, shadow, |result| events(result))
+    }
+
+    async fn query(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &QueryFilter,
+    ) -> StorageResult<Vec<ChangeEvent>> {
+        let (primary, shadow) = join(
+            self.primary.query(database, table, filter),
+            self.shadow.query(database, table, filter),
+        )
+        .await;
+        compare("query", primary, shadow, |result| events(result))
+    }
+
+    async fn stats(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &StatsFilter,
+    ) -> StorageResult<Vec<StatsBucket>> {
+        let (primary, shadow) = join(
+            self.primary.stats(database, table, filter),
+            self.shadow.stats(database, table, filter),
+        )
+        .await;
+        compare("stats", primary, shadow, Clone::clone)
+    }
+
+    // A snapshot is streamed from the primary alone, the shadow is not compared
+    fn snapshot(&self, database: &str, tables: &[String], limit: i64) -> SnapshotStream {
+        self.primary.snapshot(database, tables, limit)
+    }
+
+    async fn last_seq(&self, database: &str, table: &str) -> StorageResult<i64> {
+        let (primary, shadow) = join(
+            self.primary.last_seq(database, table),
+            self.shadow.last_seq(database, table),
+        )
+        .await;
+        compare("last_seq", primary, shadow, |last_seq| *last_seq)
+    }
+
+    async fn databases(&self) -> StorageResult<Vec<String>> {
+        let (primary, shadow) = join(self.primary.databases(), self.shadow.databases()).await;
+        compare("databases", primary, shadow, Clone::clone)
+    }
+
+    async fn tables(&self, database: &str) -> StorageResult<Vec<String>> {
+        let (primary, shadow) =
+            join(self.primary.tables(database), self.shadow.tables(database)).await;
+        compare("tables", primary, shadow, Clone::clone)
+    }
+
+    fn insert_statements(&self, database: &str, table: &str) -> StorageResult<Vec<String>> {
+        self.primary.insert_statements(database, table)
+    }
+
+    async fn size(&self, database: &str) -> StorageResult<u64> {
+        // Backends lay data out differently, sizes are not compared
+        self.primary.size(database).await
+    }
+
+    async fn row_count(&self, database: &str, table: &str) -> StorageResult<i64> {
+        let (primary, shadow) = join(
+            self.primary.row_count(database, table),
+            self.shadow.row_count(database, table),
+        )
+        .await;
+        compare("row_count", primary, shadow, |rows| *rows)
+    }
+
+    async fn row_estimate(&self, database: &str, table: &str) -> StorageResult<i64> {
+        // Estimates are made differently by each backend, they are not compared
+        self.primary.row_estimate(database, table).await
+    }
+
+    async fn purge(
+        &self,
+        database: &str,
+        table: &str,
+        before: DateTime<Utc>,
+    ) -> StorageResult<u64> {
+        let (primary, shadow) = join(
+            self.primary.purge(database, table, before),
+            self.shadow.purge(database, table, before),
+        )
+        .await;
+        compare("purge", primary, shadow, |purged| *purged)
+    }
+
+    async fn vacuum(&self, database: &str, mode: VacuumMode) -> StorageResult<()> {
+        let (primary, shadow) = join(
+            self.primary.vacuum(database, mode),
+            self.shadow.vacuum(database, mode),
+        )
+        .await;
+        compare("vacuum", primary, shadow, |_| ())
+    }
+
+    // The shadow only ever holds a copy of the primary
+    async fn backup(&self, database: &str, target: &Path) -> StorageResult<()> {
+        self.primary.backup(database, target).await
+    }
+
+    // Leases coordinate the instances sharing the primary
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> StorageResult<bool> {
+        self.primary.acquire_lease(name, holder, ttl).await
+    }
+
+    async fn release_lease(&self, name: &str, holder: &str) -> StorageResult<()> {
+        self.primary.release_lease(name, holder).await
+    }
+
+    async fn close(&self) -> StorageResult<()> {
+        let (primary, shadow) = join(self.primary.close(), self.shadow.close()).await;
+        if let Err(err) = shadow {
+            warn!("unable to close the shadow backend: {err}");
+        }
+        primary
+    }
+
+    // Only the primary decides readiness, the shadow failing never fails a request
+    async fn health(&self) -> Vec<HealthCheck> {
+        self.primary.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{record, SqliteStorage};
+
+    #[actix_web::test]
+    async fn test_shadow() {
+        let directory = std::env::temp_dir().join("adr_test_shadow");
+        std::fs::create_dir_all(directory.join("shadow")).unwrap();
+        let primary = SqliteStorage::new(directory.to_str().unwrap());
+        let shadow = SqliteStorage::new(directory.join("shadow").to_str().unwrap());
+
+        // The primary already has a row the shadow does not
+        primary
+            .insert_batch("test", "events", vec![record("{\"old\": 1}")])
+            .await
+            .unwrap();
+        let storage = ShadowStorage::new(Arc::new(primary), Arc::new(shadow.clone()));
+
+        let divergences = || {
+            metrics::SHADOW_DIVERGENCES
+                .with_label_values(&["insert", "result"])
+                .get()
+        };
+        let before = divergences();
+        let inserted = storage
+            .insert_batch("test", "events", vec![record("{\"new\": 2}")])
+            .await
+            .unwrap();
+        assert_eq!(inserted[0].seq, 2);
+        assert_eq!(divergences(), before + 1);
+
+        // Both got the write, the primary answers
+        let filter = ChangesFilter {
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            storage
+                .changes("test", "events", &filter)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            shadow
+                .changes("test", "events", &filter)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Post test, remove any files created
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,1549 @@
+// SQLite storage, one database file per database name
+// https://www.sqlite.org/about.html
+// https://www.sqlite.org/lang.html
+// https://www.sqlite.org/json1.html
+// https://www.sqlite.org/pragma.html
+// https://www.sqlite.org/wal.html
+//
+// With the `sqlcipher` feature SQLite is built as SQLCipher, and a key
+// (`--db-key`, `--db-key-file` or `key`/`key_file` of [storage.sqlite])
+// encrypts every database file. The key is set on each connection before it
+// reads anything, backups are encrypted with it too.
+// https://www.zetetic.net/sqlcipher/sqlcipher-api/
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::rt::task::spawn_blocking;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures_util::stream;
+use rusqlite::{
+    backup::{Backup, StepResult},
+    named_params, params_from_iter,
+    types::Value as SqlValue,
+    Connection, OpenFlags, OptionalExtension, Row, TransactionBehavior,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use super::query::{sqlite_path, Comparison, QueryFilter};
+use super::schema::{
+    index_name, legacy_index_name, ActualColumn, Dialect, SchemaDiff, SchemaMode, INDEXES,
+};
+use super::stats::{StatsBucket, StatsFilter, MAX_BUCKETS};
+use super::{
+    duplicates, new_records, order_column, quote, validate_name, ChangeEvent, ChangesFilter, Error,
+    HealthCheck, Inserted, NewRecord, SnapshotStream, Storage, StorageResult, VacuumMode,
+};
+
+/// How commits are journaled, WAL lets readers carry on while a write commits
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+/// How often a commit waits for the disk
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    /// Durable except for the last commits before a power loss in WAL mode
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+}
+
+impl Synchronous {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "off",
+            Synchronous::Normal => "normal",
+            Synchronous::Full => "full",
+            Synchronous::Extra => "extra",
+        }
+    }
+}
+
+/// The pragmas set on every connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SqlitePragmas {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// How long a connection waits for a lock held by another
+    pub busy_timeout: Duration,
+    /// Pages cached per connection, or KiB when negative
+    pub cache_size: i64,
+}
+
+impl Default for SqlitePragmas {
+    fn default() -> Self {
+        SqlitePragmas {
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout: Duration::from_secs(5),
+            cache_size: -2000,
+        }
+    }
+}
+
+impl SqlitePragmas {
+    /// Set the pragmas on a connection, the journal mode only when it can write
+    fn apply(&self, conn: &Connection, writable: bool) -> StorageResult<()> {
+        conn.busy_timeout(self.busy_timeout)?;
+        if writable {
+            // The journal mode is kept in the file, SQLite answers with the
+            // mode in use which differs when the file system can't do WAL
+            let journal_mode: String = conn.pragma_update_and_check(
+                None,
+                "journal_mode",
+                self.journal_mode.as_str(),
+                |row| row.get(0),
+            )?;
+            if !journal_mode.eq_ignore_ascii_case(self.journal_mode.as_str()) {
+                warn!(
+                    "journal_mode {} is not available, using {journal_mode}",
+                    self.journal_mode.as_str()
+                );
+            }
+        }
+        conn.pragma_update(None, "synchronous", self.synchronous.as_str())?;
+        conn.pragma_update(None, "cache_size", self.cache_size)?;
+        Ok(())
+    }
+}
+
+/// The key SQLCipher encrypts the database files with, kept out of logs
+#[derive(Clone, Deserialize, Hash, PartialEq)]
+#[serde(transparent)]
+pub struct DbKey(String);
+
+impl DbKey {
+    pub fn new(key: &str) -> Self {
+        DbKey(key.to_string())
+    }
+}
+
+impl fmt::Debug for DbKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DbKey(..)")
+    }
+}
+
+/// Databases kept as `<database_files>/<database name>.db`
+#[derive(Clone, Debug)]
+pub struct SqliteStorage {
+    database_files: String,
+    schema_mode: SchemaMode,
+    pragmas: SqlitePragmas,
+    key: Option<DbKey>,
+    // The tables already checked against the expected layout
+    checked: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl SqliteStorage {
+    pub fn new(database_files: &str) -> Self {
+        SqliteStorage {
+            database_files: database_files.to_string(),
+            schema_mode: SchemaMode::default(),
+            pragmas: SqlitePragmas::default(),
+            key: None,
+            checked: Arc::default(),
+        }
+    }
+
+    /// What to do when an existing table does not match the expected layout
+    pub fn schema_mode(mut self, schema_mode: SchemaMode) -> Self {
+        self.schema_mode = schema_mode;
+        self
+    }
+
+    /// The pragmas set on every connection
+    pub fn pragmas(mut self, pragmas: SqlitePragmas) -> Self {
+        self.pragmas = pragmas;
+        self
+    }
+
+    /// The key the database files are encrypted with, by a SQLCipher build
+    pub fn key(mut self, key: Option<DbKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Unlock an encrypted database, then set the pragmas on a connection
+    fn setup(&self, conn: &Connection, writable: bool) -> StorageResult<()> {
+        if let Some(key) = &self.key {
+            // Nothing can be read before the key is set
+            conn.pragma_update(None, "key", &key.0)?;
+        }
+        self.pragmas.apply(conn, writable)
+    }
+
+    /// Encrypt a plaintext database in place with the key, while no server
+    /// uses it
+    pub fn encrypt(&self, database: &str) -> StorageResult<()> {
+        validate_name(database)?;
+        let path = self.path(database);
+        if !Path::new(&path).exists() {
+            return Err(Error::NotFound(database.to_string()));
+        }
+        self.encrypt_file(&path)?;
+        info!("encrypted database {database}");
+        Ok(())
+    }
+
+    /// Encrypt the leases in place with the key while they are in plaintext,
+    /// true when they were
+    /// Once a key is set `_leases.db` is opened with it like any database
+    pub fn encrypt_leases(&self) -> StorageResult<bool> {
+        let path = self.path("_leases");
+        if !plaintext(&path) {
+            return Ok(false);
+        }
+        self.encrypt_file(&path)?;
+        info!("encrypted the leases");
+        Ok(true)
+    }
+
+    /// Replace a plaintext database file with a copy encrypted with the key
+    fn encrypt_file(&self, path: &str) -> StorageResult<()> {
+        let Some(key) = &self.key else {
+            return Err(Error::Internal(String::from("no key to encrypt with")));
+        };
+        // The encrypted copy replaces the database once it is complete
+        let encrypted = format!("{path}.encrypted");
+        let _ = fs::remove_file(&encrypted);
+        // Attached databases share the flags, the copy has to be created
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "ATTACH DATABASE ?1 AS encrypted KEY ?2;",
+            (&encrypted, &key.0),
+        )?;
+        conn.query_row("SELECT sqlcipher_export('encrypted');", [], |_| Ok(()))?;
+        conn.execute_batch("DETACH DATABASE encrypted;")?;
+        // Closing the last connection moves the write-ahead log into the file
+        conn.close().map_err(|(_, err)| err)?;
+        fs::rename(&encrypted, path)
+            .map_err(|err| Error::Internal(format!("unable to replace {path}: {err}")))
+    }
+
+    /// Rebuild a table created by an older version so the ids of deleted rows
+    /// are never handed out again, true when it was rebuilt
+    /// Writes to the table wait until every row is copied
+    pub fn rebuild_ids(&self, database: &str, table: &str) -> StorageResult<bool> {
+        validate_name(database)?;
+        validate_name(table)?;
+        if !Path::new(&self.path(database)).exists() {
+            return Err(Error::NotFound(database.to_string()));
+        }
+        let mut conn = self.open(database)?;
+        match reuses_ids(&conn, table) {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(Error::Sqlite(rusqlite::Error::QueryReturnedNoRows)) => {
+                return Err(Error::NotFound(table.to_string()))
+            }
+            Err(err) => return Err(err),
+        }
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        rebuild_table(&tx, table)?;
+        tx.commit()?;
+        info!("rebuilt table {table} of database {database} so the ids of deleted rows are not reused");
+        Ok(true)
+    }
+
+    /// Create or check a table the first time it is used
+    fn prepare_table(
+        &self,
+        conn: &mut Connection,
+        database: &str,
+        table: &str,
+    ) -> StorageResult<()> {
+        let key = (database.to_string(), table.to_string());
+        if self.checked.lock().unwrap().contains(&key) {
+            return Ok(());
+        }
+        create_table(conn, table, self.schema_mode)?;
+        self.checked.lock().unwrap().insert(key);
+        Ok(())
+    }
+
+    /// The file path of a database
+    pub fn path(&self, database: &str) -> String {
+        let database_files = &self.database_files;
+        format!("{database_files}/{database}.db")
+    }
+
+    /// Get a handle to the database
+    /// The database will be created as needed
+    fn open(&self, database: &str) -> StorageResult<Connection> {
+        validate_name(database)?;
+        let conn = Connection::open(self.path(database))?;
+        self.setup(&conn, true)?;
+        Ok(conn)
+    }
+
+    /// Read the rows of a table with a SELECT ending in `FROM <table> ...`
+    fn select(
+        &self,
+        database: &str,
+        table: &str,
+        sql_from: &str,
+        params: impl rusqlite::Params,
+    ) -> StorageResult<Vec<ChangeEvent>> {
+        // Reading never creates a database
+        let conn = self.open_existing(database)?;
+        validate_name(table)?;
+        let table_name = quote(table);
+        let sql_select = format!("SELECT seq, id, timestamp, data FROM {table_name} {sql_from}");
+        let mut stmt = conn
+            .prepare(&sql_select)
+            .map_err(|err| not_found(err, table))?;
+        let events = stmt
+            .query_map(params, change_event)?
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+
+    /// Get a handle to the leases shared by the servers using the database files
+    /// kept in `_leases.db`, which no database name can clash with
+    fn open_leases(&self) -> StorageResult<Connection> {
+        let conn = Connection::open(self.path("_leases"))?;
+        self.setup(&conn, true)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS leases (
+                name TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                expires REAL NOT NULL
+            );",
+        )?;
+        Ok(conn)
+    }
+
+    /// Get a handle to a database which already exists
+    fn open_existing(&self, database: &str) -> StorageResult<Connection> {
+        validate_name(database)?;
+        let path = self.path(database);
+        if !Path::new(&path).exists() {
+            return Err(Error::NotFound(database.to_string()));
+        }
+        // Opened for writing without creating so the last connection to close
+        // can checkpoint the WAL, then kept from writing with query_only
+        let flags = OpenFlags::default() - OpenFlags::SQLITE_OPEN_CREATE;
+        let conn = Connection::open_with_flags(path, flags)?;
+        self.setup(&conn, false)?;
+        conn.pragma_update(None, "query_only", true)?;
+        Ok(conn)
+    }
+}
+
+/// Create the indexes of a table if they don't exist
+fn create_indexes(conn: &Connection, table: &str) -> StorageResult<()> {
+    let table_name = quote(table);
+    let seq_index = quote(&index_name(table, "seq"));
+    let ordering_key_index = quote(&index_name(table, "ordering_key"));
+    let idempotency_key_index = quote(&index_name(table, "idempotency_key"));
+    conn.execute_batch(&format!(
+        "CREATE UNIQUE INDEX IF NOT EXISTS {seq_index} ON {table_name} (seq);
+        CREATE INDEX IF NOT EXISTS {ordering_key_index}
+            ON {table_name} (ordering_key, seq);
+        CREATE UNIQUE INDEX IF NOT EXISTS {idempotency_key_index}
+            ON {table_name} (idempotency_key) WHERE idempotency_key IS NOT NULL;"
+    ))?;
+    Ok(())
+}
+
+/// Free a table name held by an index named by an older version, replacing
+/// the index with one named with the prefix
+fn release_index_name(conn: &Connection, name: &str) -> StorageResult<()> {
+    let owner: Option<String> = conn
+        .query_row(
+            "SELECT tbl_name FROM sqlite_master WHERE type = 'index' AND name = :name;",
+            named_params! { ":name": name },
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+    if INDEXES
+        .iter()
+        .any(|suffix| legacy_index_name(&owner, suffix) == name)
+    {
+        create_indexes(conn, &owner)?;
+        conn.execute_batch(&format!("DROP INDEX {};", quote(name)))?;
+        info!("renamed index {name} of table {owner}");
+    }
+    Ok(())
+}
+
+/// Compare an existing table to the expected layout
+fn table_diff(conn: &Connection, table: &str) -> StorageResult<SchemaDiff> {
+    let table_name = quote(table);
+    let columns = conn
+        .prepare(&format!("PRAGMA table_info({table_name});"))?
+        .query_map([], |row| {
+            Ok(ActualColumn {
+                name: row.get("name")?,
+                sql_type: row.get("type")?,
+                not_null: row.get("notnull")?,
+                has_default: row.get::<_, Option<String>>("dflt_value")?.is_some(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let indexes = conn
+        .prepare(&format!("PRAGMA index_list({table_name});"))?
+        .query_map([], |row| row.get("name"))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(SchemaDiff::new(Dialect::Sqlite, table, &columns, &indexes))
+}
+
+/// Whether a table hands out the ids of deleted rows again, as one created
+/// by an older version without AUTOINCREMENT does once the largest id is
+/// deleted
+fn reuses_ids(conn: &Connection, table: &str) -> StorageResult<bool> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = :table;",
+        named_params! { ":table": table },
+        |row| row.get(0),
+    )?;
+    Ok(!sql.to_uppercase().contains("AUTOINCREMENT"))
+}
+
+/// Bring a table created by an older version up to date
+/// Sequence numbers are handed out to existing rows in rowid order
+fn repair_table(conn: &mut Connection, diff: &SchemaDiff) -> StorageResult<()> {
+    let table = &diff.table;
+    let table_name = quote(table);
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for column in &diff.missing {
+        let definition = column.add(Dialect::Sqlite).unwrap_or_default();
+        tx.execute_batch(&format!(
+            "ALTER TABLE {table_name} ADD COLUMN {} {definition};",
+            column.name
+        ))?;
+        info!("added column {} to table {table}", column.name);
+    }
+    if diff.missing.iter().any(|column| column.name == "seq") {
+        tx.execute_batch(&format!(
+            "UPDATE {table_name} SET seq = numbered.seq
+            FROM (SELECT id, ROW_NUMBER() OVER (ORDER BY id) AS seq FROM {table_name}) AS numbered
+            WHERE {table_name}.id = numbered.id;"
+        ))?;
+        tx.execute(
+            &format!(
+                "INSERT INTO _sequences (table_name, last_seq)
+                SELECT :table_name, COALESCE(MAX(seq), 0) FROM {table_name} WHERE true
+                ON CONFLICT (table_name) DO UPDATE SET last_seq = excluded.last_seq;"
+            ),
+            named_params! { ":table_name": table },
+        )?;
+        info!("numbered the existing rows of table {table}");
+    }
+    create_indexes(&tx, table)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Copy the rows of a table into one with an AUTOINCREMENT id, which can't
+/// be added in place, keeping the other columns and the indexes as they are
+/// https://www.sqlite.org/autoinc.html
+fn rebuild_table(conn: &Connection, table: &str) -> StorageResult<()> {
+    let table_name = quote(table);
+    let mut names = vec![];
+    let mut definitions = vec![];
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table_name});"))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        let sql_type: String = row.get("type")?;
+        let mut definition = format!("{} {sql_type}", quote(&name));
+        if name == "id" {
+            definition.push_str(" PRIMARY KEY AUTOINCREMENT");
+        } else {
+            if row.get("notnull")? {
+                definition.push_str(" NOT NULL");
+            }
+            if let Some(default) = row.get::<_, Option<String>>("dflt_value")? {
+                definition.push_str(&format!(" DEFAULT ({default})"));
+            }
+        }
+        names.push(quote(&name));
+        definitions.push(definition);
+    }
+    let indexes = conn
+        .prepare(
+            "SELECT sql FROM sqlite_master
+            WHERE type = 'index' AND tbl_name = :table AND sql IS NOT NULL;",
+        )?
+        .query_map(named_params! { ":table": table }, |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    // The sqlite_sequence of the new table starts from the largest id copied
+    let names = names.join(", ");
+    conn.execute_batch(&format!(
+        "CREATE TABLE _rebuild ({});
+        INSERT INTO _rebuild ({names}) SELECT {names} FROM {table_name};
+        DROP TABLE {table_name};
+        ALTER TABLE _rebuild RENAME TO {table_name};",
+        definitions.join(", ")
+    ))?;
+    for index in indexes {
+        conn.execute_batch(&index)?;
+    }
+    Ok(())
+}
+
+/// Create the table if it doesn't exist, or check an existing table matches
+/// `seq` is a gapless per table sequence number which is independent of
+/// the rowid, so it survives a VACUUM and can be handed to consumers
+/// `id` is AUTOINCREMENT, so the ids of purged rows are never handed out
+/// again and pages can be read after an id
+fn create_table(conn: &mut Connection, table: &str, mode: SchemaMode) -> StorageResult<()> {
+    validate_name(table)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS _sequences (
+            table_name TEXT PRIMARY KEY,
+            last_seq INTEGER NOT NULL
+        );",
+    )?;
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = :table);",
+        named_params! { ":table": table },
+        |row| row.get(0),
+    )?;
+
+    if !exists {
+        release_index_name(conn, table)?;
+        let table_name = quote(table);
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {table_name} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                seq INTEGER NOT NULL,
+                timestamp DATETIME NOT NULL,
+                data TEXT NOT NULL,
+                ordering_key TEXT,
+                idempotency_key TEXT,
+                source_ip TEXT,
+                user_agent TEXT,
+                request_id TEXT
+            );"
+        ))?;
+        create_indexes(conn, table)?;
+        debug!("create table: {table}");
+        return Ok(());
+    }
+
+    // Rebuilding copies every row, which is left to the migrate subcommand
+    if reuses_ids(conn, table)? {
+        warn!(
+            "table {table} reuses the ids of deleted rows, rebuild it with the migrate subcommand"
+        );
+    }
+
+    // Fail loudly rather than insert into an incompatible layout
+    let diff = table_diff(conn, table)?;
+    if diff.is_empty() {
+        return Ok(());
+    }
+    if mode == SchemaMode::Strict || !diff.is_repairable(Dialect::Sqlite) {
+        error!("{diff}");
+        return Err(Error::Schema(diff.to_string()));
+    }
+    warn!("repairing {diff}");
+    repair_table(conn, &diff)
+}
+
+/// Claim a block of sequence numbers for the rows of a batch
+const CLAIM_SEQS_SQL: &str =
+    "INSERT INTO _sequences (table_name, last_seq) VALUES (:table_name, :count)
+ON CONFLICT (table_name) DO UPDATE SET last_seq = last_seq + :count;";
+
+/// Store a single row in a table
+fn insert_sql(table: &str) -> String {
+    let table_name = quote(table);
+    format!(
+        "INSERT INTO {table_name}
+    (seq, timestamp, data, ordering_key, idempotency_key, source_ip, user_agent, request_id)
+VALUES (:seq, :timestamp, json(:data), :ordering_key, :idempotency_key, :source_ip, :user_agent,
+    :request_id);"
+    )
+}
+
+/// Insert the data into the table
+/// The sequence numbers are claimed in the same transaction as the insert,
+/// a failed insert rolls back the claim which keeps the sequence gapless
+fn insert_records(
+    conn: &mut Connection,
+    table: &str,
+    records: &[NewRecord],
+) -> StorageResult<Vec<Inserted>> {
+    if records.is_empty() {
+        return Ok(vec![]);
+    }
+    let table_name = quote(table);
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    // The rows already stored under the idempotency keys of the batch
+    let mut stored: HashMap<&str, Inserted> = HashMap::new();
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT id, seq FROM {table_name} WHERE idempotency_key = :key;"
+        ))?;
+        for key in records
+            .iter()
+            .filter_map(|record| record.idempotency_key.as_deref())
+        {
+            let row = stmt
+                .query_row(named_params! { ":key": key }, |row| {
+                    Ok(Inserted {
+                        id: row.get(0)?,
+                        seq: row.get(1)?,
+                        duplicate: true,
+                    })
+                })
+                .optional()?;
+            if let Some(row) = row {
+                stored.insert(key, row);
+            }
+        }
+    }
+    let count = new_records(records, &stored);
+    if count == 0 {
+        return Ok(duplicates(records, &stored));
+    }
+
+    tx.execute(
+        CLAIM_SEQS_SQL,
+        named_params! { ":table_name": table, ":count": count },
+    )?;
+    let last_seq: i64 = tx.query_row(
+        "SELECT last_seq FROM _sequences WHERE table_name = :table_name;",
+        named_params! { ":table_name": table },
+        |row| row.get(0),
+    )?;
+    let mut inserted = Vec::with_capacity(records.len());
+    {
+        let mut stmt = tx.prepare(&insert_sql(table))?;
+        let mut seqs = last_seq - count + 1..;
+        for record in records {
+            let key = record.idempotency_key.as_deref();
+            if let Some(row) = key.and_then(|key| stored.get(key)) {
+                inserted.push(row.clone());
+                continue;
+            }
+            let seq = seqs.next().unwrap_or_default();
+            stmt.execute(named_params! {
+                ":seq": seq,
+                ":timestamp": record.timestamp.to_string(),
+                ":data": record.data,
+                ":ordering_key": record.ordering_key,
+                ":idempotency_key": key,
+                ":source_ip": record.metadata.source_ip,
+                ":user_agent": record.metadata.user_agent,
+                ":request_id": record.metadata.request_id,
+            })?;
+            let row = Inserted {
+                id: tx.last_insert_rowid(),
+                seq,
+                duplicate: false,
+            };
+            if let Some(key) = key {
+                let duplicate = Inserted {
+                    duplicate: true,
+                    ..row.clone()
+                };
+                stored.insert(key, duplicate);
+            }
+            inserted.push(row);
+        }
+    }
+    tx.commit()?;
+    debug!("insert count: {count}, last seq: {last_seq}");
+    Ok(inserted)
+}
+
+/// A missing table is reported as not found
+fn not_found(err: rusqlite::Error, table: &str) -> Error {
+    match err {
+        rusqlite::Error::SqliteFailure(_, Some(ref message))
+            if message.starts_with("no such table") =>
+        {
+            Error::NotFound(table.to_string())
+        }
+        err => Error::Sqlite(err),
+    }
+}
+
+/// Whether a database file is in plaintext, every plaintext SQLite file
+/// starts with the same header while an encrypted one looks random
+/// https://www.sqlite.org/fileformat.html#the_database_header
+fn plaintext(path: &str) -> bool {
+    let mut header = [0; 16];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|()| &header == b"SQLite format 3\0")
+}
+
+/// A row read by `SqliteStorage::select`
+fn change_event(row: &Row) -> rusqlite::Result<ChangeEvent> {
+    let data: String = row.get(3)?;
+    Ok(ChangeEvent {
+        seq: row.get(0)?,
+        id: row.get(1)?,
+        timestamp: row.get(2)?,
+        data: serde_json::from_str(&data).unwrap_or(Value::String(data)),
+    })
+}
+
+/// The WHERE clause of a query with its parameters in order
+/// https://www.sqlite.org/json1.html#jex
+fn query_where(filter: &QueryFilter) -> (String, Vec<SqlValue>) {
+    let mut clauses = vec![String::from("seq > ?1 AND id > ?2")];
+    let mut params = vec![
+        SqlValue::Integer(filter.since),
+        SqlValue::Integer(filter.after_id.unwrap_or(0)),
+    ];
+    for condition in &filter.conditions {
+        params.push(SqlValue::Text(sqlite_path(&condition.path)));
+        let path = params.len();
+        let clause = match condition.comparison {
+            // json_type() tells a missing value from a JSON null
+            Comparison::Exists => match condition.value == Value::Bool(true) {
+                true => format!("json_type(data, ?{path}) IS NOT NULL"),
+                false => format!("json_type(data, ?{path}) IS NULL"),
+            },
+            comparison => {
+                params.push(match &condition.value {
+                    Value::Bool(value) => SqlValue::Integer(*value as i64),
+                    Value::Number(number) => match number.as_i64() {
+                        Some(number) => SqlValue::Integer(number),
+                        None => SqlValue::Real(number.as_f64().unwrap_or_default()),
+                    },
+                    Value::String(text) => SqlValue::Text(text.clone()),
+                    value => SqlValue::Text(value.to_string()),
+                });
+                format!(
+                    "json_extract(data, ?{path}) {} ?{}",
+                    comparison.operator(),
+                    params.len()
+                )
+            }
+        };
+        clauses.push(clause);
+    }
+    params.push(SqlValue::Integer(filter.limit));
+    let sql_where = format!(
+        "WHERE {} ORDER BY {} LIMIT ?{};",
+        clauses.join(" AND "),
+        order_column(filter.after_id),
+        params.len()
+    );
+    (sql_where, params)
+}
+
+/// Send the pages of the tables of a snapshot, read in a single transaction
+/// In WAL mode the transaction reads the database as it was at its first
+/// read, other journal modes keep writers waiting until it ends
+fn read_snapshot(
+    storage: &SqliteStorage,
+    database: &str,
+    tables: &[String],
+    limit: i64,
+    sender: &mpsc::Sender<StorageResult<(String, Vec<ChangeEvent>)>>,
+) -> StorageResult<()> {
+    let mut conn = storage.open_existing(database)?;
+    let tx = conn.transaction()?;
+    tx.query_row("SELECT count(*) FROM sqlite_schema;", [], |_| Ok(()))?;
+
+    // Every table is looked up before any rows are sent
+    let mut statements = vec![];
+    for table in tables {
+        validate_name(table)?;
+        let sql_select = format!(
+            "SELECT seq, id, timestamp, data FROM {} WHERE seq > ?1 ORDER BY seq LIMIT ?2;",
+            quote(table)
+        );
+        let stmt = tx
+            .prepare(&sql_select)
+            .map_err(|err| not_found(err, table))?;
+        statements.push((table, stmt));
+    }
+
+    for (table, mut stmt) in statements {
+        let mut since = 0;
+        loop {
+            let events: Vec<ChangeEvent> = stmt
+                .query_map([since, limit], change_event)?
+                .collect::<Result<_, _>>()?;
+            let Some(last) = events.last() else {
+                break;
+            };
+            since = last.seq;
+            let last_page = (events.len() as i64) < limit;
+            // The response is gone, nothing is reading the pages anymore
+            if sender.blocking_send(Ok((table.clone(), events))).is_err() {
+                return Ok(());
+            }
+            if last_page {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run blocking SQLite work off of the async workers
+async fn blocking<T, F>(f: F) -> StorageResult<T>
+where
+    F: FnOnce() -> StorageResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_blocking(f)
+        .await
+        .map_err(|err| Error::Internal(err.to_string()))?
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert_batch(
+        &self,
+        database: &str,
+        table: &str,
+        records: Vec<NewRecord>,
+    ) -> StorageResult<Vec<Inserted>> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        blocking(move || {
+            let mut conn = storage.open(&database)?;
+            storage.prepare_table(&mut conn, &database, &table)?;
+            insert_records(&mut conn, &table, &records)
+        })
+        .await
+    }
+
+    async fn changes(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &ChangesFilter,
+    ) -> StorageResult<Vec<ChangeEvent>> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        let filter = filter.clone();
+        let sql_where = format!(
+            "WHERE seq > :since AND id > :after_id AND (:key IS NULL OR ordering_key = :key)
+            ORDER BY {} LIMIT :limit;",
+            order_column(filter.after_id)
+        );
+        blocking(move || {
+            storage.select(
+                &database,
+                &table,
+                &sql_where,
+                named_params! {
+                    ":since": filter.since,
+                    ":after_id": filter.after_id.unwrap_or(0),
+                    ":limit": filter.limit,
+                    ":key": filter.key,
+                },
+            )
+        })
+        .await
+    }
+
+    async fn query(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &QueryFilter,
+    ) -> StorageResult<Vec<ChangeEvent>> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        let (sql_where, params) = query_where(filter);
+        blocking(move || storage.select(&database, &table, &sql_where, params_from_iter(params)))
+            .await
+    }
+
+    async fn stats(
+        &self,
+        database: &str,
+        table: &str,
+        filter: &StatsFilter,
+    ) -> StorageResult<Vec<StatsBucket>> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        let filter = filter.clone();
+        blocking(move || {
+            let conn = storage.open_existing(&database)?;
+            validate_name(&table)?;
+            let table_name = quote(&table);
+            // The stored timestamps start with `YYYY-MM-DD HH:MM:SS` in UTC
+            // json_type() is NULL for a missing field or a NULL path
+            let sql_select = format!(
+                "SELECT bucket, COUNT(*), MIN(value), MAX(value), AVG(value) FROM (
+                    SELECT CAST(strftime('%s', substr(timestamp, 1, 19)) AS INTEGER)
+                        / :bucket * :bucket AS bucket,
+                    CASE WHEN json_type(data, :path) IN ('integer', 'real')
+                        THEN json_extract(data, :path) END AS value
+                    FROM {table_name} WHERE timestamp >= :since AND timestamp < :until
+                ) GROUP BY bucket ORDER BY bucket LIMIT :limit;"
+            );
+            let mut stmt = conn
+                .prepare(&sql_select)
+                .map_err(|err| not_found(err, &table))?;
+            let buckets = stmt
+                .query_map(
+                    named_params! {
+                        ":bucket": filter.bucket,
+                        ":path": filter.field.as_ref().map(sqlite_path),
+                        ":since": filter.since.to_string(),
+                        ":until": filter.until.to_string(),
+                        ":limit": MAX_BUCKETS,
+                    },
+                    |row| {
+                        Ok(StatsBucket::new(
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    },
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(buckets)
+        })
+        .await
+    }
+
+    async fn databases(&self) -> StorageResult<Vec<String>> {
+        let database_files = self.database_files.clone();
+        blocking(move || {
+            let mut databases: Vec<String> = fs::read_dir(&database_files)
+                .map_err(|err| Error::Internal(format!("{database_files}: {err}")))?
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    name.strip_suffix(".db").map(str::to_string)
+                })
+                .filter(|name| validate_name(name).is_ok())
+                .collect();
+            databases.sort();
+            Ok(databases)
+        })
+        .await
+    }
+
+    fn snapshot(&self, database: &str, tables: &[String], limit: i64) -> SnapshotStream {
+        let storage = self.clone();
+        let (database, tables) = (database.to_string(), tables.to_vec());
+        // Pages are read a little ahead of the response
+        let (sender, receiver) = mpsc::channel(2);
+        spawn_blocking(move || {
+            if let Err(err) = read_snapshot(&storage, &database, &tables, limit, &sender) {
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+        Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            let page = receiver.recv().await?;
+            Some((page, receiver))
+        }))
+    }
+
+    async fn last_seq(&self, database: &str, table: &str) -> StorageResult<i64> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        blocking(move || {
+            let conn = storage.open_existing(&database)?;
+            let last_seq = conn
+                .query_row(
+                    "SELECT last_seq FROM _sequences WHERE table_name = :table_name;",
+                    named_params! { ":table_name": table },
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|err| not_found(err, &table))?;
+            Ok(last_seq.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn tables(&self, database: &str) -> StorageResult<Vec<String>> {
+        let storage = self.clone();
+        let database = database.to_string();
+        blocking(move || {
+            let conn = storage.open_existing(&database)?;
+            let tables = conn
+                .prepare(
+                    "SELECT name FROM sqlite_master WHERE type = 'table'
+                    AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite_%'
+                    ORDER BY name;",
+                )?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(tables)
+        })
+        .await
+    }
+
+    fn insert_statements(&self, database: &str, table: &str) -> StorageResult<Vec<String>> {
+        validate_name(database)?;
+        validate_name(table)?;
+        Ok(vec![CLAIM_SEQS_SQL.to_string(), insert_sql(table)])
+    }
+
+    async fn size(&self, database: &str) -> StorageResult<u64> {
+        validate_name(database)?;
+        let path = self.path(database);
+        let database = database.to_string();
+        blocking(move || {
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Error::NotFound(database))
+                }
+                Err(err) => return Err(Error::Internal(format!("{path}: {err}"))),
+            };
+            // Commits not yet checkpointed into the database in WAL mode
+            let wal_size = fs::metadata(format!("{path}-wal")).map_or(0, |metadata| metadata.len());
+            Ok(size + wal_size)
+        })
+        .await
+    }
+
+    async fn row_count(&self, database: &str, table: &str) -> StorageResult<i64> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        blocking(move || {
+            let conn = storage.open_existing(&database)?;
+            validate_name(&table)?;
+            let table_name = quote(&table);
+            conn.query_row(&format!("SELECT count(*) FROM {table_name};"), [], |row| {
+                row.get(0)
+            })
+            .map_err(|err| not_found(err, &table))
+        })
+        .await
+    }
+
+    // Purges delete the oldest rows and ids are not reused, so the span
+    // of the ids left is the row count without reading every row, over by
+    // any rows deleted in between
+    async fn row_estimate(&self, database: &str, table: &str) -> StorageResult<i64> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        blocking(move || {
+            let conn = storage.open_existing(&database)?;
+            validate_name(&table)?;
+            let table_name = quote(&table);
+            conn.query_row(
+                &format!("SELECT coalesce(max(id) - min(id) + 1, 0) FROM {table_name};"),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| not_found(err, &table))
+        })
+        .await
+    }
+
+    async fn purge(
+        &self,
+        database: &str,
+        table: &str,
+        before: DateTime<Utc>,
+    ) -> StorageResult<u64> {
+        let storage = self.clone();
+        let (database, table) = (database.to_string(), table.to_string());
+        blocking(move || {
+            validate_name(&table)?;
+            let conn = storage.open(&database)?;
+            let table_name = quote(&table);
+            // Timestamps are stored in a format which sorts as text
+            let purged = conn.execute(
+                &format!("DELETE FROM {table_name} WHERE timestamp < :before;"),
+                named_params! { ":before": before.to_string() },
+            )?;
+            Ok(purged as u64)
+        })
+        .await
+    }
+
+    async fn vacuum(&self, database: &str, mode: VacuumMode) -> StorageResult<()> {
+        let storage = self.clone();
+        let database = database.to_string();
+        blocking(move || {
+            let conn = storage.open(&database)?;
+            match mode {
+                VacuumMode::None => {}
+                VacuumMode::Full => conn.execute_batch("VACUUM;")?,
+                VacuumMode::Incremental => conn.execute_batch("PRAGMA incremental_vacuum;")?,
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn backup(&self, database: &str, target: &Path) -> StorageResult<()> {
+        let storage = self.clone();
+        let (database, target) = (database.to_string(), target.to_path_buf());
+        blocking(move || {
+            let source = storage.open_existing(&database)?;
+            let mut copy = Connection::open(&target)?;
+            if let Some(key) = &storage.key {
+                copy.pragma_update(None, "key", &key.0)?;
+            }
+            // Every page is copied in a single step, under one read
+            // transaction, so writes made meanwhile can't restart the backup
+            match Backup::new(&source, &mut copy)?.step(-1)? {
+                StepResult::Done => {}
+                step => {
+                    return Err(Error::Internal(format!(
+                        "backup of {database} stopped: {step:?}"
+                    )))
+                }
+            }
+            debug!("backed up {database} to {}", target.display());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> StorageResult<bool> {
+        let storage = self.clone();
+        let (name, holder) = (name.to_string(), holder.to_string());
+        blocking(move || {
+            let conn = storage.open_leases()?;
+            // Expiry times are Julian days, as julianday('now') gives them
+            let acquired = conn.execute(
+                "INSERT INTO leases (name, holder, expires)
+                VALUES (:name, :holder, julianday('now') + :ttl / 86400.0)
+                ON CONFLICT (name) DO UPDATE
+                SET holder = excluded.holder, expires = excluded.expires
+                WHERE leases.holder = excluded.holder OR leases.expires < julianday('now');",
+                named_params! { ":name": name, ":holder": holder, ":ttl": ttl.as_secs_f64() },
+            )?;
+            Ok(acquired > 0)
+        })
+        .await
+    }
+
+    async fn release_lease(&self, name: &str, holder: &str) -> StorageResult<()> {
+        let storage = self.clone();
+        let (name, holder) = (name.to_string(), holder.to_string());
+        blocking(move || {
+            let conn = storage.open_leases()?;
+            conn.execute(
+                "DELETE FROM leases WHERE name = :name AND holder = :holder;",
+                named_params! { ":name": name, ":holder": holder },
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn close(&self) -> StorageResult<()> {
+        // Move the commits of the write-ahead logs into the databases, the
+        // logs are removed as the last connection closes
+        for database in self.databases().await? {
+            let storage = self.clone();
+            blocking(move || {
+                let conn = storage.open(&database)?;
+                conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |_| Ok(()))?;
+                debug!("closed database {database}");
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn health(&self) -> Vec<HealthCheck> {
+        let storage = self.clone();
+        let checks = blocking(move || {
+            let database_files = &storage.database_files;
+
+            // A file can be written to the directory of the databases
+            let probe = format!("{database_files}/.healthz");
+            let writable = fs::write(&probe, b"ok")
+                .and_then(|()| fs::remove_file(&probe))
+                .map_err(|err| format!("{database_files}: {err}"));
+
+            // A database can be opened and written to, `_healthz` is never
+            // the name of a data database
+            let path = storage.path("_healthz");
+            let open = || -> StorageResult<()> {
+                let conn = Connection::open(&path)?;
+                storage.setup(&conn, true)?;
+                conn.execute_batch("PRAGMA user_version = 1;")?;
+                Ok(())
+            };
+            let connection = open().map_err(|err| format!("{path}: {err}"));
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{path}{suffix}"));
+            }
+
+            Ok(vec![
+                HealthCheck::new("database_files_writable", writable),
+                HealthCheck::new("sqlite_connection", connection),
+            ])
+        })
+        .await;
+        checks.unwrap_or_else(|err| vec![HealthCheck::new("sqlite", Err(err))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+
+    use crate::storage::record;
+
+    #[actix_web::test]
+    async fn test_pragmas() {
+        let storage = SqliteStorage::new("./").pragmas(SqlitePragmas {
+            synchronous: Synchronous::Normal,
+            cache_size: -8000,
+            ..Default::default()
+        });
+        storage
+            .insert_batch("test_pragmas", "events", vec![record("{}")])
+            .await
+            .unwrap();
+
+        // Every connection gets the pragmas, the journal mode stays with the file
+        for conn in [
+            storage.open("test_pragmas").unwrap(),
+            storage.open_existing("test_pragmas").unwrap(),
+        ] {
+            let pragma = |name: &str| -> String {
+                conn.pragma_query_value(None, name, |row| row.get::<_, SqlValue>(0))
+                    .map(|value| match value {
+                        SqlValue::Integer(value) => value.to_string(),
+                        SqlValue::Text(value) => value,
+                        value => format!("{value:?}"),
+                    })
+                    .unwrap()
+            };
+            assert_eq!(pragma("journal_mode"), "wal");
+            assert_eq!(pragma("synchronous"), "1");
+            assert_eq!(pragma("cache_size"), "-8000");
+            assert_eq!(pragma("busy_timeout"), "5000");
+        }
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_pragmas.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_snapshot() {
+        use futures_util::StreamExt;
+
+        let storage = SqliteStorage::new("./");
+        for table in ["devices", "readings"] {
+            let records = (0..3).map(|_| record("{}")).collect();
+            storage
+                .insert_batch("test_snapshot", table, records)
+                .await
+                .unwrap();
+        }
+
+        // Rows written once the snapshot has started are left out
+        let tables = [String::from("devices"), String::from("readings")];
+        let mut pages = storage.snapshot("test_snapshot", &tables, 2);
+        let (table, events) = pages.next().await.unwrap().unwrap();
+        assert_eq!((table.as_str(), events.len()), ("devices", 2));
+        let records = (0..5).map(|_| record("{}")).collect();
+        storage
+            .insert_batch("test_snapshot", "readings", records)
+            .await
+            .unwrap();
+        let mut rows = vec![];
+        while let Some(page) = pages.next().await {
+            let (table, events) = page.unwrap();
+            rows.extend(events.iter().map(|event| (table.clone(), event.seq)));
+        }
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], (String::from("devices"), 3));
+        assert_eq!(rows[3], (String::from("readings"), 3));
+
+        // A missing table is found before any rows are read
+        let tables = [String::from("devices"), String::from("missing")];
+        let mut pages = storage.snapshot("test_snapshot", &tables, 2);
+        assert!(matches!(pages.next().await, Some(Err(Error::NotFound(_)))));
+        assert!(pages.next().await.is_none());
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_snapshot.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_schema_repair() {
+        // A table as created before sequence numbers and ordering keys
+        let storage = SqliteStorage::new("./");
+        let path = storage.path("test_schema_repair");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE events (
+                id INTEGER PRIMARY KEY,
+                timestamp DATETIME NOT NULL,
+                data TEXT NOT NULL
+            );
+            INSERT INTO events (timestamp, data) VALUES ('then', '{\"old\": 1}');
+            INSERT INTO events (timestamp, data) VALUES ('then', '{\"old\": 2}');
+            CREATE TABLE altered (
+                id INTEGER PRIMARY KEY,
+                seq INTEGER NOT NULL,
+                timestamp DATETIME NOT NULL,
+                data BLOB NOT NULL,
+                ordering_key TEXT
+            );",
+        )
+        .unwrap();
+
+        // Strict mode refuses the older table
+        let strict = SqliteStorage::new("./").schema_mode(SchemaMode::Strict);
+        let result = strict
+            .insert_batch("test_schema_repair", "events", vec![record("{}")])
+            .await;
+        assert!(matches!(result, Err(Error::Schema(_))));
+
+        // Migrate mode numbers the existing rows and carries on from there
+        let inserted = storage
+            .insert_batch("test_schema_repair", "events", vec![record("{\"new\": 3}")])
+            .await
+            .unwrap();
+        assert_eq!(inserted[0].seq, 3);
+        let filter = ChangesFilter {
+            limit: 10,
+            ..Default::default()
+        };
+        let events = storage
+            .changes("test_schema_repair", "events", &filter)
+            .await
+            .unwrap();
+        let seqs: Vec<i64> = events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+
+        // Reused ids alone don't stop writes, even in strict mode
+        strict
+            .insert_batch("test_schema_repair", "events", vec![record("{}")])
+            .await
+            .unwrap();
+
+        // The table is rebuilt with an AUTOINCREMENT id on request, keeping the rows
+        let autoincrement = |conn: &Connection| {
+            let sql: String = conn
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'events';",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            sql.contains("AUTOINCREMENT")
+        };
+        assert!(!autoincrement(&conn));
+        assert!(storage.rebuild_ids("test_schema_repair", "events").unwrap());
+        assert!(!storage.rebuild_ids("test_schema_repair", "events").unwrap());
+        assert!(matches!(
+            storage.rebuild_ids("test_schema_repair", "missing"),
+            Err(Error::NotFound(_))
+        ));
+        assert!(autoincrement(&conn));
+        let events = storage
+            .changes("test_schema_repair", "events", &filter)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        // A changed column type can't be repaired
+        let result = storage
+            .insert_batch("test_schema_repair", "altered", vec![record("{}")])
+            .await;
+        match result {
+            Err(Error::Schema(diff)) => assert!(diff.contains("column data is BLOB")),
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        // Post test, remove any database files created
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_index_names() {
+        // A table whose indexes were named by an older version
+        let storage = SqliteStorage::new("./");
+        storage
+            .insert_batch("test_index_names", "events", vec![record("{}")])
+            .await
+            .unwrap();
+        let conn = Connection::open(storage.path("test_index_names")).unwrap();
+        conn.execute_batch(
+            "DROP INDEX _idx_events_seq;
+            CREATE UNIQUE INDEX events_seq ON events (seq);",
+        )
+        .unwrap();
+        let strict = SqliteStorage::new("./").schema_mode(SchemaMode::Strict);
+        strict
+            .insert_batch("test_index_names", "events", vec![record("{}")])
+            .await
+            .unwrap();
+
+        // Tables named like the indexes of another can be created
+        for table in ["events_seq", "events_ordering_key"] {
+            storage
+                .insert_batch("test_index_names", table, vec![record("{}")])
+                .await
+                .unwrap();
+        }
+        let indexes: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_index_list('events') ORDER BY name;")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            indexes,
+            vec![
+                "_idx_events_idempotency_key",
+                "_idx_events_ordering_key",
+                "_idx_events_seq"
+            ]
+        );
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_index_names.db").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_ids_after_purge() {
+        let storage = SqliteStorage::new("./");
+        let records = (0..3).map(|_| record("{}")).collect();
+        storage
+            .insert_batch("test_ids_after_purge", "events", records)
+            .await
+            .unwrap();
+
+        // A purge empties the table, a reader has seen up to the last id
+        let purged = storage
+            .purge(
+                "test_ids_after_purge",
+                "events",
+                Utc::now() + chrono::Duration::seconds(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(purged, 3);
+        let estimate = storage
+            .row_estimate("test_ids_after_purge", "events")
+            .await
+            .unwrap();
+        assert_eq!(estimate, 0);
+
+        // The ids carry on, the reader pages on to the new rows
+        let records = (0..2).map(|_| record("{}")).collect();
+        storage
+            .insert_batch("test_ids_after_purge", "events", records)
+            .await
+            .unwrap();
+        let filter = ChangesFilter {
+            after_id: Some(3),
+            limit: 10,
+            ..Default::default()
+        };
+        let events = storage
+            .changes("test_ids_after_purge", "events", &filter)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![4, 5]);
+        let estimate = storage
+            .row_estimate("test_ids_after_purge", "events")
+            .await
+            .unwrap();
+        assert_eq!(estimate, 2);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_ids_after_purge.db").unwrap();
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[actix_web::test]
+    async fn test_encrypt() {
+        let filter = ChangesFilter {
+            limit: 10,
+            ..Default::default()
+        };
+        let plaintext = SqliteStorage::new("./");
+        plaintext
+            .insert_batch("test_encrypt", "events", vec![record("{\"secret\": 1}")])
+            .await
+            .unwrap();
+        drop(plaintext);
+
+        // Only a storage with a key can encrypt
+        let key = Some(DbKey::new("correct horse battery staple"));
+        assert!(SqliteStorage::new("./").encrypt("test_encrypt").is_err());
+        let storage = SqliteStorage::new("./").key(key.clone());
+        assert!(matches!(
+            storage.encrypt("test_missing"),
+            Err(Error::NotFound(_))
+        ));
+        storage.encrypt("test_encrypt").unwrap();
+        let file = std::fs::read("./test_encrypt.db").unwrap();
+        assert!(!file.starts_with(b"SQLite format 3"));
+
+        // The rows are there for the key and unreadable without it
+        let events = storage
+            .changes("test_encrypt", "events", &filter)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let wrong = SqliteStorage::new("./").key(Some(DbKey::new("wrong")));
+        assert!(wrong
+            .changes("test_encrypt", "events", &filter)
+            .await
+            .is_err());
+        let plaintext = SqliteStorage::new("./");
+        assert!(plaintext
+            .changes("test_encrypt", "events", &filter)
+            .await
+            .is_err());
+
+        // Post test, remove any database files created
+        drop((storage, wrong, plaintext));
+        std::fs::remove_file("./test_encrypt.db").unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+// Row counts and numeric summaries of a table grouped by time bucket
+//
+// GET /<database>/<table>/stats?bucket=1h&since=24h&field=$.ms
+// [{"bucket": "2024-09-03T14:00:00Z", "count": 120, "min": 3.0, "max": 950.0, "avg": 41.5}]
+//
+// Buckets are aligned to the Unix epoch, so a 1h bucket starts on the hour.
+// Buckets without rows are left out. A field which is not a number in a
+// row is left out of min, max and avg but the row is still counted.
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::jsonpath::JsonPath;
+
+/// The most buckets a single request can return
+pub const MAX_BUCKETS: i64 = 10_000;
+
+/// Which rows to summarize and how
+#[derive(Clone, Debug)]
+pub struct StatsFilter {
+    /// The length of a bucket in seconds
+    pub bucket: i64,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    /// A numeric field to summarize
+    pub field: Option<JsonPath>,
+}
+
+/// The summary of the rows stored in a bucket
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct StatsBucket {
+    /// When the bucket starts
+    pub bucket: String,
+    pub count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg: Option<f64>,
+}
+
+impl StatsFilter {
+    /// Check the range can be answered with at most `MAX_BUCKETS` buckets
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bucket < 1 {
+            return Err(String::from("the bucket has to be at least 1s"));
+        }
+        if self.until <= self.since {
+            return Err(String::from("until has to be later than since"));
+        }
+        let buckets = (self.until - self.since).num_seconds() / self.bucket;
+        if buckets > MAX_BUCKETS {
+            return Err(format!(
+                "{buckets} buckets asked for, at most {MAX_BUCKETS} can be returned"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A point in time written as RFC 3339, e.g. 2024-09-03T14:00:00Z, or as a
+/// duration before now, e.g. 24h
+pub fn parse_time(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.to_utc());
+    }
+    let ago = humantime::parse_duration(value)
+        .map_err(|_| format!("expected an RFC 3339 time or a duration ago: {value}"))?;
+    TimeDelta::from_std(ago)
+        .ok()
+        .and_then(|ago| now.checked_sub_signed(ago))
+        .ok_or_else(|| format!("too long ago: {value}"))
+}
+
+impl StatsBucket {
+    /// A bucket starting at seconds since the Unix epoch
+    pub fn new(
+        start: i64,
+        count: i64,
+        min: Option<f64>,
+        max: Option<f64>,
+        avg: Option<f64>,
+    ) -> Self {
+        let bucket = DateTime::from_timestamp(start, 0)
+            .unwrap_or_default()
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        StatsBucket {
+            bucket,
+            count,
+            min,
+            max,
+            avg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        let now = parse_time("2024-09-03T14:30:00Z", Utc::now()).unwrap();
+        assert_eq!(parse_time("2024-09-03T16:30:00+02:00", now).unwrap(), now);
+        assert_eq!(
+            parse_time("1h 30m", now).unwrap().to_rfc3339(),
+            "2024-09-03T13:00:00+00:00"
+        );
+        assert!(parse_time("yesterday", now).is_err());
+
+        let filter = StatsFilter {
+            bucket: 60,
+            since: parse_time("30d", now).unwrap(),
+            until: now,
+            field: None,
+        };
+        assert!(filter.validate().is_err());
+        assert_eq!(
+            StatsBucket::new(3600, 1, None, None, None).bucket,
+            "1970-01-01T01:00:00Z"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// Destination table templates resolved at insert time
+//
+// --table-template events=events_{yyyy_mm}
+// --table-template '*={table}_{yyyy}'
+//
+// A sender writes to `events` and the rows land in `events_2024_09`, so the
+// rotation scheme lives in one place instead of in every sender.
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+
+/// The placeholders a template can use
+const PLACEHOLDERS: &[&str] = &[
+    "yyyy",
+    "mm",
+    "dd",
+    "hh",
+    "yyyy_mm",
+    "yyyy_mm_dd",
+    "database",
+    "table",
+    "api_key_name",
+];
+
+/// A destination table template for a table, `*` matches any table
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableTemplate {
+    pub table: String,
+    pub template: String,
+}
+
+impl FromStr for TableTemplate {
+    type Err = String;
+
+    // <table>=<template>
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (table, template) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <table>=<template>: {value}"))?;
+        let template = TableTemplate {
+            table: table.trim().to_string(),
+            template: template.trim().to_string(),
+        };
+        template.validate()?;
+        Ok(template)
+    }
+}
+
+/// What a template is resolved against
+pub struct TemplateContext<'a> {
+    pub database: &'a str,
+    pub table: &'a str,
+    pub timestamp: DateTime<Utc>,
+    /// The name of the API key the request was authenticated with
+    pub api_key_name: Option<&'a str>,
+}
+
+impl TableTemplate {
+    /// Make sure every placeholder of the template is known
+    pub fn validate(&self) -> Result<(), String> {
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in template: {}", self.template))?;
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder {{{name}}} in template: {}",
+                    self.template
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(())
+    }
+
+    /// Fill in the placeholders of the template
+    pub fn render(&self, context: &TemplateContext) -> String {
+        let timestamp = context.timestamp;
+        let mut result = self.template.clone();
+        for (placeholder, value) in [
+            ("{yyyy_mm_dd}", timestamp.format("%Y_%m_%d").to_string()),
+            ("{yyyy_mm}", timestamp.format("%Y_%m").to_string()),
+            ("{yyyy}", timestamp.format("%Y").to_string()),
+            ("{mm}", timestamp.format("%m").to_string()),
+            ("{dd}", timestamp.format("%d").to_string()),
+            ("{hh}", timestamp.format("%H").to_string()),
+            ("{database}", context.database.to_string()),
+            ("{table}", context.table.to_string()),
+            (
+                "{api_key_name}",
+                context.api_key_name.unwrap_or("anonymous").to_string(),
+            ),
+        ] {
+            result = result.replace(placeholder, &value);
+        }
+        result
+    }
+}
+
+/// The table a row is written to, an exact table match wins over `*`
+pub fn resolve(templates: &[TableTemplate], context: &TemplateContext) -> String {
+    templates
+        .iter()
+        .find(|template| template.table == context.table)
+        .or_else(|| templates.iter().find(|template| template.table == "*"))
+        .map(|template| template.render(context))
+        .unwrap_or_else(|| context.table.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_resolve() {
+        let templates: Vec<TableTemplate> = vec![
+            "events=events_{yyyy_mm}".parse().unwrap(),
+            "*={database}_{table}_{yyyy}".parse().unwrap(),
+        ];
+        let mut context = TemplateContext {
+            database: "site",
+            table: "events",
+            timestamp: Utc.with_ymd_and_hms(2024, 9, 3, 14, 0, 0).unwrap(),
+            api_key_name: None,
+        };
+        assert_eq!(resolve(&templates, &context), "events_2024_09");
+        context.table = "metrics";
+        assert_eq!(resolve(&templates, &context), "site_metrics_2024");
+        assert_eq!(resolve(&[], &context), "metrics");
+
+        // Rows from each sender kept apart
+        let templates: Vec<TableTemplate> = vec!["*={table}_{api_key_name}".parse().unwrap()];
+        assert_eq!(resolve(&templates, &context), "metrics_anonymous");
+        context.api_key_name = Some("gateway");
+        assert_eq!(resolve(&templates, &context), "metrics_gateway");
+
+        assert!("events".parse::<TableTemplate>().is_err());
+        assert!("events=events_{week}".parse::<TableTemplate>().is_err());
+        assert!("events=events_{yyyy".parse::<TableTemplate>().is_err());
+    }
+}
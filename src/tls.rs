@@ -0,0 +1,43 @@
+// TLS for the HTTP server
+//
+// --tls-cert /etc/receiver/cert.pem --tls-key /etc/receiver/key.pem
+//
+// The certificate file holds the certificate chain, leaf first, the key file
+// a PKCS#8, PKCS#1 or SEC1 private key, both PEM encoded.
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+// A modern TLS library in Rust
+// https://docs.rs/rustls/latest/rustls/
+// cargo add rustls --no-default-features --features ring,std,tls12 --optional
+// https://docs.rs/rustls-pemfile/latest/rustls_pemfile/
+// cargo add rustls-pemfile --optional
+use rustls::{crypto::ring, ServerConfig};
+
+/// Build the TLS configuration from a certificate chain and private key
+pub fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("unable to read {}: {err}", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid certificate {}: {err}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", cert.display()));
+    }
+    let private_key = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|err| format!("invalid private key {}: {err}", key.display()))?
+        .ok_or_else(|| format!("no private key found in {}", key.display()))?;
+
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)
+        .map_err(|err| format!("invalid certificate or private key: {err}"))
+}
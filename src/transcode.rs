@@ -0,0 +1,240 @@
+// MessagePack and CBOR request bodies
+//
+// curl -i -X PUT -H 'Content-Type: application/msgpack' --data-binary @data.msgpack http://localhost:8888/database/test
+// curl -i -X PUT -H 'Content-Type: application/cbor' --data-binary @data.cbor http://localhost:8888/database/test
+//
+// The `Content-Type` of a write selects how its body is read. MessagePack and
+// CBOR bodies are transcoded to JSON before they are validated and stored, so
+// they are queried and exported the same as documents sent as JSON. Binary
+// values become arrays of bytes.
+//
+// Bodies without a `Content-Type`, or sent as JSON, text or a form (which is
+// what `curl -d` sends), are read as JSON. Other types are answered with 415
+// Unsupported Media Type, as are MessagePack and CBOR in a build without the
+// `msgpack` or `cbor` feature.
+use std::borrow::Cow;
+use std::fmt;
+
+use actix_web::http::header::{self, HeaderMap};
+
+// A CBOR (RFC 8949) implementation for serde
+// https://docs.rs/ciborium/latest/ciborium/
+// cargo add ciborium --optional
+// A MessagePack implementation for serde
+// https://docs.rs/rmp-serde/latest/rmp_serde/
+// cargo add rmp-serde --optional
+// Transcoding from one serde format to another
+// https://docs.rs/serde-transcode/latest/serde_transcode/
+// cargo add serde-transcode --optional
+#[cfg(feature = "cbor")]
+use serde::Serialize;
+#[cfg(feature = "msgpack")]
+use serde_transcode::transcode;
+
+use crate::errors::Error;
+
+/// The formats a request body can be sent in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFormat {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// The formats named to a client sending another one
+const SUPPORTED: &[&str] = &[
+    "JSON",
+    #[cfg(feature = "msgpack")]
+    "MessagePack",
+    #[cfg(feature = "cbor")]
+    "CBOR",
+];
+
+impl BodyFormat {
+    /// The format named by the `Content-Type` header of a request
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        let Some(value) = headers.get(header::CONTENT_TYPE) else {
+            return Ok(BodyFormat::Json);
+        };
+        let value = value.to_str().unwrap_or_default();
+        // Parameters such as `; charset=utf-8` are left out
+        let essence = value.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "" | "application/json" | "text/json" | "text/plain" => Ok(BodyFormat::Json),
+            "application/x-www-form-urlencoded" => Ok(BodyFormat::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Ok(BodyFormat::MessagePack)
+            }
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Ok(BodyFormat::Cbor),
+            essence if essence.ends_with("+json") => Ok(BodyFormat::Json),
+            _ => Err(Error::UnsupportedMediaType(format!(
+                "Content-Type {value} is not supported, send one of {}",
+                SUPPORTED.join(", ")
+            ))),
+        }
+    }
+
+    /// The body as JSON
+    pub fn to_json(self, body: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+        match self {
+            BodyFormat::Json => Ok(Cow::Borrowed(body)),
+            #[cfg(feature = "msgpack")]
+            BodyFormat::MessagePack => msgpack_to_json(body).map(Cow::Owned),
+            #[cfg(feature = "cbor")]
+            BodyFormat::Cbor => cbor_to_json(body).map(Cow::Owned),
+        }
+    }
+
+    /// The reason a body which can't be read is counted under
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => "json",
+            #[cfg(feature = "msgpack")]
+            BodyFormat::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            BodyFormat::Cbor => "cbor",
+        }
+    }
+}
+
+impl fmt::Display for BodyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyFormat::Json => write!(f, "JSON"),
+            #[cfg(feature = "msgpack")]
+            BodyFormat::MessagePack => write!(f, "MessagePack"),
+            #[cfg(feature = "cbor")]
+            BodyFormat::Cbor => write!(f, "CBOR"),
+        }
+    }
+}
+
+// MessagePack is transcoded as it is read
+#[cfg(feature = "msgpack")]
+fn msgpack_to_json(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut json = Vec::with_capacity(body.len() * 2);
+    let mut deserializer = rmp_serde::Deserializer::new(&mut body);
+    let mut serializer = serde_json::Serializer::new(&mut json);
+    transcode(&mut deserializer, &mut serializer).map_err(|err| err.to_string())?;
+    read_whole(body)?;
+    Ok(json)
+}
+
+// The deserializer of ciborium is private, so the document is read whole into
+// a CBOR value, which keeps the order of its keys
+#[cfg(feature = "cbor")]
+fn cbor_to_json(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut json = Vec::with_capacity(body.len() * 2);
+    let value: ciborium::Value = ciborium::from_reader(&mut body).map_err(|err| err.to_string())?;
+    value
+        .serialize(&mut serde_json::Serializer::new(&mut json))
+        .map_err(|err| err.to_string())?;
+    read_whole(body)?;
+    Ok(json)
+}
+
+// A body holds a single document
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn read_whole(rest: &[u8]) -> Result<(), String> {
+    match rest.len() {
+        0 => Ok(()),
+        len => Err(format!("{len} bytes after the document")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::HeaderValue;
+
+    fn format(content_type: &str) -> Result<BodyFormat, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type).unwrap(),
+        );
+        BodyFormat::from_headers(&headers)
+    }
+
+    #[test]
+    fn test_body_format() {
+        assert_eq!(
+            BodyFormat::from_headers(&HeaderMap::new()).unwrap(),
+            BodyFormat::Json
+        );
+        assert_eq!(
+            format("application/json; charset=utf-8").unwrap(),
+            BodyFormat::Json
+        );
+        assert_eq!(
+            format("application/x-www-form-urlencoded").unwrap(),
+            BodyFormat::Json
+        );
+        assert_eq!(format("application/ld+json").unwrap(), BodyFormat::Json);
+        assert!(matches!(
+            format("application/xml"),
+            Err(Error::UnsupportedMediaType(_))
+        ));
+
+        // MessagePack and CBOR are only read by a build with their feature
+        #[cfg(feature = "msgpack")]
+        assert_eq!(
+            format("Application/MsgPack").unwrap(),
+            BodyFormat::MessagePack
+        );
+        #[cfg(not(feature = "msgpack"))]
+        assert!(format("application/msgpack").is_err());
+        #[cfg(feature = "cbor")]
+        assert_eq!(format("application/cbor").unwrap(), BodyFormat::Cbor);
+        #[cfg(not(feature = "cbor"))]
+        assert!(format("application/cbor").is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert!(matches!(
+            BodyFormat::Json.to_json(b"{}").unwrap(),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_to_json() {
+        // The keys keep their order
+        let document = serde_json::json!({"device": "d1", "temperature": 21.5, "ok": true});
+        let expected = serde_json::to_vec(&document).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&document).unwrap();
+        let json = BodyFormat::MessagePack.to_json(&msgpack).unwrap();
+        assert_eq!(json.as_ref(), expected.as_slice());
+
+        // Truncated or trailing bytes are refused
+        assert!(BodyFormat::MessagePack
+            .to_json(&msgpack[..msgpack.len() - 1])
+            .is_err());
+        let mut trailing = msgpack.clone();
+        trailing.push(0);
+        assert!(BodyFormat::MessagePack.to_json(&trailing).is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_to_json() {
+        // The keys keep their order
+        let document = serde_json::json!({"device": "d1", "temperature": 21.5, "ok": true});
+        let expected = serde_json::to_vec(&document).unwrap();
+        let mut cbor = vec![];
+        ciborium::into_writer(&document, &mut cbor).unwrap();
+        let json = BodyFormat::Cbor.to_json(&cbor).unwrap();
+        assert_eq!(json.as_ref(), expected.as_slice());
+
+        // Trailing bytes are refused
+        cbor.push(0);
+        assert!(BodyFormat::Cbor.to_json(&cbor).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+// Syslog and JSON lines received over UDP
+//
+// [udp]
+// port = 5514
+// database = "network"
+// table = "syslog"
+// batch_size = 500
+// flush_interval = "1s"
+//
+// --udp-port 5514 --udp-database network --udp-table syslog
+//
+// Network gear which can only send syslog or JSON over UDP is stored through
+// the same pipeline as `PUT /<database>/<table>`, in the table of `database`
+// (the default database when unset). Each datagram holds either
+// - JSON documents, one per line
+//   echo '{"device": "sw1", "port": 7, "state": "down"}' | nc -u -w0 localhost 5514
+// - an RFC 5424 syslog message, stored as
+//   {"facility": 4, "severity": 2, "version": 1, "timestamp": "2024-05-01T12:00:00.003Z", "hostname": "sw1", "app_name": "sshd", "procid": "42", "msgid": "ID47", "structured_data": {"origin@32473": {"ip": "10.0.0.1"}}, "message": "..."}
+//   leaving out the fields sent as `-`. An older RFC 3164 message keeps its
+//   facility and severity, the rest of it is stored as the message.
+//
+// Messages are written `batch_size` at a time, or once they waited for
+// `flush_interval`. UDP has no way to tell a sender to slow down, the
+// messages which can't be stored are dropped and counted by reason on
+// /metrics as actix_data_receiver_udp_dropped_total
+// - parse: the datagram is neither JSON nor syslog
+// - queue: the writes fell behind by more than QUEUE_BATCHES batches
+// - rejected: the document was refused, e.g. by the schema of its table
+// - storage: the document could not be stored
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use actix_web::{
+    rt::{
+        self,
+        net::UdpSocket,
+        task::JoinHandle,
+        time::{self, Instant},
+    },
+    web,
+};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc::{self, Receiver};
+use tracing::{debug, error, warn};
+
+use crate::errors::Error;
+use crate::metrics;
+use crate::routes::{self, AppData};
+use crate::storage::RecordMetadata;
+
+/// Batches of messages waiting to be written before more are dropped
+const QUEUE_BATCHES: usize = 4;
+
+/// The largest UDP datagram
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Where the messages received over UDP are stored
+#[derive(Clone, Debug)]
+pub struct UdpSettings {
+    pub addr: String,
+    pub port: u16,
+    pub database: String,
+    pub table: String,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+/// Receives messages over UDP and writes them in batches
+pub struct UdpListener {
+    local_addr: SocketAddr,
+    receiver: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl UdpListener {
+    /// Bind the port and start storing the messages it receives
+    pub async fn start(appdata: web::Data<AppData>, settings: UdpSettings) -> io::Result<Self> {
+        routes::check_table(&appdata, &settings.database, &settings.table)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let socket = UdpSocket::bind((settings.addr.as_str(), settings.port)).await?;
+        let local_addr = socket.local_addr()?;
+
+        let batch_size = settings.batch_size.max(1);
+        let (sender, messages) = mpsc::channel(batch_size * QUEUE_BATCHES);
+        let receiver = rt::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        warn!("unable to receive a UDP datagram: {err}");
+                        continue;
+                    }
+                };
+                let documents = match parse(&buf[..len]) {
+                    Ok(documents) => documents,
+                    Err(err) => {
+                        debug!("dropped a UDP datagram from {peer}: {err}");
+                        metrics::UDP_MESSAGES.inc();
+                        dropped("parse", 1);
+                        continue;
+                    }
+                };
+                metrics::UDP_MESSAGES.inc_by(documents.len() as u64);
+                for document in documents {
+                    if sender.try_send((peer.ip(), document)).is_err() {
+                        dropped("queue", 1);
+                    }
+                }
+            }
+        });
+        let writer = rt::spawn(write_batches(appdata, settings, messages));
+        Ok(UdpListener {
+            local_addr,
+            receiver,
+            writer,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop receiving and write the messages already received
+    pub async fn close(self, timeout: Duration) {
+        // The queue closes with the task receiving into it
+        self.receiver.abort();
+        if time::timeout(timeout, self.writer).await.is_err() {
+            warn!(
+                "gave up writing the UDP messages received after {}",
+                humantime::format_duration(timeout)
+            );
+        }
+    }
+}
+
+// Count messages which won't be stored
+fn dropped(reason: &str, count: usize) {
+    metrics::UDP_DROPPED
+        .with_label_values(&[reason])
+        .inc_by(count as u64);
+}
+
+// Write the messages received once a batch is full or has waited long enough
+async fn write_batches(
+    appdata: web::Data<AppData>,
+    settings: UdpSettings,
+    mut messages: Receiver<(IpAddr, Value)>,
+) {
+    let batch_size = settings.batch_size.max(1);
+    while let Some(message) = messages.recv().await {
+        let mut batch = vec![message];
+        let deadline = Instant::now() + settings.flush_interval;
+        while batch.len() < batch_size {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match time::timeout(wait, messages.recv()).await {
+                Ok(Some(message)) => batch.push(message),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        write(&appdata, &settings.database, &settings.table, batch).await;
+    }
+}
+
+// Store a batch, the rows of each sender together so they can be told apart
+async fn write(appdata: &AppData, database: &str, table: &str, batch: Vec<(IpAddr, Value)>) {
+    let record_metadata = appdata.record_metadata.is_some();
+    let mut senders: BTreeMap<Option<IpAddr>, Vec<Value>> = BTreeMap::new();
+    for (peer, document) in batch {
+        senders
+            .entry(record_metadata.then_some(peer))
+            .or_default()
+            .push(document);
+    }
+    for (peer, documents) in senders {
+        let metadata = RecordMetadata {
+            source_ip: peer.map(|peer| peer.to_string()),
+            ..RecordMetadata::default()
+        };
+        let count = documents.len();
+        let result = routes::ingest(
+            appdata,
+            database,
+            table,
+            documents.clone(),
+            None,
+            None,
+            &metadata,
+        )
+        .await;
+        match result {
+            Ok(_) => {}
+            // A document refused fails its batch, the others are stored on their own
+            Err(Error::BadRequest(_)) if count > 1 => {
+                for document in documents {
+                    let result = routes::ingest(
+                        appdata,
+                        database,
+                        table,
+                        vec![document],
+                        None,
+                        None,
+                        &metadata,
+                    )
+                    .await;
+                    if let Err(err) = result {
+                        refused(database, table, &err, 1);
+                    }
+                }
+            }
+            Err(err) => refused(database, table, &err, count),
+        }
+    }
+}
+
+// Count documents which were refused or could not be stored
+fn refused(database: &str, table: &str, err: &Error, count: usize) {
+    match err {
+        Error::Storage(_) | Error::Unavailable(_) | Error::Internal(_) => {
+            error!("unable to store {count} UDP messages in {database}/{table}: {err}");
+            dropped("storage", count);
+        }
+        _ => {
+            debug!("refused {count} UDP messages for {database}/{table}: {err}");
+            dropped("rejected", count);
+        }
+    }
+}
+
+/// The documents of a datagram, JSON lines or a syslog message
+pub fn parse(datagram: &[u8]) -> Result<Vec<Value>, String> {
+    let text = String::from_utf8_lossy(datagram);
+    let text = text.trim_end_matches(['\0', '\r', '\n', ' ']);
+    if text.starts_with('<') {
+        return parse_syslog(text).map(|document| vec![document]);
+    }
+    let documents = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|err| format!("not valid JSON: {err}")))
+        .collect::<Result<Vec<Value>, String>>()?;
+    match documents.is_empty() {
+        true => Err(String::from("empty datagram")),
+        false => Ok(documents),
+    }
+}
+
+/// A syslog message as a document
+/// https://www.rfc-editor.org/rfc/rfc5424#section-6
+pub fn parse_syslog(message: &str) -> Result<Value, String> {
+    let (priority, rest) = message
+        .strip_prefix('<')
+        .and_then(|rest| rest.split_once('>'))
+        .ok_or("no syslog priority")?;
+    let priority: u8 = match priority.len() {
+        1..=3 => priority.parse().map_err(|_| "invalid syslog priority")?,
+        _ => return Err(String::from("invalid syslog priority")),
+    };
+    if priority > 191 {
+        return Err(String::from("invalid syslog priority"));
+    }
+    let mut document = Map::new();
+    document.insert(String::from("facility"), Value::from(priority / 8));
+    document.insert(String::from("severity"), Value::from(priority % 8));
+
+    // RFC 5424 has a version after the priority, RFC 3164 goes on with a date
+    let version = rest
+        .split_once(' ')
+        .and_then(|(version, rest)| Some((version.parse::<u8>().ok()?, rest)))
+        .filter(|(version, _)| *version > 0);
+    let Some((version, rest)) = version else {
+        insert_text(&mut document, "message", rest.trim());
+        return Ok(Value::Object(document));
+    };
+    document.insert(String::from("version"), Value::from(version));
+
+    let mut fields = rest.splitn(6, ' ');
+    for name in ["timestamp", "hostname", "app_name", "procid", "msgid"] {
+        let field = fields.next().ok_or("truncated syslog header")?;
+        insert_text(&mut document, name, field);
+    }
+    let rest = fields.next().ok_or("truncated syslog header")?;
+    let rest = match rest.strip_prefix('-') {
+        Some(rest) => rest,
+        None => {
+            let (elements, rest) = structured_data(rest)?;
+            document.insert(String::from("structured_data"), Value::Object(elements));
+            rest
+        }
+    };
+    if let Some(message) = rest.strip_prefix(' ') {
+        insert_text(
+            &mut document,
+            "message",
+            message.trim_start_matches('\u{feff}'),
+        );
+    } else if !rest.is_empty() {
+        return Err(String::from("no space after the structured data"));
+    }
+    Ok(Value::Object(document))
+}
+
+// Fields sent as `-`, or left empty, are left out
+fn insert_text(document: &mut Map<String, Value>, name: &str, value: &str) {
+    if !value.is_empty() && value != "-" {
+        document.insert(name.to_string(), Value::String(value.to_string()));
+    }
+}
+
+// The elements of structured data, [id name="value" ...][id ...], by id
+fn structured_data(mut rest: &str) -> Result<(Map<String, Value>, &str), String> {
+    const UNTERMINATED: &str = "unterminated structured data";
+    let mut elements = Map::new();
+    if !rest.starts_with('[') {
+        return Err(String::from("invalid structured data"));
+    }
+    while let Some(element) = rest.strip_prefix('[') {
+        let end = element.find([' ', ']']).ok_or(UNTERMINATED)?;
+        let id = &element[..end];
+        rest = &element[end..];
+        let mut params = Map::new();
+        loop {
+            if let Some(after) = rest.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+            let (name, value) = rest
+                .strip_prefix(' ')
+                .and_then(|param| param.split_once("=\""))
+                .ok_or("invalid structured data parameter")?;
+            // `"`, `\` and `]` are escaped with a backslash in values
+            let (mut text, mut end) = (String::new(), None);
+            let mut chars = value.char_indices();
+            while let Some((index, char)) = chars.next() {
+                match char {
+                    '\\' => match chars.next() {
+                        Some((_, char @ ('"' | '\\' | ']'))) => text.push(char),
+                        Some((_, char)) => {
+                            text.push('\\');
+                            text.push(char);
+                        }
+                        None => break,
+                    },
+                    '"' => {
+                        end = Some(index + 1);
+                        break;
+                    }
+                    char => text.push(char),
+                }
+            }
+            let end = end.ok_or(UNTERMINATED)?;
+            params.insert(name.to_string(), Value::String(text));
+            rest = &value[end..];
+        }
+        elements.insert(id.to_string(), Value::Object(params));
+    }
+    Ok((elements, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::storage::ChangesFilter;
+
+    #[test]
+    fn test_parse() {
+        // JSON lines
+        let documents = parse(b"{\"device\": \"sw1\"}\n\n{\"device\": \"sw2\"}\n").unwrap();
+        assert_eq!(
+            documents,
+            vec![json!({"device": "sw1"}), json!({"device": "sw2"})]
+        );
+        assert!(parse(b"{\"device\": ").is_err());
+        assert!(parse(b"\n\0").is_err());
+
+        // Syslog
+        let documents = parse(b"<34>1 - - - - - -\n").unwrap();
+        assert_eq!(
+            documents,
+            vec![json!({"facility": 4, "severity": 2, "version": 1})]
+        );
+    }
+
+    #[test]
+    fn test_parse_syslog() {
+        // The examples of RFC 5424
+        let message = "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - \u{feff}'su root' failed for lonvick on /dev/pts/8";
+        assert_eq!(
+            parse_syslog(message).unwrap(),
+            json!({
+                "facility": 4,
+                "severity": 2,
+                "version": 1,
+                "timestamp": "2003-10-11T22:14:15.003Z",
+                "hostname": "mymachine.example.com",
+                "app_name": "su",
+                "msgid": "ID47",
+                "message": "'su root' failed for lonvick on /dev/pts/8",
+            })
+        );
+        let message = r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="Application" eventID="1011"][examplePriority@32473 class="high"]"#;
+        assert_eq!(
+            parse_syslog(message).unwrap(),
+            json!({
+                "facility": 20,
+                "severity": 5,
+                "version": 1,
+                "timestamp": "2003-10-11T22:14:15.003Z",
+                "hostname": "mymachine.example.com",
+                "app_name": "evntslog",
+                "msgid": "ID47",
+                "structured_data": {
+                    "exampleSDID@32473": {"iut": "3", "eventSource": "Application", "eventID": "1011"},
+                    "examplePriority@32473": {"class": "high"},
+                },
+            })
+        );
+
+        // Escaped values
+        let message = r#"<14>1 - host app 42 - [meta note="a \"quoted\] \\ value\n"] hello"#;
+        assert_eq!(
+            parse_syslog(message).unwrap()["structured_data"]["meta"]["note"],
+            r#"a "quoted] \ value\n"#
+        );
+        assert_eq!(parse_syslog(message).unwrap()["message"], "hello");
+        assert_eq!(parse_syslog(message).unwrap()["procid"], "42");
+
+        // RFC 3164 keeps its priority
+        let message = "<13>Oct 11 22:14:15 mymachine su: 'su root' failed";
+        assert_eq!(
+            parse_syslog(message).unwrap(),
+            json!({"facility": 1, "severity": 5, "message": "Oct 11 22:14:15 mymachine su: 'su root' failed"})
+        );
+
+        // Invalid messages
+        assert!(parse_syslog("<192>1 - - - - - -").is_err());
+        assert!(parse_syslog("<1a>1 - - - - - -").is_err());
+        assert!(parse_syslog("<34>1 - - -").is_err());
+        assert!(parse_syslog("<34>1 - - - - - [id").is_err());
+        assert!(parse_syslog(r#"<34>1 - - - - - [id a="1"#).is_err());
+        assert!(parse_syslog("<34>1 - - - - - x").is_err());
+        assert!(parse_syslog("<34>1 - - - - - -x").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_udp_listener() {
+        let appdata = web::Data::new(AppData::default());
+        let storage = appdata.storage.clone();
+        let listener = UdpListener::start(
+            appdata,
+            UdpSettings {
+                addr: String::from("127.0.0.1"),
+                port: 0,
+                database: String::from("test_udp_listener"),
+                table: String::from("syslog"),
+                batch_size: 10,
+                flush_interval: Duration::from_millis(20),
+            },
+        )
+        .await
+        .unwrap();
+
+        let dropped = |reason: &str| metrics::UDP_DROPPED.with_label_values(&[reason]).get();
+        let parse_dropped = dropped("parse");
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr();
+        socket
+            .send_to(b"{\"device\": \"sw1\"}\n{\"device\": \"sw2\"}", addr)
+            .unwrap();
+        socket.send_to(b"not json", addr).unwrap();
+        socket
+            .send_to(b"<34>1 - sw3 sshd - - - login failed", addr)
+            .unwrap();
+
+        // The messages received are written once the listener closes
+        time::sleep(Duration::from_millis(100)).await;
+        listener.close(Duration::from_secs(5)).await;
+        let rows = storage
+            .changes(
+                "test_udp_listener",
+                "syslog",
+                &ChangesFilter {
+                    since: 0,
+                    after_id: None,
+                    limit: 10,
+                    key: None,
+                },
+            )
+            .await
+            .unwrap();
+        let data: Vec<Value> = rows.into_iter().map(|row| row.data).collect();
+        assert_eq!(
+            data,
+            vec![
+                json!({"device": "sw1"}),
+                json!({"device": "sw2"}),
+                json!({"facility": 4, "severity": 2, "version": 1, "hostname": "sw3", "app_name": "sshd", "message": "login failed"}),
+            ]
+        );
+        assert_eq!(dropped("parse"), parse_dropped + 1);
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_udp_listener.db").unwrap();
+    }
+}
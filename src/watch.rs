@@ -0,0 +1,156 @@
+// Reloading the configuration file when it changes
+//
+// [server]
+// watch_config = true
+// log_filter = "warn,actix_data_receiver::storage=debug"
+//
+// With `watch_config` (or `--watch-config`) the directory of the `--config`
+// file is watched with inotify, and the file is read again whenever its
+// contents change. This follows a Kubernetes ConfigMap mounted as a volume,
+// whose files are symbolic links swapped all at once when the ConfigMap is
+// updated, so a rollout of a configuration change needs no pod restarts.
+// On systems without inotify the file is checked every POLL_INTERVAL.
+//
+// Only some settings take effect without a restart: the `log_filter` of the
+// [server] section and the rate of the [rate_limit] section. A file which
+// fails to load is logged and the running configuration is kept.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// Interfaces to the C library
+// https://docs.rs/libc/latest/libc/
+// cargo add libc
+#[cfg(target_os = "linux")]
+use libc::{
+    inotify_add_watch, inotify_init1, IN_CLOEXEC, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_MOVED_TO,
+};
+use tracing::{error, info};
+
+/// How long changes are left to settle before the file is read
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// How often the file is checked without inotify
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Call `on_change` from a thread of its own each time the contents of the
+/// file at `path` change
+pub fn spawn_file_watch<F>(path: PathBuf, on_change: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut watch = Watch::new(&directory)?;
+    let mut contents = fs::read(&path).ok();
+    info!("Watching {} for changes", path.display());
+    thread::Builder::new()
+        .name(String::from("config-watch"))
+        .spawn(move || loop {
+            if let Err(err) = watch.wait() {
+                error!("stopped watching {}: {err}", path.display());
+                return;
+            }
+            // Editors and ConfigMap updates change files in several steps
+            thread::sleep(SETTLE);
+            let current = fs::read(&path).ok();
+            if current.is_some() && current != contents {
+                contents = current;
+                on_change();
+            }
+        })?;
+    Ok(())
+}
+
+// inotify watching a directory, the events themselves are not needed as the
+// file is compared to what it was
+// https://man7.org/linux/man-pages/man7/inotify.7.html
+#[cfg(target_os = "linux")]
+struct Watch {
+    inotify: fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl Watch {
+    fn new(directory: &Path) -> io::Result<Self> {
+        use std::ffi::CString;
+        use std::os::unix::{ffi::OsStrExt, io::FromRawFd};
+
+        let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owned from here, so it is closed on every path
+        let inotify = unsafe { fs::File::from_raw_fd(fd) };
+        let directory = CString::new(directory.as_os_str().as_bytes())?;
+        let mask = IN_CLOSE_WRITE | IN_MOVED_TO | IN_CREATE | IN_DELETE;
+        if unsafe { inotify_add_watch(fd, directory.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watch { inotify })
+    }
+
+    // Block until something in the directory changes
+    fn wait(&mut self) -> io::Result<()> {
+        use std::io::Read;
+
+        let mut events = [0; 4096];
+        match self.inotify.read(&mut events)? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Watch;
+
+#[cfg(not(target_os = "linux"))]
+impl Watch {
+    fn new(_directory: &Path) -> io::Result<Self> {
+        Ok(Watch)
+    }
+
+    fn wait(&mut self) -> io::Result<()> {
+        thread::sleep(POLL_INTERVAL);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_file_watch() {
+        let directory = std::env::temp_dir().join("adr_test_file_watch");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.toml");
+        std::fs::write(&path, "[server]\nport = 8888\n").unwrap();
+        let (sender, changes) = mpsc::channel();
+        spawn_file_watch(path.clone(), move || {
+            let _ = sender.send(());
+        })
+        .unwrap();
+
+        // Writing the same contents again is not a change
+        std::fs::write(&path, "[server]\nport = 8888\n").unwrap();
+        assert!(changes.recv_timeout(SETTLE * 5).is_err());
+
+        // A ConfigMap update replaces the file with another one
+        let update = directory.join("config.toml.tmp");
+        std::fs::write(&update, "[server]\nport = 8443\n").unwrap();
+        std::fs::rename(&update, &path).unwrap();
+        changes.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // Post test, remove any files created
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,449 @@
+// Write-behind queue, inserts are acknowledged before they are stored
+//
+// [write_queue]
+// capacity = 10000
+// batch_rows = 1000
+// flush_interval = "50ms"
+// adaptive = true
+// target_latency = "100ms"
+//
+// A write is checked and transformed as usual, then put on a bounded queue
+// and answered with 202 Accepted. A single writer task takes the queued
+// writes off in arrival order and stores them in batches, one transaction per
+// table, once `batch_rows` rows are waiting or `flush_interval` after the
+// first of them arrived. A full queue is answered with 503 so senders back
+// off and retry, as is a write which would go over the memory budget.
+//
+// With `adaptive` the batch size follows how fast the disk commits, so it
+// suits SD cards, SSDs and network disks alike. A batch committed within
+// `target_latency` while writes are still waiting grows the next batch by a
+// step, a slower commit halves it, never above `batch_rows` nor below
+// MIN_BATCH_ROWS. The current size is exported as `write_queue_batch_rows`.
+//
+// Each write is numbered by a ticket, its consistency token, which readers
+// can pass back to wait until the write is stored. Tickets start over when
+// the server restarts, which stores every queued write before it exits.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::rt::{self, task::JoinHandle, time};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error};
+
+use crate::errors::Error;
+use crate::forward::Forwarder;
+use crate::memory::{MemoryBudget, Reservation};
+use crate::metrics;
+use crate::storage::{NewRecord, Storage};
+
+/// The most failed tickets remembered for readers waiting on them
+const FAILED_TICKETS: usize = 10_000;
+
+/// The smallest adaptive batch
+const MIN_BATCH_ROWS: usize = 16;
+
+/// Adaptive batches grow from the smallest to `batch_rows` in this many steps
+const GROWTH_STEPS: usize = 20;
+
+/// How queued writes are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteQueueSettings {
+    /// The most writes waiting to be stored
+    pub capacity: usize,
+    /// Store a batch once this many rows are waiting
+    pub batch_rows: usize,
+    /// Store a batch at the latest this long after its first write arrived
+    pub flush_interval: Duration,
+    /// Adapt the batch size to keep commits within this long, `batch_rows`
+    /// is the largest batch then
+    pub target_latency: Option<Duration>,
+}
+
+/// Where a queued write is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TicketStatus {
+    Queued,
+    Stored,
+    Failed,
+}
+
+// A write waiting to be stored
+struct Queued {
+    ticket: u64,
+    database: String,
+    table: String,
+    records: Vec<NewRecord>,
+    // Counted against the memory budget until stored
+    _reservation: Reservation,
+}
+
+// How many rows the writer stores together, additive increase and
+// multiplicative decrease driven by how long commits take
+struct BatchSize {
+    rows: usize,
+    min: usize,
+    max: usize,
+    step: usize,
+    target_latency: Option<Duration>,
+}
+
+impl BatchSize {
+    fn new(settings: &WriteQueueSettings) -> Self {
+        let max = settings.batch_rows.max(1);
+        let min = MIN_BATCH_ROWS.min(max);
+        BatchSize {
+            rows: max,
+            min,
+            max,
+            step: ((max - min) / GROWTH_STEPS).max(1),
+            target_latency: settings.target_latency,
+        }
+    }
+
+    // Size the next batch after one took `latency` with `waiting` writes left
+    fn adjust(&mut self, latency: Duration, waiting: usize) {
+        let Some(target_latency) = self.target_latency else {
+            return;
+        };
+        if latency > target_latency {
+            self.rows = (self.rows / 2).max(self.min);
+        } else if waiting > 0 {
+            // Larger batches only help when writes are piling up
+            self.rows = (self.rows + self.step).min(self.max);
+        }
+    }
+}
+
+// The tickets the writer is done with
+#[derive(Default)]
+struct Progress {
+    // Every ticket up to this one was stored or failed
+    done: u64,
+    failed: VecDeque<u64>,
+}
+
+/// The queue in front of the storage backend
+pub struct WriteQueue {
+    // The next ticket, locked while its write is queued so tickets are queued
+    // in order, no sender once the queue is closed
+    sender: Mutex<(u64, Option<mpsc::Sender<Queued>>)>,
+    progress: Arc<Mutex<Progress>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    budget: MemoryBudget,
+}
+
+impl WriteQueue {
+    /// Start the writer task storing queued writes
+    pub fn start(
+        storage: Arc<dyn Storage>,
+        settings: WriteQueueSettings,
+        budget: MemoryBudget,
+        forwarder: Option<Forwarder>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(settings.capacity.max(1));
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let writer = rt::spawn(run(
+            storage,
+            receiver,
+            progress.clone(),
+            settings,
+            forwarder,
+        ));
+        WriteQueue {
+            sender: Mutex::new((1, Some(sender))),
+            progress,
+            writer: Mutex::new(Some(writer)),
+            budget,
+        }
+    }
+
+    /// Refuse new writes and wait up to `timeout` for the queued ones to be
+    /// stored, false when some were left
+    pub async fn close(&self, timeout: Duration) -> bool {
+        // The writer stops once the writes queued before the sender went are stored
+        self.sender.lock().unwrap().1.take();
+        let Some(writer) = self.writer.lock().unwrap().take() else {
+            return true;
+        };
+        match time::timeout(timeout, writer).await {
+            Ok(_) => true,
+            Err(_) => {
+                error!(
+                    "{} queued writes were not stored within {}",
+                    metrics::WRITE_QUEUE_DEPTH.get(),
+                    humantime::format_duration(timeout)
+                );
+                false
+            }
+        }
+    }
+
+    /// Queue the records of a write, the ticket of the write is returned
+    pub fn enqueue(
+        &self,
+        database: &str,
+        table: &str,
+        records: Vec<NewRecord>,
+    ) -> Result<u64, Error> {
+        let size = records
+            .iter()
+            .map(|record| size_of::<NewRecord>() + record.data.len())
+            .sum();
+        let reservation = self.budget.reserve(size)?;
+
+        let mut sender = self.sender.lock().unwrap();
+        let ticket = sender.0;
+        let Some(channel) = &sender.1 else {
+            return Err(Error::Unavailable(String::from(
+                "the write queue is closed",
+            )));
+        };
+        let queued = Queued {
+            ticket,
+            database: database.to_string(),
+            table: table.to_string(),
+            records,
+            _reservation: reservation,
+        };
+        match channel.try_send(queued) {
+            Ok(()) => {
+                sender.0 += 1;
+                metrics::WRITE_QUEUE_DEPTH.inc();
+                Ok(ticket)
+            }
+            Err(TrySendError::Full(_)) => Err(Error::Unavailable(String::from(
+                "the write queue is full, retry later",
+            ))),
+            Err(TrySendError::Closed(_)) => Err(Error::Unavailable(String::from(
+                "the write queue is closed",
+            ))),
+        }
+    }
+
+    /// Whether the write of a ticket was stored yet
+    pub fn status(&self, ticket: u64) -> TicketStatus {
+        let progress = self.progress.lock().unwrap();
+        if ticket > progress.done {
+            TicketStatus::Queued
+        } else if progress.failed.contains(&ticket) {
+            TicketStatus::Failed
+        } else {
+            TicketStatus::Stored
+        }
+    }
+}
+
+// Take queued writes off in batches until every sender is gone
+async fn run(
+    storage: Arc<dyn Storage>,
+    mut receiver: mpsc::Receiver<Queued>,
+    progress: Arc<Mutex<Progress>>,
+    settings: WriteQueueSettings,
+    forwarder: Option<Forwarder>,
+) {
+    let mut batch_size = BatchSize::new(&settings);
+    metrics::WRITE_QUEUE_BATCH_ROWS.set(batch_size.rows as i64);
+
+    // Wait for the first write of a batch
+    while let Some(first) = receiver.recv().await {
+        let deadline = time::Instant::now() + settings.flush_interval;
+        let mut rows = first.records.len();
+        let mut batch = vec![first];
+        while rows < batch_size.rows {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            match time::timeout(left, receiver.recv()).await {
+                Ok(Some(queued)) => {
+                    rows += queued.records.len();
+                    batch.push(queued);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+        metrics::WRITE_QUEUE_DEPTH.sub(batch.len() as i64);
+        let latency = flush(storage.as_ref(), batch, &progress, forwarder.as_ref()).await;
+
+        let previous = batch_size.rows;
+        batch_size.adjust(latency, receiver.len());
+        if batch_size.rows != previous {
+            debug!(
+                "write queue batches now {} rows, the last took {latency:?}",
+                batch_size.rows
+            );
+            metrics::WRITE_QUEUE_BATCH_ROWS.set(batch_size.rows as i64);
+        }
+    }
+}
+
+// Store a batch, a transaction per table in the order the tables arrived
+// How long storing it took is returned
+async fn flush(
+    storage: &dyn Storage,
+    batch: Vec<Queued>,
+    progress: &Mutex<Progress>,
+    forwarder: Option<&Forwarder>,
+) -> Duration {
+    let started = time::Instant::now();
+    let last_ticket = batch.last().map_or(0, |queued| queued.ticket);
+    let mut tables: Vec<(String, String, Vec<NewRecord>, Vec<u64>)> = vec![];
+    for queued in batch {
+        let table = tables
+            .iter_mut()
+            .find(|(database, table, _, _)| *database == queued.database && *table == queued.table);
+        match table {
+            Some((_, _, records, tickets)) => {
+                records.extend(queued.records);
+                tickets.push(queued.ticket);
+            }
+            None => tables.push((
+                queued.database,
+                queued.table,
+                queued.records,
+                vec![queued.ticket],
+            )),
+        }
+    }
+
+    let mut failed = vec![];
+    for (database, table, records, tickets) in tables {
+        let count = records.len();
+        let forwarded = forwarder
+            .filter(|forwarder| forwarder.forwards(&database, &table))
+            .map(|forwarder| (forwarder, records.clone()));
+        let started = time::Instant::now();
+        match storage.insert_batch(&database, &table, records).await {
+            Ok(inserted) => {
+                metrics::observe_insert(&database, &table, &inserted, started.elapsed());
+                if let Some((forwarder, records)) = forwarded {
+                    forwarder.send(&database, &table, &records, &inserted);
+                }
+                debug!("flushed {count} rows to {database}/{table}");
+            }
+            Err(err) => {
+                error!("unable to store {count} queued rows in {database}/{table}: {err}");
+                metrics::WRITE_QUEUE_FAILED_ROWS.inc_by(count as u64);
+                failed.extend(tickets);
+            }
+        }
+    }
+    let latency = started.elapsed();
+    metrics::WRITE_QUEUE_FLUSH_SECONDS.observe(latency.as_secs_f64());
+
+    let mut progress = progress.lock().unwrap();
+    progress.done = last_ticket;
+    progress.failed.extend(failed);
+    while progress.failed.len() > FAILED_TICKETS {
+        progress.failed.pop_front();
+    }
+    latency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{record, ChangesFilter, SqliteStorage};
+
+    #[actix_web::test]
+    async fn test_write_queue() {
+        let storage = Arc::new(SqliteStorage::new("./"));
+        let queue = WriteQueue::start(
+            storage.clone(),
+            WriteQueueSettings {
+                capacity: 2,
+                batch_rows: 100,
+                flush_interval: Duration::from_millis(20),
+                target_latency: None,
+            },
+            MemoryBudget::default(),
+            None,
+        );
+
+        // Writes are stored together once the flush interval is up
+        let first = queue
+            .enqueue("test_write_queue", "events", vec![record("{\"n\": 1}")])
+            .unwrap();
+        let second = queue
+            .enqueue("test_write_queue", "events", vec![record("{\"n\": 2}")])
+            .unwrap();
+        assert_eq!(second, first + 1);
+        assert_eq!(queue.status(second), TicketStatus::Queued);
+
+        // Full until the writer takes the batch
+        assert!(queue
+            .enqueue("test_write_queue", "events", vec![record("{\"n\": 3}")])
+            .is_err());
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(queue.status(second), TicketStatus::Stored);
+        let filter = ChangesFilter {
+            limit: 10,
+            ..Default::default()
+        };
+        let events = storage
+            .changes("test_write_queue", "events", &filter)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 2);
+
+        // A write which can't be stored fails its ticket
+        let ticket = queue
+            .enqueue("test_write_queue", "bad name", vec![record("{}")])
+            .unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(queue.status(ticket), TicketStatus::Failed);
+
+        // Closing stores what was queued and refuses more
+        let ticket = queue
+            .enqueue("test_write_queue", "events", vec![record("{\"n\": 4}")])
+            .unwrap();
+        assert!(queue.close(Duration::from_secs(5)).await);
+        assert_eq!(queue.status(ticket), TicketStatus::Stored);
+        assert!(queue
+            .enqueue("test_write_queue", "events", vec![record("{}")])
+            .is_err());
+
+        // Post test, remove any database files created
+        std::fs::remove_file("./test_write_queue.db").unwrap();
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let settings = WriteQueueSettings {
+            capacity: 10,
+            batch_rows: 1_016,
+            flush_interval: Duration::from_millis(50),
+            target_latency: Some(Duration::from_millis(100)),
+        };
+        let fast = Duration::from_millis(10);
+        let slow = Duration::from_millis(500);
+
+        // Slow commits halve the batch down to the smallest
+        let mut batch_size = BatchSize::new(&settings);
+        assert_eq!(batch_size.rows, 1_016);
+        batch_size.adjust(slow, 0);
+        assert_eq!(batch_size.rows, 508);
+        for _ in 0..10 {
+            batch_size.adjust(slow, 0);
+        }
+        assert_eq!(batch_size.rows, MIN_BATCH_ROWS);
+
+        // Fast commits grow it by a step while writes are waiting
+        batch_size.adjust(fast, 0);
+        assert_eq!(batch_size.rows, MIN_BATCH_ROWS);
+        batch_size.adjust(fast, 5);
+        assert_eq!(batch_size.rows, MIN_BATCH_ROWS + 50);
+        for _ in 0..100 {
+            batch_size.adjust(fast, 5);
+        }
+        assert_eq!(batch_size.rows, 1_016);
+
+        // A fixed batch size stays put
+        let mut batch_size = BatchSize::new(&WriteQueueSettings {
+            target_latency: None,
+            ..settings
+        });
+        batch_size.adjust(slow, 0);
+        assert_eq!(batch_size.rows, 1_016);
+    }
+}